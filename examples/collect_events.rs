@@ -0,0 +1,71 @@
+//! Runs the updater with everything it reports collected into a `Vec` instead of printed,
+//! the way a GUI would receive it before drawing its own log view and progress bar. Takes
+//! the updater's own arguments, from a directory with `sd_source` and `assets`:
+//!
+//! ```text
+//! cargo run --example collect_events -- --build-only
+//! ```
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use dolphin_auto_updater::logging::{set_output, set_quiet, Level, Output, Progress};
+use dolphin_auto_updater::options::Options;
+use dolphin_auto_updater::run;
+
+#[derive(Debug)]
+enum Event {
+    Message(Level, String),
+    Progress { label: String, current: u64, total: u64 },
+}
+
+/// A real frontend would send the events down a channel to its UI thread instead.
+struct Collect(Arc<Mutex<Vec<Event>>>);
+
+impl Output for Collect {
+    fn message(&mut self, level: Level, msg: &str) {
+        self.0.lock().unwrap().push(Event::Message(level, msg.to_string()));
+    }
+
+    fn progress(&mut self, progress: &Progress) {
+        self.0.lock().unwrap().push(Event::Progress {
+            label: progress.label.to_string(),
+            current: progress.current,
+            total: progress.total,
+        });
+    }
+}
+
+fn main() {
+    let options = match Options::from_args() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    set_quiet(options.quiet);
+    let events = Arc::new(Mutex::new(Vec::new()));
+    set_output(Some(Box::new(Collect(events.clone()))));
+    let result = run(&options, Path::new("sd_source"));
+    set_output(None);
+
+    let events = events.lock().unwrap();
+    let mut updates = 0;
+    for event in events.iter() {
+        match event {
+            Event::Message(level, msg) => print!("{:?}: {}", level, msg),
+            Event::Progress { label, current, total } => {
+                updates += 1;
+                if current == total {
+                    println!("{} finished at {}", label, total);
+                }
+            }
+        }
+    }
+    println!("{} events, {} of them progress", events.len(), updates);
+    match result {
+        Ok(outcome) => println!("{}", outcome.describe()),
+        Err(e) => println!("failed: {}", e),
+    }
+}
@@ -0,0 +1,137 @@
+//! Which `sd.xz` the image at `--output` was decompressed from, recorded in
+//! `<output>.asset`. `--incremental` and resuming only build on top of the image while
+//! that is still the `sd.xz` in use. When a new base image shipped, the one in the image
+//! is outdated, so it is decompressed again from the new one.
+//!
+//! Hashing a large `sd.xz` on every run would take a while, so the record keeps its size
+//! and modification time too, and it is only hashed again when those changed. The
+//! embedded `sd.xz` has neither and is always hashed.
+//!
+//! The record also names the commit of `sd_source` last copied into the image completely,
+//! which `delta.rs` diffs against to find what an update has to copy.
+
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+use crate::error::UpdateError;
+use crate::hash::hash_reader;
+use crate::image::{open_sd_xz, sd_xz_path};
+use crate::options::{HashAlgorithm, Options};
+use crate::debug;
+
+/// Where the record goes for a `--device`, which has no directory to put it next to.
+pub const DEVICE_ASSET_FILE: &str = ".updater.asset";
+
+#[derive(Clone, PartialEq, Eq)]
+struct Stamp {
+    size: u64,
+    /// Nanoseconds since the epoch.
+    modified: u128,
+    hash: String,
+    /// Unset while a build is copying into the image.
+    commit: Option<String>,
+}
+
+impl Stamp {
+    fn parse(contents: &str) -> Option<Stamp> {
+        let mut stamp = Stamp { size: 0, modified: 0, hash: String::new(), commit: None };
+        for line in contents.lines() {
+            match line.split_once('=')? {
+                ("size", value) => stamp.size = value.parse().ok()?,
+                ("modified", value) => stamp.modified = value.parse().ok()?,
+                ("hash", value) => stamp.hash = value.to_string(),
+                ("commit", value) => stamp.commit = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        (!stamp.hash.is_empty()).then_some(stamp)
+    }
+
+    fn render(&self) -> String {
+        let mut rendered = format!("size={}\nmodified={}\nhash={}\n", self.size, self.modified, self.hash);
+        if let Some(commit) = &self.commit {
+            rendered.push_str(&format!("commit={}\n", commit));
+        }
+        rendered
+    }
+}
+
+/// `<output>.asset` next to an image file.
+fn stamp_path(options: &Options) -> PathBuf {
+    if options.device.is_some() {
+        return PathBuf::from(DEVICE_ASSET_FILE);
+    }
+    let mut path = options.output().into_os_string();
+    path.push(".asset");
+    PathBuf::from(path)
+}
+
+fn recorded(options: &Options) -> Option<Stamp> {
+    std::fs::read_to_string(stamp_path(options)).ok().as_deref().and_then(Stamp::parse)
+}
+
+/// The stamp of the `sd.xz` in use, reusing the hash of `recorded` if size and
+/// modification time still match it.
+fn current(options: &Options, recorded: Option<&Stamp>) -> std::io::Result<Stamp> {
+    let (size, modified) = match sd_xz_path(options) {
+        Some(path) => {
+            let metadata = std::fs::metadata(path)?;
+            let modified = metadata.modified()?.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+            (metadata.len(), modified)
+        }
+        None => (0, 0),
+    };
+    if let Some(recorded) = recorded.filter(|recorded| size != 0 && recorded.size == size && recorded.modified == modified) {
+        return Ok(Stamp { commit: None, ..recorded.clone() });
+    }
+    let hash = hash_reader(&mut open_sd_xz(options)?, &mut vec![0_u8; 1024 * 1024], HashAlgorithm::Blake3)?;
+    Ok(Stamp { size, modified, hash, commit: None })
+}
+
+/// Whether the image was decompressed from the `sd.xz` in use. An image without a record,
+/// e.g. from before records were kept, is taken to be, and so is any image when `sd.xz`
+/// can't be read, since updating in place doesn't need it.
+pub fn is_current(options: &Options) -> bool {
+    let Some(recorded) = recorded(options) else {
+        debug("No record of which sd.xz the image was built from\n");
+        return true;
+    };
+    match current(options, Some(&recorded)) {
+        Ok(current) => current.hash == recorded.hash,
+        Err(e) => {
+            debug(format!("Can't check which sd.xz is in use: {}\n", e).as_str());
+            true
+        }
+    }
+}
+
+/// Records that the image was just decompressed from the `sd.xz` in use.
+pub fn record(options: &Options) -> Result<(), UpdateError> {
+    let stamp = current(options, recorded(options).as_ref())?;
+    std::fs::write(stamp_path(options), stamp.render())?;
+    Ok(())
+}
+
+/// The commit the image was last built from completely, if it is known.
+pub fn built_commit(options: &Options) -> Option<String> {
+    recorded(options)?.commit
+}
+
+/// Records the commit the image now holds, or with `None` that a build is copying into it
+/// and what it holds isn't known until it finishes. Nothing is recorded for an image
+/// without a record of its `sd.xz`.
+pub fn set_built_commit(options: &Options, commit: Option<&str>) -> std::io::Result<()> {
+    let Some(mut stamp) = recorded(options) else {
+        return Ok(());
+    };
+    stamp.commit = commit.map(str::to_string);
+    std::fs::write(stamp_path(options), stamp.render())
+}
+
+/// Drops the record, the image wasn't made from `sd.xz`.
+pub fn forget(options: &Options) -> std::io::Result<()> {
+    match std::fs::remove_file(stamp_path(options)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
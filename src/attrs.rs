@@ -0,0 +1,224 @@
+//! `--preserve-attrs`: carries the read-only, hidden and system attributes of source files
+//! over to the files on the card, for homebrew that looks for them.
+//!
+//! Read-only comes from the permissions on every platform, a file nobody may write to is
+//! read-only. Hidden and system come from the file attributes on Windows, and hidden from
+//! the `UF_HIDDEN` flag on macOS. Linux has neither, so only read-only carries over there.
+//! Directories keep what fatfs gave them, and so does the archive bit. Directories restored
+//! by `--preserve` went through a scratch directory and come back without attributes.
+//!
+//! fatfs can't set attributes, so they are written into the directory entries directly once
+//! the image is unmounted, the same way `--wipe-free` zeroes free clusters.
+
+use std::collections::HashMap;
+use std::fs::{File, Metadata};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::dirsort::sorted_entries;
+use crate::error::UpdateError;
+use crate::image::{exclusion, FileFilter};
+use crate::longpath::LongPaths;
+use crate::options::Options;
+use crate::wipe::{fat_entry, read_fat, read_layout, Layout};
+use crate::debug;
+
+const READ_ONLY: u8 = 0x01;
+const HIDDEN: u8 = 0x02;
+const SYSTEM: u8 = 0x04;
+const VOLUME_LABEL: u8 = 0x08;
+const DIRECTORY: u8 = 0x10;
+/// All four low bits set marks a long name entry.
+const LONG_NAME: u8 = 0x0F;
+/// The attributes taken from the source, the rest of the byte is left as it is.
+const CARRIED: u8 = READ_ONLY | HIDDEN | SYSTEM;
+
+/// Where the UTF-16 units of a long name entry's part of the name sit.
+const LONG_NAME_UNITS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// The FAT attributes of a source file.
+fn source_attributes(metadata: &Metadata) -> u8 {
+    #[allow(unused_mut)]
+    let mut attributes = if metadata.permissions().readonly() { READ_ONLY } else { 0 };
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::MetadataExt;
+        // Windows took these bits from FAT, they have the same values.
+        attributes |= metadata.file_attributes() as u8 & (HIDDEN | SYSTEM);
+    }
+    #[cfg(target_os = "macos")]
+    {
+        use std::os::macos::fs::MetadataExt;
+        const UF_HIDDEN: u32 = 0x8000;
+        if metadata.st_flags() & UF_HIDDEN != 0 {
+            attributes |= HIDDEN;
+        }
+    }
+    attributes
+}
+
+/// A directory entry on the image.
+struct RawEntry {
+    attributes: u8,
+    first_cluster: u32,
+    /// Where the 32 byte entry is in the image.
+    position: u64,
+}
+
+fn short_name(entry: &[u8]) -> String {
+    let base = String::from_utf8_lossy(&entry[..8]).trim_end().to_string();
+    let extension = String::from_utf8_lossy(&entry[8..11]).trim_end().to_string();
+    if extension.is_empty() {
+        base
+    } else {
+        format!("{}.{}", base, extension)
+    }
+}
+
+struct Walk<'a> {
+    image: &'a mut File,
+    layout: Layout,
+    fat: Vec<u8>,
+    long_paths: &'a LongPaths,
+    filter: FileFilter,
+    changed: u64,
+}
+
+impl Walk<'_> {
+    /// Where the directory starting at `first_cluster` lies in the image, or the fixed root
+    /// directory of FAT12 and FAT16 for `None`.
+    fn extents(&self, first_cluster: Option<u32>) -> Vec<(u64, u64)> {
+        let Some(mut cluster) = first_cluster else {
+            return vec![(self.layout.root_dir_start, self.layout.root_dir_bytes)];
+        };
+        let cluster_bytes = self.layout.bytes_per_sector * self.layout.sectors_per_cluster;
+        let mut extents = Vec::new();
+        // Bounded, so a loop in a corrupt FAT can't hang the build. End-of-chain markers are
+        // past the last cluster.
+        while (2..self.layout.clusters + 2).contains(&cluster) && extents.len() <= self.layout.clusters as usize {
+            extents.push((self.layout.data_start + (cluster as u64 - 2) * cluster_bytes, cluster_bytes));
+            cluster = fat_entry(&self.fat, self.layout.fat_type, cluster);
+        }
+        extents
+    }
+
+    /// The entries of a directory on the image, keyed by lowercased long name, or short
+    /// name where there is no long one.
+    fn read_dir(&mut self, first_cluster: Option<u32>) -> std::io::Result<HashMap<String, RawEntry>> {
+        let mut entries = HashMap::new();
+        let mut long_name: Vec<u16> = Vec::new();
+        let mut buffer = Vec::new();
+        for (start, len) in self.extents(first_cluster) {
+            buffer.resize(len as usize, 0);
+            self.image.seek(SeekFrom::Start(start))?;
+            self.image.read_exact(&mut buffer)?;
+            for (index, entry) in buffer.chunks(32).enumerate() {
+                match entry[0] {
+                    // Nothing follows the first free entry.
+                    0x00 => return Ok(entries),
+                    0xE5 => {
+                        long_name.clear();
+                        continue;
+                    }
+                    _ => {}
+                }
+                let attributes = entry[11];
+                if attributes == LONG_NAME {
+                    // The parts come last first, the first one says how many there are.
+                    let order = (entry[0] & 0x1F) as usize;
+                    if entry[0] & 0x40 != 0 {
+                        long_name = vec![0xFFFF; order * LONG_NAME_UNITS.len()];
+                    }
+                    let at = order.saturating_sub(1) * LONG_NAME_UNITS.len();
+                    if let Some(part) = long_name.get_mut(at..at + LONG_NAME_UNITS.len()) {
+                        for (unit, index) in part.iter_mut().zip(LONG_NAME_UNITS) {
+                            *unit = u16::from_le_bytes([entry[index], entry[index + 1]]);
+                        }
+                    }
+                    continue;
+                }
+                let name = if long_name.is_empty() {
+                    short_name(entry)
+                } else {
+                    // Terminated by a NUL and padded with 0xFFFF, unless it fills the last part.
+                    let end = long_name.iter().position(|&unit| unit == 0 || unit == 0xFFFF).unwrap_or(long_name.len());
+                    String::from_utf16_lossy(&long_name[..end])
+                };
+                long_name.clear();
+                if attributes & VOLUME_LABEL != 0 {
+                    continue;
+                }
+                let high = match self.layout.fat_type {
+                    fatfs::FatType::Fat32 => u16::from_le_bytes([entry[20], entry[21]]) as u32,
+                    _ => 0,
+                };
+                let first_cluster = high << 16 | u16::from_le_bytes([entry[26], entry[27]]) as u32;
+                entries.insert(name.to_lowercase(), RawEntry { attributes, first_cluster, position: start + index as u64 * 32 });
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Gives the files copied from `host_path` into the directory at `first_cluster` the
+    /// attributes of their source.
+    fn apply_dir(&mut self, host_path: &Path, first_cluster: Option<u32>, exclude: &[PathBuf]) -> Result<(), UpdateError> {
+        let entries = self.read_dir(first_cluster)?;
+        for path in sorted_entries(host_path)? {
+            let path = path?;
+            if exclusion(&path, exclude, self.long_paths, &self.filter)?.is_some() {
+                continue;
+            }
+            let name = match self.long_paths.get(&path) {
+                Some(Some(shorter)) => shorter.clone(),
+                _ => path.file_name().unwrap().to_string_lossy().to_string(),
+            };
+            // Not there if it failed to copy with --keep-going.
+            let Some(entry) = entries.get(&name.to_lowercase()) else {
+                continue;
+            };
+            // Follow symlinks, like the copy does.
+            let metadata = std::fs::metadata(&path)?;
+            if metadata.is_dir() {
+                if entry.attributes & DIRECTORY != 0 {
+                    self.apply_dir(&path, Some(entry.first_cluster), exclude)?;
+                }
+                continue;
+            }
+            let attributes = (entry.attributes & !CARRIED) | source_attributes(&metadata);
+            if attributes != entry.attributes {
+                debug(format!("Attributes {:#04x}: {}\n", attributes & CARRIED, path.display()).as_str());
+                self.image.seek(SeekFrom::Start(entry.position + 11))?;
+                self.image.write_all(&[attributes])?;
+                self.changed += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sets the attributes of the files copied from the source and the overlays, under
+/// `--target-dir` if given, on the filesystem starting `offset` bytes into `image`, which
+/// must be unmounted. Overlays go last, like in the copy, so the tree the file came from
+/// wins. Returns how many files changed.
+pub fn apply(image: &mut File, offset: u64, sd_source_path: &Path, options: &Options, long_paths: &LongPaths) -> Result<u64, UpdateError> {
+    let layout = read_layout(image, offset)?;
+    let fat = read_fat(image, &layout)?;
+    let mut root = match layout.fat_type {
+        fatfs::FatType::Fat32 => Some(layout.root_cluster),
+        _ => None,
+    };
+    let mut walk = Walk { image, layout, fat, long_paths, filter: FileFilter::new(options), changed: 0 };
+    // The copy went under `--target-dir`, see `in_target_dir`.
+    for dir in options.target_dir.iter().flat_map(|target_dir| target_dir.split('/')) {
+        match walk.read_dir(root)?.get(&dir.to_lowercase()) {
+            Some(entry) if entry.attributes & DIRECTORY != 0 => root = Some(entry.first_cluster),
+            _ => return Ok(0),
+        }
+    }
+    let exclude: Vec<PathBuf> = options.exclude.iter().map(|path| sd_source_path.join(path)).collect();
+    walk.apply_dir(sd_source_path, root, &exclude)?;
+    for overlay in &options.overlays {
+        walk.apply_dir(overlay, root, &[])?;
+    }
+    Ok(walk.changed)
+}
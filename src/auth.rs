@@ -0,0 +1,65 @@
+//! Credentials for private upstreams. git2 asks again after every rejected attempt, so each
+//! method is tried once, in order: the SSH agent, the configured git credential helper,
+//! which is where access tokens usually live, and finally a prompt on the terminal when
+//! someone is there to answer it. Without a terminal the operation fails as before.
+
+use std::io::IsTerminal;
+
+use git2::{Cred, CredentialType, RemoteCallbacks};
+
+use crate::logging::is_quiet;
+
+/// Which methods were already tried for this operation.
+#[derive(Default)]
+struct Attempts {
+    agent: bool,
+    helper: bool,
+    prompt: bool,
+}
+
+/// Whether a person can answer a prompt. `--quiet` runs are for scripts, so they never get one.
+fn is_interactive() -> bool {
+    std::io::stdin().is_terminal() && std::io::stdout().is_terminal() && !is_quiet()
+}
+
+fn prompt(url: &str, username: Option<&str>) -> Result<Cred, git2::Error> {
+    let to_git_error = |e: std::io::Error| git2::Error::from_str(&format!("Can't read credentials from the terminal: {}", e));
+    let username = match username {
+        Some(username) => username.to_string(),
+        None => {
+            eprint!("Username for {}: ", url);
+            let mut username = String::new();
+            std::io::stdin().read_line(&mut username).map_err(to_git_error)?;
+            username.trim().to_string()
+        }
+    };
+    let password = rpassword::prompt_password(format!("Password or token for {}@{}: ", username, url)).map_err(to_git_error)?;
+    Cred::userpass_plaintext(&username, &password)
+}
+
+/// Adds the credentials callback to `callbacks`.
+pub fn add_credentials(callbacks: &mut RemoteCallbacks) {
+    let mut attempts = Attempts::default();
+    callbacks.credentials(move |url, username, allowed| {
+        if allowed.contains(CredentialType::USERNAME) {
+            return Cred::username(username.unwrap_or("git"));
+        }
+        if allowed.contains(CredentialType::SSH_KEY) && !attempts.agent {
+            attempts.agent = true;
+            return Cred::ssh_key_from_agent(username.unwrap_or("git"));
+        }
+        if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            if !attempts.helper {
+                attempts.helper = true;
+                if let Ok(cred) = git2::Config::open_default().and_then(|config| Cred::credential_helper(&config, url, username)) {
+                    return Ok(cred);
+                }
+            }
+            if !attempts.prompt && is_interactive() {
+                attempts.prompt = true;
+                return prompt(url, username);
+            }
+        }
+        Err(git2::Error::from_str(&format!("No credentials were accepted by {}", url)))
+    });
+}
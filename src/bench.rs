@@ -0,0 +1,138 @@
+//! `--bench`: times the decompression and the copy on generated fixtures, so changes to
+//! buffer sizes or the fatfs interaction can be measured against a reproducible number.
+//!
+//! Everything is generated in a scratch directory in `--tmpdir`: an empty FAT image compressed into an
+//! `sd.xz`, and a source tree of `--bench-files` files. File sizes halve from
+//! `--bench-file-size` down to 1/128th of it and then start over, so the tree has a few
+//! large files and many small ones like a real build. The rest of the options, like
+//! `--max-memory`, `--mmap-threshold`, `--fs-buffer` or `--verify`, apply as usual, so
+//! running it with and without one compares the two.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+use fatfs::StdIoWrapper;
+use fscommon::BufStream;
+
+use crate::error::UpdateError;
+use crate::image::build;
+use crate::options::Options;
+use crate::scratch::ScratchDir;
+use crate::units::format_bytes;
+use crate::{format, info, report};
+
+/// Files per directory of the generated tree.
+const FILES_PER_DIR: u64 = 100;
+
+fn file_size(options: &Options, index: u64) -> u64 {
+    (options.bench_file_size() >> (index % 8)).max(1)
+}
+
+fn source_bytes(options: &Options) -> u64 {
+    (0..options.bench_files()).map(|index| file_size(options, index)).sum()
+}
+
+/// Room for the files, their directories and the FAT, rounded up to whole megabytes.
+fn image_size(source_bytes: u64) -> u64 {
+    (source_bytes + source_bytes / 4 + 32 * 1024 * 1024).div_ceil(1024 * 1024) * 1024 * 1024
+}
+
+/// Writes the source tree and returns its total size.
+fn generate_source(source: &Path, options: &Options) -> Result<u64, UpdateError> {
+    let mut total = 0;
+    let mut contents = Vec::new();
+    for index in 0..options.bench_files() {
+        let dir = source.join(format!("dir{:04}", index / FILES_PER_DIR));
+        std::fs::create_dir_all(&dir)?;
+        let size = file_size(options, index);
+        // Varied contents, so nothing along the way can take a shortcut on zeroes.
+        contents.clear();
+        contents.extend((0..size).map(|i| (i.wrapping_mul(31).wrapping_add(index) % 251) as u8));
+        File::create(dir.join(format!("file{:06}.bin", index)))?.write_all(&contents)?;
+        total += size;
+    }
+    Ok(total)
+}
+
+/// Writes `assets/sd.xz` holding an empty FAT filesystem of `size` bytes, formatted with
+/// `--cluster-size` so cluster sizes can be compared.
+fn generate_sd_xz(assets: &Path, size: u64, options: &Options) -> Result<(), UpdateError> {
+    std::fs::create_dir_all(assets)?;
+    let raw_path = assets.join("base.raw");
+    let raw = File::create(&raw_path)?;
+    raw.set_len(size)?;
+    let mut storage = StdIoWrapper::from(BufStream::new(raw));
+    fatfs::format_volume(&mut storage, format::format_options(options))?;
+    drop(storage);
+    let mut encoder = xz2::write::XzEncoder::new(File::create(assets.join("sd.xz"))?, 6);
+    std::io::copy(&mut File::open(&raw_path)?, &mut encoder)?;
+    encoder.finish()?;
+    std::fs::remove_file(raw_path)?;
+    Ok(())
+}
+
+/// How long the last run of the phase called `name` took.
+fn last_phase(name: &str) -> Duration {
+    report::phases()
+        .into_iter()
+        .rev()
+        .find(|(phase, _)| *phase == name)
+        .map(|(_, elapsed)| elapsed)
+        .unwrap_or_default()
+}
+
+fn per_second(amount: f64, elapsed: Duration) -> f64 {
+    amount / elapsed.as_secs_f64().max(f64::EPSILON)
+}
+
+fn run_bench(temp: &Path, options: &Options) -> Result<(), UpdateError> {
+    let source = temp.join("sd_source");
+    let assets = temp.join("assets");
+    info(format!("Generating {} files in {}\n", options.bench_files(), temp.display()).as_str());
+    let source_bytes = generate_source(&source, options)?;
+    let sd_size = image_size(source_bytes);
+    generate_sd_xz(&assets, sd_size, options)?;
+    info(format!("Benchmarking {} of files in a {} image\n", format_bytes(source_bytes), format_bytes(sd_size)).as_str());
+
+    let mut bench_options = options.clone();
+    bench_options.assets_dir = Some(assets);
+    bench_options.output = Some(temp.join("sd.raw"));
+    bench_options.sd_size = Some(sd_size);
+    bench_options.device = None;
+    bench_options.dest_dir = None;
+    bench_options.incremental = false;
+    bench_options.overlays = Vec::new();
+    bench_options.preserve = Some(Vec::new());
+    bench_options.pre_build_hook = None;
+    bench_options.post_build_hook = None;
+    build(&source, &bench_options)?;
+
+    let decompress = last_phase("decompress");
+    let copy = last_phase("copy");
+    const MB: f64 = 1024.0 * 1024.0;
+    info(format!(
+        "Decompress: {} in {:.2}s, {:.1} MB/s\n",
+        format_bytes(sd_size),
+        decompress.as_secs_f64(),
+        per_second(sd_size as f64 / MB, decompress)
+    ).as_str());
+    info(format!(
+        "Copy: {} files, {} in {:.2}s, {:.1} MB/s, {:.0} files/s\n",
+        options.bench_files(),
+        format_bytes(source_bytes),
+        copy.as_secs_f64(),
+        per_second(source_bytes as f64 / MB, copy),
+        per_second(options.bench_files() as f64, copy)
+    ).as_str());
+    Ok(())
+}
+
+/// Runs the benchmark in a scratch directory, which is removed again afterwards.
+pub fn bench(options: &Options) -> Result<(), UpdateError> {
+    let source_bytes = source_bytes(options);
+    // The source, and the base image twice: once before it is compressed and once built.
+    let scratch = ScratchDir::create("bench", source_bytes + 2 * image_size(source_bytes))?;
+    run_bench(scratch.path(), options)
+}
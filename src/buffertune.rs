@@ -0,0 +1,89 @@
+//! `--auto-buffer`: picks the size of the copy and decompression buffers by trying a few
+//! at the start of each, instead of the fixed 8M and 1M that suit some storage far better
+//! than others. Each size in `--buffer-range`, growing four times at a step, gets a short
+//! trial, then the fastest one is used for the rest.
+//!
+//! A copy or decompression too short to try them all settles on the fastest it tried.
+
+use std::time::{Duration, Instant};
+
+use crate::units::format_bytes;
+use crate::{debug, info};
+
+/// How long each size is tried for.
+const TRIAL: Duration = Duration::from_millis(750);
+
+pub(crate) struct BufferTuner {
+    /// `copy` or `decompression`, for the log.
+    what: &'static str,
+    /// The sizes to try, smallest first.
+    candidates: Vec<usize>,
+    /// Bytes per second each size tried so far reached, in the order of `candidates`.
+    rates: Vec<f64>,
+    /// Started by the first chunk of a trial, which isn't counted since it was read
+    /// before the clock ran.
+    trial_started: Option<Instant>,
+    trial_bytes: u64,
+    settled: Option<usize>,
+}
+
+impl BufferTuner {
+    /// Tries sizes from `min` to `max`, both multiples of 4096.
+    pub(crate) fn new(what: &'static str, (min, max): (usize, usize)) -> BufferTuner {
+        let mut candidates = Vec::new();
+        let mut size = min;
+        while size < max {
+            candidates.push(size);
+            size *= 4;
+        }
+        candidates.push(max);
+        BufferTuner { what, candidates, rates: Vec::new(), trial_started: None, trial_bytes: 0, settled: None }
+    }
+
+    /// The size the buffer should be for the next chunk.
+    pub(crate) fn size(&self) -> usize {
+        self.settled.unwrap_or(self.candidates[self.rates.len()])
+    }
+
+    /// Counts a chunk of `bytes` moved through a buffer of `size()`.
+    pub(crate) fn record(&mut self, bytes: u64) {
+        if self.settled.is_some() {
+            return;
+        }
+        let Some(started) = self.trial_started else {
+            self.trial_started = Some(Instant::now());
+            return;
+        };
+        self.trial_bytes += bytes;
+        let elapsed = started.elapsed();
+        if elapsed < TRIAL {
+            return;
+        }
+        let rate = self.trial_bytes as f64 / elapsed.as_secs_f64();
+        debug(format!("A {} {} buffer ran at {}/s\n", format_bytes(self.size() as u64), self.what, format_bytes(rate as u64)).as_str());
+        self.rates.push(rate);
+        self.trial_started = None;
+        self.trial_bytes = 0;
+        if self.rates.len() == self.candidates.len() {
+            self.settle();
+        }
+    }
+
+    /// Settles on the fastest size tried, if that didn't happen yet.
+    pub(crate) fn finish(&mut self) {
+        if self.settled.is_none() && !self.rates.is_empty() {
+            self.settle();
+        }
+    }
+
+    fn settle(&mut self) {
+        let (best, rate) = self
+            .rates
+            .iter()
+            .enumerate()
+            .fold((0, 0.0), |best, (i, rate)| if *rate > best.1 { (i, *rate) } else { best });
+        let size = self.candidates[best];
+        self.settled = Some(size);
+        info(format!("Tuned the {} buffer to {}, the fastest of {} tried at {}/s\n", self.what, format_bytes(size as u64), self.rates.len(), format_bytes(rate as u64)).as_str());
+    }
+}
@@ -0,0 +1,66 @@
+//! `--include-manifest-in-image <path>`: a small JSON file on the card saying what built
+//! it: the commit, when, the updater version and where the source came from. Every build
+//! writes it again, last. The updater owns it, so it is left out of `--compare`, `verify`
+//! and `--diff-image`: it isn't in the source, and no two builds write the same one.
+
+use std::time::SystemTime;
+
+use crate::dest::{DestDir, DestFile};
+use crate::error::UpdateError;
+use crate::image::create_dir_path;
+use crate::options::Options;
+use crate::report::json_string;
+use crate::timestamps::format_utc;
+use crate::debug;
+
+/// `url` without a user name or token in it, those don't belong on a card anyone can read.
+pub(crate) fn without_credentials(url: &str) -> String {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return url.to_string();
+    };
+    let host_end = rest.find('/').unwrap_or(rest.len());
+    match rest[..host_end].rfind('@') {
+        Some(at) => format!("{}://{}", scheme, &rest[at + 1..]),
+        None => url.to_string(),
+    }
+}
+
+fn to_json(options: &Options, commit: Option<&str>) -> String {
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or_default();
+    format!(
+        "{{\"commit\":{},\"built\":{},\"updater_version\":{},\"source_url\":{}}}\n",
+        commit.map(json_string).unwrap_or_else(|| "null".to_string()),
+        json_string(&format_utc(now as i64)),
+        json_string(env!("CARGO_PKG_VERSION")),
+        json_string(&without_credentials(&options.url()))
+    )
+}
+
+/// Writes the build metadata to `path`, relative to `root_dir` with `/` between the
+/// directories, which are created if they aren't there.
+pub(crate) fn write<D: DestDir>(root_dir: &D, options: &Options, path: &str, commit: Option<&str>) -> Result<(), UpdateError> {
+    let parent;
+    let (sd_folder, name) = match path.rsplit_once('/') {
+        Some((dir, name)) => {
+            parent = create_dir_path(root_dir, dir)?;
+            (&parent, name)
+        }
+        None => (root_dir, path),
+    };
+    let existing = sd_folder.entries()?.remove(&name.to_lowercase());
+    if existing.as_ref().is_some_and(|entry| entry.is_dir) {
+        return Err(UpdateError::Other(format!("--include-manifest-in-image {} is a directory on the card", path)));
+    }
+    let contents = to_json(options, commit);
+    let mut sd_file = sd_folder.create_file(name, existing.is_some())?;
+    let mut written = 0;
+    while written < contents.len() {
+        match sd_file.write(&contents.as_bytes()[written..])? {
+            0 => return Err(UpdateError::DiskFull(format!("No room on the card for --include-manifest-in-image {}", path))),
+            bytes => written += bytes,
+        }
+    }
+    sd_file.finish(SystemTime::now())?;
+    debug(format!("Wrote the build metadata to {}\n", path).as_str());
+    Ok(())
+}
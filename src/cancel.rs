@@ -0,0 +1,41 @@
+//! Ctrl+C for work that can stop cleanly part way, like `--watch` between cycles or a long
+//! `verify`. The first Ctrl+C only asks, the work checks `requested` where it can stop and
+//! reports how far it got. A second one stops right away.
+//!
+//! There is one handler per process, so the first caller's message is the one shown. A
+//! request is cleared when the next run starts, so one that stopped a `verify` doesn't stop
+//! the next run in the same process before it began.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Once;
+
+use crate::error::UpdateError;
+use crate::warn;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+static INSTALLED: Once = Once::new();
+
+/// Catches Ctrl+C from here on, warning with `message` the first time it is pressed.
+pub(crate) fn catch_ctrl_c(message: &'static str) -> Result<(), UpdateError> {
+    let mut result = Ok(());
+    INSTALLED.call_once(|| {
+        result = ctrlc::set_handler(move || {
+            if REQUESTED.swap(true, Ordering::SeqCst) {
+                std::process::exit(130);
+            }
+            warn(message);
+        })
+        .map_err(|e| UpdateError::Other(format!("Can't handle Ctrl+C: {}", e)));
+    });
+    result
+}
+
+/// Whether Ctrl+C was pressed.
+pub(crate) fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Forgets a Ctrl+C that stopped an earlier run.
+pub(crate) fn reset() {
+    REQUESTED.store(false, Ordering::SeqCst);
+}
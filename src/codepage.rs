@@ -0,0 +1,85 @@
+//! OEM code pages for FAT short (8.3) names.
+//!
+//! Only short names go through the code page: fatfs also writes a long name entry in
+//! UTF-16 for anything that isn't plain 8.3, and that keeps every character. Dolphin's
+//! own FAT code and libfat on the Wii read the long names, so the code page only matters
+//! to readers that ignore them. Those almost always assume 437, the DOS and US Windows
+//! default; 850 is the Western European alternative.
+
+use fatfs::OemCpConverter;
+
+/// Characters 0x80-0xFF of code page 437.
+const CP437_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{A0}',
+];
+
+/// Characters 0x80-0xFF of code page 850.
+const CP850_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', 'ø', '£', 'Ø', '×', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '®', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', 'Á', 'Â', 'À', '©', '╣', '║', '╗', '╝', '¢', '¥', '┐',
+    '└', '┴', '┬', '├', '─', '┼', 'ã', 'Ã', '╚', '╔', '╩', '╦', '╠', '═', '╬', '¤',
+    'ð', 'Ð', 'Ê', 'Ë', 'È', 'ı', 'Í', 'Î', 'Ï', '┘', '┌', '█', '▄', '¦', 'Ì', '▀',
+    'Ó', 'ß', 'Ô', 'Ò', 'õ', 'Õ', 'µ', 'þ', 'Þ', 'Ú', 'Û', 'Ù', 'ý', 'Ý', '¯', '´',
+    '\u{AD}', '±', '‗', '¾', '¶', '§', '÷', '¸', '°', '¨', '·', '¹', '³', '²', '■', '\u{A0}',
+];
+
+/// The code page used for short names. A single type for all of them keeps the
+/// `FileSystem` type the same whichever one is picked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CodePage {
+    /// ASCII only, which is all short names got before this was configurable.
+    #[default]
+    Lossy,
+    Cp437,
+    Cp850,
+}
+
+impl CodePage {
+    pub fn parse(value: &str) -> Option<CodePage> {
+        match value.trim() {
+            "lossy" | "ascii" => Some(CodePage::Lossy),
+            "437" | "cp437" => Some(CodePage::Cp437),
+            "850" | "cp850" => Some(CodePage::Cp850),
+            _ => None,
+        }
+    }
+
+    fn high_half(&self) -> Option<&'static [char; 128]> {
+        match self {
+            CodePage::Lossy => None,
+            CodePage::Cp437 => Some(&CP437_HIGH),
+            CodePage::Cp850 => Some(&CP850_HIGH),
+        }
+    }
+}
+
+impl OemCpConverter for CodePage {
+    fn decode(&self, oem_char: u8) -> char {
+        if oem_char < 0x80 {
+            return oem_char as char;
+        }
+        match self.high_half() {
+            Some(table) => table[(oem_char - 0x80) as usize],
+            None => '\u{FFFD}',
+        }
+    }
+
+    fn encode(&self, uni_char: char) -> Option<u8> {
+        if uni_char.is_ascii() {
+            return Some(uni_char as u8);
+        }
+        self.high_half()?
+            .iter()
+            .position(|&c| c == uni_char)
+            .map(|index| 0x80 + index as u8)
+    }
+}
@@ -0,0 +1,150 @@
+//! `--require-signed-commit`: refuse to build unless the commit `sd_source` is at carries a
+//! valid signature from a trusted key. Where `--require-signature` checks a manifest that
+//! upstream signs on the side, this ties trust to the commit itself.
+//!
+//! git keeps the signature in the commit, and checking it is left to the tools git uses:
+//! `gpg` for OpenPGP signatures, against the keys in `--trusted-gpg-keyring` (as written by
+//! `gpg --export`), and `ssh-keygen` for SSH signatures, against the `--trusted-ssh-signers`
+//! file (the `allowed_signers` format of git's `gpg.ssh.allowedSignersFile`). Neither sees
+//! the keys of whoever runs the updater, only the ones given.
+
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+use git2::Repository;
+
+use crate::error::UpdateError;
+use crate::info;
+use crate::options::Options;
+use crate::scratch::ScratchDir;
+
+const PGP_ARMOR: &str = "-----BEGIN PGP SIGNATURE-----";
+const SSH_ARMOR: &str = "-----BEGIN SSH SIGNATURE-----";
+
+fn untrusted(message: String) -> UpdateError {
+    UpdateError::UntrustedSource(message)
+}
+
+/// Runs a verifier with `data` on its stdin.
+fn run_verifier(mut command: Command, data: &[u8]) -> Result<Output, UpdateError> {
+    let program = command.get_program().to_string_lossy().to_string();
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| UpdateError::Other(format!("Can't run {} to check the commit signature: {}", program, e)))?;
+    // A verifier that gives up early closes its stdin, its exit status says why.
+    let _ = child.stdin.take().unwrap().write_all(data);
+    Ok(child.wait_with_output()?)
+}
+
+/// Checks an OpenPGP signature with `gpg` in a throwaway home directory, and returns the
+/// signer and the fingerprint of their key.
+fn verify_gpg(keyring: &Path, signature: &Path, data: &[u8], home: &Path) -> Result<(String, String), UpdateError> {
+    let mut gpg = Command::new("gpg");
+    gpg.arg("--homedir").arg(home);
+    gpg.args(["--batch", "--no-default-keyring", "--status-fd", "1", "--keyring"]).arg(keyring);
+    gpg.arg("--verify").arg(signature).arg("-");
+    let output = run_verifier(gpg, data)?;
+    let mut signer = None;
+    let mut key = None;
+    // The status lines are gpg's machine-readable output, see doc/DETAILS in GnuPG.
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let Some(line) = line.strip_prefix("[GNUPG:] ") else {
+            continue;
+        };
+        let (keyword, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let first = rest.split(' ').next().unwrap_or_default();
+        match keyword {
+            "GOODSIG" => signer = rest.split_once(' ').map(|(_, user)| user.to_string()),
+            "VALIDSIG" => key = Some(first.to_string()),
+            "ERRSIG" => return Err(untrusted(format!("HEAD is signed with key {}, which isn't in --trusted-gpg-keyring", first))),
+            "BADSIG" => return Err(untrusted("The signature on HEAD doesn't match the commit".to_string())),
+            "EXPKEYSIG" | "REVKEYSIG" => {
+                return Err(untrusted(format!("HEAD is signed with key {}, which has expired or was revoked", first)));
+            }
+            _ => {}
+        }
+    }
+    match (signer, key) {
+        (Some(signer), Some(key)) if output.status.success() => Ok((signer, key)),
+        _ => Err(untrusted(format!(
+            "gpg couldn't check the signature on HEAD: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))),
+    }
+}
+
+/// Checks an SSH signature with `ssh-keygen`, and returns the signer and their key.
+fn verify_ssh(signers: &Path, signature: &Path, data: &[u8]) -> Result<(String, String), UpdateError> {
+    // Verifying needs to be told who signed, which the signers file says by key.
+    let mut find = Command::new("ssh-keygen");
+    find.args(["-Y", "find-principals", "-f"]).arg(signers).arg("-s").arg(signature);
+    let found = run_verifier(find, &[])?;
+    let principal = String::from_utf8_lossy(&found.stdout)
+        .lines()
+        .next()
+        .map(|line| line.trim().to_string())
+        .filter(|principal| found.status.success() && !principal.is_empty())
+        .ok_or_else(|| untrusted("HEAD is signed with an SSH key that isn't in --trusted-ssh-signers".to_string()))?;
+    let mut verify = Command::new("ssh-keygen");
+    verify.args(["-Y", "verify", "-n", "git", "-f"]).arg(signers).arg("-I").arg(&principal).arg("-s").arg(signature);
+    let output = run_verifier(verify, data)?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() {
+        return Err(untrusted(format!(
+            "The SSH signature on HEAD doesn't verify: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    // Good "git" signature for <principal> with ED25519 key SHA256:...
+    let key = stdout.split_once(" with ").map(|(_, key)| key.trim().to_string()).unwrap_or_default();
+    Ok((principal, key))
+}
+
+/// Checks that the commit `sd_source_path` is at is signed by a key in
+/// `--trusted-gpg-keyring` or `--trusted-ssh-signers`, and logs who signed it.
+pub fn verify_head(sd_source_path: &Path, options: &Options) -> Result<(), UpdateError> {
+    let repo = Repository::open(sd_source_path)
+        .map_err(|_| untrusted(format!("{} isn't a git checkout, there's no commit to check the signature of", sd_source_path.display())))?;
+    let head = repo.head()?.peel_to_commit()?.id();
+    let id = head.to_string();
+    let short = &id[..8];
+    let (signature, data) = match repo.extract_signature(&head, None) {
+        Ok(parts) => parts,
+        Err(e) if e.code() == git2::ErrorCode::NotFound => {
+            return Err(untrusted(format!(
+                "Commit {} isn't signed, so there's nothing to verify. If upstream doesn't sign its commits, \
+                 --require-signed-commit can't be used with it",
+                short
+            )));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let scratch = ScratchDir::create("commitsig", 0)?;
+    let signature_path = scratch.path().join("HEAD.sig");
+    std::fs::write(&signature_path, &*signature)?;
+    let armor = signature.as_str().unwrap_or_default().trim_start();
+    let (signer, key) = if armor.starts_with(SSH_ARMOR) {
+        let signers = options.trusted_ssh_signers.as_ref().ok_or_else(|| {
+            untrusted(format!("Commit {} is signed with an SSH key, but there's no --trusted-ssh-signers to check it against", short))
+        })?;
+        verify_ssh(signers, &signature_path, &data)?
+    } else if armor.starts_with(PGP_ARMOR) {
+        let keyring = options.trusted_gpg_keyring.as_ref().ok_or_else(|| {
+            untrusted(format!("Commit {} is signed with OpenPGP, but there's no --trusted-gpg-keyring to check it against", short))
+        })?;
+        // gpg looks for a keyring given without a directory in its home directory.
+        let keyring = std::fs::canonicalize(keyring)
+            .map_err(|e| UpdateError::Usage(format!("--trusted-gpg-keyring {}: {}", keyring.display(), e)))?;
+        let home = scratch.path().join("gnupg");
+        std::fs::create_dir(&home)?;
+        verify_gpg(&keyring, &signature_path, &data, &home)?
+    } else {
+        return Err(untrusted(format!("Commit {} carries a signature that is neither OpenPGP nor SSH, it can't be verified", short)));
+    };
+    info(format!("Commit {} is signed by {} with the trusted key {}\n", short, signer, key).as_str());
+    Ok(())
+}
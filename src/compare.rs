@@ -0,0 +1,521 @@
+//! `--compare`: what an incremental build would change, worked out from the image that is
+//! actually there rather than from what the last build is believed to have written.
+//!
+//! `verify` runs the same pass as a check of a card suspected to be corrupt: every file of
+//! the source has to be on it with the same contents. Both work on the image at `--output`
+//! or on a `--dest-dir`. `--sample-verify` checks a random share of the files instead, for
+//! a quick check. The sample is picked from a seed that is logged, and `--sample-seed`
+//! picks the same files again. Both show how many files and bytes are checked so far, and
+//! stop at Ctrl+C with what they found up to there.
+//!
+//! `--diff-image` compares two built images file by file instead, say from two machines
+//! that should have produced the same build.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use fatfs::FileSystem;
+
+use crate::cancel::{self, catch_ctrl_c};
+use crate::dest::{DestDir, DestFile, HostDir};
+use crate::error::UpdateError;
+use crate::hash::{hash_reader, ContentHasher};
+use crate::image::{exclusion, open_fs_region, source_root, FileFilter};
+use crate::longpath::{self, LongPaths};
+use crate::options::{HashAlgorithm, Options};
+use crate::policy::Action;
+use crate::progress::ProgressBar;
+use crate::{debug, info, packaging, partition, warn};
+
+/// A file or directory of the source, keyed by its lowercase path since FAT names are
+/// case-insensitive.
+struct SourceEntry {
+    path: String,
+    host_path: PathBuf,
+    is_dir: bool,
+    len: u64,
+    /// Left out of a `--sample-verify` sample, neither checked nor reported.
+    skipped: bool,
+}
+
+/// Adds everything under `host_path` to `entries`, replacing what an earlier tree put at
+/// the same path, the same way overlays replace source files. Leaves out and renames what
+/// the copy does.
+fn collect_source(
+    host_path: &Path,
+    prefix: &str,
+    exclude: &[PathBuf],
+    long_paths: &LongPaths,
+    filter: &FileFilter,
+    entries: &mut BTreeMap<String, SourceEntry>,
+) -> std::io::Result<()> {
+    for entry in host_path.read_dir()? {
+        let entry = entry?;
+        if exclusion(&entry.path(), exclude, long_paths, filter)?.is_some() {
+            continue;
+        }
+        let name = match long_paths.get(&entry.path()) {
+            Some(Some(shorter)) => shorter.clone(),
+            _ => entry.file_name().to_string_lossy().to_string(),
+        };
+        let path = format!("{}{}", prefix, name);
+        // Follow symlinks, like the copy does.
+        let metadata = std::fs::metadata(entry.path())?;
+        let is_dir = metadata.is_dir();
+        entries.insert(
+            path.to_lowercase(),
+            SourceEntry { path: path.clone(), host_path: entry.path(), is_dir, len: metadata.len(), skipped: false },
+        );
+        if is_dir {
+            collect_source(&entry.path(), &format!("{}/", path), exclude, long_paths, filter, entries)?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct Differences {
+    /// On the card with the same contents as in the source.
+    matching: Vec<String>,
+    /// Different on the card.
+    changed: Vec<String>,
+    /// In the source but not on the card.
+    missing: Vec<String>,
+    /// On the card but not in the source. A build never deletes these.
+    card_only: Vec<String>,
+    /// How many files of the source there are to check.
+    files: usize,
+    /// Ctrl+C stopped the walk, so the files not looked at yet aren't in `missing`.
+    cancelled: bool,
+}
+
+impl Differences {
+    /// How many files were read back and checked.
+    fn checked(&self) -> usize {
+        self.matching.len() + self.changed.len()
+    }
+}
+
+fn compare_dir<D: DestDir>(
+    sd_folder: &D,
+    prefix: &str,
+    source: &mut BTreeMap<String, SourceEntry>,
+    differences: &mut Differences,
+    buffer: &mut [u8],
+    hash: HashAlgorithm,
+    progress: &mut ProgressBar,
+) -> Result<(), UpdateError> {
+    let mut entries: Vec<_> = sd_folder.entries()?.into_values().collect();
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    for entry in entries {
+        if differences.cancelled || cancel::requested() {
+            differences.cancelled = true;
+            return Ok(());
+        }
+        let path = format!("{}{}", prefix, entry.name);
+        let source_entry = match source.remove(&path.to_lowercase()) {
+            Some(source_entry) => source_entry,
+            None => {
+                // Everything below a directory that isn't in the source is card-only as well.
+                differences.card_only.push(if entry.is_dir { format!("{}/", path) } else { path });
+                continue;
+            }
+        };
+        match (source_entry.is_dir, entry.is_dir) {
+            (true, true) => {
+                compare_dir(&sd_folder.open_dir(&entry.name)?, &format!("{}/", path), source, differences, buffer, hash, progress)?
+            }
+            (true, false) => differences.changed.push(format!("{} (a directory in the source, a file on the card)", path)),
+            (false, true) => differences.changed.push(format!("{} (a file in the source, a directory on the card)", path)),
+            (false, false) if source_entry.skipped => {}
+            (false, false) => {
+                let source_len = source_entry.len;
+                if source_len != entry.len {
+                    differences.changed.push(format!("{} ({} bytes in the source, {} on the card)", path, source_len, entry.len));
+                    progress.inc(source_len);
+                    progress.file_done();
+                    continue;
+                }
+                let source_hash = hash_reader(&mut File::open(&source_entry.host_path)?, buffer, hash)?;
+                let mut sd_file = sd_folder.open_file(&entry.name)?;
+                let mut hasher = ContentHasher::new(hash);
+                loop {
+                    if cancel::requested() {
+                        differences.cancelled = true;
+                        return Ok(());
+                    }
+                    let bytes_read = sd_file.read(buffer)?;
+                    if bytes_read == 0 {
+                        break;
+                    }
+                    hasher.update(&buffer[..bytes_read]);
+                    progress.inc(bytes_read as u64);
+                }
+                progress.file_done();
+                if hasher.finish() != source_hash {
+                    differences.changed.push(format!("{} (same size, different contents)", path));
+                } else {
+                    differences.matching.push(path);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// SplitMix64, enough to pick a reproducible sample without a dependency for it.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+/// Marks all files but `percent` of them, picked by `seed`, as skipped. Directories are
+/// always kept so the walk still gets to the sampled files. The same seed picks the same
+/// files out of the same source.
+fn pick_sample(source: &mut BTreeMap<String, SourceEntry>, percent: f64, seed: u64) {
+    // In path order, so the seed alone decides.
+    let mut files: Vec<String> = source.iter().filter(|(_, entry)| !entry.is_dir).map(|(key, _)| key.clone()).collect();
+    let count = ((files.len() as f64 * percent / 100.0).ceil() as usize).min(files.len());
+    let mut rng = SplitMix64(seed);
+    // The first `count` of a partial Fisher-Yates shuffle.
+    for i in 0..count {
+        let j = i + (rng.next() % (files.len() - i) as u64) as usize;
+        files.swap(i, j);
+    }
+    for key in &files[count..] {
+        source.get_mut(key).unwrap().skipped = true;
+    }
+    info(format!(
+        "Checking a sample of {} of {} files ({}%), seed {}. Run with --sample-seed {} to check the same files again\n",
+        count,
+        files.len(),
+        percent,
+        seed,
+        seed
+    ).as_str());
+}
+
+/// `entries` moved under `--target-dir`, with the directories on the way to it.
+fn under_target_dir(target_dir: &str, root: &Path, entries: BTreeMap<String, SourceEntry>) -> BTreeMap<String, SourceEntry> {
+    let mut moved = BTreeMap::new();
+    for end in target_dir.match_indices('/').map(|(end, _)| end).chain([target_dir.len()]) {
+        let dir = &target_dir[..end];
+        moved.insert(dir.to_lowercase(), SourceEntry { path: dir.to_string(), host_path: root.to_path_buf(), is_dir: true, len: 0, skipped: false });
+    }
+    for (_, mut entry) in entries {
+        entry.path = format!("{}/{}", target_dir, entry.path);
+        moved.insert(entry.path.to_lowercase(), entry);
+    }
+    moved
+}
+
+/// Adds an `--add-file` file at `path`, and the directories on the way to it.
+fn add_source_file(host_path: &Path, path: &str, entries: &mut BTreeMap<String, SourceEntry>) -> std::io::Result<()> {
+    let len = host_path.metadata()?.len();
+    for (end, _) in path.match_indices('/') {
+        let dir = &path[..end];
+        entries.entry(dir.to_lowercase()).or_insert_with(|| SourceEntry {
+            path: dir.to_string(),
+            host_path: host_path.to_path_buf(),
+            is_dir: true,
+            len: 0,
+            skipped: false,
+        });
+    }
+    entries.insert(path.to_lowercase(), SourceEntry { path: path.to_string(), host_path: host_path.to_path_buf(), is_dir: false, len, skipped: false });
+    Ok(())
+}
+
+/// Compares the source and overlays against the card, the image at `--output` or the
+/// `--dest-dir`. With `sample`, only that percentage of the files, see `pick_sample`.
+/// Shows progress as `label`, and stops at Ctrl+C with what it found so far.
+fn compare_card(sd_source_path: &Path, options: &Options, sample: Option<f64>, label: &'static str) -> Result<Differences, UpdateError> {
+    let options = &packaging::merged(sd_source_path, options)?;
+    let root = source_root(sd_source_path, options)?;
+    let mut roots = vec![root.as_path()];
+    roots.extend(options.overlays.iter().map(PathBuf::as_path));
+    let filter = FileFilter::new(options);
+    let exclude: Vec<PathBuf> = options.exclude.iter().map(|path| root.join(path)).collect();
+    let long_paths = longpath::check(&roots, &exclude, &filter, &options.target_prefix(), options.on_longpath)?;
+    let mut source = BTreeMap::new();
+    collect_source(&root, "", &exclude, &long_paths, &filter, &mut source)?;
+    for overlay in &options.overlays {
+        collect_source(overlay, "", &[], &long_paths, &filter, &mut source)?;
+    }
+    if let Some(target_dir) = &options.target_dir {
+        source = under_target_dir(target_dir, &root, source);
+    }
+    for (host_path, path) in &options.add_files {
+        add_source_file(host_path, path, &mut source)?;
+    }
+    // Not the source's, the build writes its own over it. From the root of the card like
+    // `--add-file`, whatever `--target-dir` is.
+    if let Some(path) = &options.include_manifest_in_image {
+        source.remove(&path.to_lowercase());
+    }
+    if let Some(percent) = sample {
+        let seed = options.sample_seed.unwrap_or_else(|| {
+            std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|elapsed| elapsed.as_nanos() as u64).unwrap_or_default()
+        });
+        pick_sample(&mut source, percent, seed);
+    }
+
+    let checked = source.values().filter(|entry| !entry.is_dir && !entry.skipped);
+    let mut differences = Differences { files: checked.clone().count(), ..Differences::default() };
+    let mut progress = ProgressBar::new(label, checked.map(|entry| entry.len).sum()).with_files(differences.files as u64);
+    catch_ctrl_c("Stopping, press Ctrl+C again to stop right away\n")?;
+    let mut buffer = vec![0_u8; 1024 * 1024];
+    match &options.dest_dir {
+        Some(dest_dir) => {
+            compare_dir(&HostDir(dest_dir.clone()), "", &mut source, &mut differences, &mut buffer, options.hash, &mut progress)?
+        }
+        None => {
+            let offset = if options.partitioned { partition::PARTITION_OFFSET } else { 0 };
+            let (fs_region, _) = open_fs_region(options, offset)?;
+            let fs_options = fatfs::FsOptions::new().oem_cp_converter(options.code_page);
+            let fs = FileSystem::new(fs_region, fs_options)?;
+            compare_dir(&fs.root_dir(), "", &mut source, &mut differences, &mut buffer, options.hash, &mut progress)?;
+        }
+    }
+    progress.finish();
+    if differences.cancelled {
+        return Ok(differences);
+    }
+
+    if let Some(path) = &options.include_manifest_in_image {
+        differences.card_only.retain(|card_only| card_only.to_lowercase() != path.to_lowercase());
+    }
+
+    // Whatever is left wasn't on the card. Only the top of a missing tree is worth listing.
+    let mut missing_dirs: Vec<String> = Vec::new();
+    for entry in source.into_values() {
+        let key = entry.path.to_lowercase();
+        if entry.skipped || missing_dirs.iter().any(|dir| key.starts_with(dir.as_str())) {
+            continue;
+        }
+        if entry.is_dir {
+            missing_dirs.push(format!("{}/", key));
+            differences.missing.push(format!("{}/", entry.path));
+        } else {
+            differences.missing.push(entry.path);
+        }
+    }
+    Ok(differences)
+}
+
+/// The card the options point at, for messages, or an error if there is none yet.
+fn card(options: &Options, what: &str) -> Result<PathBuf, UpdateError> {
+    let card = options.dest_dir.clone().unwrap_or_else(|| options.output());
+    if !card.exists() {
+        return Err(UpdateError::Other(format!("There is no {} to {} yet", card.display(), what)));
+    }
+    Ok(card)
+}
+
+/// Compares the source and overlays against the card and reports every difference. Files
+/// that are only on the card don't count, they come from `sd.xz` and a rebuild leaves them
+/// alone.
+pub fn compare(sd_source_path: &Path, options: &Options) -> Result<(), UpdateError> {
+    let card = card(options, "compare against")?;
+    info(format!("Comparing {} against {}\n", sd_source_path.display(), card.display()).as_str());
+    let differences = compare_card(sd_source_path, options, None, "Comparing")?;
+
+    for path in &differences.card_only {
+        debug(format!("Only on the card: {}\n", path).as_str());
+    }
+    for change in &differences.changed {
+        info(format!("Differs: {}\n", change).as_str());
+    }
+    for path in &differences.missing {
+        info(format!("Differs: {} (missing from the card)\n", path).as_str());
+    }
+    let count = differences.changed.len() + differences.missing.len();
+    if differences.cancelled {
+        return Err(UpdateError::Interrupted(format!(
+            "Comparing stopped after {} of {} files, {} differences so far",
+            differences.checked(),
+            differences.files,
+            count
+        )));
+    }
+    info(format!("{} differences, {} entries only on the card\n", count, differences.card_only.len()).as_str());
+    if count > 0 {
+        return Err(UpdateError::ImageDiffers(count));
+    }
+    Ok(())
+}
+
+/// `verify`: checks every file of the source and overlays, or a sample of them with
+/// `--sample-verify`, reads back from the card with the same contents, without changing
+/// anything. Extra files on the card are listed but
+/// don't fail the check, the base image in `sd.xz` puts those there.
+pub fn verify(sd_source_path: &Path, options: &Options) -> Result<(), UpdateError> {
+    let card = card(options, "verify")?;
+    info(format!("Verifying {} against {}\n", card.display(), sd_source_path.display()).as_str());
+    let differences = compare_card(sd_source_path, options, options.sample_verify, "Verifying")?;
+
+    for path in &differences.matching {
+        debug(format!("OK: {}\n", path).as_str());
+    }
+    for path in &differences.card_only {
+        debug(format!("Extra: {}\n", path).as_str());
+    }
+    for change in &differences.changed {
+        warn(format!("Mismatch: {}\n", change).as_str());
+    }
+    for path in &differences.missing {
+        warn(format!("Missing: {}\n", path).as_str());
+    }
+    if differences.cancelled {
+        return Err(UpdateError::Interrupted(format!(
+            "Verification stopped after {} of {} files: {} match, {} mismatched so far",
+            differences.checked(),
+            differences.files,
+            differences.matching.len(),
+            differences.changed.len()
+        )));
+    }
+    info(format!(
+        "{} files match, {} mismatched, {} missing, {} extra\n",
+        differences.matching.len(),
+        differences.changed.len(),
+        differences.missing.len(),
+        differences.card_only.len()
+    ).as_str());
+    if !differences.changed.is_empty() || !differences.missing.is_empty() {
+        let message = format!(
+            "{} doesn't match the source: {} files mismatched, {} missing",
+            card.display(),
+            differences.changed.len(),
+            differences.missing.len()
+        );
+        if options.policy.verify_mismatch == Action::Error {
+            return Err(UpdateError::Verification(message));
+        }
+        // A damaged card is worth knowing about whatever the exit code says.
+        warn(format!("{} (--policy verify-mismatch)\n", message).as_str());
+    }
+    Ok(())
+}
+
+/// A file or directory on one of the images `diff_images` compares.
+struct ImageEntry {
+    path: String,
+    is_dir: bool,
+    len: u64,
+    hash: String,
+}
+
+/// Adds everything on the card under `sd_folder` to `entries`, keyed by lowercased path.
+fn collect_image<D: DestDir>(
+    sd_folder: &D,
+    prefix: &str,
+    entries: &mut BTreeMap<String, ImageEntry>,
+    buffer: &mut [u8],
+    hash: HashAlgorithm,
+) -> Result<(), UpdateError> {
+    for entry in sd_folder.entries()?.into_values() {
+        let path = format!("{}{}", prefix, entry.name);
+        if entry.is_dir {
+            collect_image(&sd_folder.open_dir(&entry.name)?, &format!("{}/", path), entries, buffer, hash)?;
+            entries.insert(path.to_lowercase(), ImageEntry { path, is_dir: true, len: 0, hash: String::new() });
+            continue;
+        }
+        let mut sd_file = sd_folder.open_file(&entry.name)?;
+        let mut hasher = ContentHasher::new(hash);
+        loop {
+            let bytes_read = sd_file.read(buffer)?;
+            if bytes_read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..bytes_read]);
+        }
+        entries.insert(path.to_lowercase(), ImageEntry { path, is_dir: false, len: entry.len, hash: hasher.finish() });
+    }
+    Ok(())
+}
+
+/// Everything on the image at `image`, which is partitioned if the options say so.
+fn image_entries(image: &Path, options: &Options, buffer: &mut [u8]) -> Result<BTreeMap<String, ImageEntry>, UpdateError> {
+    let options = Options { output: Some(image.to_path_buf()), device: None, ..options.clone() };
+    let offset = if options.partitioned { partition::PARTITION_OFFSET } else { 0 };
+    let (fs_region, _) = open_fs_region(&options, offset)?;
+    let fs_options = fatfs::FsOptions::new().oem_cp_converter(options.code_page);
+    let fs = FileSystem::new(fs_region, fs_options)?;
+    let mut entries = BTreeMap::new();
+    collect_image(&fs.root_dir(), "", &mut entries, buffer, options.hash)?;
+    // Different on any two builds.
+    if let Some(path) = &options.include_manifest_in_image {
+        entries.remove(&path.to_lowercase());
+    }
+    Ok(entries)
+}
+
+/// The paths of `entries` not in `other`, leaving out what's below a directory that is
+/// listed already.
+fn only_in(entries: &BTreeMap<String, ImageEntry>, other: &BTreeMap<String, ImageEntry>) -> Vec<String> {
+    let mut only = Vec::new();
+    let mut dirs: Vec<String> = Vec::new();
+    for (key, entry) in entries {
+        if other.contains_key(key) || dirs.iter().any(|dir| key.starts_with(dir.as_str())) {
+            continue;
+        }
+        if entry.is_dir {
+            dirs.push(format!("{}/", key));
+            only.push(format!("{}/", entry.path));
+        } else {
+            only.push(entry.path.clone());
+        }
+    }
+    only
+}
+
+/// `--diff-image`: reports the files that differ between the image at `--output` and
+/// `other`, by path, size and contents. Timestamps and the layout on disk don't count.
+pub fn diff_images(options: &Options, other: &Path) -> Result<(), UpdateError> {
+    let image = card(options, "diff")?;
+    if !other.exists() {
+        return Err(UpdateError::Other(format!("There is no {} to diff against", other.display())));
+    }
+    info(format!("Comparing the files on {} and {}\n", image.display(), other.display()).as_str());
+    let mut buffer = vec![0_u8; 1024 * 1024];
+    let ours = image_entries(&image, options, &mut buffer)?;
+    let theirs = image_entries(other, options, &mut buffer)?;
+
+    let mut count = 0;
+    for path in only_in(&ours, &theirs) {
+        info(format!("Only in {}: {}\n", image.display(), path).as_str());
+        count += 1;
+    }
+    for path in only_in(&theirs, &ours) {
+        info(format!("Only in {}: {}\n", other.display(), path).as_str());
+        count += 1;
+    }
+    for (key, entry) in &ours {
+        let Some(their_entry) = theirs.get(key) else {
+            continue;
+        };
+        let difference = match (entry.is_dir, their_entry.is_dir) {
+            (true, true) => continue,
+            (true, false) | (false, true) => "a directory on one, a file on the other".to_string(),
+            (false, false) if entry.len != their_entry.len => format!("{} bytes and {} bytes", entry.len, their_entry.len),
+            (false, false) if entry.hash != their_entry.hash => "same size, different contents".to_string(),
+            (false, false) => continue,
+        };
+        info(format!("Differs: {} ({})\n", entry.path, difference).as_str());
+        count += 1;
+    }
+    info(format!("{} differences between {} and {}\n", count, image.display(), other.display()).as_str());
+    if count > 0 {
+        return Err(UpdateError::ImagesDiffer(count));
+    }
+    Ok(())
+}
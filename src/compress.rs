@@ -0,0 +1,94 @@
+//! `--compress-output`: a compressed copy of the finished image for distributing it, written
+//! next to the image as `sd.xz`, `sd.zst` or `sd.gz`. It runs last, after `--wipe-free`
+//! zeroed the free space, which is what lets that space compress to almost nothing. The
+//! image is streamed through one buffer, so memory use doesn't grow with its size.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::error::UpdateError;
+use crate::image::sd_xz_path;
+use crate::options::{Compression, Options};
+use crate::progress::{Phase, ProgressBar};
+use crate::units::format_bytes;
+use crate::{info, report};
+
+/// Feeds all of `image` to `encoder`.
+fn pump<W: Write>(image: &mut File, encoder: &mut W, progress: &mut ProgressBar) -> Result<(), UpdateError> {
+    let mut buffer = vec![0_u8; 1024 * 1024];
+    loop {
+        let bytes_read = image.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+        encoder.write_all(&buffer[..bytes_read])?;
+        progress.inc(bytes_read as u64);
+    }
+    progress.finish();
+    Ok(())
+}
+
+fn write_compressed(image: &mut File, size: u64, file: File, compression: Compression, level: u32) -> Result<(), UpdateError> {
+    let mut progress = ProgressBar::new("Compressing", size);
+    match compression {
+        Compression::Xz => {
+            let mut encoder = xz2::write::XzEncoder::new(file, level);
+            pump(image, &mut encoder, &mut progress)?;
+            encoder.finish()?.sync_all()?;
+        }
+        Compression::Zstd => {
+            let mut encoder = zstd::Encoder::new(file, level as i32)?;
+            pump(image, &mut encoder, &mut progress)?;
+            encoder.finish()?.sync_all()?;
+        }
+        Compression::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(file, flate2::Compression::new(level));
+            pump(image, &mut encoder, &mut progress)?;
+            encoder.finish()?.sync_all()?;
+        }
+    }
+    Ok(())
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Compresses the image at `--output` and returns the path of the compressed copy. It's
+/// written under a temporary name first, so a failed run never leaves a truncated archive
+/// that looks finished.
+pub fn compress_image(options: &Options, compression: Compression) -> Result<PathBuf, UpdateError> {
+    let output = options.output();
+    let target = output.with_extension(compression.extension());
+    if sd_xz_path(options).is_some_and(|sd_xz| same_file(&target, &sd_xz)) {
+        return Err(UpdateError::Other(format!(
+            "--compress-output would overwrite {}, the sd.xz this image was built from. Put --output somewhere else",
+            target.display()
+        )));
+    }
+    let level = options.compress_level(compression);
+    let partial = target.with_extension(format!("{}.part", compression.extension()));
+    let phase = Phase::start("Compressing the image");
+    let mut image = File::open(&output)?;
+    let size = image.metadata()?.len();
+    info(format!("Compressing {} into {} ({} level {})\n", output.display(), target.display(), compression.name(), level).as_str());
+    if let Err(e) = write_compressed(&mut image, size, File::create(&partial)?, compression, level) {
+        let _ = std::fs::remove_file(&partial);
+        return Err(e);
+    }
+    std::fs::rename(&partial, &target)?;
+    report::record_phase("compress", phase.finish());
+    let compressed = std::fs::metadata(&target)?.len();
+    info(format!(
+        "Wrote {}: {} from {}, {:.1}% of the image\n",
+        target.display(),
+        format_bytes(compressed),
+        format_bytes(size),
+        compressed as f64 * 100.0 / size.max(1) as f64
+    ).as_str());
+    Ok(target)
+}
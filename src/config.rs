@@ -0,0 +1,88 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::textfile::read_text;
+
+/// Read from the working directory when `--config` isn't given.
+pub const CONFIG_FILE: &str = "updater.conf";
+
+/// One `key = value` line. Keys are option names without the leading `--`.
+#[derive(Debug)]
+pub struct Setting {
+    pub key: String,
+    pub value: String,
+    pub line: usize,
+}
+
+/// The config file: settings that always apply, then named profiles in `[profile.<name>]`
+/// sections that only apply when selected with `--profile`.
+///
+/// ```text
+/// # applies to every run
+/// min-interval = 12h
+///
+/// [profile.minimal]
+/// sd-size = 1G
+/// ```
+#[derive(Debug, Default)]
+pub struct Config {
+    pub settings: Vec<Setting>,
+    pub profiles: BTreeMap<String, Vec<Setting>>,
+}
+
+impl Config {
+    /// Loads `path`. A missing file is only an error if it was asked for explicitly.
+    pub fn load(path: &Path, explicit: bool) -> Result<Config, String> {
+        match read_text(path) {
+            Ok(contents) => Config::parse(&contents).map_err(|e| format!("{}: {}", path.display(), e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !explicit => Ok(Config::default()),
+            Err(e) => Err(format!("Can't read {}: {}", path.display(), e)),
+        }
+    }
+
+    fn parse(contents: &str) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut profile: Option<String> = None;
+        for (index, line) in contents.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(section) = line.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+                let name = section
+                    .trim()
+                    .strip_prefix("profile.")
+                    .ok_or_else(|| format!("line {}: unknown section [{}], expected [profile.<name>]", line_number, section))?;
+                config.profiles.entry(name.to_string()).or_default();
+                profile = Some(name.to_string());
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("line {}: expected key = value", line_number))?;
+            let setting = Setting {
+                key: key.trim().to_string(),
+                value: value.trim().to_string(),
+                line: line_number,
+            };
+            match &profile {
+                Some(profile) => config.profiles.get_mut(profile).unwrap().push(setting),
+                None => config.settings.push(setting),
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn profile(&self, name: &str) -> Result<&[Setting], String> {
+        match self.profiles.get(name) {
+            Some(settings) => Ok(settings),
+            None if self.profiles.is_empty() => Err(format!("Profile '{}' doesn't exist, the config has no profiles", name)),
+            None => Err(format!(
+                "Profile '{}' doesn't exist, available profiles: {}",
+                name,
+                self.profiles.keys().cloned().collect::<Vec<_>>().join(", ")
+            )),
+        }
+    }
+}
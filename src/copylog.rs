@@ -0,0 +1,75 @@
+//! `--copy-log <file>`: a line of JSON for every file the copy writes, leaves alone, fails
+//! on or deletes, with where it came from, where it went on the card, its size and how long
+//! it took. For finding out which file broke a build, so every line is written out as soon
+//! as the file is done and a crash leaves the log up to the file it crashed on.
+//!
+//! ```text
+//! {"source":"sd_source/boot.dol","destination":"boot.dol","size":1234,"outcome":"created","ms":0.412}
+//! ```
+//!
+//! The outcome is `created` for a new file, `replaced` for one written over a copy already
+//! on the card, `skipped` for one left as it was, `failed` with an `error`, or `deleted`
+//! for a file an incremental update removed.
+
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::error::UpdateError;
+use crate::report::json_string;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CopyOutcome {
+    Created,
+    Replaced,
+    Skipped,
+    Failed,
+    Deleted,
+}
+
+impl CopyOutcome {
+    fn name(self) -> &'static str {
+        match self {
+            CopyOutcome::Created => "created",
+            CopyOutcome::Replaced => "replaced",
+            CopyOutcome::Skipped => "skipped",
+            CopyOutcome::Failed => "failed",
+            CopyOutcome::Deleted => "deleted",
+        }
+    }
+}
+
+pub(crate) struct CopyLog {
+    file: LineWriter<File>,
+}
+
+impl CopyLog {
+    /// Starts a new log at `path`, replacing one from an earlier run.
+    pub(crate) fn create(path: &Path) -> Result<CopyLog, UpdateError> {
+        let file = File::create(path).map_err(|e| UpdateError::Other(format!("Can't write the copy log {}: {}", path.display(), e)))?;
+        Ok(CopyLog { file: LineWriter::new(file) })
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        source: &Path,
+        destination: &str,
+        size: u64,
+        outcome: CopyOutcome,
+        error: Option<&UpdateError>,
+        elapsed: Duration,
+    ) -> std::io::Result<()> {
+        let error = error.map(|e| format!(",\"error\":{}", json_string(&e.to_string()))).unwrap_or_default();
+        writeln!(
+            self.file,
+            "{{\"source\":{},\"destination\":{},\"size\":{},\"outcome\":\"{}\"{},\"ms\":{:.3}}}",
+            json_string(&source.to_string_lossy()),
+            json_string(destination),
+            size,
+            outcome.name(),
+            error,
+            elapsed.as_secs_f64() * 1000.0
+        )
+    }
+}
@@ -0,0 +1,107 @@
+//! The fast path of `--incremental`. When the image was last built completely from a
+//! commit `sd_source` still has, git already knows which paths changed since, so only
+//! those are copied or deleted instead of walking the whole source to find them.
+//!
+//! Anything the fast path can't be sure to get right falls back to the full walk:
+//! overlays, which can shadow any source file, an age window files move into as time
+//! passes, paths `--on-longpath` renames or leaves out, symlinks and submodules, and more
+//! than `MAX_DELTA_PATHS` changes.
+
+use std::path::{Component, Path};
+
+use git2::Repository;
+
+use crate::git::{changed_paths, head_commit};
+use crate::longpath::LongPaths;
+use crate::options::Options;
+use crate::{asset, debug, info};
+
+/// More changed paths than this and walking the whole source is about as quick.
+pub const MAX_DELTA_PATHS: usize = 1000;
+
+/// What changed since the last build, as `/`-separated paths relative to what is copied to
+/// the root of the card.
+pub struct Delta {
+    /// Added or modified, to copy.
+    pub changed: Vec<String>,
+    /// Deleted, to remove from the card.
+    pub deleted: Vec<String>,
+}
+
+impl Delta {
+    pub fn total(&self) -> usize {
+        self.changed.len() + self.deleted.len()
+    }
+}
+
+/// `path` relative to the copied root, or `None` if it isn't copied at all: outside
+/// `--source-subdir`, excluded by the manifest, or a dotfile or below a dot directory.
+fn card_path(path: &str, options: &Options) -> Option<String> {
+    let subdir: Vec<String> = options
+        .source_subdir
+        .iter()
+        .flat_map(|subdir| subdir.components())
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part.to_string_lossy().to_string()),
+            _ => None,
+        })
+        .collect();
+    let path = if subdir.is_empty() {
+        path.to_string()
+    } else {
+        path.strip_prefix(&subdir.join("/"))?.strip_prefix('/')?.to_string()
+    };
+    let excluded = options.exclude.iter().any(|exclude| path == *exclude || path.starts_with(&format!("{}/", exclude)));
+    (!excluded && !path.split('/').any(|component| component.starts_with('.'))).then_some(path)
+}
+
+/// What changed in `sd_source_path` since the image was last built, if the fast path can
+/// apply it. `root` is what is copied to the root of the card.
+pub fn since_build(sd_source_path: &Path, root: &Path, options: &Options, long_paths: &LongPaths) -> Option<Delta> {
+    if !options.incremental || options.dest_dir.is_some() {
+        return None;
+    }
+    if !options.overlays.is_empty() {
+        debug("Overlays can shadow any file of the source, checking all of it\n");
+        return None;
+    }
+    if options.newer_than.is_some() || options.older_than.is_some() {
+        debug("Files age into what --older-than lets through without changing, checking the whole source\n");
+        return None;
+    }
+    let Some(built) = asset::built_commit(options) else {
+        debug("No record of the commit the image was built from, checking the whole source\n");
+        return None;
+    };
+    let repo = Repository::open(sd_source_path).ok()?;
+    let head = head_commit(&repo)?;
+    let (changed, deleted) = match changed_paths(&repo, &built, &head) {
+        Ok(Some(paths)) => paths,
+        Ok(None) => {
+            debug("Symlinks or submodules changed since the last build, checking the whole source\n");
+            return None;
+        }
+        Err(e) => {
+            debug(format!("Can't tell what changed since {}, checking the whole source: {}\n", built, e.message()).as_str());
+            return None;
+        }
+    };
+    let delta = Delta {
+        changed: changed.iter().filter_map(|path| card_path(path, options)).collect(),
+        deleted: deleted.iter().filter_map(|path| card_path(path, options)).collect(),
+    };
+    if delta.total() > MAX_DELTA_PATHS {
+        info(format!("{} paths changed since the last build, checking the whole source\n", delta.total()).as_str());
+        return None;
+    }
+    let renamed = delta.changed.iter().chain(&delta.deleted).find(|path| {
+        let host_path = root.join(path);
+        long_paths.keys().any(|long_path| host_path.starts_with(long_path))
+    });
+    if let Some(path) = renamed {
+        debug(format!("{} is too long for FAT, checking the whole source\n", path).as_str());
+        return None;
+    }
+    info(format!("{} paths changed since the last build ({}), copying only those\n", delta.total(), &built[..8.min(built.len())]).as_str());
+    Some(delta)
+}
@@ -0,0 +1,203 @@
+//! Where `recursive_copy` puts the build: a directory in the FAT image, through fatfs, or with
+//! `--dest-dir` a directory on a card the host OS has mounted, through `std::fs`. Both go
+//! through the same traits so every copy feature works the same on either.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use fatfs::ReadWriteSeek;
+
+use crate::error::UpdateError;
+use crate::timestamps;
+
+/// An entry already on the card, as far as an incremental copy cares about it.
+pub struct CardEntry {
+    /// The name as it is stored, `entries` keys are lowercased.
+    pub name: String,
+    pub is_dir: bool,
+    pub len: u64,
+    /// In FAT resolution for both backends, so it compares equal to the converted source
+    /// timestamp after a copy.
+    pub modified: fatfs::DateTime,
+}
+
+/// A file on the card that is being written.
+pub trait DestFile {
+    fn write(&mut self, data: &[u8]) -> Result<usize, UpdateError>;
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, UpdateError>;
+    /// The size of the file according to the filesystem.
+    fn len(&mut self) -> Result<u64, UpdateError>;
+    /// Seeks back to the start, to read back what was written.
+    fn rewind(&mut self) -> Result<(), UpdateError>;
+    /// Called once the contents are written. fatfs stamps files as they are written, the
+    /// host has to be told afterwards.
+    fn finish(&mut self, modified: SystemTime) -> Result<(), UpdateError>;
+    /// Sets the creation time, for `--preserve-created`. Only FAT images can, `std::fs`
+    /// has no portable way, so the option is for images only.
+    fn set_created(&mut self, created: fatfs::DateTime);
+}
+
+/// A directory on the card.
+pub trait DestDir: Sized {
+    type File: DestFile;
+
+    /// Lists the directory keyed by lowercased name, since FAT names are case-insensitive.
+    fn entries(&self) -> Result<HashMap<String, CardEntry>, UpdateError>;
+    fn open_dir(&self, name: &str) -> Result<Self, UpdateError>;
+    /// Opens an existing file for reading.
+    fn open_file(&self, name: &str) -> Result<Self::File, UpdateError>;
+    fn create_dir(&self, name: &str) -> Result<Self, UpdateError>;
+    /// Creates `name`, or empties it if `exists`.
+    fn create_file(&self, name: &str, exists: bool) -> Result<Self::File, UpdateError>;
+    fn remove(&self, name: &str) -> Result<(), UpdateError>;
+}
+
+impl<IO: ReadWriteSeek, TP: fatfs::TimeProvider, OCC: fatfs::OemCpConverter> DestFile for fatfs::File<'_, IO, TP, OCC> {
+    fn write(&mut self, data: &[u8]) -> Result<usize, UpdateError> {
+        Ok(fatfs::Write::write(self, data)?)
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, UpdateError> {
+        Ok(fatfs::Read::read(self, buffer)?)
+    }
+
+    fn len(&mut self) -> Result<u64, UpdateError> {
+        Ok(fatfs::Seek::seek(self, fatfs::SeekFrom::End(0))?)
+    }
+
+    fn rewind(&mut self) -> Result<(), UpdateError> {
+        fatfs::Seek::seek(self, fatfs::SeekFrom::Start(0))?;
+        Ok(())
+    }
+
+    fn finish(&mut self, _modified: SystemTime) -> Result<(), UpdateError> {
+        Ok(())
+    }
+
+    fn set_created(&mut self, created: fatfs::DateTime) {
+        fatfs::File::set_created(self, created);
+    }
+}
+
+impl<'a, IO: ReadWriteSeek, TP: fatfs::TimeProvider, OCC: fatfs::OemCpConverter> DestDir for fatfs::Dir<'a, IO, TP, OCC> {
+    type File = fatfs::File<'a, IO, TP, OCC>;
+
+    fn entries(&self) -> Result<HashMap<String, CardEntry>, UpdateError> {
+        let mut entries = HashMap::new();
+        for entry in self.iter() {
+            let entry = entry?;
+            let name = entry.file_name();
+            if name == "." || name == ".." {
+                continue;
+            }
+            entries.insert(name.to_lowercase(), CardEntry {
+                name: name.clone(),
+                is_dir: entry.is_dir(),
+                len: entry.len(),
+                modified: entry.modified(),
+            });
+        }
+        Ok(entries)
+    }
+
+    fn open_dir(&self, name: &str) -> Result<Self, UpdateError> {
+        Ok(fatfs::Dir::open_dir(self, name)?)
+    }
+
+    fn open_file(&self, name: &str) -> Result<Self::File, UpdateError> {
+        Ok(fatfs::Dir::open_file(self, name)?)
+    }
+
+    fn create_dir(&self, name: &str) -> Result<Self, UpdateError> {
+        Ok(fatfs::Dir::create_dir(self, name)?)
+    }
+
+    fn create_file(&self, name: &str, exists: bool) -> Result<Self::File, UpdateError> {
+        let mut sd_file = fatfs::Dir::create_file(self, name)?;
+        if exists {
+            // create_file opens an existing file as is, so drop the old contents first.
+            sd_file.truncate()?;
+        }
+        Ok(sd_file)
+    }
+
+    fn remove(&self, name: &str) -> Result<(), UpdateError> {
+        Ok(fatfs::Dir::remove(self, name)?)
+    }
+}
+
+impl DestFile for std::fs::File {
+    fn write(&mut self, data: &[u8]) -> Result<usize, UpdateError> {
+        Ok(std::io::Write::write(self, data)?)
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, UpdateError> {
+        Ok(std::io::Read::read(self, buffer)?)
+    }
+
+    fn len(&mut self) -> Result<u64, UpdateError> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn rewind(&mut self) -> Result<(), UpdateError> {
+        std::io::Seek::rewind(self)?;
+        Ok(())
+    }
+
+    fn finish(&mut self, modified: SystemTime) -> Result<(), UpdateError> {
+        Ok(self.set_modified(modified)?)
+    }
+
+    fn set_created(&mut self, _created: fatfs::DateTime) {}
+}
+
+/// A directory on a card mounted by the host.
+pub struct HostDir(pub PathBuf);
+
+impl DestDir for HostDir {
+    type File = std::fs::File;
+
+    fn entries(&self) -> Result<HashMap<String, CardEntry>, UpdateError> {
+        let mut entries = HashMap::new();
+        for entry in self.0.read_dir()? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            entries.insert(name.to_lowercase(), CardEntry {
+                name,
+                is_dir: metadata.is_dir(),
+                len: metadata.len(),
+                modified: timestamps::to_fat_datetime(metadata.modified()?),
+            });
+        }
+        Ok(entries)
+    }
+
+    fn open_dir(&self, name: &str) -> Result<Self, UpdateError> {
+        Ok(HostDir(self.0.join(name)))
+    }
+
+    fn open_file(&self, name: &str) -> Result<Self::File, UpdateError> {
+        Ok(std::fs::File::open(self.0.join(name))?)
+    }
+
+    fn create_dir(&self, name: &str) -> Result<Self, UpdateError> {
+        std::fs::create_dir(self.0.join(name))?;
+        Ok(HostDir(self.0.join(name)))
+    }
+
+    fn create_file(&self, name: &str, _exists: bool) -> Result<Self::File, UpdateError> {
+        // Read access is for reading the file back with --verify.
+        Ok(std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(self.0.join(name))?)
+    }
+
+    fn remove(&self, name: &str) -> Result<(), UpdateError> {
+        Ok(std::fs::remove_file(self.0.join(name))?)
+    }
+}
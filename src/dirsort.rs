@@ -0,0 +1,130 @@
+//! Directory listings in name order. `read_dir` order depends on the host filesystem, and
+//! copying in it would make the directory tables, and so the image bytes, differ between
+//! runs with the same source, so every walk of the source goes through `sorted_entries`.
+//!
+//! Up to `--dir-spill-threshold` names are sorted in memory. A directory with more is read
+//! in runs of that many, each sorted and written to scratch space, and the runs are merged
+//! as the walk asks for the next entry, so only one name per run is held at a time. Every
+//! run keeps a file open until the listing is dropped.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::error::UpdateError;
+use crate::scratch::ScratchDir;
+use crate::debug;
+
+/// How many entries of one directory are sorted in memory without `--dir-spill-threshold`.
+pub const DEFAULT_SPILL_THRESHOLD: usize = 100_000;
+
+static SPILL_THRESHOLD: AtomicUsize = AtomicUsize::new(DEFAULT_SPILL_THRESHOLD);
+
+/// Tells apart the scratch directories of listings that are open at the same time, one for
+/// every level of a walk.
+static NEXT_SPILL: AtomicU64 = AtomicU64::new(0);
+
+pub fn set_spill_threshold(threshold: Option<usize>) {
+    SPILL_THRESHOLD.store(threshold.unwrap_or(DEFAULT_SPILL_THRESHOLD), Ordering::Relaxed);
+}
+
+/// The entries of a directory in name order, see above.
+pub(crate) enum SortedEntries {
+    Sorted { dir: PathBuf, names: std::vec::IntoIter<OsString> },
+    Merged(Merge),
+}
+
+/// The runs of a directory too large to sort in memory, and the next name of each.
+pub(crate) struct Merge {
+    dir: PathBuf,
+    runs: Vec<BufReader<File>>,
+    heads: BinaryHeap<Reverse<(OsString, usize)>>,
+    // Last, so the runs are closed before their directory is removed.
+    _scratch: ScratchDir,
+}
+
+fn write_run(path: &Path, names: &mut Vec<OsString>) -> std::io::Result<()> {
+    names.sort();
+    let mut run = BufWriter::new(File::create(path)?);
+    for name in names.drain(..) {
+        let bytes = name.into_encoded_bytes();
+        run.write_all(&(bytes.len() as u32).to_le_bytes())?;
+        run.write_all(&bytes)?;
+    }
+    run.flush()
+}
+
+fn read_name(run: &mut BufReader<File>) -> std::io::Result<Option<OsString>> {
+    let mut len = [0; 4];
+    match run.read_exact(&mut len) {
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        result => result?,
+    }
+    let mut bytes = vec![0; u32::from_le_bytes(len) as usize];
+    run.read_exact(&mut bytes)?;
+    // SAFETY: the bytes came from `into_encoded_bytes` in `write_run`, in this same process.
+    Ok(Some(unsafe { OsString::from_encoded_bytes_unchecked(bytes) }))
+}
+
+/// Lists `host_path` sorted by name, see above.
+pub(crate) fn sorted_entries(host_path: &Path) -> Result<SortedEntries, UpdateError> {
+    let threshold = SPILL_THRESHOLD.load(Ordering::Relaxed);
+    let mut names = Vec::new();
+    let mut scratch: Option<ScratchDir> = None;
+    let mut run_paths = Vec::new();
+    for entry in host_path.read_dir()? {
+        names.push(entry?.file_name());
+        if names.len() < threshold {
+            continue;
+        }
+        if scratch.is_none() {
+            debug(format!("{} has more than {} entries, sorting them in scratch space\n", host_path.display(), threshold).as_str());
+            scratch = Some(ScratchDir::create(&format!("dirsort{}", NEXT_SPILL.fetch_add(1, Ordering::Relaxed)), 0)?);
+        }
+        let run_path = scratch.as_ref().unwrap().path().join(run_paths.len().to_string());
+        write_run(&run_path, &mut names)?;
+        run_paths.push(run_path);
+    }
+    let dir = host_path.to_path_buf();
+    let Some(scratch) = scratch else {
+        names.sort();
+        return Ok(SortedEntries::Sorted { dir, names: names.into_iter() });
+    };
+    if !names.is_empty() {
+        let run_path = scratch.path().join(run_paths.len().to_string());
+        write_run(&run_path, &mut names)?;
+        run_paths.push(run_path);
+    }
+    let mut merge = Merge { dir, runs: Vec::with_capacity(run_paths.len()), heads: BinaryHeap::new(), _scratch: scratch };
+    for (index, run_path) in run_paths.iter().enumerate() {
+        let mut run = BufReader::new(File::open(run_path)?);
+        if let Some(name) = read_name(&mut run)? {
+            merge.heads.push(Reverse((name, index)));
+        }
+        merge.runs.push(run);
+    }
+    Ok(SortedEntries::Merged(merge))
+}
+
+impl Iterator for SortedEntries {
+    type Item = std::io::Result<PathBuf>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            SortedEntries::Sorted { dir, names } => names.next().map(|name| Ok(dir.join(name))),
+            SortedEntries::Merged(merge) => {
+                let Reverse((name, index)) = merge.heads.pop()?;
+                match read_name(&mut merge.runs[index]) {
+                    Ok(Some(following)) => merge.heads.push(Reverse((following, index))),
+                    Ok(None) => {}
+                    Err(e) => return Some(Err(e)),
+                }
+                Some(Ok(merge.dir.join(name)))
+            }
+        }
+    }
+}
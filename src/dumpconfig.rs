@@ -0,0 +1,164 @@
+//! `--dump-config`: every setting that was given, what it ends up as and where that came
+//! from, printed instead of running. Later sources replace earlier ones: the config file,
+//! then the `--profile`, then the command line. The packaging manifest in `sd_source` only
+//! fills in what none of those set, see `packaging.rs`. Of the settings nobody gave, the
+//! ones that say where things come from and go are listed with their defaults.
+//!
+//! `--dump-config=json` prints one JSON object keyed by setting instead.
+
+use std::path::Path;
+
+use crate::buildinfo::without_credentials;
+use crate::config::CONFIG_FILE;
+use crate::error::UpdateError;
+use crate::options::{DumpFormat, Options, SIZE_SETTINGS};
+use crate::packaging::{merge_manifest, PACKAGING_MANIFEST};
+use crate::report::json_string;
+use crate::units::{format_size, parse_bytes};
+
+/// Settings that add to what was given before them instead of replacing it.
+const REPEATABLE: &[&str] = &["overlay", "add-file", "preserve", "include-ext", "exclude-ext", "target", "allowed-host", "policy"];
+
+/// A value and where it came from.
+type Sourced = (String, String);
+
+struct Entry {
+    name: String,
+    /// One value, or all of them for a `REPEATABLE` setting.
+    effective: Vec<Sourced>,
+    /// What later sources replaced.
+    overridden: Vec<Sourced>,
+}
+
+impl Entry {
+    fn new(name: &str, effective: Vec<Sourced>) -> Entry {
+        Entry { name: name.to_string(), effective, overridden: Vec::new() }
+    }
+}
+
+fn entries(sd_source_path: &Path, options: &Options) -> Result<Vec<Entry>, UpdateError> {
+    let mut entries: Vec<Entry> = Vec::new();
+    for applied in &options.applied {
+        let mut value = applied.value.clone().unwrap_or_else(|| "true".to_string());
+        if applied.name.ends_with("url") {
+            value = without_credentials(&value);
+        }
+        // `1536M` and `1.5GiB` both say 1.5G.
+        if SIZE_SETTINGS.contains(&applied.name.as_str()) {
+            if let Ok(bytes) = parse_bytes(&value) {
+                value = format_size(bytes);
+            }
+        }
+        let index = match entries.iter().position(|entry| entry.name == applied.name) {
+            Some(index) => index,
+            None => {
+                entries.push(Entry::new(&applied.name, Vec::new()));
+                entries.len() - 1
+            }
+        };
+        let entry = &mut entries[index];
+        // `--preserve none` throws away the paths given before it.
+        if !REPEATABLE.contains(&applied.name.as_str()) || (applied.name == "preserve" && value.trim() == "none") {
+            let replaced = std::mem::take(&mut entry.effective);
+            entry.overridden.extend(replaced);
+        }
+        entry.effective.push((value, applied.source.clone()));
+    }
+
+    let (merged, found) = merge_manifest(sd_source_path, options)?;
+    if found {
+        let manifest = sd_source_path.join(PACKAGING_MANIFEST).display().to_string();
+        let from_manifest = |values: Vec<String>| -> Vec<Sourced> { values.into_iter().map(|value| (value, manifest.clone())).collect() };
+        if merged.source_subdir != options.source_subdir {
+            let subdir = merged.source_subdir.iter().map(|subdir| subdir.display().to_string()).collect();
+            entries.push(Entry::new("source-subdir", from_manifest(subdir)));
+        }
+        if merged.target_dir != options.target_dir {
+            entries.push(Entry::new("target-dir", from_manifest(merged.target_dir.iter().cloned().collect())));
+        }
+        if merged.preserve != options.preserve {
+            entries.push(Entry::new("preserve", from_manifest(merged.preserve())));
+        }
+        if !merged.exclude.is_empty() {
+            entries.push(Entry::new("exclude", from_manifest(merged.exclude.clone())));
+        }
+        if !merged.empty_dirs.is_empty() {
+            entries.push(Entry::new("empty-dirs", from_manifest(merged.empty_dirs.clone())));
+        }
+    }
+
+    let given = |names: &[&str]| entries.iter().any(|entry| names.contains(&entry.name.as_str()));
+    let mut defaults = Vec::new();
+    for (name, value) in [
+        ("config", CONFIG_FILE.to_string()),
+        ("url", without_credentials(&options.url())),
+        ("branch", options.branch()),
+        ("assets-dir", options.assets_dir().display().to_string()),
+        ("sd-size", format_size(options.sd_size())),
+    ] {
+        if !given(&[name]) {
+            defaults.push(Entry::new(name, vec![(value, "default".to_string())]));
+        }
+    }
+    if !given(&["output", "device", "dest-dir"]) {
+        defaults.push(Entry::new("output", vec![(options.output().display().to_string(), "default".to_string())]));
+    }
+    entries.extend(defaults);
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+fn to_text(entries: &[Entry]) -> String {
+    let width = entries.iter().map(|entry| entry.name.len()).max().unwrap_or_default();
+    let mut text = String::new();
+    for entry in entries {
+        let replacing = entry
+            .overridden
+            .iter()
+            .map(|(value, source)| format!(", replacing {} from {}", value, source))
+            .collect::<String>();
+        for (value, source) in &entry.effective {
+            text.push_str(&format!("{:width$} = {}  ({}{})\n", entry.name, value, source, replacing, width = width));
+        }
+    }
+    text
+}
+
+fn json_list<'a>(values: impl Iterator<Item = &'a String>) -> String {
+    let items: Vec<String> = values.map(|value| json_string(value)).collect();
+    format!("[{}]", items.join(","))
+}
+
+fn to_json(entries: &[Entry]) -> String {
+    let settings: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            let (value, source) = match (REPEATABLE.contains(&entry.name.as_str()), entry.effective.as_slice()) {
+                (false, [(value, source)]) => (json_string(value), json_string(source)),
+                (_, effective) => (json_list(effective.iter().map(|(value, _)| value)), json_list(effective.iter().map(|(_, source)| source))),
+            };
+            let overridden: Vec<String> = entry
+                .overridden
+                .iter()
+                .map(|(value, source)| format!("{{\"value\":{},\"source\":{}}}", json_string(value), json_string(source)))
+                .collect();
+            format!(
+                "{}:{{\"value\":{},\"source\":{},\"overridden\":[{}]}}",
+                json_string(&entry.name),
+                value,
+                source,
+                overridden.join(",")
+            )
+        })
+        .collect();
+    format!("{{{}}}\n", settings.join(","))
+}
+
+/// The effective settings as `--dump-config` prints them.
+pub fn dump_config(sd_source_path: &Path, options: &Options, format: DumpFormat) -> Result<String, UpdateError> {
+    let entries = entries(sd_source_path, options)?;
+    Ok(match format {
+        DumpFormat::Text => to_text(&entries),
+        DumpFormat::Json => to_json(&entries),
+    })
+}
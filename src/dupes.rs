@@ -0,0 +1,53 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::units::format_bytes;
+use crate::info;
+
+/// Collects content hashes during the copy so byte-identical files can be reported.
+#[derive(Default)]
+pub struct DupeReport {
+    by_hash: HashMap<String, (u64, Vec<PathBuf>)>,
+}
+
+impl DupeReport {
+    pub fn add(&mut self, hash: String, size: u64, path: PathBuf) {
+        // Empty files are all identical but don't waste anything.
+        if size == 0 {
+            return;
+        }
+        self.by_hash.entry(hash).or_insert((size, Vec::new())).1.push(path);
+    }
+
+    /// Prints every group of identical files, the most wasteful first.
+    pub fn print(&self) {
+        let mut groups: Vec<(u64, u64, &Vec<PathBuf>)> = self
+            .by_hash
+            .values()
+            .filter(|(_, paths)| paths.len() > 1)
+            .map(|(size, paths)| (size * (paths.len() as u64 - 1), *size, paths))
+            .collect();
+        if groups.is_empty() {
+            info("No duplicate files found\n");
+            return;
+        }
+        groups.sort_by_key(|group| std::cmp::Reverse(group.0));
+        let total: u64 = groups.iter().map(|(wasted, _, _)| wasted).sum();
+        info(format!(
+            "Found {} groups of duplicate files wasting {}\n",
+            groups.len(),
+            format_bytes(total)
+        ).as_str());
+        for (wasted, size, paths) in groups {
+            info(format!(
+                "{} wasted by {} copies of {}:\n",
+                format_bytes(wasted),
+                paths.len(),
+                format_bytes(size)
+            ).as_str());
+            for path in paths {
+                info(format!("    {}\n", path.display()).as_str());
+            }
+        }
+    }
+}
@@ -0,0 +1,257 @@
+//! Why a run failed, and the exit code that tells a calling script about it.
+//!
+//! | Code | Meaning                                                          |
+//! |------|------------------------------------------------------------------|
+//! | 0    | Success: up to date, built, fetched or checked                   |
+//! | 1    | Any other failure                                                |
+//! | 2    | Invalid command line                                             |
+//! | 3    | Network failure talking to the remote                            |
+//! | 4    | Upstream can't be merged into the local checkout                 |
+//! | 5    | Out of space, in the image or on the host disk                   |
+//! | 6    | A file doesn't match its source after `--verify`, or in `verify` |
+//! | 7    | Some files failed to copy with `--keep-going`                    |
+//! | 8    | `sd.xz` is corrupt, doesn't match its checksum or isn't FAT      |
+//! | 9    | `--compare` or `--diff-image` found differences                   |
+//! | 10   | Another updater is already running in this directory             |
+//! | 11   | A `--pre-build-hook` or `--post-build-hook` command failed       |
+//! | 12   | A signature check or `--allowed-host` refused the source         |
+//! | 13   | Upstream was force-pushed or rebased, see `--allow-rewrite`      |
+//! | 14   | `sd_source`'s git data is damaged, see `--auto-repair`           |
+//! | 130  | Interrupted with Ctrl+C                                          |
+
+use std::fmt;
+use std::time::Duration;
+
+/// How long to back off after upstream turned a request down for hitting its rate limit,
+/// when it didn't say how long with `Retry-After`.
+pub const RATE_LIMIT_DELAY: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug)]
+pub enum UpdateError {
+    Usage(String),
+    Network(git2::Error),
+    MergeConflict(String),
+    DiskFull(String),
+    Verification(String),
+    /// This many files failed to copy under `--keep-going`; the rest of the image is fine.
+    CopyFailed(usize),
+    CorruptAsset(String),
+    /// `--compare` found this many paths where the image doesn't match the source.
+    ImageDiffers(usize),
+    /// `--diff-image` found this many paths where the two images don't match.
+    ImagesDiffer(usize),
+    AlreadyRunning(String),
+    HookFailed(String),
+    UntrustedSource(String),
+    /// Upstream was force-pushed or rebased since the last pull, see `--allow-rewrite`.
+    UpstreamRewritten(String),
+    /// Reading `sd_source`'s own objects or refs failed, so pulling again won't help.
+    CorruptCheckout(git2::Error),
+    /// Stopped part way by Ctrl+C, see `cancel.rs`.
+    Interrupted(String),
+    Git(git2::Error),
+    Io(std::io::Error),
+    Other(String),
+}
+
+impl UpdateError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            UpdateError::Usage(_) => 2,
+            UpdateError::Network(_) => 3,
+            UpdateError::MergeConflict(_) => 4,
+            UpdateError::DiskFull(_) => 5,
+            UpdateError::Verification(_) => 6,
+            UpdateError::CopyFailed(_) => 7,
+            UpdateError::CorruptAsset(_) => 8,
+            UpdateError::ImageDiffers(_) | UpdateError::ImagesDiffer(_) => 9,
+            UpdateError::AlreadyRunning(_) => 10,
+            UpdateError::HookFailed(_) => 11,
+            UpdateError::UntrustedSource(_) => 12,
+            UpdateError::UpstreamRewritten(_) => 13,
+            UpdateError::CorruptCheckout(_) => 14,
+            UpdateError::Interrupted(_) => 130,
+            UpdateError::Git(_) | UpdateError::Io(_) | UpdateError::Other(_) => 1,
+        }
+    }
+
+    /// Whether running the whole pipeline again later might succeed, like after a dropped
+    /// connection. Anything that needs someone to look at it first, like a merge conflict,
+    /// a failed signature check or a full disk, is not.
+    pub fn is_recoverable(&self) -> bool {
+        match self {
+            UpdateError::Network(_) => true,
+            UpdateError::Git(_) => self.rate_limit_delay().is_some(),
+            UpdateError::Io(e) => matches!(
+                e.kind(),
+                std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::Interrupted
+                    | std::io::ErrorKind::WouldBlock
+                    | std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::BrokenPipe
+            ),
+            _ => false,
+        }
+    }
+
+    /// How long to wait before asking upstream again if it turned the request down for hitting
+    /// its rate limit: the `Retry-After` it sent if git passed that on, `RATE_LIMIT_DELAY`
+    /// otherwise.
+    pub fn rate_limit_delay(&self) -> Option<Duration> {
+        let (UpdateError::Network(e) | UpdateError::Git(e)) = self else {
+            return None;
+        };
+        let message = e.message().to_lowercase();
+        is_rate_limited(&message).then(|| retry_after(&message).unwrap_or(RATE_LIMIT_DELAY))
+    }
+
+    /// A couple of words for the summary line.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            UpdateError::Usage(_) => "invalid arguments",
+            UpdateError::Network(_) => "network failure",
+            UpdateError::MergeConflict(_) => "merge conflict",
+            UpdateError::DiskFull(_) => "out of space",
+            UpdateError::Verification(_) => "verification failure",
+            UpdateError::CopyFailed(_) => "some files failed to copy",
+            UpdateError::CorruptAsset(_) => "corrupt sd.xz",
+            UpdateError::ImageDiffers(_) => "the image differs from the source",
+            UpdateError::ImagesDiffer(_) => "the images differ",
+            UpdateError::AlreadyRunning(_) => "already running",
+            UpdateError::HookFailed(_) => "build hook failed",
+            UpdateError::UntrustedSource(_) => "untrusted source",
+            UpdateError::UpstreamRewritten(_) => "upstream history rewritten",
+            UpdateError::CorruptCheckout(_) => "corrupt sd_source",
+            UpdateError::Interrupted(_) => "interrupted",
+            UpdateError::Git(_) => "git error",
+            UpdateError::Io(_) => "I/O error",
+            UpdateError::Other(_) => "error",
+        }
+    }
+}
+
+impl fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UpdateError::Network(e) | UpdateError::Git(e) => write!(f, "{}", describe_git_error(e)),
+            UpdateError::CorruptCheckout(e) => write!(
+                f,
+                "sd_source's git data is damaged ({}). Run again with --force-reclone to download a fresh copy, \
+                 or with --auto-repair to have that done when it happens",
+                e.message()
+            ),
+            UpdateError::Io(e) => write!(f, "{}", e),
+            UpdateError::CopyFailed(count) => write!(f, "{} files failed to copy, see the warnings above", count),
+            UpdateError::ImageDiffers(count) => write!(f, "{} paths differ between the source and the image", count),
+            UpdateError::ImagesDiffer(count) => write!(f, "{} paths differ between the two images", count),
+            UpdateError::Usage(message)
+            | UpdateError::MergeConflict(message)
+            | UpdateError::DiskFull(message)
+            | UpdateError::Verification(message)
+            | UpdateError::CorruptAsset(message)
+            | UpdateError::AlreadyRunning(message)
+            | UpdateError::HookFailed(message)
+            | UpdateError::UntrustedSource(message)
+            | UpdateError::UpstreamRewritten(message)
+            | UpdateError::Interrupted(message)
+            | UpdateError::Other(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+fn is_network_error(e: &git2::Error) -> bool {
+    matches!(e.code(), git2::ErrorCode::Auth | git2::ErrorCode::Certificate)
+        || matches!(e.class(), git2::ErrorClass::Net | git2::ErrorClass::Http | git2::ErrorClass::Ssl | git2::ErrorClass::Ssh)
+}
+
+/// Whether `e` comes from the local repository's own data being broken, a missing or
+/// unreadable object or a ref that doesn't parse, rather than from the remote.
+fn is_corruption(e: &git2::Error) -> bool {
+    let message = e.message().to_lowercase();
+    matches!(e.class(), git2::ErrorClass::Odb | git2::ErrorClass::Object | git2::ErrorClass::Zlib)
+        // A ref that isn't there is more likely a branch or tag that doesn't exist upstream.
+        || (e.class() == git2::ErrorClass::Reference && e.code() != git2::ErrorCode::NotFound)
+        || message.contains("corrupt")
+        || message.contains("bad object")
+}
+
+/// Turns a git error from reading `sd_source` into `CorruptCheckout` if it looks like one.
+/// Network errors stay what they are, whatever the message says.
+pub fn check_corruption(e: UpdateError) -> UpdateError {
+    match e {
+        UpdateError::Git(e) if is_corruption(&e) => UpdateError::CorruptCheckout(e),
+        e => e,
+    }
+}
+
+impl From<git2::Error> for UpdateError {
+    fn from(e: git2::Error) -> Self {
+        if is_network_error(&e) {
+            UpdateError::Network(e)
+        } else {
+            UpdateError::Git(e)
+        }
+    }
+}
+
+impl From<std::io::Error> for UpdateError {
+    fn from(e: std::io::Error) -> Self {
+        if e.kind() == std::io::ErrorKind::StorageFull {
+            UpdateError::DiskFull(e.to_string())
+        } else {
+            UpdateError::Io(e)
+        }
+    }
+}
+
+impl<T: fmt::Debug> From<fatfs::Error<T>> for UpdateError {
+    fn from(e: fatfs::Error<T>) -> Self {
+        match e {
+            fatfs::Error::NotEnoughSpace => UpdateError::DiskFull("The image has no free space left".to_string()),
+            e => UpdateError::Other(format!("{:?}", e)),
+        }
+    }
+}
+
+/// Whether a lowercased git2 message is upstream refusing for too many requests. GitHub
+/// answers with 429, or with 403 and a message about the rate limit.
+fn is_rate_limited(message: &str) -> bool {
+    message.contains("rate limit")
+        || message.contains("too many requests")
+        || message.split(|c: char| !c.is_ascii_alphanumeric()).any(|word| word == "429")
+}
+
+/// The seconds of a `Retry-After` header in a lowercased git2 message, if there is one.
+fn retry_after(message: &str) -> Option<Duration> {
+    let at = message.find("retry-after")? + "retry-after".len();
+    let digits: String = message[at..]
+        .trim_start_matches(|c: char| c == ':' || c.is_whitespace())
+        .chars()
+        .take_while(char::is_ascii_digit)
+        .collect();
+    digits.parse().ok().map(Duration::from_secs)
+}
+
+/// Turns the git2 errors people actually hit on a first run into something actionable.
+pub fn describe_git_error(e: &git2::Error) -> String {
+    let message = e.message().to_lowercase();
+    let hint = if is_rate_limited(&message) {
+        "Upstream's rate limit was hit, too many requests came from this address: wait a while before trying again"
+    } else if e.code() == git2::ErrorCode::Auth || message.contains("authentication") || message.contains("401") {
+        "Authentication required: the repository may be private, or the URL may be wrong"
+    } else if e.code() == git2::ErrorCode::Certificate || e.class() == git2::ErrorClass::Ssl {
+        "TLS certificate verification failed: check the system clock and any proxy intercepting HTTPS"
+    } else if message.contains("404") || (e.class() == git2::ErrorClass::Http && message.contains("not found")) {
+        "Repository not found: check the URL"
+    } else if message.contains("resolve") {
+        "Could not resolve the host: check your internet connection and the URL"
+    } else if message.contains("timed out") {
+        "The connection timed out: check your internet connection or try again later"
+    } else if e.class() == git2::ErrorClass::Net || e.class() == git2::ErrorClass::Http {
+        "Network error while talking to the remote"
+    } else {
+        return e.message().to_string();
+    };
+    format!("{} ({})", hint, e.message())
+}
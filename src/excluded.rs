@@ -0,0 +1,94 @@
+//! `--list-excluded`: every path of the source and overlays that the copy leaves out,
+//! grouped by the rule that leaves it out, without copying anything. Each path is judged
+//! by `image::exclusion`, the check the copy itself makes.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::dirsort::sorted_entries;
+use crate::error::UpdateError;
+use crate::image::{exclusion, source_root, Exclusion, FileFilter};
+use crate::longpath::{self, LongPaths};
+use crate::options::{OnLongPath, Options};
+use crate::policy::Action;
+use crate::units::format_bytes;
+use crate::{info, packaging, warn};
+
+/// The paths the copy leaves out, by rule.
+pub type Excluded = BTreeMap<Exclusion, Vec<PathBuf>>;
+
+/// Collects what the copy leaves out under `host_path`. Nothing below an excluded
+/// directory is looked at, the copy never gets there either.
+fn walk(host_path: &Path, exclude: &[PathBuf], long_paths: &LongPaths, filter: &FileFilter, excluded: &mut Excluded) -> Result<(), UpdateError> {
+    for path in sorted_entries(host_path)? {
+        let path = path?;
+        match exclusion(&path, exclude, long_paths, filter)? {
+            Some(rule) => excluded.entry(rule).or_default().push(path),
+            None if path.is_dir() => walk(&path, exclude, long_paths, filter, excluded)?,
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+/// Lists what a build from `sd_source_path` would leave out, and returns it.
+pub fn list_excluded(sd_source_path: &Path, options: &Options) -> Result<Excluded, UpdateError> {
+    let options = &packaging::merged(sd_source_path, options)?;
+    let root = source_root(sd_source_path, options)?;
+    let mut roots = vec![root.as_path()];
+    roots.extend(options.overlays.iter().map(PathBuf::as_path));
+    // A build with `error` stops on these instead, they are listed with the rest here.
+    let policy = match options.on_longpath {
+        OnLongPath::Error => OnLongPath::Skip,
+        policy => policy,
+    };
+    let filter = FileFilter::new(options);
+    let exclude: Vec<PathBuf> = options.exclude.iter().map(|path| root.join(path)).collect();
+    let long_paths = longpath::check(&roots, &exclude, &filter, &options.target_prefix(), policy)?;
+
+    let mut excluded = Excluded::new();
+    walk(&root, &exclude, &long_paths, &filter, &mut excluded)?;
+    // The manifest only applies to the source, like in `copy_sources`.
+    for overlay in &options.overlays {
+        walk(overlay, &[], &long_paths, &filter, &mut excluded)?;
+    }
+
+    for (rule, paths) in &excluded {
+        info(format!("Left out, {} ({}):\n", rule.describe(), paths.len()).as_str());
+        for path in paths {
+            // Overlays aren't under the source, they keep their full path.
+            info(format!("  {}\n", path.strip_prefix(&root).unwrap_or(path).display()).as_str());
+        }
+    }
+    let total: usize = excluded.values().map(Vec::len).sum();
+    info(format!("{} paths left out\n", total).as_str());
+    if options.on_longpath == OnLongPath::Error && excluded.contains_key(&Exclusion::LongPath) {
+        warn("With --on-longpath=error a build stops on the paths too long for FAT instead of leaving them out\n");
+    }
+    Ok(excluded)
+}
+
+/// With `--policy oversized-file=error`, fails on the files under `root` and the overlays
+/// larger than `--exclude-larger-than` before the copy writes anything.
+pub(crate) fn check_oversized(root: &Path, options: &Options, long_paths: &LongPaths) -> Result<(), UpdateError> {
+    let Some(limit) = options.exclude_larger_than.filter(|_| options.policy.oversized_file == Action::Error) else {
+        return Ok(());
+    };
+    let filter = FileFilter::new(options);
+    let exclude: Vec<PathBuf> = options.exclude.iter().map(|path| root.join(path)).collect();
+    let mut excluded = Excluded::new();
+    walk(root, &exclude, long_paths, &filter, &mut excluded)?;
+    for overlay in &options.overlays {
+        walk(overlay, &[], long_paths, &filter, &mut excluded)?;
+    }
+    match excluded.get(&Exclusion::LargerThan).map(Vec::as_slice) {
+        Some([path, rest @ ..]) => Err(UpdateError::Other(format!(
+            "{} files are larger than --exclude-larger-than {}, e.g. {} ({}) (--policy oversized-file)",
+            rest.len() + 1,
+            format_bytes(limit),
+            path.display(),
+            format_bytes(path.metadata()?.len())
+        ))),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,303 @@
+//! `--explain <glob>`: why a build copies, skips or removes the paths matching a glob,
+//! worked out without touching the image or the card. For each path it lists every tree
+//! that has it, the source, the overlays and `--add-file`, with the rule that leaves it out
+//! if one does, which of them wins, how the winner compares to the file on the card by
+//! size, time and contents, and what the build does with it.
+//!
+//! A pattern without a `/` matches names anywhere, like `*.ini`, one with a `/` the whole
+//! path on the card, like `User/Config/*`. `*` and `?` stay within a name, `**` spans
+//! directories. Case doesn't matter, like on FAT.
+//!
+//! The build goes through the same walk to report the paths more than one tree writes,
+//! see `report_collisions`.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use fatfs::FileSystem;
+
+use crate::dest::{CardEntry, DestDir, DestFile, HostDir};
+use crate::dirsort::sorted_entries;
+use crate::error::UpdateError;
+use crate::hash::{hash_reader, ContentHasher};
+use crate::image::{exclusion, on_card, open_fs_region, source_root, Exclusion, FileFilter, OnCard};
+use crate::logging::is_verbose;
+use crate::longpath::{self, LongPaths};
+use crate::options::{OnLongPath, OnNewer, Options};
+use crate::policy::Action;
+use crate::timestamps::{format_utc, from_fat_datetime, to_fat_datetime};
+use crate::units::format_bytes;
+use crate::{delta, info, packaging, partition};
+
+fn matches(pattern: &[char], text: &[char]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some(('*', ['*', rest @ ..])) => {
+            // `**/` matches no directories at all too.
+            (0..=text.len()).any(|i| matches(rest, &text[i..])) || (rest.first() == Some(&'/') && matches(&rest[1..], text))
+        }
+        Some(('*', rest)) => (0..=text.len()).take_while(|i| *i == 0 || text[i - 1] != '/').any(|i| matches(rest, &text[i..])),
+        Some(('?', rest)) => text.first().is_some_and(|c| *c != '/') && matches(rest, &text[1..]),
+        Some((c, rest)) => text.first() == Some(c) && matches(rest, &text[1..]),
+    }
+}
+
+/// Whether the path on the card `path` matches `pattern`.
+pub(crate) fn glob_matches(pattern: &str, path: &str) -> bool {
+    let path = if pattern.contains('/') { path } else { path.rsplit('/').next().unwrap_or(path) };
+    let pattern: Vec<char> = pattern.trim_start_matches('/').to_lowercase().chars().collect();
+    let path: Vec<char> = path.to_lowercase().chars().collect();
+    matches(&pattern, &path)
+}
+
+/// A tree that has a path, and whether the copy takes it from there.
+struct Candidate {
+    from: String,
+    host_path: PathBuf,
+    excluded: Option<Exclusion>,
+}
+
+/// Paths on the card matching the pattern, keyed by their lowercase path, with their
+/// spelling and the trees that have them in the order the copy goes through them.
+type Found = BTreeMap<String, (String, Vec<Candidate>)>;
+
+struct Walker<'a> {
+    pattern: &'a str,
+    long_paths: &'a LongPaths,
+    filter: FileFilter,
+}
+
+impl Walker<'_> {
+    /// Collects what matches under `host_path`, which goes to `prefix` on the card. An
+    /// excluded directory is listed itself, the copy never looks inside.
+    fn walk(&self, host_path: &Path, prefix: &str, from: &str, exclude: &[PathBuf], found: &mut Found) -> Result<(), UpdateError> {
+        for path in sorted_entries(host_path)? {
+            let path = path?;
+            let name = match self.long_paths.get(&path) {
+                Some(Some(shorter)) => shorter.clone(),
+                _ => path.file_name().unwrap().to_string_lossy().to_string(),
+            };
+            let card_path = format!("{}{}", prefix, name);
+            let excluded = exclusion(&path, exclude, self.long_paths, &self.filter)?;
+            let is_dir = path.is_dir();
+            if (!is_dir || excluded.is_some()) && glob_matches(self.pattern, &card_path) {
+                let candidate = Candidate { from: from.to_string(), host_path: path.clone(), excluded };
+                found.entry(card_path.to_lowercase()).or_insert_with(|| (card_path.clone(), Vec::new())).1.push(candidate);
+            }
+            if is_dir && excluded.is_none() {
+                self.walk(&path, &format!("{}/", card_path), from, exclude, found)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The paths on the card matching `pattern` and the trees that have them: the source at
+/// `root`, the overlays and `--add-file`.
+fn find(pattern: &str, root: &Path, options: &Options, long_paths: &LongPaths) -> Result<Found, UpdateError> {
+    let walker = Walker { pattern, long_paths, filter: FileFilter::new(options) };
+    let mut found = Found::new();
+    let exclude: Vec<PathBuf> = options.exclude.iter().map(|path| root.join(path)).collect();
+    let prefix = options.target_prefix();
+    walker.walk(root, &prefix, "the source", &exclude, &mut found)?;
+    for overlay in &options.overlays {
+        walker.walk(overlay, &prefix, &format!("overlay {}", overlay.display()), &[], &mut found)?;
+    }
+    for (host_path, path) in options.add_files.iter().filter(|(_, path)| glob_matches(pattern, path)) {
+        let candidate = Candidate { from: "--add-file".to_string(), host_path: host_path.clone(), excluded: None };
+        found.entry(path.to_lowercase()).or_insert_with(|| (path.clone(), Vec::new())).1.push(candidate);
+    }
+    Ok(found)
+}
+
+/// Reports the paths on the card more than one tree writes, which of them do and which
+/// one wins, before the copy quietly lets the last one win. `--policy path-collision`
+/// says whether that is logged, a warning or stops the build. Only the overlays and
+/// `--add-file` can collide with the source.
+pub(crate) fn report_collisions(root: &Path, options: &Options, long_paths: &LongPaths) -> Result<(), UpdateError> {
+    let action = options.policy.path_collision;
+    if (options.overlays.is_empty() && options.add_files.is_empty()) || (action == Action::Skip && !is_verbose()) {
+        return Ok(());
+    }
+    let mut collisions = 0;
+    for (path, candidates) in find("**", root, options, long_paths)?.values() {
+        let copied: Vec<&str> = candidates.iter().filter(|candidate| candidate.excluded.is_none()).map(|candidate| candidate.from.as_str()).collect();
+        let Some((winner, others)) = copied.split_last().filter(|(_, others)| !others.is_empty()) else {
+            continue;
+        };
+        collisions += 1;
+        action.report(format!("{} is written by {} and {}, {} wins\n", path, others.join(", "), winner, winner).as_str());
+    }
+    if collisions > 0 && action == Action::Error {
+        return Err(UpdateError::Other(format!(
+            "{} paths on the card are written by more than one tree, see above (--policy path-collision)",
+            collisions
+        )));
+    }
+    Ok(())
+}
+
+/// Calls `f` with the entry at `path` under `dir` and the directory it is in, if the card
+/// has it.
+fn with_card_entry<D: DestDir, T>(dir: &D, path: &str, f: impl FnOnce(&D, CardEntry) -> Result<T, UpdateError>) -> Result<Option<T>, UpdateError> {
+    let mut entries = dir.entries()?;
+    match path.split_once('/') {
+        Some((name, rest)) => match entries.remove(&name.to_lowercase()) {
+            Some(entry) if entry.is_dir => with_card_entry(&dir.open_dir(&entry.name)?, rest, f),
+            _ => Ok(None),
+        },
+        None => entries.remove(&path.to_lowercase()).map(|entry| f(dir, entry)).transpose(),
+    }
+}
+
+fn describe_time(time: fatfs::DateTime) -> String {
+    let secs = from_fat_datetime(time).duration_since(std::time::UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or_default();
+    format_utc(secs as i64)
+}
+
+/// Whether the file `name` in `dir` on the card holds the same as `host_path`.
+fn same_contents<D: DestDir>(dir: &D, name: &str, host_path: &Path, options: &Options) -> Result<bool, UpdateError> {
+    let mut buffer = vec![0_u8; 1024 * 1024];
+    let source_hash = hash_reader(&mut File::open(host_path)?, &mut buffer, options.hash)?;
+    let mut sd_file = dir.open_file(name)?;
+    let mut hasher = ContentHasher::new(options.hash);
+    loop {
+        let bytes_read = sd_file.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..bytes_read]);
+    }
+    Ok(hasher.finish() == source_hash)
+}
+
+/// What the build does with `winner`, the last tree to copy the path, compared to `card`,
+/// the card or image being updated in place. `None` for an image rebuilt from `sd.xz`.
+fn action<D: DestDir>(card: Option<&D>, path: &str, winner: &Candidate, over_another: bool, options: &Options) -> Result<String, UpdateError> {
+    let Some(card) = card else {
+        return Ok("copied, the image is rebuilt from sd.xz".to_string());
+    };
+    let metadata = std::fs::metadata(&winner.host_path)?;
+    let (len, modified) = (metadata.len(), to_fat_datetime(metadata.modified()?));
+    let state = with_card_entry(card, path, |dir, entry| {
+        let state = on_card(Some(&entry), len, modified);
+        if state == OnCard::Directory {
+            return Ok(state);
+        }
+        info(format!(
+            "  On the card: {} modified {}, here: {} modified {}\n",
+            format_bytes(entry.len),
+            describe_time(entry.modified),
+            format_bytes(len),
+            describe_time(modified)
+        ).as_str());
+        // The copy goes by size and time alone, the contents tell whether that is right.
+        if state != OnCard::Unchanged && entry.len == len {
+            let same = same_contents(dir, &entry.name, &winner.host_path, options)?;
+            info(format!("  By {}, the contents are {}\n", options.hash.name(), if same { "the same" } else { "different" }).as_str());
+        }
+        Ok(state)
+    })?;
+    // Overlays and added files go over what is there whatever its time, see `write_file`.
+    let overlay = winner.from != "the source";
+    Ok(match state.unwrap_or(OnCard::Missing) {
+        _ if over_another => "copied over the earlier tree's copy".to_string(),
+        OnCard::Missing => "copied, it isn't on the card".to_string(),
+        OnCard::Directory => "fails, it's a directory on the card".to_string(),
+        OnCard::Unchanged => "skipped, the card has it with the same size and time".to_string(),
+        OnCard::Newer if !overlay => match options.on_newer {
+            OnNewer::Overwrite => "copied over the card's newer copy (--on-newer=overwrite)".to_string(),
+            OnNewer::Skip => "skipped, the card's copy is newer (--on-newer=skip)".to_string(),
+            OnNewer::Error => "fails, the card's copy is newer (--on-newer=error)".to_string(),
+        },
+        OnCard::Newer | OnCard::Changed => "copied, the card's copy differs in size or time".to_string(),
+    })
+}
+
+/// What the build does with each path, by its path on the card.
+pub type Explained = BTreeMap<String, String>;
+
+fn explain_found<D: DestDir>(found: &Found, deleted: &[String], card: Option<&D>, options: &Options) -> Result<Explained, UpdateError> {
+    let mut explained = Explained::new();
+    let preserved: Vec<String> = if card.is_none() { options.preserve().iter().map(|dir| format!("{}/", dir.to_lowercase())).collect() } else { Vec::new() };
+    for (key, (path, candidates)) in found {
+        info(format!("{}:\n", path).as_str());
+        for candidate in candidates {
+            match candidate.excluded {
+                Some(rule) => info(format!("  {} has it at {}, left out: {}\n", candidate.from, candidate.host_path.display(), rule.describe()).as_str()),
+                None => info(format!("  {} has it at {}\n", candidate.from, candidate.host_path.display()).as_str()),
+            }
+        }
+        let copied: Vec<&Candidate> = candidates.iter().filter(|candidate| candidate.excluded.is_none()).collect();
+        let oversized = candidates.iter().any(|candidate| candidate.excluded == Some(Exclusion::LargerThan));
+        if oversized && options.policy.oversized_file == Action::Error {
+            let action = "fails, it's larger than --exclude-larger-than (--policy oversized-file=error)".to_string();
+            info(format!("  Action: {}\n", action).as_str());
+            explained.insert(path.clone(), action);
+            continue;
+        }
+        let Some(winner) = copied.last() else {
+            info("  Not copied\n");
+            explained.insert(path.clone(), "not copied".to_string());
+            continue;
+        };
+        if copied.len() > 1 {
+            info(format!("  {} wins, it is copied last\n", winner.from).as_str());
+        }
+        let action = action(card, path, winner, copied.len() > 1, options)?;
+        info(format!("  Action: {}\n", action).as_str());
+        explained.insert(path.clone(), action);
+        if options.verify {
+            info(format!("  --verify reads it back and checks it by {}\n", options.hash.name()).as_str());
+        }
+        if preserved.iter().any(|dir| key.starts_with(dir.as_str())) {
+            info("  Then replaced by the old image's copy, kept by --preserve\n");
+        }
+    }
+    for path in deleted {
+        let action = "removed from the card, it was deleted upstream since the image was built";
+        info(format!("{}:\n  Action: {}\n", path, action).as_str());
+        explained.insert(path.clone(), action.to_string());
+    }
+    if explained.is_empty() {
+        info("Nothing the build copies matches\n");
+    }
+    Ok(explained)
+}
+
+/// Logs what a build from `sd_source_path` would do with the paths matching `--explain`,
+/// and returns it.
+pub fn explain(sd_source_path: &Path, options: &Options) -> Result<Explained, UpdateError> {
+    let options = &packaging::merged(sd_source_path, options)?;
+    let Some(pattern) = options.explain.as_deref() else {
+        return Ok(Explained::new());
+    };
+    let root = source_root(sd_source_path, options)?;
+    let mut roots = vec![root.as_path()];
+    roots.extend(options.overlays.iter().map(PathBuf::as_path));
+    // Explained like the rest instead of stopping on them.
+    let policy = match options.on_longpath {
+        OnLongPath::Error => OnLongPath::Skip,
+        policy => policy,
+    };
+    let exclude: Vec<PathBuf> = options.exclude.iter().map(|path| root.join(path)).collect();
+    let long_paths = longpath::check(&roots, &exclude, &FileFilter::new(options), &options.target_prefix(), policy)?;
+    let found = find(pattern, &root, options, &long_paths)?;
+    let prefix = options.target_prefix();
+    let deleted: Vec<String> = match delta::since_build(sd_source_path, &root, options, &long_paths) {
+        Some(delta) => delta.deleted.into_iter().map(|path| format!("{}{}", prefix, path)).filter(|path| glob_matches(pattern, path)).collect(),
+        None => Vec::new(),
+    };
+
+    match &options.dest_dir {
+        Some(dest_dir) => explain_found(&found, &deleted, Some(&HostDir(dest_dir.clone())), options),
+        None if options.incremental && options.output().exists() => {
+            let offset = if options.partitioned { partition::PARTITION_OFFSET } else { 0 };
+            let (fs_region, _) = open_fs_region(options, offset)?;
+            let fs = FileSystem::new(fs_region, fatfs::FsOptions::new().oem_cp_converter(options.code_page))?;
+            explain_found(&found, &deleted, Some(&fs.root_dir()), options)
+        }
+        None => explain_found::<HostDir>(&found, &deleted, None, options),
+    }
+}
@@ -0,0 +1,102 @@
+//! Formatting a FAT filesystem from scratch, where the updater makes its own base image
+//! instead of taking the one in `sd.xz`: with `--format`, and for `--bench`.
+//!
+//! `--fat-type` picks FAT12, FAT16 or FAT32, otherwise fatfs picks from the volume size.
+//! The type is defined by the number of clusters, so each one only fits a range of sizes:
+//! FAT16 tops out around 2G with 32K clusters, and FAT32 needs at least 65525 clusters,
+//! about 32M with the smallest ones. The Wii reads all three.
+//!
+//! `--cluster-size` picks the allocation unit. Every file takes at least one cluster, so
+//! large clusters waste space on trees of many small files: 10,000 files of 1K each take
+//! 320M with 32K clusters but 40M with 4K ones. Small clusters mean a longer FAT and more
+//! of it to walk for large files, which slows writing ISOs and other big files. Unset lets
+//! fatfs pick from the volume size the way common formatters do, e.g. 32K for a 2G FAT32
+//! volume.
+
+use crate::options::Options;
+use crate::units::format_bytes;
+
+/// The smallest cluster is one 512 byte sector. 32K is the largest cluster every FAT
+/// implementation handles; Windows can make 64K clusters but many readers reject them.
+pub const MIN_CLUSTER_SIZE: u64 = 512;
+pub const MAX_CLUSTER_SIZE: u64 = 32 * 1024;
+
+/// Checks a `--cluster-size`.
+pub fn check_cluster_size(cluster_size: u64) -> Result<(), String> {
+    if !cluster_size.is_power_of_two() || !(MIN_CLUSTER_SIZE..=MAX_CLUSTER_SIZE).contains(&cluster_size) {
+        return Err(format!(
+            "--cluster-size must be a power of two from {} to {}, got {}",
+            MIN_CLUSTER_SIZE, MAX_CLUSTER_SIZE, cluster_size
+        ));
+    }
+    Ok(())
+}
+
+/// The largest volume FAT can address, 2^32 sectors of 512 bytes.
+pub const MAX_VOLUME_SIZE: u64 = (1 << 32) * 512;
+
+/// How many clusters a filesystem of each type can have.
+fn cluster_range(fat_type: fatfs::FatType) -> (u64, u64) {
+    match fat_type {
+        fatfs::FatType::Fat12 => (1, 4084),
+        fatfs::FatType::Fat16 => (4085, 65524),
+        fatfs::FatType::Fat32 => (65525, 0x0FFF_FFF4),
+    }
+}
+
+pub fn parse_fat_type(name: &str, value: &str) -> Result<Option<fatfs::FatType>, String> {
+    match value.trim().to_lowercase().as_str() {
+        "auto" => Ok(None),
+        "12" | "fat12" => Ok(Some(fatfs::FatType::Fat12)),
+        "16" | "fat16" => Ok(Some(fatfs::FatType::Fat16)),
+        "32" | "fat32" => Ok(Some(fatfs::FatType::Fat32)),
+        other => Err(format!("--{} expects 12, 16, 32 or auto, got '{}'", name, other)),
+    }
+}
+
+/// Checks that a filesystem of `size` bytes can be formatted with `--fat-type` and
+/// `--cluster-size`. Only roughly, the FAT and the root directory take a little of it.
+pub fn check_size(size: u64, options: &Options) -> Result<(), String> {
+    if size > MAX_VOLUME_SIZE {
+        return Err(format!("--sd-size {} is larger than FAT can address", format_bytes(size)));
+    }
+    let Some(fat_type) = options.fat_type else {
+        return Ok(());
+    };
+    let (min_clusters, max_clusters) = cluster_range(fat_type);
+    let (smallest, largest) = match options.cluster_size {
+        Some(cluster_size) => (cluster_size, cluster_size),
+        None => (MIN_CLUSTER_SIZE, MAX_CLUSTER_SIZE),
+    };
+    if size / smallest < min_clusters {
+        return Err(format!(
+            "--sd-size {} is too small for {:?}{}, which needs at least {} clusters",
+            format_bytes(size),
+            fat_type,
+            options.cluster_size.map(|_| " with that --cluster-size").unwrap_or_default(),
+            min_clusters
+        ));
+    }
+    if size / largest > max_clusters {
+        return Err(format!(
+            "--sd-size {} is too large for {:?}{}, which holds at most {} clusters",
+            format_bytes(size),
+            fat_type,
+            options.cluster_size.map(|_| " with that --cluster-size").unwrap_or_default(),
+            max_clusters
+        ));
+    }
+    Ok(())
+}
+
+/// The options to format a fresh filesystem with.
+pub fn format_options(options: &Options) -> fatfs::FormatVolumeOptions {
+    let mut format = fatfs::FormatVolumeOptions::new();
+    if let Some(cluster_size) = options.cluster_size {
+        format = format.bytes_per_cluster(cluster_size as u32);
+    }
+    if let Some(fat_type) = options.fat_type {
+        format = format.fat_type(fat_type);
+    }
+    format
+}
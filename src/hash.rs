@@ -0,0 +1,82 @@
+//! Content hashing for `--verify`, `--compare`, `--verify-only`, `--report-dupes` and
+//! `--image-hash`, with the algorithm `--hash` picks. Checksums whose format fixes the
+//! algorithm, like `sd.xz.blake3`, always use BLAKE3.
+
+use sha2::Digest;
+
+use crate::options::HashAlgorithm;
+
+/// One of the algorithms `--hash` can pick.
+pub trait Hasher {
+    fn update(&mut self, data: &[u8]);
+
+    /// The digest as a lowercase hex string.
+    fn finish(&self) -> String;
+}
+
+impl Hasher for blake3::Hasher {
+    fn update(&mut self, data: &[u8]) {
+        blake3::Hasher::update(self, data);
+    }
+
+    fn finish(&self) -> String {
+        self.finalize().to_hex().to_string()
+    }
+}
+
+impl Hasher for xxhash_rust::xxh3::Xxh3 {
+    fn update(&mut self, data: &[u8]) {
+        xxhash_rust::xxh3::Xxh3::update(self, data);
+    }
+
+    fn finish(&self) -> String {
+        format!("{:032x}", self.digest128())
+    }
+}
+
+impl Hasher for sha2::Sha256 {
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(self, data);
+    }
+
+    fn finish(&self) -> String {
+        self.clone().finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+/// Hashes file contents as they stream through the copy buffer.
+pub struct ContentHasher {
+    inner: Box<dyn Hasher>,
+}
+
+impl ContentHasher {
+    pub fn new(algorithm: HashAlgorithm) -> ContentHasher {
+        let inner: Box<dyn Hasher> = match algorithm {
+            HashAlgorithm::Xxh3 => Box::new(xxhash_rust::xxh3::Xxh3::new()),
+            HashAlgorithm::Blake3 => Box::new(blake3::Hasher::new()),
+            HashAlgorithm::Sha256 => Box::new(sha2::Sha256::new()),
+        };
+        ContentHasher { inner }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    /// The digest as a lowercase hex string.
+    pub fn finish(&self) -> String {
+        self.inner.finish()
+    }
+}
+
+/// Hashes everything left in `reader` with `algorithm`, using `buffer` to read through it.
+pub fn hash_reader(reader: &mut dyn std::io::Read, buffer: &mut [u8], algorithm: HashAlgorithm) -> std::io::Result<String> {
+    let mut hasher = ContentHasher::new(algorithm);
+    loop {
+        let bytes_read = reader.read(buffer)?;
+        if bytes_read == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.update(&buffer[..bytes_read]);
+    }
+}
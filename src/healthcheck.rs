@@ -0,0 +1,187 @@
+//! `healthcheck`: a quick look at whether a run could work, for monitoring, without
+//! building anything. `sd.xz` has to be there and intact as far as its index goes, the
+//! output has to be writable with room for the image, upstream has to answer, and an image
+//! that is already there has to mount as FAT.
+//!
+//! Every check runs even after one failed, and each is reported as passed, failed or
+//! skipped, as text or as JSON with `--health-check=json`. The exit code is that of the
+//! first failure.
+
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+
+use fatfs::FileSystem;
+
+use crate::error::UpdateError;
+use crate::git::remote_reachable;
+use crate::image::{check_sd_xz, open_fs_region, sd_xz_path};
+use crate::options::{DumpFormat, Options};
+use crate::partition;
+use crate::report::json_string;
+use crate::units::format_bytes;
+
+pub enum Status {
+    Passed(String),
+    Failed(UpdateError),
+    /// Doesn't apply to this setup, like mounting an image that isn't built yet.
+    Skipped(String),
+}
+
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+}
+
+impl Check {
+    fn new(name: &'static str, result: Result<Status, UpdateError>) -> Check {
+        Check { name, status: result.unwrap_or_else(Status::Failed) }
+    }
+
+    /// `pass`, `fail` or `skip`, and what was found.
+    fn outcome(&self) -> (&'static str, String) {
+        match &self.status {
+            Status::Passed(detail) => ("pass", detail.clone()),
+            Status::Failed(e) => ("fail", e.to_string()),
+            Status::Skipped(detail) => ("skip", detail.clone()),
+        }
+    }
+}
+
+fn sd_xz(options: &Options) -> Result<Status, UpdateError> {
+    let path = sd_xz_path(options);
+    if let Some(path) = path.as_ref().filter(|path| !path.exists()) {
+        return Err(UpdateError::CorruptAsset(format!("{} is missing", path.display())));
+    }
+    check_sd_xz(options)?;
+    Ok(Status::Passed(match path {
+        Some(path) => format!("{} is intact", path.display()),
+        None => "the embedded sd.xz is intact".to_string(),
+    }))
+}
+
+/// Creates and removes a file in `dir`, which is what writing there takes.
+fn probe_writable(dir: &Path) -> Result<(), UpdateError> {
+    let probe = dir.join(".updater.healthcheck");
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&probe)
+        .map_err(|e| UpdateError::Other(format!("Can't write to {}: {}", dir.display(), e)))?;
+    std::fs::remove_file(&probe)?;
+    Ok(())
+}
+
+fn output(options: &Options) -> Result<Status, UpdateError> {
+    let output = options.output();
+    if options.device.is_some() {
+        OpenOptions::new()
+            .write(true)
+            .open(&output)
+            .map_err(|e| UpdateError::Other(format!("Can't open {} for writing: {}", output.display(), e)))?;
+        return Ok(Status::Passed(format!("{} is writable", output.display())));
+    }
+    if options.dest_dir.is_some() {
+        probe_writable(&output)?;
+        return Ok(Status::Passed(format!("{} is writable", output.display())));
+    }
+    let dir = match output.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    probe_writable(&dir)?;
+    // A rebuild replaces the image that is there, so its space counts as free.
+    let existing = std::fs::metadata(&output).map(|metadata| metadata.len()).unwrap_or_default();
+    let needed = options.sd_size().saturating_sub(existing);
+    let free = fs2::available_space(&dir)?;
+    if free < needed {
+        return Err(UpdateError::DiskFull(format!(
+            "{} has {} free, {} needs {} more",
+            dir.display(),
+            format_bytes(free),
+            output.display(),
+            format_bytes(needed)
+        )));
+    }
+    Ok(Status::Passed(format!("{} is writable, {} free", dir.display(), format_bytes(free))))
+}
+
+fn remote(options: &Options) -> Result<Status, UpdateError> {
+    if options.offline {
+        return Ok(Status::Skipped("--offline".to_string()));
+    }
+    let refs = remote_reachable(options)?;
+    Ok(Status::Passed(format!("{} answers, {} refs", options.url(), refs)))
+}
+
+fn image(options: &Options) -> Result<Status, UpdateError> {
+    let output = options.output();
+    if options.dest_dir.is_some() {
+        return Ok(Status::Skipped("--dest-dir has no image".to_string()));
+    }
+    if !output.exists() {
+        return Ok(Status::Skipped(format!("there is no {} yet", output.display())));
+    }
+    let offset = if options.partitioned { partition::PARTITION_OFFSET } else { 0 };
+    let (fs_region, _) = open_fs_region(options, offset)?;
+    let fs = FileSystem::new(fs_region, fatfs::FsOptions::new().oem_cp_converter(options.code_page))
+        .map_err(|e| UpdateError::CorruptAsset(format!("{} doesn't mount as FAT: {:?}", output.display(), e)))?;
+    let stats = fs.stats()?;
+    let cluster_size = stats.cluster_size() as u64;
+    Ok(Status::Passed(format!(
+        "{} mounts as FAT, {} free of {}",
+        output.display(),
+        format_bytes(stats.free_clusters() as u64 * cluster_size),
+        format_bytes(stats.total_clusters() as u64 * cluster_size)
+    )))
+}
+
+/// Runs every check, see above.
+pub fn health_check(options: &Options) -> Vec<Check> {
+    vec![
+        Check::new("sd.xz", sd_xz(options)),
+        Check::new("output", output(options)),
+        Check::new("remote", remote(options)),
+        Check::new("image", image(options)),
+    ]
+}
+
+/// The exit code for `checks`: that of the first failure, 0 if none failed.
+pub fn exit_code(checks: &[Check]) -> i32 {
+    checks
+        .iter()
+        .find_map(|check| match &check.status {
+            Status::Failed(e) => Some(e.exit_code()),
+            _ => None,
+        })
+        .unwrap_or(0)
+}
+
+fn to_text(checks: &[Check]) -> String {
+    let mut text = String::new();
+    for check in checks {
+        let (status, detail) = check.outcome();
+        text.push_str(&format!("{} {:<6} {}\n", status, check.name, detail));
+    }
+    text
+}
+
+fn to_json(checks: &[Check]) -> String {
+    let healthy = exit_code(checks) == 0;
+    let checks: Vec<String> = checks
+        .iter()
+        .map(|check| {
+            let (status, detail) = check.outcome();
+            format!("{{\"name\":{},\"status\":\"{}\",\"detail\":{}}}", json_string(check.name), status, json_string(&detail))
+        })
+        .collect();
+    format!("{{\"healthy\":{},\"checks\":[{}]}}\n", healthy, checks.join(","))
+}
+
+/// The report `healthcheck` prints.
+pub fn render(checks: &[Check], format: DumpFormat) -> String {
+    match format {
+        DumpFormat::Text => to_text(checks),
+        DumpFormat::Json => to_json(checks),
+    }
+}
@@ -0,0 +1,52 @@
+//! `--pre-build-hook` and `--post-build-hook`: user commands run around the build, through
+//! `sh -c` (or `cmd /C` on Windows). They get these environment variables:
+//!
+//! | Variable         | Value                                                      |
+//! |------------------|------------------------------------------------------------|
+//! | `UPDATER_HOOK`   | `pre-build` or `post-build`                                |
+//! | `UPDATER_IMAGE`  | Path of the image or device being built                    |
+//! | `UPDATER_COMMIT` | Commit `sd_source` is at, empty if it isn't a git checkout |
+//!
+//! A hook that exits non-zero fails the run.
+
+use std::path::Path;
+use std::process::Command;
+
+use crate::error::UpdateError;
+use crate::{info, warn};
+
+fn shell(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.args(["/C", command]);
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.args(["-c", command]);
+        shell
+    }
+}
+
+/// Runs `command` as the `hook` hook and logs what it printed.
+pub fn run_hook(hook: &str, command: &str, image: &Path, commit: Option<&str>) -> Result<(), UpdateError> {
+    info(format!("Running the {} hook: {}\n", hook, command).as_str());
+    let output = shell(command)
+        .env("UPDATER_HOOK", hook)
+        .env("UPDATER_IMAGE", image)
+        .env("UPDATER_COMMIT", commit.unwrap_or_default())
+        .output()?;
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        info(format!("[{}] {}\n", hook, line).as_str());
+    }
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        warn(format!("[{}] {}\n", hook, line).as_str());
+    }
+    if !output.status.success() {
+        let status = match output.status.code() {
+            Some(code) => format!("exit code {}", code),
+            None => "killed by a signal".to_string(),
+        };
+        return Err(UpdateError::HookFailed(format!("The {} hook failed ({})", hook, status)));
+    }
+    Ok(())
+}
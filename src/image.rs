@@ -0,0 +1,1683 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use fatfs::{FileSystem, StdIoWrapper};
+use fscommon::{BufStream, StreamSlice};
+use xz2::read::XzDecoder;
+
+use crate::buffertune::BufferTuner;
+use crate::codepage::CodePage;
+use crate::copylog::{CopyLog, CopyOutcome};
+use crate::delta::{self, Delta};
+use crate::dest::{CardEntry, DestDir, DestFile, HostDir};
+use crate::dirsort::sorted_entries;
+use crate::dupes::DupeReport;
+use crate::error::UpdateError;
+use crate::git::{check_source_matches, head_commit};
+use crate::hash::{hash_reader, ContentHasher};
+use crate::options::{HashAlgorithm, OnNewer, Options, OutputFormat, ProgressStyle};
+use crate::policy::Action;
+use crate::progress::{Counter, Phase, ProgressBar, VERIFYING};
+use crate::state::{UpdateState, STATE_FILE};
+use crate::throttle::WriteThrottle;
+use crate::timestamps::{self, SourceTimeProvider};
+use crate::units::format_bytes;
+use crate::longpath::{self, LongPaths};
+use crate::{asset, attrs, buildinfo, commitsig, compress, debug, excluded, explain, format, hooks, imagehash, imageurl, info, label, outputlock, packaging, partition, preserve, report, resume, rootdir, shortname, signature, space, sync, vhd, warn, wipe, xzcheck, xzseek};
+
+/// The base image, baked into the binary so it can bootstrap without an `assets` folder.
+#[cfg(feature = "embedded-asset")]
+static EMBEDDED_SD_XZ: &[u8] = include_bytes!("../assets/sd.xz");
+
+/// The `sd.xz` on disk that `open_sd_xz` reads, `None` for the embedded copy.
+pub(crate) fn sd_xz_path(options: &Options) -> Option<PathBuf> {
+    #[cfg(feature = "embedded-asset")]
+    {
+        if options.assets_dir.is_none() {
+            return None;
+        }
+    }
+    Some(options.assets_dir().join("sd.xz"))
+}
+
+/// Opens the compressed base image. An explicit `--assets-dir` always wins over the
+/// embedded copy so a newer image can be swapped in without rebuilding the updater.
+pub(crate) fn open_sd_xz(options: &Options) -> Result<Box<dyn std::io::Read>, std::io::Error> {
+    #[cfg(feature = "embedded-asset")]
+    {
+        if options.assets_dir.is_none() {
+            debug("Using the sd.xz embedded in the binary\n");
+            return Ok(Box::new(EMBEDDED_SD_XZ));
+        }
+    }
+    let path = options.assets_dir().join("sd.xz");
+    debug(format!("Using {}\n", path.display()).as_str());
+    Ok(Box::new(File::open(path)?))
+}
+
+/// Catches a truncated or corrupt `sd.xz` up front, so it isn't mistaken for a failing
+/// disk halfway through decompression. If a `sd.xz.blake3` file sits next to it, the
+/// archive also has to match the checksum in there.
+pub(crate) fn check_sd_xz(options: &Options) -> Result<(), UpdateError> {
+    #[cfg(feature = "embedded-asset")]
+    {
+        if options.assets_dir.is_none() {
+            xzcheck::check(&mut std::io::Cursor::new(EMBEDDED_SD_XZ))
+                .map_err(|e| UpdateError::CorruptAsset(format!("The embedded sd.xz is corrupt: {}", e)))?;
+            return Ok(());
+        }
+    }
+    let path = options.assets_dir().join("sd.xz");
+    let mut archive = File::open(&path)?;
+    let uncompressed = xzcheck::check(&mut archive)
+        .map_err(|e| UpdateError::CorruptAsset(format!("{} is corrupt: {}", path.display(), e)))?;
+    debug(format!("{} decompresses to {}\n", path.display(), format_bytes(uncompressed)).as_str());
+
+    let sidecar = path.with_extension("xz.blake3");
+    if !sidecar.exists() {
+        return Ok(());
+    }
+    let contents = std::fs::read_to_string(&sidecar)?;
+    let expected = contents.split_whitespace().next().unwrap_or_default().to_lowercase();
+    if hash_reader(&mut archive, &mut vec![0_u8; 1024 * 1024], HashAlgorithm::Blake3)? != expected {
+        return Err(UpdateError::CorruptAsset(format!(
+            "{} doesn't match the checksum in {}",
+            path.display(),
+            sidecar.display()
+        )));
+    }
+    debug(format!("{} matches {}\n", path.display(), sidecar.display()).as_str());
+    Ok(())
+}
+
+/// `selftest`: checks `sd.xz` like a build would, then decompresses all of it into a null
+/// sink so the integrity check of every block is verified too, without writing anything.
+/// Returns the uncompressed size.
+pub(crate) fn self_test(options: &Options) -> Result<u64, UpdateError> {
+    let phase = Phase::start("Checking sd.xz");
+    check_sd_xz(options)?;
+    let mut decoder = XzDecoder::new_multi_decoder(open_sd_xz(options)?);
+    let uncompressed = std::io::copy(&mut decoder, &mut std::io::sink())
+        .map_err(|e| UpdateError::CorruptAsset(format!("sd.xz doesn't decompress: {}", e)))?;
+    phase.finish();
+    info(format!("sd.xz is intact and decompresses to {} ({} bytes)\n", format_bytes(uncompressed), uncompressed).as_str());
+    if uncompressed != options.sd_size() {
+        warn(format!(
+            "That doesn't match --sd-size {} ({} bytes), builds would fail\n",
+            format_bytes(options.sd_size()),
+            options.sd_size()
+        ).as_str());
+    }
+    Ok(uncompressed)
+}
+
+/// Writes one decompressed chunk at `offset`, retrying with a short backoff so a flaky
+/// USB device or a briefly full disk doesn't throw away the whole decompression.
+fn write_chunk_with_retry(file: &mut File, path: &Path, offset: u64, chunk: &[u8], retries: u32) -> Result<(), std::io::Error> {
+    let mut attempt = 0;
+    loop {
+        // Seek back first so a write that failed halfway is redone from the chunk start.
+        let result = std::io::Seek::seek(file, std::io::SeekFrom::Start(offset))
+            .and_then(|_| std::io::Write::write_all(file, chunk));
+        match result {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                warn(format!(
+                    "Writing {} at offset {} failed ({}), retrying ({}/{})\n",
+                    path.display(), offset, e, attempt, retries
+                ).as_str());
+                std::thread::sleep(Duration::from_millis(500 * attempt as u64));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Opens the output for decompressing into. Regular files are recreated, unless `keep`
+/// resumes writing into them; anything else is a fixed-size target like an SD card, which
+/// has to be able to hold `required` bytes.
+fn open_output(path: &Path, required: u64, device: bool, keep: bool) -> Result<File, UpdateError> {
+    if !device {
+        let is_file = std::fs::metadata(path).map(|m| m.is_file()).unwrap_or(true);
+        if is_file {
+            // Readable too, the filesystem is mounted on the same handle afterwards.
+            return Ok(std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(!keep).open(path)?);
+        }
+    } else if std::fs::metadata(path).map(|m| m.is_file()).unwrap_or(false) {
+        return Err(UpdateError::Usage(format!("--device {} is a regular file, use --output for image files", path.display())));
+    }
+    // Never create or truncate a device, only write over it.
+    let mut output = std::fs::OpenOptions::new().read(true).write(true).open(path)?;
+    let capacity = std::io::Seek::seek(&mut output, std::io::SeekFrom::End(0))?;
+    // Windows physical drives don't report their size through a seek, so there's nothing to
+    // check against there.
+    if capacity != 0 && capacity < required {
+        return Err(UpdateError::DiskFull(format!(
+            "{} holds {} ({} bytes) but the image needs {} ({} bytes)",
+            path.display(),
+            format_bytes(capacity),
+            capacity,
+            format_bytes(required),
+            required
+        )));
+    }
+    Ok(output)
+}
+
+/// Reads until `buffer` is full or the input ends, so every write but the last one is a
+/// whole buffer and stays sector aligned on devices.
+fn fill_buffer(input: &mut dyn std::io::Read, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match input.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(bytes_read) => filled += bytes_read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Buffer size for the filesystem on a `--device`. Raw devices want whole sectors, and
+/// 4096 is a multiple of both 512 byte and 4K native sector sizes.
+const DEVICE_BUFFER_SIZE: usize = 4096 * 16;
+
+/// Decompresses `sd.xz` into the output, starting `offset` bytes in, and returns the
+/// output still open so the filesystem can be mounted on it.
+fn init_sd(options: &Options, offset: u64) -> Result<File, UpdateError> {
+    const BUFFERSIZE_MB: usize = 1;
+    const BUFFERSIZE: usize = 1024 * 1024 * BUFFERSIZE_MB;
+    let started = Instant::now();
+    let sd_size = options.sd_size();
+    let output = options.output();
+    // Decompress sd.xz to sd.raw
+    imageurl::fetch(options)?;
+    info(format!("Decompressing sd.xz to {}\n", output.display()).as_str());
+    if !options.skip_asset_check {
+        check_sd_xz(options)?;
+    }
+    let resumed = match (resume::decompressed(options, offset), sd_xz_path(options)) {
+        (Some(reached), Some(path)) => {
+            let resumed = xzseek::resume_at(File::open(&path)?, reached)
+                .map_err(|e| UpdateError::CorruptAsset(format!("{} is corrupt: {}", path.display(), e)))?;
+            if resumed.is_none() {
+                debug("sd.xz has no block to start at part way in, decompressing all of it again\n");
+            }
+            resumed
+        }
+        _ => None,
+    };
+    if resumed.is_none() {
+        resume::forget_decompression(options)?;
+    }
+    let mut sd_raw = open_output(&output, offset + sd_size, options.device.is_some(), resumed.is_some())?;
+    let mut written: u64 = 0;
+    let archive: Box<dyn std::io::Read> = match resumed {
+        Some((from, archive)) => {
+            info(format!("Resuming the interrupted decompression {} in\n", format_bytes(from)).as_str());
+            written = from;
+            Box::new(archive)
+        }
+        None => open_sd_xz(options)?,
+    };
+    // Some tools write concatenated streams, which a single-stream decoder would silently
+    // stop at the end of the first one.
+    let mut sd_7zip = XzDecoder::new_multi_decoder(archive);
+    // Flushing dirty pages every so often keeps the final sync from stalling for a long
+    // time with gigabytes still buffered by the OS.
+    const SYNC_INTERVAL: u64 = 1024 * 1024 * 256;
+    let mut unsynced: u64 = 0;
+    let mut progress = ProgressBar::new("Decompressing", sd_size.saturating_sub(written));
+    let mut throttle = options.write_throttle.filter(|_| options.device.is_some()).map(WriteThrottle::new);
+    let mut tuner = options.tune_range().map(|range| BufferTuner::new("decompression", range));
+    let mut buffer = vec![0; options.buffer_size(BUFFERSIZE)];
+    loop {
+        if let Some(tuner) = tuner.as_ref() {
+            buffer.resize(tuner.size(), 0);
+        }
+        let bytes_read = fill_buffer(&mut sd_7zip, &mut buffer)?;
+        if bytes_read == 0 {
+            progress.finish();
+            break;
+        }
+        progress.inc(bytes_read as u64);
+        write_chunk_with_retry(&mut sd_raw, &output, offset + written, &buffer[0..bytes_read], options.write_retries())?;
+        if let Some(throttle) = throttle.as_mut() {
+            throttle.pace(bytes_read as u64);
+        }
+        if let Some(tuner) = tuner.as_mut() {
+            tuner.record(bytes_read as u64);
+        }
+        written += bytes_read as u64;
+        unsynced += bytes_read as u64;
+        if unsynced >= SYNC_INTERVAL {
+            sync::sync_data(&mut sd_raw, options)?;
+            // Without syncs, nothing says how much of the image made it to disk.
+            if !options.no_sync {
+                resume::checkpoint(options, offset, written)?;
+            }
+            unsynced = 0;
+        }
+    }
+    std::io::Write::flush(&mut sd_raw)?;
+    report_throttle(throttle.as_ref());
+    if let Some(tuner) = tuner.as_mut() {
+        tuner.finish();
+    }
+    let phase = Phase::start("Syncing image to disk");
+    sync::sync_all(&mut sd_raw, options)?;
+    resume::forget_decompression(options)?;
+    report::record_phase("sync", phase.finish());
+    report::record_phase("decompress", started.elapsed());
+    info(format!("Decompressed sd.xz to {}\n", output.display()).as_str());
+    Ok(sd_raw)
+}
+
+/// Catches an `sd.xz` that decompressed fine but holds something other than a FAT
+/// filesystem, which fatfs would only refuse to mount with an error that doesn't say why.
+fn check_fat_image(image: &mut File, offset: u64) -> Result<(), UpdateError> {
+    match wipe::boot_sector_problem(&wipe::read_boot_sector(image, offset)?) {
+        Some(problem) => Err(UpdateError::CorruptAsset(format!(
+            "sd.xz decompressed without errors, but what it holds isn't a FAT filesystem: {}. Is it the right sd.xz?",
+            problem
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Formats a blank filesystem of `--sd-size` in the output, starting `offset` bytes in,
+/// instead of decompressing `sd.xz`. Returns the output still open, like `init_sd`.
+fn format_sd(options: &Options, offset: u64) -> Result<File, UpdateError> {
+    let sd_size = options.sd_size();
+    let output = options.output();
+    let phase = Phase::start("Formatting a blank image");
+    let mut sd_raw = open_output(&output, offset + sd_size, options.device.is_some(), false)?;
+    if options.device.is_none() {
+        sd_raw.set_len(offset + sd_size)?;
+    }
+    let region = StreamSlice::new(BufStream::new(sd_raw.try_clone()?), offset, offset + sd_size)?;
+    let mut storage = StdIoWrapper::from(region);
+    fatfs::format_volume(&mut storage, format::format_options(options))?;
+    // Dropping the buffer flushes it.
+    drop(storage);
+    sync::sync_all(&mut sd_raw, options)?;
+    report::record_phase("format", phase.finish());
+    info(format!("Formatted {} of {}\n", format_bytes(sd_size), output.display()).as_str());
+    Ok(sd_raw)
+}
+
+/// State shared by every level of `recursive_copy`.
+struct CopyContext {
+    /// Set when `--report-dupes` asked for a duplicate content report.
+    dupes: Option<DupeReport>,
+    /// Reused for every file so small-file heavy trees don't allocate per file.
+    buffer: Vec<u8>,
+    /// Stamps new entries with the modification time of what is being copied.
+    clock: SourceTimeProvider,
+    /// Whether the card already has a previous build on it that can be updated in place.
+    incremental: bool,
+    /// Set while copying an overlay, whose files always replace what's already there.
+    overlay: bool,
+    on_newer: OnNewer,
+    progress: ProgressBar,
+    /// Directories created or opened so far, so a deep tree doesn't look stalled while no
+    /// bytes move.
+    dirs: Counter,
+    /// Read every file back from the card after writing it and compare hashes.
+    verify: bool,
+    /// How much of the copy `verify` read back, for `--progress-style`. Drawn on its own,
+    /// it would take turns with the copy's line.
+    verifying: Option<ProgressBar>,
+    /// `--policy verify-mismatch`, what a file that reads back differently does.
+    on_verify_mismatch: Action,
+    /// `--hash`, for `verify` and the duplicate report.
+    hash: HashAlgorithm,
+    /// `--mmap-threshold`, files at least this large are mapped instead of read.
+    mmap_threshold: Option<u64>,
+    /// `--preserve-created`.
+    preserve_created: bool,
+    /// `--exclude-larger-than`, `--include-ext` and `--exclude-ext`.
+    filter: FileFilter,
+    /// `--policy oversized-file`, what a file larger than `--exclude-larger-than` does.
+    on_oversized: Action,
+    /// How many files and bytes `--exclude-larger-than` left out so far.
+    excluded: (u64, u64),
+    /// How many files the extension lists let through and left out so far.
+    by_extension: (u64, u64),
+    /// How many zero-byte files `--skip-empty-files` left out so far.
+    empty_skipped: u64,
+    /// How many files `--newer-than` and `--older-than` left out so far.
+    age_skipped: u64,
+    /// With `--keep-going`, files that failed to copy and why, instead of stopping.
+    failures: Option<Vec<(PathBuf, String)>>,
+    /// Source paths too long for FAT that `--on-longpath` leaves out or shortens.
+    long_paths: LongPaths,
+    /// Source paths the manifest excludes, while the source is being copied.
+    exclude: Vec<PathBuf>,
+    /// `--copy-log`.
+    copy_log: Option<CopyLog>,
+    /// `--write-throttle`.
+    throttle: Option<WriteThrottle>,
+    /// `--auto-buffer`, resizing `buffer` as it tries sizes.
+    tuner: Option<BufferTuner>,
+    /// The directory on the card being copied into, like `apps/mnn/`, for the copy log.
+    card_dir: String,
+}
+
+impl CopyContext {
+    fn new(options: &Options, clock: SourceTimeProvider, incremental: bool, size: SourceSize) -> CopyContext {
+        CopyContext {
+            dupes: if options.report_dupes { Some(DupeReport::default()) } else { None },
+            buffer: vec![0_u8; options.buffer_size(options.copy_buffer.map_or(1024*1024*8, |size| size as usize))],
+            clock,
+            incremental,
+            overlay: false,
+            on_newer: options.on_newer,
+            progress: ProgressBar::new("Copying", size.bytes),
+            dirs: Counter::new("Copying: creating directory", size.dirs),
+            verify: options.verify,
+            verifying: (options.verify && options.progress_style != ProgressStyle::Phases).then(|| ProgressBar::new(VERIFYING, size.bytes)),
+            on_verify_mismatch: options.policy.verify_mismatch,
+            hash: options.hash,
+            mmap_threshold: options.mmap_threshold,
+            preserve_created: options.preserve_created,
+            filter: FileFilter::new(options),
+            on_oversized: options.policy.oversized_file,
+            excluded: (0, 0),
+            by_extension: (0, 0),
+            empty_skipped: 0,
+            age_skipped: 0,
+            failures: if options.keep_going { Some(Vec::new()) } else { None },
+            long_paths: LongPaths::new(),
+            exclude: Vec::new(),
+            copy_log: None,
+            card_dir: String::new(),
+            throttle: options.write_throttle.map(WriteThrottle::new),
+            // `--copy-buffer` fixes the size.
+            tuner: options.tune_range().filter(|_| options.copy_buffer.is_none()).map(|range| BufferTuner::new("copy", range)),
+        }
+    }
+
+    /// Counts `bytes` of the copy as read back, or as needing no reading back.
+    fn verified(&mut self, bytes: u64) {
+        if let Some(verifying) = self.verifying.as_mut() {
+            verifying.inc(bytes);
+        }
+    }
+
+    /// Leaves out `path`, a file of `len` bytes larger than `--exclude-larger-than`, or
+    /// stops the build at it, as `--policy oversized-file` says.
+    fn leave_out_larger(&mut self, path: &Path, len: u64) -> Result<(), UpdateError> {
+        let limit = self.filter.larger_than.unwrap_or_default();
+        if self.on_oversized == Action::Error {
+            return Err(UpdateError::Other(format!(
+                "{} ({}) is larger than --exclude-larger-than {} (--policy oversized-file)",
+                path.display(),
+                format_bytes(len),
+                format_bytes(limit)
+            )));
+        }
+        self.on_oversized.report(format!("Skipping {} ({}), it's larger than {}\n", path.display(), format_bytes(len), format_bytes(limit)).as_str());
+        self.excluded.0 += 1;
+        self.excluded.1 += len;
+        Ok(())
+    }
+}
+
+/// How a source file compares to the file at the same path on the card.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum OnCard {
+    Missing,
+    Directory,
+    /// The same size and modification time, so it isn't copied again.
+    Unchanged,
+    /// Changed on the card after the source was, which `--on-newer` decides about.
+    Newer,
+    Changed,
+}
+
+/// Compares a source file of `len` bytes modified at `modified` to `existing` on the card.
+pub(crate) fn on_card(existing: Option<&CardEntry>, len: u64, modified: fatfs::DateTime) -> OnCard {
+    match existing {
+        None => OnCard::Missing,
+        Some(existing) if existing.is_dir => OnCard::Directory,
+        Some(existing) if existing.len == len && existing.modified == modified => OnCard::Unchanged,
+        Some(existing) if existing.modified > modified => OnCard::Newer,
+        Some(_) => OnCard::Changed,
+    }
+}
+
+/// Copies the file at `path` into `sd_folder` as `filename`, and logs it to `--copy-log`.
+fn copy_file<D: DestDir>(ctx: &mut CopyContext, path: &Path, filename: &str, sd_folder: &D, existing: Option<&CardEntry>) -> Result<(), UpdateError> {
+    let started = Instant::now();
+    let result = write_file(ctx, path, filename, sd_folder, existing);
+    if let Some(copy_log) = ctx.copy_log.as_mut() {
+        let size = std::fs::metadata(path).map(|metadata| metadata.len()).unwrap_or_default();
+        let destination = format!("{}{}", ctx.card_dir, filename);
+        match &result {
+            Ok(outcome) => copy_log.record(path, &destination, size, *outcome, None, started.elapsed())?,
+            Err(e) => copy_log.record(path, &destination, size, CopyOutcome::Failed, Some(e), started.elapsed())?,
+        }
+    }
+    result.map(|_| ())
+}
+
+fn write_file<D: DestDir>(ctx: &mut CopyContext, path: &Path, filename: &str, sd_folder: &D, existing: Option<&CardEntry>) -> Result<CopyOutcome, UpdateError> {
+    let mut file = File::open(path)?;
+    let metadata = file.metadata()?;
+    let expected = metadata.len();
+    let source_modified = metadata.modified()?;
+    let modified = timestamps::to_fat_datetime(source_modified);
+    match on_card(existing, expected, modified) {
+        OnCard::Directory => {
+            return Err(UpdateError::Other(format!(
+                "{} is a file in the source but a directory on the card",
+                path.display()
+            )));
+        }
+        OnCard::Unchanged => {
+            debug(format!("Unchanged: {}\n", path.display()).as_str());
+            ctx.progress.inc(expected);
+            ctx.verified(expected);
+            return Ok(CopyOutcome::Skipped);
+        }
+        OnCard::Newer if !ctx.overlay => match ctx.on_newer {
+            OnNewer::Overwrite => {
+                info(format!("{} was changed on the card, overwriting it with the source\n", path.display()).as_str());
+            }
+            OnNewer::Skip => {
+                info(format!("{} was changed on the card, keeping the card's copy\n", path.display()).as_str());
+                ctx.progress.inc(expected);
+                ctx.verified(expected);
+                return Ok(CopyOutcome::Skipped);
+            }
+            OnNewer::Error => {
+                return Err(UpdateError::Other(format!(
+                    "{} is newer on the card than in the source (--on-newer=error)",
+                    path.display()
+                )));
+            }
+        },
+        _ => {}
+    }
+    ctx.clock.set(modified);
+    // fatfs has no way to reserve a file's clusters up front, a file only grows as it is
+    // written, a cluster at a time from the first free one after the last allocation. With
+    // one file written at a time and a directory's subdirectories created in one batch,
+    // that already puts each file of a fresh image in one contiguous run, and writing
+    // zeroes first would take the very same clusters. What fragments are files written
+    // into the holes an incremental update leaves, which no write order avoids.
+    let result = sd_folder.create_file(filename, existing.is_some()).and_then(|mut sd_file| {
+        let result = write_contents(ctx, path, &mut file, &mut sd_file, expected)?;
+        sd_file.finish(source_modified)?;
+        // Hosts without a creation time leave the one fatfs gave it, the modification time.
+        if let Some(created) = metadata.created().ok().filter(|_| ctx.preserve_created) {
+            sd_file.set_created(timestamps::to_fat_datetime(created));
+        }
+        Ok(result)
+    });
+    let (written, hasher) = match result {
+        Ok(result) => result,
+        Err(e) => {
+            // Don't leave a half-written file behind on the card.
+            let _ = sd_folder.remove(filename);
+            if let UpdateError::DiskFull(_) = e {
+                return Err(UpdateError::DiskFull(format!(
+                    "Ran out of space copying {} ({})",
+                    path.display(),
+                    format_bytes(expected)
+                )));
+            }
+            return Err(e);
+        }
+    };
+    if let (Some(dupes), Some(hasher)) = (ctx.dupes.as_mut(), hasher) {
+        dupes.add(hasher.finish(), written, path.to_path_buf());
+    }
+    report::record_copy(written);
+    debug(format!("Copying: {}\n", path.display()).as_str());
+    Ok(if existing.is_some() { CopyOutcome::Replaced } else { CopyOutcome::Created })
+}
+
+/// Maps `file` for `--mmap-threshold`. `None` falls back to reading it through the copy
+/// buffer, for files and filesystems that can't be mapped.
+fn map_source(file: &File, path: &Path) -> Option<memmap2::Mmap> {
+    // Safety: the map is only ever read, and `write_contents` checks the file is still as
+    // long as the map before reading each chunk of it.
+    match unsafe { memmap2::Mmap::map(file) } {
+        Ok(map) => Some(map),
+        Err(e) => {
+            debug(format!("Can't map {}, reading it instead: {}\n", path.display(), e).as_str());
+            None
+        }
+    }
+}
+
+/// Streams `file` into `sd_file` and checks the result is `expected` bytes long. Returns
+/// the bytes written and, with `--report-dupes`, the hash of the contents.
+fn write_contents<F: DestFile>(ctx: &mut CopyContext, path: &Path, file: &mut File, sd_file: &mut F, expected: u64) -> Result<(u64, Option<ContentHasher>), UpdateError> {
+    // The source is hashed as it streams through the copy buffer anyway, so verifying only
+    // costs reading the file back from the card, not reading the source twice.
+    let mut hasher = if ctx.dupes.is_some() || ctx.verify { Some(ContentHasher::new(ctx.hash)) } else { None };
+    let mut written: u64 = 0;
+    let map = match ctx.mmap_threshold {
+        Some(threshold) if expected >= threshold && expected > 0 => map_source(file, path),
+        _ => None,
+    };
+    if let Some(tuner) = ctx.tuner.as_ref() {
+        ctx.buffer.resize(tuner.size(), 0);
+    }
+    if let Some(map) = &map {
+        // Chunks as large as the copy buffer, so progress and write sizes stay the same.
+        for chunk in map.chunks(ctx.buffer.len()) {
+            // Touching a page past the end of a file that was truncated after it was mapped
+            // kills the process with SIGBUS. Checking before every chunk leaves a truncate
+            // only a tiny window to land in.
+            let len = file.metadata()?.len();
+            if len < map.len() as u64 {
+                return Err(UpdateError::Other(format!(
+                    "{} was truncated from {} to {} bytes while it was being copied",
+                    path.display(),
+                    map.len(),
+                    len
+                )));
+            }
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(chunk);
+            }
+            let chunk_written = sd_file.write(chunk)? as u64;
+            written += chunk_written;
+            ctx.progress.inc(chunk_written);
+            if let Some(throttle) = ctx.throttle.as_mut() {
+                throttle.pace(chunk_written);
+            }
+            if let Some(tuner) = ctx.tuner.as_mut() {
+                tuner.record(chunk_written);
+            }
+        }
+    } else {
+        loop {
+            if let Some(tuner) = ctx.tuner.as_ref() {
+                ctx.buffer.resize(tuner.size(), 0);
+            }
+            let bytes_read = std::io::Read::read(file, &mut ctx.buffer)?;
+            if bytes_read == 0 {
+                break;
+            }
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(&ctx.buffer[..bytes_read]);
+            }
+            let chunk_written = sd_file.write(&ctx.buffer[..bytes_read])? as u64;
+            written += chunk_written;
+            ctx.progress.inc(chunk_written);
+            if let Some(throttle) = ctx.throttle.as_mut() {
+                throttle.pace(chunk_written);
+            }
+            if let Some(tuner) = ctx.tuner.as_mut() {
+                tuner.record(chunk_written);
+            }
+        }
+    }
+    // Cheap sanity check against short writes and a full FAT silently truncating files.
+    let on_card = sd_file.len()?;
+    if written != expected || on_card != expected {
+        return Err(UpdateError::Verification(format!(
+            "Size mismatch for {}: source is {} bytes, wrote {}, file on the card is {}",
+            path.display(),
+            expected,
+            written,
+            on_card
+        )));
+    }
+    if let (true, Some(hasher)) = (ctx.verify, hasher.as_ref()) {
+        sd_file.rewind()?;
+        let mut read_back = ContentHasher::new(ctx.hash);
+        loop {
+            let bytes_read = sd_file.read(&mut ctx.buffer)?;
+            if bytes_read == 0 {
+                break;
+            }
+            read_back.update(&ctx.buffer[..bytes_read]);
+            ctx.verified(bytes_read as u64);
+        }
+        if read_back.finish() != hasher.finish() {
+            let message = format!("{} reads back from the card differently than it was written", path.display());
+            if ctx.on_verify_mismatch == Action::Error {
+                return Err(UpdateError::Verification(message));
+            }
+            // A damaged card is worth knowing about whatever the exit code says.
+            warn(format!("{} (--policy verify-mismatch)\n", message).as_str());
+        }
+    }
+    Ok((written, hasher))
+}
+
+/// How much `recursive_copy` has to do for a tree.
+#[derive(Clone, Copy, Default)]
+struct SourceSize {
+    /// The size of the files it copies.
+    bytes: u64,
+    /// The directories it creates, or opens when they are on the card already.
+    dirs: u64,
+}
+
+impl std::ops::AddAssign for SourceSize {
+    fn add_assign(&mut self, other: SourceSize) {
+        self.bytes += other.bytes;
+        self.dirs += other.dirs;
+    }
+}
+
+/// Bounds on the walk `source_size` takes of the source before anything is copied, so a
+/// huge tree, a slow network mount or a symlink loop fails early with a clear error
+/// instead of scanning forever.
+struct Walk {
+    /// `--walk-max-entries`.
+    max_entries: Option<u64>,
+    /// `--walk-timeout`.
+    timeout: Option<Duration>,
+    started: Instant,
+    entries: u64,
+    /// The canonical paths of the directories from the root down to the one being walked.
+    ancestors: Vec<PathBuf>,
+}
+
+impl Walk {
+    fn new(options: &Options) -> Walk {
+        Walk { max_entries: options.walk_max_entries, timeout: options.walk_timeout, started: Instant::now(), entries: 0, ancestors: Vec::new() }
+    }
+
+    /// Counts an entry, failing once it is one too many or the walk ran out of time.
+    fn count(&mut self, host_path: &Path) -> Result<(), UpdateError> {
+        self.entries += 1;
+        if let Some(max_entries) = self.max_entries.filter(|max_entries| self.entries > *max_entries) {
+            return Err(UpdateError::Other(format!(
+                "The source has more than {} entries (--walk-max-entries), stopped at {}",
+                max_entries,
+                host_path.display()
+            )));
+        }
+        if let Some(timeout) = self.timeout.filter(|timeout| self.started.elapsed() > *timeout) {
+            return Err(UpdateError::Other(format!(
+                "Walking the source took longer than {}s (--walk-timeout), stopped after {} entries at {}",
+                timeout.as_secs(),
+                self.entries,
+                host_path.display()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// What `recursive_copy` will copy from `host_path`, leaving out the paths in `exclude` and
+/// the files `filter` does.
+fn source_size(host_path: &Path, exclude: &[PathBuf], filter: &FileFilter, walk: &mut Walk) -> Result<SourceSize, UpdateError> {
+    // A symlink to a directory it's in would have the copy go round forever.
+    let canonical = host_path.canonicalize()?;
+    if let Some(ancestor) = walk.ancestors.iter().find(|ancestor| **ancestor == canonical) {
+        return Err(UpdateError::Other(format!(
+            "{} leads back to {}, a symlink loop in the source",
+            host_path.display(),
+            ancestor.display()
+        )));
+    }
+    walk.ancestors.push(canonical);
+    let mut total = SourceSize::default();
+    for entry in host_path.read_dir()? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') || exclude.contains(&entry.path()) {
+            continue;
+        }
+        walk.count(&entry.path())?;
+        // Follow symlinks, like the copy does.
+        let metadata = std::fs::metadata(entry.path())?;
+        if metadata.is_dir() {
+            total.dirs += 1;
+            total += source_size(&entry.path(), exclude, filter, walk)?;
+        } else if filter.excludes(&entry.path(), &metadata).is_none() {
+            total.bytes += metadata.len();
+        }
+    }
+    walk.ancestors.pop();
+    Ok(total)
+}
+
+/// Why the copy leaves a source path out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Exclusion {
+    /// The name starts with a dot.
+    Dotfile,
+    /// `exclude` in the source's `.updater/manifest.toml`.
+    Manifest,
+    /// Too long for FAT, and `--on-longpath` leaves it out.
+    LongPath,
+    /// A file larger than `--exclude-larger-than`.
+    LargerThan,
+    /// A file `--include-ext` doesn't list, or `--exclude-ext` does.
+    Extension,
+    /// A zero-byte file, with `--skip-empty-files`.
+    Empty,
+    /// A file modified outside what `--newer-than` and `--older-than` let through.
+    Age,
+}
+
+impl Exclusion {
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Exclusion::Dotfile => "names starting with a dot",
+            Exclusion::Manifest => "exclude in .updater/manifest.toml",
+            Exclusion::LongPath => "too long for FAT, left out by --on-longpath",
+            Exclusion::LargerThan => "larger than --exclude-larger-than",
+            Exclusion::Extension => "left out by --include-ext or --exclude-ext",
+            Exclusion::Empty => "empty, left out by --skip-empty-files",
+            Exclusion::Age => "modified outside --newer-than or --older-than",
+        }
+    }
+}
+
+/// What the copy leaves out of the files it comes across, from the command line.
+/// Directories are always walked.
+#[derive(Clone, Default)]
+pub(crate) struct FileFilter {
+    /// `--exclude-larger-than`.
+    pub(crate) larger_than: Option<u64>,
+    /// `--include-ext`, lowercased and without the dot. Empty takes every extension.
+    include_ext: Vec<String>,
+    /// `--exclude-ext`, the same way.
+    exclude_ext: Vec<String>,
+    /// `--skip-empty-files`.
+    skip_empty: bool,
+    /// `--newer-than`, as the time a file has to be modified at or after.
+    newer_than: Option<SystemTime>,
+    /// `--older-than`, as the time a file has to be modified at or before.
+    older_than: Option<SystemTime>,
+}
+
+impl FileFilter {
+    pub(crate) fn new(options: &Options) -> FileFilter {
+        FileFilter {
+            larger_than: options.exclude_larger_than,
+            include_ext: options.include_ext.clone(),
+            exclude_ext: options.exclude_ext.clone(),
+            skip_empty: options.skip_empty_files,
+            newer_than: options.newer_than.map(cutoff),
+            older_than: options.older_than.map(cutoff),
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.larger_than.is_some() || self.filters_extensions() || self.skip_empty || self.filters_age()
+    }
+
+    pub(crate) fn filters_age(&self) -> bool {
+        self.newer_than.is_some() || self.older_than.is_some()
+    }
+
+    fn filters_extensions(&self) -> bool {
+        !self.include_ext.is_empty() || !self.exclude_ext.is_empty()
+    }
+
+    /// Whether the file at `path` is left out, and why.
+    pub(crate) fn excludes(&self, path: &Path, metadata: &std::fs::Metadata) -> Option<Exclusion> {
+        let len = metadata.len();
+        if self.larger_than.is_some_and(|limit| len > limit) {
+            return Some(Exclusion::LargerThan);
+        }
+        if self.skip_empty && len == 0 {
+            return Some(Exclusion::Empty);
+        }
+        if self.filters_extensions() {
+            // A file without one only gets through without an --include-ext.
+            let extension = path.extension().map(|extension| extension.to_string_lossy().to_lowercase()).unwrap_or_default();
+            if (!self.include_ext.is_empty() && !self.include_ext.contains(&extension)) || self.exclude_ext.contains(&extension) {
+                return Some(Exclusion::Extension);
+            }
+        }
+        // A file without a modification time can't be placed, so it is copied.
+        if let (true, Ok(modified)) = (self.filters_age(), metadata.modified()) {
+            if self.newer_than.is_some_and(|cutoff| modified < cutoff) || self.older_than.is_some_and(|cutoff| modified > cutoff) {
+                return Some(Exclusion::Age);
+            }
+        }
+        None
+    }
+}
+
+/// The time `age` before now, the oldest there is for an age longer than that.
+fn cutoff(age: Duration) -> SystemTime {
+    SystemTime::now().checked_sub(age).unwrap_or(UNIX_EPOCH)
+}
+
+/// Whether `recursive_copy` leaves `path` out, and why. `--list-excluded` asks the same
+/// question, so its report matches what the copy does.
+pub(crate) fn exclusion(path: &Path, exclude: &[PathBuf], long_paths: &LongPaths, filter: &FileFilter) -> std::io::Result<Option<Exclusion>> {
+    if path.file_name().unwrap().to_string_lossy().starts_with('.') {
+        return Ok(Some(Exclusion::Dotfile));
+    }
+    if exclude.iter().any(|excluded| excluded == path) {
+        return Ok(Some(Exclusion::Manifest));
+    }
+    if let Some(None) = long_paths.get(path) {
+        return Ok(Some(Exclusion::LongPath));
+    }
+    if filter.is_active() {
+        // Follow symlinks, like the copy does.
+        let metadata = std::fs::metadata(path)?;
+        if !metadata.is_dir() {
+            return Ok(filter.excludes(path, &metadata));
+        }
+    }
+    Ok(None)
+}
+
+/// Copies `host_path` into `sd_folder`. Anything whose name starts with a dot is left
+/// out, but every other directory is created on the card even when it ends up empty,
+/// because git can't hold empty directories and a `saves/.gitkeep` is how the source
+/// asks for a folder that homebrew expects to exist.
+fn recursive_copy<D: DestDir>(ctx: &mut CopyContext, host_path: &Path, sd_folder: &mut D) -> Result<(), UpdateError> {
+    // Only an incremental copy or an overlay can find anything on the card worth comparing
+    // against.
+    let mut existing = if ctx.incremental || ctx.overlay { sd_folder.entries()? } else { HashMap::new() };
+    // Copy the files of this directory first and only then descend, so the entries of one
+    // directory table get written together instead of interleaved with deep subtrees.
+    let mut subdirs = Vec::new();
+    for path in sorted_entries(host_path)? {
+        let path = path?;
+        match exclusion(&path, &ctx.exclude, &ctx.long_paths, &ctx.filter)? {
+            None => {}
+            Some(Exclusion::Manifest) => {
+                debug(format!("Excluded: {}\n", path.display()).as_str());
+                continue;
+            }
+            Some(Exclusion::LargerThan) => {
+                // Left out before anything is written, so it never counts as a --keep-going
+                // failure, and a copy already on the card from an earlier build stays there.
+                let len = path.metadata()?.len();
+                ctx.leave_out_larger(&path, len)?;
+                continue;
+            }
+            Some(Exclusion::Extension) => {
+                debug(format!("Excluded by extension: {}\n", path.display()).as_str());
+                ctx.by_extension.1 += 1;
+                continue;
+            }
+            Some(Exclusion::Empty) => {
+                debug(format!("Skipping empty file: {}\n", path.display()).as_str());
+                ctx.empty_skipped += 1;
+                continue;
+            }
+            Some(Exclusion::Age) => {
+                debug(format!("Excluded by age: {}\n", path.display()).as_str());
+                ctx.age_skipped += 1;
+                continue;
+            }
+            Some(_) => continue,
+        }
+        let name = match ctx.long_paths.get(&path) {
+            Some(Some(shorter)) => shorter.clone(),
+            _ => path.file_name().unwrap().to_str().unwrap().to_string(),
+        };
+        let on_card = existing.remove(&name.to_lowercase());
+        if path.is_dir() {
+            subdirs.push((path, name, on_card));
+        } else {
+            if ctx.filter.filters_extensions() {
+                ctx.by_extension.0 += 1;
+            }
+            match copy_file(ctx, &path, &name, sd_folder, on_card.as_ref()) {
+                // Running out of space would just fail every file after this one.
+                Err(e) if !matches!(e, UpdateError::DiskFull(_)) && ctx.failures.is_some() => {
+                    warn(format!("Failed to copy {}: {}\n", path.display(), e).as_str());
+                    ctx.failures.as_mut().unwrap().push((path, e.to_string()));
+                }
+                result => result?,
+            }
+        }
+    }
+    // Create all subdirectory entries in one batch, keeping the returned handles so
+    // nothing has to be looked up by name again before recursing.
+    let mut created = Vec::with_capacity(subdirs.len());
+    for (path, dir_name, on_card) in subdirs {
+        let sd_dir = match on_card {
+            Some(entry) if entry.is_dir => sd_folder.open_dir(&dir_name)?,
+            Some(_) => {
+                return Err(UpdateError::Other(format!(
+                    "{} is a directory in the source but a file on the card",
+                    path.display()
+                )));
+            }
+            None => {
+                ctx.clock.set(timestamps::to_fat_datetime(path.metadata()?.modified()?));
+                sd_folder.create_dir(&dir_name)?
+            }
+        };
+        ctx.dirs.inc();
+        created.push((path, dir_name, sd_dir));
+    }
+    for (path, dir_name, mut sd_dir) in created {
+        let parent = ctx.card_dir.len();
+        ctx.card_dir.push_str(&dir_name);
+        ctx.card_dir.push('/');
+        recursive_copy(ctx, &path, &mut sd_dir)?;
+        ctx.card_dir.truncate(parent);
+    }
+    Ok(())
+}
+
+/// The storage fatfs is mounted on.
+pub(crate) type FsRegion = StdIoWrapper<StreamSlice<BufStream<File>>>;
+
+/// Opens the filesystem region of the image at `--output`, starting `offset` bytes in, and
+/// returns it with the size of the whole image.
+pub(crate) fn open_fs_region(options: &Options, offset: u64) -> Result<(FsRegion, u64), UpdateError> {
+    let img_file = std::fs::OpenOptions::new().read(true).write(true).open(options.output())?;
+    fs_region(img_file, options, offset)
+}
+
+/// The filesystem region of an already open image, and the size of the whole image.
+fn fs_region(mut img_file: File, options: &Options, offset: u64) -> Result<(FsRegion, u64), UpdateError> {
+    // A device is usually larger than the image written to it.
+    let image_size = match options.device {
+        Some(_) => offset + options.sd_size(),
+        None => std::io::Seek::seek(&mut img_file, std::io::SeekFrom::End(0))?,
+    };
+    // Unmounting flushes it, before the image is synced.
+    let buf_stream = match (options.fs_buffer, options.device.as_ref()) {
+        (Some(size), _) => BufStream::with_capacity(options.buffer_size(size as usize), img_file),
+        (None, Some(_)) => BufStream::with_capacity(DEVICE_BUFFER_SIZE, img_file),
+        (None, None) => BufStream::new(img_file),
+    };
+    // fatfs only ever sees the filesystem region, wherever it sits in the image.
+    let region = StreamSlice::new(buf_stream, offset, image_size)?;
+    Ok((StdIoWrapper::from(region), image_size))
+}
+
+/// Copies the source, the overlays and then anything `--preserve` kept from the old image
+/// into `root_dir`, and reports on the copy.
+fn copy_sources<D: DestDir>(ctx: &mut CopyContext, sd_source_path: &Path, options: &Options, preserved: Option<&Path>, root_dir: &mut D) -> Result<(), UpdateError> {
+    let started = Instant::now();
+    in_target_dir(ctx, options, root_dir, |ctx, target| {
+        ctx.exclude = options.exclude.iter().map(|path| sd_source_path.join(path)).collect();
+        recursive_copy(ctx, sd_source_path, target)?;
+        ctx.exclude.clear();
+        create_empty_dirs(&options.empty_dirs, target)?;
+        // Overlays go on top in the order they were given, so later ones win.
+        ctx.overlay = true;
+        for overlay in &options.overlays {
+            info(format!("Copying overlay {}\n", overlay.display()).as_str());
+            recursive_copy(ctx, overlay, target)?;
+        }
+        Ok(())
+    })?;
+    // `--add-file` and `--preserve` paths are from the root of the card.
+    copy_added_files(ctx, options, root_dir)?;
+    // Last, so saves made on the card win over anything the build ships.
+    if let Some(preserved) = preserved {
+        info("Restoring the preserved directories\n");
+        recursive_copy(ctx, preserved, root_dir)?;
+    }
+    ctx.progress.finish();
+    report::record_phase("copy", started.elapsed());
+
+    info(format!("Done copying the build to {}\n", options.output().display()).as_str());
+    if let Some(dupes) = &ctx.dupes {
+        dupes.print();
+    }
+    report_left_out(ctx);
+    report_throttle(ctx.throttle.as_ref());
+    if let Some(tuner) = ctx.tuner.as_mut() {
+        tuner.finish();
+    }
+    if let Some(failures) = ctx.failures.as_ref().filter(|failures| !failures.is_empty()) {
+        warn(format!("{} files failed to copy:\n", failures.len()).as_str());
+        for (path, reason) in failures {
+            warn(format!("  {}: {}\n", path.display(), reason).as_str());
+        }
+    }
+    Ok(())
+}
+
+/// Logs what `--exclude-larger-than`, the extension lists, `--skip-empty-files` and the
+/// age window left out of the copy.
+fn report_left_out(ctx: &CopyContext) {
+    if ctx.excluded.0 > 0 {
+        ctx.on_oversized.report(format!(
+            "Left out {} files ({}) larger than --exclude-larger-than\n",
+            ctx.excluded.0,
+            format_bytes(ctx.excluded.1)
+        ).as_str());
+    }
+    if ctx.filter.filters_extensions() {
+        info(format!("Copied {} files with a matching extension, left out {} by extension\n", ctx.by_extension.0, ctx.by_extension.1).as_str());
+    }
+    if ctx.empty_skipped > 0 {
+        info(format!("Left out {} empty files\n", ctx.empty_skipped).as_str());
+        report::record_empty_files_skipped(ctx.empty_skipped);
+    }
+    if ctx.filter.filters_age() {
+        info(format!("Left out {} files modified outside --newer-than or --older-than\n", ctx.age_skipped).as_str());
+        report::record_files_filtered_by_age(ctx.age_skipped);
+    }
+}
+
+/// Logs the rate `--write-throttle` let the writes go at.
+fn report_throttle(throttle: Option<&WriteThrottle>) {
+    if let Some(throttle) = throttle {
+        let (rate, waited) = throttle.finish();
+        info(format!("Wrote at {}/s, held back by --write-throttle for {:.1}s\n", format_bytes(rate), waited.as_secs_f64()).as_str());
+    }
+}
+
+/// Copies `path`, relative to `host_root`, into `sd_folder`, creating the directories on
+/// the way to it.
+fn copy_path<D: DestDir>(ctx: &mut CopyContext, host_root: &Path, sd_folder: &D, path: &str) -> Result<(), UpdateError> {
+    let mut existing = sd_folder.entries()?;
+    let Some((dir_name, rest)) = path.split_once('/') else {
+        let host_path = host_root.join(path);
+        let metadata = host_path.metadata()?;
+        let len = metadata.len();
+        match ctx.filter.excludes(&host_path, &metadata) {
+            Some(Exclusion::LargerThan) => {
+                ctx.leave_out_larger(&host_path, len)?;
+                return Ok(());
+            }
+            Some(Exclusion::Empty) => {
+                debug(format!("Skipping empty file: {}\n", host_path.display()).as_str());
+                ctx.empty_skipped += 1;
+                return Ok(());
+            }
+            Some(Exclusion::Age) => {
+                debug(format!("Excluded by age: {}\n", host_path.display()).as_str());
+                ctx.age_skipped += 1;
+                return Ok(());
+            }
+            Some(_) => {
+                debug(format!("Excluded by extension: {}\n", host_path.display()).as_str());
+                ctx.by_extension.1 += 1;
+                return Ok(());
+            }
+            None if ctx.filter.filters_extensions() => ctx.by_extension.0 += 1,
+            None => {}
+        }
+        return copy_file(ctx, &host_path, path, sd_folder, existing.get(&path.to_lowercase()));
+    };
+    let host_dir = host_root.join(dir_name);
+    let sd_dir = match existing.remove(&dir_name.to_lowercase()) {
+        Some(entry) if entry.is_dir => sd_folder.open_dir(&entry.name)?,
+        Some(_) => {
+            return Err(UpdateError::Other(format!(
+                "{} is a directory in the source but a file on the card",
+                host_dir.display()
+            )));
+        }
+        None => {
+            ctx.clock.set(timestamps::to_fat_datetime(host_dir.metadata()?.modified()?));
+            sd_folder.create_dir(dir_name)?
+        }
+    };
+    let parent = ctx.card_dir.len();
+    ctx.card_dir.push_str(dir_name);
+    ctx.card_dir.push('/');
+    let result = copy_path(ctx, &host_dir, &sd_dir, rest);
+    ctx.card_dir.truncate(parent);
+    result
+}
+
+/// Creates the directory at `path` in `sd_folder` unless it's there, with the directories
+/// on the way to it, and returns it.
+pub(crate) fn create_dir_path<D: DestDir>(sd_folder: &D, path: &str) -> Result<D, UpdateError> {
+    let (name, rest) = match path.split_once('/') {
+        Some((name, rest)) => (name, Some(rest)),
+        None => (path, None),
+    };
+    let sd_dir = match sd_folder.entries()?.remove(&name.to_lowercase()) {
+        Some(entry) if entry.is_dir => sd_folder.open_dir(&entry.name)?,
+        Some(_) => return Err(UpdateError::Other(format!("{} is a file on the card, not a directory", name))),
+        None => sd_folder.create_dir(name)?,
+    };
+    match rest {
+        Some(rest) => create_dir_path(&sd_dir, rest),
+        None => Ok(sd_dir),
+    }
+}
+
+/// Runs `copy` in `--target-dir`, creating it and the directories on the way unless an
+/// earlier build did, or in `root_dir` without one.
+fn in_target_dir<D: DestDir>(
+    ctx: &mut CopyContext,
+    options: &Options,
+    root_dir: &mut D,
+    copy: impl FnOnce(&mut CopyContext, &mut D) -> Result<(), UpdateError>,
+) -> Result<(), UpdateError> {
+    let Some(target_dir) = &options.target_dir else {
+        return copy(ctx, root_dir);
+    };
+    let mut target = create_dir_path(root_dir, target_dir)?;
+    ctx.card_dir = format!("{}/", target_dir);
+    let result = copy(ctx, &mut target);
+    ctx.card_dir.clear();
+    result
+}
+
+/// Copies the `--add-file` files to their paths on the card, creating the directories on
+/// the way. Like an overlay, they replace what is there even when it's newer.
+fn copy_added_files<D: DestDir>(ctx: &mut CopyContext, options: &Options, root_dir: &D) -> Result<(), UpdateError> {
+    for (host_path, image_path) in &options.add_files {
+        if !host_path.is_file() {
+            return Err(UpdateError::Other(format!("--add-file {} isn't a file", host_path.display())));
+        }
+        debug(format!("Adding {} as {}\n", host_path.display(), image_path).as_str());
+        let (dir, filename) = match image_path.rsplit_once('/') {
+            Some((dir, filename)) => (Some(dir), filename),
+            None => (None, image_path.as_str()),
+        };
+        ctx.card_dir = dir.map(|dir| format!("{}/", dir)).unwrap_or_default();
+        let result = match dir {
+            Some(dir) => {
+                let sd_dir = create_dir_path(root_dir, dir)?;
+                add_file(ctx, host_path, filename, &sd_dir)
+            }
+            None => add_file(ctx, host_path, filename, root_dir),
+        };
+        ctx.card_dir.clear();
+        result?;
+    }
+    Ok(())
+}
+
+fn add_file<D: DestDir>(ctx: &mut CopyContext, host_path: &Path, filename: &str, sd_folder: &D) -> Result<(), UpdateError> {
+    let existing = sd_folder.entries()?.remove(&filename.to_lowercase());
+    copy_file(ctx, host_path, filename, sd_folder, existing.as_ref())
+}
+
+/// Creates the manifest's `empty-dirs`, which git can't carry, see `packaging.rs`.
+fn create_empty_dirs<D: DestDir>(empty_dirs: &[String], root_dir: &D) -> Result<(), UpdateError> {
+    for path in empty_dirs {
+        create_dir_path(root_dir, path)?;
+    }
+    Ok(())
+}
+
+/// Removes the file at `path` from `sd_folder`. Returns whether it was there.
+fn remove_path<D: DestDir>(sd_folder: &D, path: &str) -> Result<bool, UpdateError> {
+    let existing = sd_folder.entries()?;
+    match path.split_once('/') {
+        Some((dir_name, rest)) => match existing.get(&dir_name.to_lowercase()) {
+            Some(entry) if entry.is_dir => remove_path(&sd_folder.open_dir(&entry.name)?, rest),
+            _ => Ok(false),
+        },
+        None => match existing.get(&path.to_lowercase()) {
+            Some(entry) if !entry.is_dir => {
+                sd_folder.remove(&entry.name)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        },
+    }
+}
+
+/// Applies what changed since the last build to the card: removes what the source deleted,
+/// then copies what it added or modified. Directories left empty stay, like they would
+/// after a full walk.
+fn apply_delta<D: DestDir>(ctx: &mut CopyContext, sd_source_path: &Path, options: &Options, delta: &Delta, root_dir: &mut D) -> Result<(), UpdateError> {
+    let started = Instant::now();
+    in_target_dir(ctx, options, root_dir, |ctx, target| {
+        for path in &delta.deleted {
+            let removing = Instant::now();
+            if remove_path(target, path)? {
+                debug(format!("Removing: {}{}\n", ctx.card_dir, path).as_str());
+                if let Some(copy_log) = ctx.copy_log.as_mut() {
+                    copy_log.record(&sd_source_path.join(path), &format!("{}{}", ctx.card_dir, path), 0, CopyOutcome::Deleted, None, removing.elapsed())?;
+                }
+            }
+        }
+        for path in &delta.changed {
+            match copy_path(ctx, sd_source_path, target, path) {
+                Err(e) if !matches!(e, UpdateError::DiskFull(_)) && ctx.failures.is_some() => {
+                    warn(format!("Failed to copy {}: {}\n", path, e).as_str());
+                    ctx.failures.as_mut().unwrap().push((sd_source_path.join(path), e.to_string()));
+                }
+                result => result?,
+            }
+        }
+        create_empty_dirs(&options.empty_dirs, target)
+    })?;
+    ctx.overlay = true;
+    copy_added_files(ctx, options, root_dir)?;
+    ctx.progress.finish();
+    report::record_phase("copy", started.elapsed());
+    info(format!("Copied {} changed paths and removed {} deleted ones\n", delta.changed.len(), delta.deleted.len()).as_str());
+    report_left_out(ctx);
+    report_throttle(ctx.throttle.as_ref());
+    if let Some(tuner) = ctx.tuner.as_mut() {
+        tuner.finish();
+    }
+    Ok(())
+}
+
+/// The names the copy puts at the root of the card, whether they are there already or not.
+fn top_level_names(sd_source_path: &Path, options: &Options, long_paths: &LongPaths, preserved: Option<&Path>) -> Result<Vec<String>, UpdateError> {
+    let exclude: Vec<PathBuf> = options.exclude.iter().map(|path| sd_source_path.join(path)).collect();
+    let mut trees = Vec::new();
+    // The source goes under the one directory instead, see `in_target_dir`.
+    let mut names = match &options.target_dir {
+        Some(target_dir) => target_dir.split('/').next().map(str::to_string).into_iter().collect(),
+        None => {
+            trees.push(sd_source_path);
+            trees.extend(options.overlays.iter().map(PathBuf::as_path));
+            options.empty_dirs.iter().filter_map(|path| path.split('/').next()).map(str::to_string).collect()
+        }
+    };
+    trees.extend(preserved);
+    for tree in trees {
+        for path in sorted_entries(tree)? {
+            let path = path?;
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            if name.starts_with('.') || exclude.contains(&path) {
+                continue;
+            }
+            match long_paths.get(&path) {
+                Some(Some(shorter)) => names.push(shorter.clone()),
+                Some(None) => {}
+                None => names.push(name),
+            }
+        }
+    }
+    names.extend(options.add_files.iter().filter_map(|(_, path)| path.split('/').next()).map(str::to_string));
+    Ok(names)
+}
+
+fn added_files_size(options: &Options) -> u64 {
+    options.add_files.iter().filter_map(|(host_path, _)| host_path.metadata().ok()).map(|metadata| metadata.len()).sum()
+}
+
+fn total_source_size(sd_source_path: &Path, options: &Options) -> Result<SourceSize, UpdateError> {
+    let filter = FileFilter::new(options);
+    let mut walk = Walk::new(options);
+    let exclude: Vec<PathBuf> = options.exclude.iter().map(|path| sd_source_path.join(path)).collect();
+    let mut total = source_size(sd_source_path, &exclude, &filter, &mut walk)?;
+    // The manifest only applies to the source, like in `copy_sources`.
+    for overlay in &options.overlays {
+        total += source_size(overlay, &[], &filter, &mut walk)?;
+    }
+    total.bytes += added_files_size(options);
+    Ok(total)
+}
+
+/// What the copy needs to know about the source before it starts.
+struct CopyPlan {
+    /// How much there is to copy, for the progress bar.
+    size: SourceSize,
+    /// The names a full copy puts at the root of the card, to check they fit. A delta only
+    /// ever adds a few.
+    top_level_names: Option<Vec<String>>,
+}
+
+/// Works out the `CopyPlan` for copying the source, or only the `delta`, and what was
+/// `preserved`. It only reads directories and file sizes, not what the files hold, so
+/// running it while sd.xz decompresses takes little of the disk from the decompression.
+fn plan_copy(sd_source_path: &Path, options: &Options, long_paths: &LongPaths, delta: Option<&Delta>, preserved: Option<&Path>) -> Result<CopyPlan, UpdateError> {
+    let mut size = match delta {
+        Some(delta) => SourceSize {
+            bytes: delta.changed.iter().filter_map(|path| sd_source_path.join(path).metadata().ok()).map(|metadata| metadata.len()).sum::<u64>()
+                + added_files_size(options),
+            dirs: 0,
+        },
+        None => total_source_size(sd_source_path, options)?,
+    };
+    if let Some(preserved) = preserved {
+        size += source_size(preserved, &[], &FileFilter::new(options), &mut Walk::new(options))?;
+    }
+    let top_level_names = match delta {
+        Some(_) => None,
+        None => Some(top_level_names(sd_source_path, options, long_paths, preserved)?),
+    };
+    Ok(CopyPlan { size, top_level_names })
+}
+
+/// Whether the image at `--output` still starts with a FAT boot sector at `offset`.
+fn image_is_fat(options: &Options, offset: u64) -> bool {
+    File::open(options.output())
+        .and_then(|mut image| wipe::read_boot_sector(&mut image, offset))
+        .is_ok_and(|sector| wipe::boot_sector_problem(&sector).is_none())
+}
+
+/// Decompresses a fresh image unless updating one in place, and copies the build into it,
+/// or with a `delta`, only what changed since the last build.
+fn build_image(
+    sd_source_path: &Path,
+    options: &Options,
+    long_paths: LongPaths,
+    volume_label: Option<[u8; label::MAX_LABEL_LEN]>,
+    delta: Option<Delta>,
+    commit: Option<&str>,
+) -> Result<CopyContext, UpdateError> {
+    let output = options.output();
+    // Files on the card that aren't in the source came from sd.xz, so an incremental update
+    // only ever adds and replaces files and never deletes anything.
+    let mut resuming = !options.incremental && resume::can_resume(options, commit);
+    let mut incremental = (options.incremental && output.exists()) || resuming;
+    // The image is only worth building on while it holds the sd.xz in use.
+    if incremental && !options.format && !asset::is_current(options) {
+        warn(format!("sd.xz changed since {} was built, rebuilding it from scratch\n", output.display()).as_str());
+        resuming = false;
+        incremental = false;
+    }
+    let offset = if options.partitioned { partition::PARTITION_OFFSET } else { 0 };
+    if incremental && !image_is_fat(options, offset) {
+        warn(format!("{} isn't a FAT image anymore, rebuilding it from scratch\n", output.display()).as_str());
+        resuming = false;
+        incremental = false;
+    }
+    // A resumed build never finished, so it may not hold what the last build's commit did.
+    let delta = delta.filter(|_| incremental && !resuming);
+    // An incremental update leaves everything on the card alone anyway.
+    let preserved = if incremental { None } else { preserve::extract(options, offset)? };
+    if resuming {
+        match resume::copied_before(options) {
+            Some(copied) => info(format!("The last build into {} was interrupted {} into the copy, resuming it\n", output.display(), format_bytes(copied)).as_str()),
+            None => info(format!("The last build into {} was interrupted, resuming it\n", output.display()).as_str()),
+        }
+    } else if incremental {
+        info(format!("Updating the existing {} in place\n", output.display()).as_str());
+    }
+    // The image is opened once. Everything that touches it directly, the MBR before the
+    // filesystem is mounted and the wipe and sync after it is unmounted, goes through
+    // `image` while fatfs owns a clone of the handle.
+    let open = || {
+        if incremental {
+            std::fs::OpenOptions::new().read(true).write(true).open(&output).map_err(UpdateError::from)
+        } else if options.format {
+            format_sd(options, offset).and_then(|image| {
+                asset::forget(options)?;
+                Ok(image)
+            })
+        } else {
+            init_sd(options, offset).and_then(|mut image| {
+                check_fat_image(&mut image, offset)?;
+                asset::record(options)?;
+                Ok(image)
+            })
+        }
+    };
+    let preserved_dir = preserved.as_ref().map(|preserved| preserved.dir());
+    let plan = || plan_copy(sd_source_path, options, &long_paths, delta.as_ref(), preserved_dir);
+    let (opened, plan) = if !incremental && !options.format && !options.no_parallel_scan {
+        std::thread::scope(|scope| {
+            let started = Instant::now();
+            let planning = scope.spawn(|| {
+                let started = Instant::now();
+                plan().map(|plan| (plan, started.elapsed()))
+            });
+            let opened = open();
+            let decompressed = started.elapsed();
+            let plan = planning.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+            let plan = plan.map(|(plan, scanned)| {
+                debug(format!(
+                    "Scanned the source in {:.1}s while sd.xz decompressed in {:.1}s, {:.1}s less than one after the other\n",
+                    scanned.as_secs_f64(),
+                    decompressed.as_secs_f64(),
+                    scanned.min(decompressed).as_secs_f64()
+                ).as_str());
+                plan
+            });
+            (opened, plan)
+        })
+    } else {
+        let opened = open();
+        (opened, plan())
+    };
+    let (mut image, plan) = match (opened, plan) {
+        (Ok(image), Ok(plan)) => (image, plan),
+        (Err(e), _) | (_, Err(e)) => {
+            if let Some(preserved) = preserved {
+                preserved.keep();
+            }
+            return Err(e);
+        }
+    };
+    if options.partitioned && !incremental {
+        // A device is usually larger than the image written to it.
+        let partition_size = match options.device {
+            Some(_) => options.sd_size(),
+            None => image.metadata()?.len() - offset,
+        };
+        let fat_type = wipe::fat_type(&mut image, offset)?;
+        partition::write_mbr(&mut image, partition_size, fat_type)?;
+    }
+    if let Some(label) = &volume_label {
+        debug(format!("Setting the volume label to {}\n", String::from_utf8_lossy(label).trim_end()).as_str());
+        if !label::write_label(&mut image, offset, label)? {
+            warn("The root directory is full, the volume label is only set in the boot sector\n");
+        }
+    }
+    if !incremental {
+        resume::start(options, commit)?;
+    }
+    // Until the copy finished, the image doesn't hold any one commit.
+    asset::set_built_commit(options, None)?;
+    let root_usage = rootdir::usage(&mut image, offset)?;
+
+    info(format!("Copying the build to {}...\n", output.display()).as_str());
+    // Initialize a filesystem object
+    let (wrapped_buf_stream, _) = fs_region(image.try_clone()?, options, offset)?;
+    let clock = SourceTimeProvider::default();
+    let fs_options = fatfs::FsOptions::new()
+        .time_provider(clock.clone())
+        .oem_cp_converter(options.code_page);
+    let fs: FileSystem<FsRegion, SourceTimeProvider, CodePage> = fatfs::FileSystem::new(wrapped_buf_stream, fs_options)?;
+    let mut root_dir = fs.root_dir();
+    // A delta only ever adds a few entries.
+    if let (Some(usage), Some(names)) = (&root_usage, &plan.top_level_names) {
+        let existing = root_dir.entries()?.into_keys().collect();
+        if let Err(e) = rootdir::check_capacity(usage, names.iter().map(String::as_str), &existing) {
+            if let Some(preserved) = preserved {
+                preserved.keep();
+            }
+            return Err(e);
+        }
+    }
+
+    // Running out part way would leave some files updated and others not.
+    if incremental {
+        let stats = fs.stats()?;
+        space::check_free_space(&root_dir, sd_source_path, options, &long_paths, delta.as_ref(), stats.cluster_size() as u64, stats.free_clusters() as u64)?;
+    }
+
+    // Copy the files
+    let mut ctx = CopyContext::new(options, clock, incremental, plan.size);
+    ctx.long_paths = long_paths;
+    ctx.copy_log = options.copy_log.as_deref().map(CopyLog::create).transpose()?;
+    let result = match &delta {
+        Some(delta) => apply_delta(&mut ctx, sd_source_path, options, delta, &mut root_dir),
+        None => copy_sources(&mut ctx, sd_source_path, options, preserved.as_ref().map(|preserved| preserved.dir()), &mut root_dir),
+    };
+    match preserved {
+        Some(preserved) if result.is_ok() => preserved.discard(),
+        Some(preserved) => preserved.keep(),
+        None => {}
+    }
+    if let Err(e) = result {
+        ctx.progress.finish();
+        resume::stopped_copying(options, ctx.progress.current())?;
+        let UpdateError::DiskFull(message) = e else {
+            return Err(e);
+        };
+        let stats = fs.stats()?;
+        let free = stats.free_clusters() as u64 * stats.cluster_size() as u64;
+        // The filesystem is as large as the image in sd.xz, --sd-size only has to match it.
+        return Err(UpdateError::DiskFull(format!(
+            "{}. Copied {} so far, {} is still left to copy but the image only has {} free. \
+             Use a larger base image in sd.xz (with a matching --sd-size), or copy fewer files",
+            message,
+            format_bytes(ctx.progress.current()),
+            format_bytes(ctx.progress.remaining()),
+            format_bytes(free)
+        )));
+    }
+    if let Some(path) = &options.include_manifest_in_image {
+        buildinfo::write(&root_dir, options, path, commit)?;
+    }
+    let free_clusters = fs.stats()?.free_clusters();
+    // Flushes the FAT and the buffer, after which fatfs is done with the image.
+    fs.unmount()?;
+    if options.preserve_attrs {
+        let phase = Phase::start("Setting file attributes");
+        let changed = attrs::apply(&mut image, offset, sd_source_path, options, &ctx.long_paths)?;
+        report::record_phase("attributes", phase.finish());
+        info(format!("Set the attributes of {} files\n", changed).as_str());
+    }
+    if options.wipe_free {
+        let phase = Phase::start("Wiping free space");
+        let wiped = wipe::wipe_free_clusters(&mut image, offset)?;
+        sync::sync_data(&mut image, options)?;
+        report::record_phase("wipe", phase.finish());
+        info(format!("Zeroed {} of free space in {} free clusters\n", format_bytes(wiped), free_clusters).as_str());
+    }
+    if options.device.is_some() {
+        // Everything has to be on the card, not in the OS cache, before it is pulled out.
+        let phase = Phase::start("Syncing device");
+        sync::sync_all(&mut image, options)?;
+        report::record_phase("device-sync", phase.finish());
+    }
+    Ok(ctx)
+}
+
+/// Copies the build onto the card mounted at `dest_dir`. There is no fresh image to start
+/// from, so this always works like an incremental update.
+fn build_into_dir(sd_source_path: &Path, options: &Options, dest_dir: &Path, long_paths: LongPaths, commit: Option<&str>) -> Result<CopyContext, UpdateError> {
+    if !dest_dir.is_dir() {
+        return Err(UpdateError::Other(format!("--dest-dir {} isn't a directory, is the card mounted?", dest_dir.display())));
+    }
+    info(format!("Copying the build to the card mounted at {}...\n", dest_dir.display()).as_str());
+    let mut ctx = CopyContext::new(options, SourceTimeProvider::default(), true, total_source_size(sd_source_path, options)?);
+    ctx.long_paths = long_paths;
+    ctx.copy_log = options.copy_log.as_deref().map(CopyLog::create).transpose()?;
+    let mut root_dir = HostDir(dest_dir.to_path_buf());
+    copy_sources(&mut ctx, sd_source_path, options, None, &mut root_dir)?;
+    if let Some(path) = &options.include_manifest_in_image {
+        buildinfo::write(&root_dir, options, path, commit)?;
+    }
+    Ok(ctx)
+}
+
+/// What of `sd_source_path` goes on the card: all of it, or only `--source-subdir`.
+pub(crate) fn source_root(sd_source_path: &Path, options: &Options) -> Result<PathBuf, UpdateError> {
+    let Some(subdir) = &options.source_subdir else {
+        return Ok(sd_source_path.to_path_buf());
+    };
+    let root = sd_source_path.join(subdir);
+    if !root.is_dir() {
+        return Err(UpdateError::Other(format!(
+            "--source-subdir {} doesn't exist in {}",
+            subdir.display(),
+            sd_source_path.display()
+        )));
+    }
+    Ok(root)
+}
+
+/// Builds the image at `--output` and those of `--target`, or updates the card at
+/// `--dest-dir`, from the files in `sd_source_path`.
+pub fn build(sd_source_path: &Path, options: &Options) -> Result<(), UpdateError> {
+    let options = &packaging::merged(sd_source_path, options)?;
+    // The signature covers the whole checkout, whatever part of it is copied.
+    if let Some(public_key) = &options.require_signature {
+        signature::verify_source(sd_source_path, public_key)?;
+    }
+    if options.require_signed_commit {
+        commitsig::verify_head(sd_source_path, options)?;
+    }
+    let repo = git2::Repository::open(sd_source_path).ok();
+    if let Some(repo) = &repo {
+        let recorded = UpdateState::load(Path::new(STATE_FILE)).last_commit;
+        check_source_matches(repo, recorded.as_deref(), options.on_source_mismatch)?;
+    }
+    let commit = repo.as_ref().and_then(head_commit);
+    let root = source_root(sd_source_path, options)?;
+    // Before anything is written, so a source that can't be copied leaves the old image alone.
+    let mut roots = vec![root.as_path()];
+    roots.extend(options.overlays.iter().map(PathBuf::as_path));
+    // First, the other checks would go round a symlink loop until its paths got too long
+    // for FAT.
+    total_source_size(&root, options)?;
+    let exclude: Vec<PathBuf> = options.exclude.iter().map(|path| root.join(path)).collect();
+    // A target's extension lists can let through what these leave out.
+    let filter = if options.targets.is_empty() { FileFilter::new(options) } else { FileFilter::default() };
+    let long_paths = longpath::check(&roots, &exclude, &filter, &options.target_prefix(), options.on_longpath)?;
+    explain::report_collisions(&root, options, &long_paths)?;
+    let mut targets = vec![options.clone()];
+    for target in &options.targets {
+        targets.push(options.for_target(target).map_err(UpdateError::Usage)?);
+    }
+    // A target's extension lists can let through names that collide.
+    if options.dest_dir.is_none() {
+        for target in &targets {
+            shortname::check(&roots, target, &long_paths)?;
+        }
+    }
+    // One image failing, say for lack of space, doesn't keep the others from being built.
+    let results: Vec<(PathBuf, Result<(), UpdateError>)> = targets
+        .iter()
+        .map(|target| (target.output(), build_target(sd_source_path, &root, target, long_paths.clone(), commit.as_deref())))
+        .collect();
+    if results.len() > 1 {
+        for (output, result) in &results {
+            match result {
+                Ok(()) => info(format!("{}: built\n", output.display()).as_str()),
+                Err(e) => warn(format!("{}: failed, {}\n", output.display(), e).as_str()),
+            }
+        }
+    }
+    if let Some(e) = results.into_iter().find_map(|(_, result)| result.err()) {
+        return Err(e);
+    }
+    info("All done!\n");
+    Ok(())
+}
+
+/// Builds one image, or updates the card at `--dest-dir`, from the checked source at `root`.
+fn build_target(sd_source_path: &Path, root: &Path, options: &Options, long_paths: LongPaths, commit: Option<&str>) -> Result<(), UpdateError> {
+    let output = options.output();
+    info(format!("Building {}\n", output.display()).as_str());
+    excluded::check_oversized(root, options, &long_paths)?;
+    outputlock::unlock(options)?;
+    vhd::strip(options)?;
+    let delta = delta::since_build(sd_source_path, root, options, &long_paths);
+    if let Some(hook) = &options.pre_build_hook {
+        hooks::run_hook("pre-build", hook, &output, commit)?;
+    }
+    let ctx = match &options.dest_dir {
+        Some(dest_dir) => build_into_dir(root, options, dest_dir, long_paths, commit)?,
+        None => build_image(root, options, long_paths, label::for_build(options, commit), delta, commit)?,
+    };
+    // Failed files leave the checkpoint in place, so the next run only retries those.
+    if let Some(failures) = ctx.failures.filter(|failures| !failures.is_empty()) {
+        return Err(UpdateError::CopyFailed(failures.len()));
+    }
+    resume::finish(options)?;
+    if options.dest_dir.is_none() {
+        asset::set_built_commit(options, commit)?;
+    }
+    if let Some(kind) = options.image_hash {
+        imagehash::hash_image(options, kind)?;
+    }
+    // After the hash, which is of the image itself, and before the compressed copy of it.
+    if options.output_format == OutputFormat::Vhd {
+        vhd::wrap(&output)?;
+    }
+    if let Some(compression) = options.compress_output {
+        compress::compress_image(options, compression)?;
+    }
+    if let Some(hook) = &options.post_build_hook {
+        hooks::run_hook("post-build", hook, &output, commit)?;
+    }
+    if options.lock_output {
+        outputlock::lock(&output)?;
+    }
+    Ok(())
+}
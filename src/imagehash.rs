@@ -0,0 +1,87 @@
+//! `--image-hash`: a hash that identifies a finished build. `raw` hashes every byte of the
+//! image, so it also changes with anything that only moves data around, like a different
+//! cluster layout. `tree` hashes the directories and files on it, paths and contents in
+//! name order, so builds of the same files hash the same however they ended up laid out.
+//! Together with the sorted copy order that makes it a reproducible name for a set of files,
+//! and it works for a `--dest-dir` as well.
+
+use std::fs::File;
+use std::io::Read;
+
+use fatfs::FileSystem;
+
+use crate::dest::{DestDir, DestFile, HostDir};
+use crate::error::UpdateError;
+use crate::hash::{hash_reader, ContentHasher};
+use crate::image::open_fs_region;
+use crate::options::{HashAlgorithm, ImageHash, Options};
+use crate::progress::Phase;
+use crate::{info, partition, report};
+
+fn hash_tree<D: DestDir>(sd_folder: &D, prefix: &str, hasher: &mut ContentHasher, buffer: &mut [u8], algorithm: HashAlgorithm) -> Result<(), UpdateError> {
+    let mut entries: Vec<_> = sd_folder.entries()?.into_iter().collect();
+    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (_, entry) in entries {
+        // The copy never writes dotfiles, and an OS puts its own on cards it mounts.
+        if entry.name.starts_with('.') {
+            continue;
+        }
+        let path = format!("{}{}", prefix, entry.name);
+        if entry.is_dir {
+            hasher.update(format!("dir {}\n", path).as_bytes());
+            hash_tree(&sd_folder.open_dir(&entry.name)?, &format!("{}/", path), hasher, buffer, algorithm)?;
+            continue;
+        }
+        let mut sd_file = sd_folder.open_file(&entry.name)?;
+        let mut contents = ContentHasher::new(algorithm);
+        loop {
+            let bytes_read = sd_file.read(buffer)?;
+            if bytes_read == 0 {
+                break;
+            }
+            contents.update(&buffer[..bytes_read]);
+        }
+        hasher.update(format!("file {} {} {}\n", path, entry.len, contents.finish()).as_bytes());
+    }
+    Ok(())
+}
+
+/// Hashes the finished build the way `--image-hash` asks, logs the hash and records it for
+/// the JSON summary.
+pub fn hash_image(options: &Options, kind: ImageHash) -> Result<String, UpdateError> {
+    let phase = Phase::start("Hashing the image");
+    let offset = if options.partitioned { partition::PARTITION_OFFSET } else { 0 };
+    // Streamed through one buffer, the image is never held in memory.
+    let mut buffer = vec![0_u8; 1024 * 1024];
+    let hash = match kind {
+        ImageHash::Raw => {
+            let image = File::open(options.output())?;
+            match options.device {
+                // Only the part that was written, a device is usually larger.
+                Some(_) => hash_reader(&mut image.take(offset + options.sd_size()), &mut buffer, options.hash)?,
+                None => hash_reader(&mut &image, &mut buffer, options.hash)?,
+            }
+        }
+        ImageHash::Tree => {
+            let mut hasher = ContentHasher::new(options.hash);
+            match &options.dest_dir {
+                Some(dest_dir) => hash_tree(&HostDir(dest_dir.clone()), "", &mut hasher, &mut buffer, options.hash)?,
+                None => {
+                    let (fs_region, _) = open_fs_region(options, offset)?;
+                    let fs_options = fatfs::FsOptions::new().oem_cp_converter(options.code_page);
+                    let fs = FileSystem::new(fs_region, fs_options)?;
+                    hash_tree(&fs.root_dir(), "", &mut hasher, &mut buffer, options.hash)?;
+                }
+            }
+            hasher.finish()
+        }
+    };
+    report::record_phase("image-hash", phase.finish());
+    let kind = match kind {
+        ImageHash::Raw => "raw",
+        ImageHash::Tree => "tree",
+    };
+    info(format!("Image hash ({}, {}): {}\n", kind, options.hash.name(), hash).as_str());
+    report::record_image_hash(&hash, options.hash);
+    Ok(hash)
+}
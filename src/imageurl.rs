@@ -0,0 +1,135 @@
+//! `--image-url <url>`: downloads `sd.xz` into the assets directory instead of shipping it,
+//! for setups that would rather not carry 2GB around. Next to it, `<url>.blake3` has to hold
+//! its hash in the format `b3sum` prints, which is kept as `sd.xz.blake3` so every later
+//! `check_sd_xz` checks against it too.
+//!
+//! An `sd.xz` that already matches the hash is kept, so only a new image is downloaded.
+//! The download goes to `sd.xz.part` first and picks up where it left off after a dropped
+//! connection, in this run or the next. `--download-limit` holds it to a rate. An embedded
+//! `sd.xz` wins over the URL, like over an `assets` folder.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::error::UpdateError;
+use crate::git::check_host;
+use crate::hash::hash_reader;
+use crate::image::sd_xz_path;
+use crate::options::{HashAlgorithm, Options};
+use crate::progress::ProgressBar;
+use crate::throttle::WriteThrottle;
+use crate::units::format_bytes;
+use crate::{debug, info, warn};
+
+/// How often a dropped download is picked up again before giving up on this run.
+const RETRIES: u32 = 3;
+
+fn get(url: &str, from: u64) -> Result<ureq::Response, UpdateError> {
+    let mut request = ureq::get(url).set("User-Agent", concat!("dolphin_auto_updater/", env!("CARGO_PKG_VERSION")));
+    if from > 0 {
+        request = request.set("Range", &format!("bytes={}-", from));
+    }
+    request.call().map_err(|e| match e {
+        ureq::Error::Status(status, _) => UpdateError::Other(format!("Can't download {}: the server answered {}", url, status)),
+        // Counts as a dropped connection, so `--max-pipeline-retries` tries again.
+        e => UpdateError::Io(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, format!("Can't download {}: {}", url, e))),
+    })
+}
+
+/// The hash `<url>.blake3` gives.
+fn expected_hash(url: &str) -> Result<String, UpdateError> {
+    let checksum_url = format!("{}.blake3", url);
+    let checksum = get(&checksum_url, 0)?.into_string()?;
+    match checksum.split_whitespace().next() {
+        Some(hash) => Ok(hash.to_lowercase()),
+        None => Err(UpdateError::UntrustedSource(format!("{} is empty, refusing to use the image without it", checksum_url))),
+    }
+}
+
+fn blake3_of(path: &Path) -> Result<String, UpdateError> {
+    Ok(hash_reader(&mut File::open(path)?, &mut vec![0_u8; 1024 * 1024], HashAlgorithm::Blake3)?)
+}
+
+/// Downloads `url` onto the end of `part`, resuming what is there if the server can.
+fn download_to(url: &str, part: &Path, options: &Options) -> Result<(), UpdateError> {
+    let mut from = std::fs::metadata(part).map(|metadata| metadata.len()).unwrap_or(0);
+    let response = get(url, from)?;
+    // A server that ignores the range sends all of it again.
+    if from > 0 && response.status() != 206 {
+        debug(format!("{} can't resume, downloading all of it again\n", url).as_str());
+        from = 0;
+    }
+    if from > 0 {
+        info(format!("Resuming the download {} in\n", format_bytes(from)).as_str());
+    }
+    let length = response.header("Content-Length").and_then(|length| length.parse::<u64>().ok());
+    let mut file = OpenOptions::new().create(true).write(true).append(from > 0).truncate(from == 0).open(part)?;
+    let mut progress = ProgressBar::new("Downloading sd.xz", length.unwrap_or(0));
+    let mut throttle = options.download_limit.map(WriteThrottle::new);
+    let mut reader = response.into_reader();
+    let mut buffer = vec![0_u8; 256 * 1024];
+    loop {
+        let bytes_read = reader.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+        file.write_all(&buffer[..bytes_read])?;
+        progress.inc(bytes_read as u64);
+        if let Some(throttle) = throttle.as_mut() {
+            throttle.pace(bytes_read as u64);
+        }
+    }
+    progress.finish();
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Makes sure the `sd.xz` from `--image-url` is in the assets directory, downloading it if
+/// it isn't there or doesn't match the hash upstream gives.
+pub(crate) fn fetch(options: &Options) -> Result<(), UpdateError> {
+    let (Some(url), Some(path)) = (&options.image_url, sd_xz_path(options)) else {
+        return Ok(());
+    };
+    if options.offline {
+        if !path.exists() {
+            return Err(UpdateError::Other(format!("--offline can't download {} from {}", path.display(), url)));
+        }
+        debug(format!("Offline, using {} as it is\n", path.display()).as_str());
+        return Ok(());
+    }
+    check_host(url, options)?;
+    let expected = expected_hash(url)?;
+    let sidecar = path.with_extension("xz.blake3");
+    if path.exists() {
+        // The recorded hash is checked against the file by `check_sd_xz` anyway.
+        let recorded = std::fs::read_to_string(&sidecar).unwrap_or_default();
+        if recorded.split_whitespace().next() == Some(expected.as_str()) || blake3_of(&path)? == expected {
+            std::fs::write(&sidecar, format!("{}  sd.xz\n", expected))?;
+            debug(format!("{} is the image at {}, not downloading it again\n", path.display(), url).as_str());
+            return Ok(());
+        }
+        info(format!("{} differs from the image at {}, downloading the new one\n", path.display(), url).as_str());
+    }
+    std::fs::create_dir_all(options.assets_dir())?;
+    let part = path.with_extension("xz.part");
+    info(format!("Downloading {}\n", url).as_str());
+    let mut attempt = 0;
+    while let Err(e) = download_to(url, &part, options) {
+        if attempt == RETRIES || !e.is_recoverable() {
+            return Err(e);
+        }
+        attempt += 1;
+        warn(format!("The download failed ({}), picking it up again ({}/{})\n", e, attempt, RETRIES).as_str());
+        std::thread::sleep(Duration::from_secs(2 * attempt as u64));
+    }
+    if blake3_of(&part)? != expected {
+        std::fs::remove_file(&part)?;
+        return Err(UpdateError::CorruptAsset(format!("The image downloaded from {} doesn't match {}.blake3", url, url)));
+    }
+    std::fs::rename(&part, &path)?;
+    std::fs::write(&sidecar, format!("{}  sd.xz\n", expected))?;
+    info(format!("Downloaded {}\n", path.display()).as_str());
+    Ok(())
+}
@@ -0,0 +1,42 @@
+//! Job control with a progress line on screen. The line is redrawn in place with `\r`, so
+//! after Ctrl+Z the shell's prompt would land on the end of it, and after `fg` nothing is
+//! shown until the next redraw, which can be a while during a slow write. On SIGTSTP the
+//! line is cleared before the process stops, and on SIGCONT it is drawn again.
+//!
+//! Signal handlers can't take the logging lock, so a thread waits for the signals and does
+//! it. Windows has no job control, there `install` does nothing.
+
+/// Starts handling SIGTSTP and SIGCONT, when progress lines are drawn at all.
+#[cfg(unix)]
+pub fn install() {
+    use signal_hook::consts::{SIGCONT, SIGTSTP};
+    use signal_hook::iterator::Signals;
+
+    use crate::logging::{resume_line, show_progress, suspend_line};
+    use crate::debug;
+
+    if !show_progress() {
+        return;
+    }
+    let mut signals = match Signals::new([SIGTSTP, SIGCONT]) {
+        Ok(signals) => signals,
+        Err(e) => {
+            debug(format!("Can't handle Ctrl+Z, a progress line may be left behind on suspend: {}\n", e).as_str());
+            return;
+        }
+    };
+    std::thread::spawn(move || {
+        for signal in signals.forever() {
+            if signal == SIGTSTP {
+                suspend_line();
+                // What SIGTSTP would have done without the handler: stop the process.
+                let _ = signal_hook::low_level::emulate_default_handler(SIGTSTP);
+            } else {
+                resume_line();
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn install() {}
@@ -0,0 +1,132 @@
+//! `--volume-label`: the name hosts show for the card, by default the commit the build was
+//! made from so it's easy to tell which one is in the Wii. FAT keeps the label twice, in
+//! the boot sector and as a special entry in the root directory, and most hosts only read
+//! the entry, so both are written. fatfs can only set a label while formatting, so this
+//! edits the image directly, before the filesystem is mounted.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::options::Options;
+use crate::wipe::read_layout;
+
+pub const MAX_LABEL_LEN: usize = 11;
+
+/// What FAT allows in a label besides letters and digits, the same as in short names.
+const SPECIAL_CHARS: &str = " !#$%&'()-@^_`{}~";
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+/// Long name entries set every low attribute bit, volume ID included.
+const ATTR_LONG_NAME: u8 = 0x0F;
+const DIR_ENTRY_SIZE: usize = 32;
+
+/// Checks a `--volume-label` and returns it the way FAT stores it, in upper case. Lower
+/// case letters are accepted and converted, like Windows does.
+pub fn check_label(name: &str, value: &str) -> Result<String, String> {
+    let label = value.to_ascii_uppercase();
+    if let Some(c) = label.chars().find(|c| !c.is_ascii_alphanumeric() && !SPECIAL_CHARS.contains(*c)) {
+        return Err(format!(
+            "--{} can't contain '{}', FAT labels only allow letters, digits, spaces and {}",
+            name,
+            c,
+            SPECIAL_CHARS.trim()
+        ));
+    }
+    if label.len() > MAX_LABEL_LEN {
+        return Err(format!("--{} '{}' is longer than the {} characters FAT allows", name, value, MAX_LABEL_LEN));
+    }
+    if label.starts_with(' ') {
+        return Err(format!("--{} can't start with a space", name));
+    }
+    Ok(label)
+}
+
+/// The label to give the image: `--volume-label`, or the short commit hash. An empty
+/// `--volume-label` keeps whatever label the base image has.
+pub fn for_build(options: &Options, commit: Option<&str>) -> Option<[u8; MAX_LABEL_LEN]> {
+    let label = match options.volume_label.as_deref() {
+        Some("") => return None,
+        Some(label) => label.to_string(),
+        None => commit?[..8].to_ascii_uppercase(),
+    };
+    let mut padded = [b' '; MAX_LABEL_LEN];
+    padded[..label.len()].copy_from_slice(label.as_bytes());
+    Some(padded)
+}
+
+/// The byte ranges the root directory takes up, in order.
+fn root_dir_regions(image: &mut File, offset: u64) -> std::io::Result<Vec<(u64, u64)>> {
+    let layout = read_layout(image, offset)?;
+    if layout.fat_type != fatfs::FatType::Fat32 {
+        return Ok(vec![(layout.root_dir_start, layout.root_dir_bytes)]);
+    }
+    let cluster_bytes = layout.bytes_per_sector * layout.sectors_per_cluster;
+    let mut regions = Vec::new();
+    let mut cluster = layout.root_cluster;
+    // Bounded by the cluster count, so a corrupt FAT with a loop in it can't hang the build.
+    while (2..layout.clusters + 2).contains(&cluster) && regions.len() <= layout.clusters as usize {
+        regions.push((layout.data_start + (cluster as u64 - 2) * cluster_bytes, cluster_bytes));
+        let mut next = [0_u8; 4];
+        image.seek(SeekFrom::Start(layout.fat_start + cluster as u64 * 4))?;
+        image.read_exact(&mut next)?;
+        cluster = u32::from_le_bytes(next) & 0x0FFF_FFFF;
+    }
+    Ok(regions)
+}
+
+/// Finds the root directory's label entry, or the end of the directory where one can be
+/// added. `None` if the root directory is full.
+fn find_label_slot(image: &mut File, offset: u64) -> std::io::Result<Option<(u64, bool)>> {
+    for (start, len) in root_dir_regions(image, offset)? {
+        let mut entries = vec![0_u8; len as usize];
+        image.seek(SeekFrom::Start(start))?;
+        image.read_exact(&mut entries)?;
+        for (index, entry) in entries.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+            let at = start + (index * DIR_ENTRY_SIZE) as u64;
+            match entry[0] {
+                // The end of the directory. Everything after it has to be free as well,
+                // or an entry written here would bring garbage after it to life.
+                0x00 => {
+                    let rest = &entries[(index + 1) * DIR_ENTRY_SIZE..];
+                    return Ok(rest.iter().all(|byte| *byte == 0).then_some((at, false)));
+                }
+                // Deleted.
+                0xE5 => continue,
+                _ if entry[11] != ATTR_LONG_NAME && entry[11] & ATTR_VOLUME_ID != 0 => return Ok(Some((at, true))),
+                _ => {}
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Writes `label` into the filesystem starting `offset` bytes into `image`. Returns whether
+/// the root directory got it too, which fails when it's full.
+pub fn write_label(image: &mut File, offset: u64, label: &[u8; MAX_LABEL_LEN]) -> std::io::Result<bool> {
+    let layout = read_layout(image, offset)?;
+    // The boot sector only has the field when the extended boot signature says so.
+    let (signature_at, label_at) = match layout.fat_type {
+        fatfs::FatType::Fat32 => (66, 71),
+        _ => (38, 43),
+    };
+    let mut sector = [0_u8; 512];
+    image.seek(SeekFrom::Start(offset))?;
+    image.read_exact(&mut sector)?;
+    if sector[signature_at] == 0x29 {
+        image.seek(SeekFrom::Start(offset + label_at))?;
+        image.write_all(label)?;
+    }
+    let Some((at, exists)) = find_label_slot(image, offset)? else {
+        return Ok(false);
+    };
+    image.seek(SeekFrom::Start(at))?;
+    if exists {
+        image.write_all(label)?;
+    } else {
+        let mut entry = [0_u8; DIR_ENTRY_SIZE];
+        entry[..MAX_LABEL_LEN].copy_from_slice(label);
+        entry[11] = ATTR_VOLUME_ID;
+        image.write_all(&entry)?;
+    }
+    Ok(true)
+}
@@ -0,0 +1,620 @@
+extern crate fatfs;
+extern crate fscommon;
+
+mod asset;
+mod attrs;
+mod auth;
+mod bench;
+mod buffertune;
+mod buildinfo;
+mod cancel;
+mod codepage;
+mod commitsig;
+mod compare;
+mod compress;
+mod config;
+mod copylog;
+mod delta;
+mod dest;
+mod dirsort;
+mod dumpconfig;
+mod dupes;
+pub mod error;
+mod excluded;
+mod explain;
+mod format;
+mod git;
+mod hash;
+pub mod healthcheck;
+mod hooks;
+mod image;
+mod imagehash;
+mod imageurl;
+pub mod jobcontrol;
+mod label;
+pub mod lock;
+pub mod logging;
+mod longpath;
+pub mod options;
+mod outputlock;
+mod overlap;
+mod pack;
+mod packaging;
+mod partition;
+mod policy;
+mod preserve;
+mod progress;
+mod repair;
+pub mod report;
+mod resume;
+mod rootdir;
+mod scratch;
+mod selfupdate;
+mod shortname;
+mod signature;
+mod space;
+mod state;
+mod sync;
+mod textfile;
+mod throttle;
+mod timestamps;
+mod units;
+mod vhd;
+mod watch;
+mod wipe;
+mod xzcheck;
+mod xzseek;
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use git2::Repository;
+
+use error::{check_corruption, UpdateError};
+use git::{autotag, changelog, check_repo, check_state, checkout_tag, clone_repo, ensure_remote_url, list_changes, pull_repo, remote_tip, report_head, source_url};
+use logging::{debug, end_line, info, warn};
+use options::{OnNotARepo, Options};
+use state::{UpdateState, STATE_FILE};
+
+pub use git::head_commit;
+pub use dumpconfig::dump_config;
+pub use excluded::{list_excluded, Excluded};
+pub use explain::{explain, Explained};
+pub use image::{build, Exclusion};
+pub use pack::pack;
+pub use repair::{repair_image, Repaired};
+pub use watch::watch;
+
+/// The branch to pull: the one `--follow-default-branch` switched to, unless `--branch`
+/// names one explicitly.
+fn tracked_branch(options: &Options, state: &UpdateState) -> String {
+    match (&options.branch, &state.branch) {
+        (None, Some(followed)) => followed.clone(),
+        _ => options.branch(),
+    }
+}
+
+/// Whether upstream's branch is still at the commit the last pull merged, the checkout is at
+/// it and the image was built from it, found out from the refs the remote lists, without a
+/// fetch. Listing them failing only costs the shortcut, the pull that follows tells why.
+fn upstream_unchanged(repo: &Repository, options: &Options, state: &UpdateState) -> bool {
+    if options.force || options.tag.is_some() || !options.output().exists() {
+        return false;
+    }
+    let (Some(upstream), Some(head)) = (state.upstream.as_deref(), head_commit(repo)) else {
+        return false;
+    };
+    if head != upstream || asset::built_commit(options).as_deref() != Some(upstream) {
+        return false;
+    }
+    match remote_tip(repo, &tracked_branch(options, state)) {
+        Ok(tip) => tip.is_some_and(|tip| tip.to_string() == upstream),
+        Err(e) => {
+            debug(format!("Can't list upstream's branches, fetching instead: {}\n", e.message()).as_str());
+            false
+        }
+    }
+}
+
+/// Whether `sd_source_path` is a directory without a `.git`, as opposed to a checkout,
+/// broken or not.
+fn is_plain_dir(sd_source_path: &Path) -> bool {
+    sd_source_path.is_dir() && !sd_source_path.join(".git").exists()
+}
+
+/// Fails if `sd_source_path` can't be written to, so `what` can't update it. A read-only
+/// mount of a source kept up to date elsewhere can still be built from with `--build-only`.
+fn check_writable(sd_source_path: &Path, what: &str) -> Result<(), UpdateError> {
+    if !sd_source_path.is_dir() {
+        return Ok(());
+    }
+    // A dotfile the build leaves out, next to `.git` rather than in it.
+    let probe = sd_source_path.join(".updater-write-probe");
+    match std::fs::OpenOptions::new().write(true).create_new(true).open(&probe) {
+        Ok(_) => {
+            let _ = std::fs::remove_file(&probe);
+            Ok(())
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(()),
+        Err(e) => Err(unwritable(sd_source_path, what, e)),
+    }
+}
+
+fn unwritable(sd_source_path: &Path, what: &str, e: std::io::Error) -> UpdateError {
+    match e.kind() {
+        std::io::ErrorKind::ReadOnlyFilesystem => UpdateError::Usage(format!(
+            "{} is on a read-only filesystem, so {} can't update it. Update it where it is writable, \
+             or run with --build-only to build from it as it is",
+            sd_source_path.display(),
+            what
+        )),
+        std::io::ErrorKind::PermissionDenied => UpdateError::Other(format!(
+            "{} can't be written to ({}), so {} can't update it. Check who owns it, or run with \
+             --build-only to build from it as it is",
+            sd_source_path.display(),
+            e,
+            what
+        )),
+        _ => UpdateError::Io(e),
+    }
+}
+
+fn not_a_repo(sd_source_path: &Path) -> UpdateError {
+    UpdateError::Other(format!(
+        "{} is there but isn't a git checkout of the MNN Build, so it can't be updated. Run with \
+         --on-not-a-repo=build to build from its files as they are, or --on-not-a-repo=reclone to move it \
+         aside and download the MNN Build again",
+        sd_source_path.display()
+    ))
+}
+
+/// Renames `sd_source_path` to the first free `sd_source.moved`, `sd_source.moved-2`, ...
+/// and returns where it went.
+fn move_aside(sd_source_path: &Path) -> Result<PathBuf, UpdateError> {
+    let name = sd_source_path.file_name().unwrap_or_default().to_string_lossy();
+    let moved = (1..)
+        .map(|n| match n {
+            1 => sd_source_path.with_file_name(format!("{}.moved", name)),
+            n => sd_source_path.with_file_name(format!("{}.moved-{}", name, n)),
+        })
+        .find(|moved| !moved.exists())
+        .expect("an unused name");
+    std::fs::rename(sd_source_path, &moved)?;
+    Ok(moved)
+}
+
+/// Clones or pulls `sd_source`. Returns whether anything changed, or `None` if the check
+/// was skipped because the last one was more recent than `--min-interval`.
+fn update_source(sd_source_path: &Path, options: &Options, state: &mut UpdateState) -> Result<Option<bool>, UpdateError> {
+    let state_path = PathBuf::from(STATE_FILE);
+    // check if the /sd_source folder exists
+    info("Checking if MNN Build already downloaded\n");
+    if options.force_reclone && sd_source_path.exists() {
+        warn("--force-reclone given, removing the existing MNN Build\n");
+        std::fs::remove_dir_all(sd_source_path)?;
+    }
+    // An empty one, like a failed clone leaves, holds nothing to lose.
+    if is_plain_dir(sd_source_path) && std::fs::read_dir(sd_source_path)?.next().is_none() {
+        std::fs::remove_dir(sd_source_path)?;
+    }
+    if is_plain_dir(sd_source_path) {
+        match options.on_not_a_repo {
+            OnNotARepo::Error => return Err(not_a_repo(sd_source_path)),
+            OnNotARepo::Build => {
+                warn(format!("{} isn't a git checkout, building from its files as they are\n", sd_source_path.display()).as_str());
+                return Ok(Some(true));
+            }
+            OnNotARepo::Reclone => {
+                let moved = move_aside(sd_source_path)?;
+                warn(format!("{} isn't a git checkout, moved it to {}\n", sd_source_path.display(), moved.display()).as_str());
+            }
+        }
+    }
+    if !sd_source_path.exists() {
+        warn("MNN Build not found\n");
+        debug("This is not really a problem, we will now download the build from GitHub\n");
+        debug("In future runs, we will update the local MNN Build\n");
+        debug("This means that the current download should only ever happen once\n");
+        debug("So perhaps sit tight as this may take a while\n");
+        info("Downloading MNN Build (can take some time)\n");
+        let url = source_url(options)?;
+        std::fs::create_dir(sd_source_path)?;
+        clone_repo(&url, sd_source_path, autotag(options))?;
+        info("Downloaded MNN Build\n");
+        let repo = Repository::open(sd_source_path)?;
+        if let Some(tag) = &options.tag {
+            checkout_tag(&repo, tag)?;
+        }
+        report_head(&repo);
+        if options.tag.is_none() {
+            state.upstream = head_commit(&repo);
+        }
+        state.record_check(head_commit(&repo));
+        state.save(&state_path)?;
+        return Ok(Some(true));
+    }
+    info("MNN Build found\n");
+    if let (Some(min_interval), Some(elapsed)) = (options.min_interval, state.since_last_check()) {
+        if !options.force && elapsed < min_interval {
+            info(format!(
+                "Checked recently ({}s ago, minimum interval {}s), skipping\n",
+                elapsed.as_secs(),
+                min_interval.as_secs()
+            ).as_str());
+            return Ok(None);
+        }
+    }
+    info("Checking for updates...\n");
+    // Only now, a check skipped by --min-interval shouldn't bring the mirror up to date.
+    let url = source_url(options)?;
+    // It has a `.git`, so one that doesn't open is broken rather than not a checkout at all.
+    let repo = Repository::open(sd_source_path).map_err(UpdateError::CorruptCheckout)?;
+    ensure_remote_url(&repo, &url)?;
+    // The usual scheduled run: nothing was pushed, so there's nothing to download.
+    if upstream_unchanged(&repo, options, state) {
+        debug("Upstream's branch hasn't moved since the last pull, skipping the fetch\n");
+        state.record_check(head_commit(&repo));
+        state.save(&state_path)?;
+        info("MNN Build is up to date\n");
+        return Ok(Some(false));
+    }
+    let before = repo.head().ok().and_then(|head| head.target());
+    let mut branch = tracked_branch(options, state);
+    let needs_update = pull_repo(&repo, options, &mut branch, &mut state.upstream).map_err(check_corruption)?;
+    state.branch = (branch != options.branch()).then_some(branch);
+    report_head(&repo);
+    let after = repo.head().ok().and_then(|head| head.target());
+    if let (true, Some(before), Some(after)) = (options.list_changes, before, after) {
+        if before != after {
+            list_changes(&repo, before, after)?;
+        }
+    }
+    // --commit-range goes back to the commit recorded by the last check, which can be further
+    // back than the checkout before this pull, say after pulling by hand in between.
+    let baseline = if options.commit_range {
+        let recorded = state
+            .last_commit
+            .as_deref()
+            .and_then(|commit| git2::Oid::from_str(commit).ok())
+            .filter(|oid| repo.find_commit(*oid).is_ok());
+        if recorded.is_none() {
+            info("No earlier commit of the MNN Build recorded, no changelog this time\n");
+        }
+        recorded
+    } else if options.list_changes {
+        before
+    } else {
+        None
+    };
+    if let (Some(baseline), Some(after)) = (baseline, after) {
+        if baseline != after {
+            changelog(&repo, baseline, after)?;
+        }
+    }
+    state.record_check(head_commit(&repo));
+    state.save(&state_path)?;
+    if needs_update {
+        info("MNN Build updated\n");
+    }
+    else {
+        info("MNN Build is up to date\n");
+    }
+    Ok(Some(needs_update))
+}
+
+/// `3d 4h`, `2h 10m` or `5m`, for how long ago something happened.
+fn format_age(age: std::time::Duration) -> String {
+    let minutes = age.as_secs() / 60;
+    match (minutes / (60 * 24), minutes / 60 % 24, minutes % 60) {
+        (0, 0, minutes) => format!("{}m", minutes),
+        (0, hours, minutes) => format!("{}h {}m", hours, minutes),
+        (days, hours, _) => format!("{}d {}h", days, hours),
+    }
+}
+
+/// How a successful run ended, for the summary line.
+#[derive(Debug)]
+pub enum Outcome {
+    Built,
+    UpToDate,
+    Skipped,
+    Fetched(bool),
+    Checked(bool),
+    Compared,
+    Verified,
+    NotDownloaded,
+    SelfUpdated(bool),
+    Benchmarked,
+    SelfTested,
+    ListedExcluded,
+    Explained,
+    ImagesMatch,
+    Packed,
+    Repaired,
+}
+
+impl Outcome {
+    /// Whether the run changed `sd_source` or the image.
+    pub fn updated(&self) -> bool {
+        matches!(self, Outcome::Built | Outcome::Packed | Outcome::Repaired | Outcome::Fetched(true) | Outcome::SelfUpdated(true))
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Outcome::Built => "built the image",
+            Outcome::UpToDate => "up to date, nothing to build",
+            Outcome::Skipped => "checked recently, skipped",
+            Outcome::Fetched(true) => "fetched upstream changes",
+            Outcome::Fetched(false) => "fetched, no upstream changes",
+            Outcome::Checked(true) => "update available",
+            Outcome::Checked(false) => "up to date",
+            Outcome::Compared => "the image matches the source",
+            Outcome::Verified => "the card matches the source",
+            Outcome::NotDownloaded => "MNN Build not downloaded yet",
+            Outcome::SelfUpdated(true) => "updated the updater",
+            Outcome::SelfUpdated(false) => "the updater is up to date",
+            Outcome::Benchmarked => "benchmark finished",
+            Outcome::SelfTested => "sd.xz is intact",
+            Outcome::ListedExcluded => "listed what the build leaves out",
+            Outcome::Explained => "explained what the build does with the matching paths",
+            Outcome::ImagesMatch => "the images hold the same files",
+            Outcome::Packed => "packed the directory",
+            Outcome::Repaired => "rebuilt the image from what could be recovered",
+        }
+    }
+}
+
+/// Runs whatever the options ask for, without exiting or printing the summary.
+pub fn run(options: &Options, sd_source_path: &Path) -> Result<Outcome, UpdateError> {
+    let mut state = UpdateState::load(Path::new(STATE_FILE));
+    cancel::reset();
+    scratch::set_tmpdir(options.tmpdir.clone());
+    dirsort::set_spill_threshold(options.dir_spill_threshold);
+    selfupdate::remove_old_binary();
+    progress::start_pipeline(options);
+
+    if options.self_update {
+        return Ok(Outcome::SelfUpdated(selfupdate::self_update(options)?));
+    }
+
+    if options.self_test {
+        image::self_test(options)?;
+        return Ok(Outcome::SelfTested);
+    }
+
+    if options.bench {
+        bench::bench(options)?;
+        return Ok(Outcome::Benchmarked);
+    }
+
+    if let Some(other) = &options.diff_image {
+        compare::diff_images(options, other)?;
+        return Ok(Outcome::ImagesMatch);
+    }
+
+    if options.repair_image {
+        repair_image(options)?;
+        return Ok(Outcome::Repaired);
+    }
+
+    overlap::check(options.pack.as_deref().unwrap_or(sd_source_path), options)?;
+
+    if let Some(source) = &options.pack {
+        pack(source, options)?;
+        return Ok(Outcome::Packed);
+    }
+
+    if options.reset_merge_state {
+        check_writable(sd_source_path, "--reset-merge-state")?;
+    }
+
+    // Whatever comes next reads the checkout, or pulls into it.
+    if let Ok(repo) = Repository::open(sd_source_path) {
+        check_state(&repo, options)?;
+    }
+
+    if options.build_only {
+        if !sd_source_path.exists() {
+            return Err(UpdateError::Other(
+                "There is no MNN Build to build from yet, run without --build-only first".to_string(),
+            ));
+        }
+        info("Building from the current MNN Build checkout\n");
+        if let Ok(repo) = Repository::open(sd_source_path) {
+            report_head(&repo);
+        }
+        build(sd_source_path, options)?;
+        return Ok(Outcome::Built);
+    }
+
+    if options.compare {
+        compare::compare(sd_source_path, options)?;
+        return Ok(Outcome::Compared);
+    }
+
+    if options.verify_only || options.sample_verify.is_some() {
+        compare::verify(sd_source_path, options)?;
+        return Ok(Outcome::Verified);
+    }
+
+    if options.list_excluded {
+        list_excluded(sd_source_path, options)?;
+        return Ok(Outcome::ListedExcluded);
+    }
+
+    if options.explain.is_some() {
+        explain(sd_source_path, options)?;
+        return Ok(Outcome::Explained);
+    }
+
+    if options.offline {
+        if !sd_source_path.exists() {
+            return Err(UpdateError::Other(
+                "--offline needs an MNN Build that was downloaded before, run once with network access first".to_string(),
+            ));
+        }
+        match state.since_last_check() {
+            Some(elapsed) => warn(format!(
+                "Offline, building from the MNN Build as of the last check {} ago, it may be out of date\n",
+                format_age(elapsed)
+            ).as_str()),
+            None => warn("Offline, building from the MNN Build as it is, it may be out of date\n"),
+        }
+        if let Ok(repo) = Repository::open(sd_source_path) {
+            report_head(&repo);
+        }
+        build(sd_source_path, options)?;
+        return Ok(Outcome::Built);
+    }
+
+    // Everything from here on writes to the source.
+    let updating = [("--fetch-only", options.fetch_only), ("--check", options.check), ("--force-reclone", options.force_reclone)]
+        .into_iter()
+        .find_map(|(flag, given)| given.then_some(flag))
+        .unwrap_or("a run without --build-only");
+    check_writable(sd_source_path, updating)?;
+
+    if options.check {
+        if !sd_source_path.exists() {
+            info("MNN Build not downloaded yet, the first run will download it\n");
+            return Ok(Outcome::NotDownloaded);
+        }
+        if is_plain_dir(sd_source_path) {
+            return Err(not_a_repo(sd_source_path));
+        }
+        let repo = Repository::open(sd_source_path)?;
+        ensure_remote_url(&repo, &source_url(options)?)?;
+        return Ok(Outcome::Checked(check_repo(&repo, &tracked_branch(options, &state), autotag(options))?));
+    }
+
+    let started = Instant::now();
+    let changed = update_source(sd_source_path, options, &mut state)?;
+    report::record_phase("update", started.elapsed());
+    if options.fetch_only {
+        // One machine-readable line so wrapper scripts can decide whether to run the build step.
+        let result = match changed {
+            Some(true) => "changed",
+            Some(false) => "unchanged",
+            None => "skipped",
+        };
+        let commit = Repository::open(sd_source_path)
+            .ok()
+            .and_then(|repo| head_commit(&repo))
+            .unwrap_or_default();
+        if !options.quiet {
+            println!("fetch_result={} commit={}", result, commit);
+        }
+        return Ok(match changed {
+            Some(changed) => Outcome::Fetched(changed),
+            None => Outcome::Skipped,
+        });
+    }
+    match changed {
+        Some(true) => {
+            resume::source_ready(options)?;
+            build(sd_source_path, options)?;
+            Ok(Outcome::Built)
+        }
+        // The source is as the last run left it, but the image it was building isn't done.
+        _ if resume::pending(options) => {
+            info("The last build was interrupted before it finished, building again\n");
+            build(sd_source_path, options)?;
+            Ok(Outcome::Built)
+        }
+        Some(false) => Ok(Outcome::UpToDate),
+        None => Ok(Outcome::Skipped),
+    }
+}
+
+/// How long to wait before the first pipeline retry. Doubles with every retry after it, up
+/// to 16 times this.
+const PIPELINE_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Like `run`, but with `--max-pipeline-retries` runs the whole update and build again
+/// after a backoff when it fails with a recoverable error. With `--auto-repair`, a damaged
+/// `sd_source` is cloned again once instead, since pulling into it again won't get further.
+pub fn run_with_retries(options: &Options, sd_source_path: &Path) -> Result<Outcome, UpdateError> {
+    let retries = options.max_pipeline_retries.unwrap_or(0);
+    let mut attempt = 0;
+    let mut repaired: Option<Options> = None;
+    loop {
+        if attempt > 0 {
+            info(format!("Pipeline attempt {}/{}\n", attempt + 1, retries + 1).as_str());
+        }
+        // The summary is of the attempt that finished, not what the failed ones got through.
+        report::reset();
+        match run(repaired.as_ref().unwrap_or(options), sd_source_path) {
+            Err(e @ UpdateError::CorruptCheckout(_)) if options.auto_repair && repaired.is_none() => {
+                warn(format!("{}\n", e).as_str());
+                warn("--auto-repair given, downloading a fresh copy of the MNN Build\n");
+                repaired = Some(Options { force_reclone: true, ..options.clone() });
+            }
+            Err(e) if attempt < retries && e.is_recoverable() => {
+                let mut delay = PIPELINE_RETRY_DELAY * (1 << attempt.min(4));
+                // Trying again sooner would only keep the limit in place.
+                if let Some(wait) = e.rate_limit_delay() {
+                    delay = delay.max(wait);
+                    warn(format!("Upstream's rate limit was hit, waiting {}s before asking again\n", delay.as_secs()).as_str());
+                }
+                warn(format!(
+                    "Pipeline attempt {}/{} failed ({}), trying again in {}s\n",
+                    attempt + 1,
+                    retries + 1,
+                    e,
+                    delay.as_secs()
+                ).as_str());
+                std::thread::sleep(delay);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_read_only_filesystem_is_told_apart_from_missing_permissions() {
+        let source = Path::new("sd_source");
+        let read_only = unwritable(source, "--fetch-only", std::io::ErrorKind::ReadOnlyFilesystem.into());
+        assert_eq!(read_only.exit_code(), 2);
+        assert!(read_only.to_string().contains("read-only filesystem, so --fetch-only"), "{}", read_only);
+        assert!(read_only.to_string().contains("--build-only"), "{}", read_only);
+
+        let denied = unwritable(source, "a run without --build-only", std::io::ErrorKind::PermissionDenied.into());
+        assert_eq!(denied.exit_code(), 1);
+        assert!(denied.to_string().contains("can't be written to"), "{}", denied);
+        assert!(!denied.to_string().contains("read-only filesystem"), "{}", denied);
+    }
+
+    #[test]
+    fn the_fetch_is_skipped_only_for_an_image_built_from_upstreams_tip() {
+        let temp = std::env::temp_dir().join(format!("dolphin_auto_updater_unchanged_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&temp);
+        let upstream_path = temp.join("upstream");
+        let upstream = Repository::init(&upstream_path).unwrap();
+        upstream.set_head("refs/heads/main").unwrap();
+        let signature = git2::Signature::now("test", "test@example.com").unwrap();
+        let tree = upstream.find_tree(upstream.index().unwrap().write_tree().unwrap()).unwrap();
+        let tip = upstream.commit(Some("HEAD"), &signature, &signature, "base", &tree, &[]).unwrap().to_string();
+        clone_repo(upstream_path.to_str().unwrap(), &temp.join("local"), git2::AutotagOption::Auto).unwrap();
+        let local = Repository::open(temp.join("local")).unwrap();
+
+        let output = temp.join("sd.raw");
+        std::fs::write(&output, b"image").unwrap();
+        let options = Options { output: Some(output), ..Options::default() };
+        let state = UpdateState { upstream: Some(tip.clone()), ..UpdateState::default() };
+        let built_from = |commit: &str| std::fs::write(temp.join("sd.raw.asset"), format!("size=0\nmodified=0\nhash=x\ncommit={}\n", commit)).unwrap();
+
+        assert!(!upstream_unchanged(&local, &options, &state), "nothing says what the image was built from");
+        built_from("0000000000000000000000000000000000000000");
+        assert!(!upstream_unchanged(&local, &options, &state), "the image is of another commit");
+        built_from(&tip);
+        assert!(upstream_unchanged(&local, &options, &state));
+        local.remote_set_url("origin", temp.join("gone").to_str().unwrap()).unwrap();
+        assert!(!upstream_unchanged(&local, &options, &state), "a remote that can't be listed is fetched from instead");
+        let _ = std::fs::remove_dir_all(&temp);
+    }
+}
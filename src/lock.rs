@@ -0,0 +1,49 @@
+use std::fs::{File, TryLockError};
+use std::io::{Read, Seek, Write};
+use std::path::Path;
+
+use crate::error::UpdateError;
+use crate::info;
+
+/// Held for the whole run so a second instance, say one started by a scheduler while a
+/// manual run is still going, can't touch `sd_source` or `sd.raw` at the same time.
+pub const LOCK_FILE: &str = ".updater.lock";
+
+/// Holds the lock until dropped, which also happens when unwinding from a panic. If the
+/// process is killed outright the OS releases the lock along with the file handle, so a
+/// stale lock file left behind never blocks the next run.
+pub struct LockGuard {
+    _file: File,
+}
+
+/// Takes the lock at `path`, or waits for it with `wait`. The holder's pid is written into
+/// the file so the other instance can say who it is waiting for.
+pub fn acquire(path: &Path, wait: bool) -> Result<LockGuard, UpdateError> {
+    let mut file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            // Windows locks are mandatory, so the pid can't always be read while it's held.
+            let mut holder = String::new();
+            let holder = match file.read_to_string(&mut holder) {
+                Ok(_) if !holder.trim().is_empty() => format!("pid {}", holder.trim()),
+                _ => "another process".to_string(),
+            };
+            if !wait {
+                return Err(UpdateError::AlreadyRunning(format!(
+                    "Another updater is already running ({}, holding {}), run again with --wait to wait for it",
+                    holder,
+                    path.display()
+                )));
+            }
+            info(format!("Waiting for the other updater ({}) to finish\n", holder).as_str());
+            file.lock()?;
+        }
+        Err(TryLockError::Error(e)) => return Err(e.into()),
+    }
+    file.set_len(0)?;
+    file.rewind()?;
+    write!(file, "{}", std::process::id())?;
+    file.flush()?;
+    Ok(LockGuard { _file: file })
+}
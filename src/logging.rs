@@ -0,0 +1,434 @@
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use colored::Colorize;
+
+use crate::options::ColorChoice;
+
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+pub fn is_verbose() -> bool {
+    VERBOSE.load(Ordering::Relaxed)
+}
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+static PROGRESS: AtomicBool = AtomicBool::new(true);
+
+/// Decides once at startup whether progress lines, redrawn in place with `\r`, are drawn.
+/// Not with `--no-progress`, and not when stdout isn't a terminal, where every redraw
+/// would be kept in the log. Phases are still logged when they start and finish.
+pub fn set_progress(no_progress: bool) {
+    PROGRESS.store(!no_progress && std::io::stdout().is_terminal(), Ordering::Relaxed);
+}
+
+pub fn show_progress() -> bool {
+    PROGRESS.load(Ordering::Relaxed)
+}
+
+/// Asks the Windows console to interpret ANSI escape codes, which older consoles print as
+/// literal text otherwise. Returns whether they will be rendered.
+#[cfg(windows)]
+fn enable_ansi() -> bool {
+    colored::control::set_virtual_terminal(true).is_ok()
+}
+
+#[cfg(not(windows))]
+fn enable_ansi() -> bool {
+    true
+}
+
+/// `plain_env` is set when `NO_COLOR` (see no-color.org) or `TERM=dumb` asks for plain
+/// output. `renders_ansi` is only asked when color would be used.
+fn color_enabled(choice: ColorChoice, plain_env: bool, is_terminal: bool, renders_ansi: impl FnOnce() -> bool) -> bool {
+    match choice {
+        // Still worth switching the console over, but used even if that fails.
+        ColorChoice::Always => {
+            renders_ansi();
+            true
+        }
+        ColorChoice::Never => false,
+        ColorChoice::Auto => !plain_env && is_terminal && renders_ansi(),
+    }
+}
+
+/// Decides once at startup whether log messages and progress lines, which are all written
+/// through `log`, are colored.
+pub fn set_color(choice: ColorChoice) {
+    let plain_env = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty())
+        || std::env::var_os("TERM").is_some_and(|term| term == "dumb");
+    let enabled = color_enabled(choice, plain_env, std::io::stdout().is_terminal(), enable_ansi);
+    colored::control::set_override(enabled);
+}
+
+/// Where log output goes. `None` is stdout. Every message is written while holding the
+/// lock, so output from several threads never interleaves within a message.
+static SINK: Mutex<Option<Box<dyn Write + Send>>> = Mutex::new(None);
+
+/// The progress line on screen if the last message was one, that is it ends in `\r` and
+/// the next message overwrites it. Only locked while holding `SINK`.
+static OPEN_LINE: Mutex<Option<String>> = Mutex::new(None);
+
+fn lock_sink() -> std::sync::MutexGuard<'static, Option<Box<dyn Write + Send>>> {
+    // A thread that panicked mid-log shouldn't silence everyone else.
+    SINK.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn lock_open_line() -> std::sync::MutexGuard<'static, Option<String>> {
+    OPEN_LINE.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn write_to(sink: &mut Option<Box<dyn Write + Send>>, text: &str) {
+    let result = match sink.as_mut() {
+        Some(sink) => sink.write_all(text.as_bytes()).and_then(|_| sink.flush()),
+        None => {
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(text.as_bytes()).and_then(|_| stdout.flush())
+        }
+    };
+    // Logging is best effort, a closed stdout shouldn't abort a build.
+    let _ = result;
+}
+
+fn emit(text: &str) {
+    let mut sink = lock_sink();
+    write_to(&mut sink, text);
+    // Colored, the `\r` comes before the code that resets the color.
+    let open = text.trim_end_matches("\u{1b}[0m").ends_with('\r');
+    *lock_open_line() = open.then(|| text.to_string());
+}
+
+/// Clears the progress line on screen, if there is one, before the process is suspended,
+/// so the shell's prompt doesn't land on the end of it. See `jobcontrol.rs`.
+pub fn suspend_line() {
+    let mut sink = lock_sink();
+    if lock_open_line().is_some() {
+        write_to(&mut sink, "\r\u{1b}[K");
+    }
+}
+
+/// Draws the progress line `suspend_line` cleared again, once the process is resumed.
+pub fn resume_line() {
+    let mut sink = lock_sink();
+    let open_line = lock_open_line().clone();
+    if let Some(line) = open_line {
+        write_to(&mut sink, &line);
+    }
+}
+
+#[cfg(test)]
+fn set_sink(sink: Option<Box<dyn Write + Send>>) {
+    *lock_sink() = sink;
+}
+
+/// How important a message is, one per logging function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+/// One redraw of a progress line, see `Output::progress`.
+#[derive(Debug)]
+pub struct Progress<'a> {
+    /// What is counted, like `Copying` or `Receiving objects`.
+    pub label: &'a str,
+    pub current: u64,
+    pub total: u64,
+    /// The line the terminal shows for it, with the rate and ETA where there are any.
+    pub text: &'a str,
+}
+
+/// Where an application embedding the updater receives what it reports, to show it in its
+/// own window instead of a terminal, say by sending it down a channel to the UI thread.
+/// Without one, messages and progress lines are written to stdout.
+///
+/// Both are called with a lock held, so they must not log anything themselves.
+pub trait Output: Send {
+    /// A message as it was logged, without the level tag, colors or `--log-format` prefix.
+    fn message(&mut self, level: Level, msg: &str);
+    /// Progress, as often as the terminal would redraw its line for it.
+    fn progress(&mut self, progress: &Progress);
+}
+
+static OUTPUT: Mutex<Option<Box<dyn Output>>> = Mutex::new(None);
+
+fn lock_output() -> std::sync::MutexGuard<'static, Option<Box<dyn Output>>> {
+    OUTPUT.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Sends everything reported from now on to `output`, or back to stdout for `None`. An
+/// `Output` gets progress whether or not stdout is a terminal.
+pub fn set_output(output: Option<Box<dyn Output>>) {
+    if output.is_some() {
+        PROGRESS.store(true, Ordering::Relaxed);
+    }
+    *lock_output() = output;
+}
+
+/// Reports progress to the `Output`, or redraws the progress line on the terminal.
+pub fn progress(progress: &Progress) {
+    if is_quiet() {
+        return;
+    }
+    let pipeline = crate::progress::pipeline_text(progress);
+    let progress = &Progress { text: pipeline.as_deref().unwrap_or(progress.text), ..*progress };
+    if let Some(output) = lock_output().as_mut() {
+        output.progress(progress);
+        return;
+    }
+    debug(format!("{}   \r", progress.text).as_str());
+}
+
+/// Ends a line left open by a `\r` progress message. An `Output` has no lines to end.
+pub fn end_line() {
+    if !is_quiet() && lock_output().is_none() {
+        emit("\n");
+    }
+}
+
+/// What goes in front of every message, from `--log-format`. `{level}` is the level tag,
+/// `{time}` the UTC time of day and `{phase}` the phase running, if any. Unset means just
+/// the level tag.
+static FORMAT: Mutex<Option<String>> = Mutex::new(None);
+
+pub fn set_log_format(format: Option<String>) {
+    *FORMAT.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = format;
+}
+
+/// The `progress::Phase` running right now, for `{phase}`.
+static PHASE: Mutex<Option<&'static str>> = Mutex::new(None);
+
+pub fn set_phase(phase: Option<&'static str>) {
+    *PHASE.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = phase;
+}
+
+/// Level tags are padded to the longest one, so messages of every level line up.
+const TAG_WIDTH: usize = "[DEBUG]".len();
+
+fn time_of_day() -> String {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+        % 86400;
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs % 3600 / 60, secs % 60)
+}
+
+/// Puts the prefix in front of `msg`. A leading `\r` stays in front of it so redrawing a
+/// progress line doesn't overwrite the prefix, and lines after the first are indented to
+/// line up under the first one.
+fn format_line(tag: &str, msg: &str, format: Option<&str>, time: &str, phase: Option<&str>) -> String {
+    let tag = format!("{:<width$}", tag, width = TAG_WIDTH);
+    let prefix = match format {
+        Some(format) => format
+            .replace("{level}", &tag)
+            .replace("{time}", time)
+            .replace("{phase}", phase.unwrap_or_default()),
+        None => tag,
+    };
+    let body = msg.trim_start_matches('\r');
+    let mut line = String::with_capacity(msg.len() + prefix.len() + 1);
+    line.push_str(&msg[..msg.len() - body.len()]);
+    line.push_str(&prefix);
+    line.push(' ');
+    let indent = " ".repeat(prefix.chars().count() + 1);
+    for (index, part) in body.split_inclusive('\n').enumerate() {
+        if index > 0 {
+            line.push_str(&indent);
+        }
+        line.push_str(part);
+    }
+    line
+}
+
+fn log(level: Level, tag: &str, msg: &str, color: colored::Color) {
+    // Before anything is sent anywhere, `--quiet` holds for an `Output` too.
+    if is_quiet() {
+        return;
+    }
+    if let Some(output) = lock_output().as_mut() {
+        output.message(level, msg);
+        return;
+    }
+    let format = FORMAT.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone();
+    let phase = *PHASE.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    emit(&format_line(tag, msg, format.as_deref(), &time_of_day(), phase).color(color).to_string());
+}
+
+pub fn debug(msg: &str) {
+    log(Level::Debug, "[DEBUG]", msg, colored::Color::TrueColor { r: 125, g: 125, b: 125 });
+}
+
+pub fn error(msg: &str) {
+    log(Level::Error, "[ERROR]", msg, colored::Color::Red);
+}
+
+pub fn warn(msg: &str) {
+    log(Level::Warn, "[WARN]", msg, colored::Color::Yellow);
+}
+
+pub fn info(msg: &str) {
+    log(Level::Info, "[INFO]", msg, colored::Color::White);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// Held by the tests that change where messages go or whether they are shown.
+    static LOGGING: Mutex<()> = Mutex::new(());
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            // Write byte by byte to give other threads every chance to get in between.
+            let mut captured = self.0.lock().unwrap();
+            for byte in buf {
+                captured.push(*byte);
+                drop(captured);
+                std::thread::yield_now();
+                captured = self.0.lock().unwrap();
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn messages_of_every_level_line_up() {
+        let warn = format_line("[WARN]", "first\nsecond\n", None, "12:00:00", None);
+        let debug = format_line("[DEBUG]", "first\n", None, "12:00:00", None);
+        assert_eq!(warn, "[WARN]  first\n        second\n");
+        assert_eq!(warn.find("first"), debug.find("first"));
+
+        let formatted = format_line("[INFO]", "\rDone\r", Some("{time} {level} {phase}:"), "12:34:56", Some("Copying"));
+        assert_eq!(formatted, "\r12:34:56 [INFO]  Copying: Done\r");
+    }
+
+    #[test]
+    fn color_is_only_used_where_it_renders() {
+        // A console without ANSI support would show the escape codes as text.
+        assert!(!color_enabled(ColorChoice::Auto, false, true, || false));
+        assert!(color_enabled(ColorChoice::Auto, false, true, || true));
+        assert!(!color_enabled(ColorChoice::Auto, true, true, || true));
+        assert!(!color_enabled(ColorChoice::Auto, false, false, || true));
+        assert!(color_enabled(ColorChoice::Always, true, false, || false));
+        assert!(!color_enabled(ColorChoice::Never, false, true, || panic!("not asked when color is off")));
+
+        colored::control::set_override(false);
+        let plain = format_line("[WARN]", "careful\n", None, "12:00:00", None).color(colored::Color::Yellow).to_string();
+        colored::control::unset_override();
+        assert!(!plain.contains('\u{1b}'), "{:?}", plain);
+    }
+
+    #[test]
+    fn the_pipeline_line_weighs_each_phase_by_its_cost() {
+        use crate::options::{Options, ProgressStyle};
+        use crate::progress::{pipeline_text, start_pipeline};
+        let line = |label: &str, current: u64| pipeline_text(&Progress { label, current, total: 100, text: "phase line" });
+        start_pipeline(&Options { progress_style: ProgressStyle::Pipeline, build_only: true, ..Options::default() });
+        // Decompressing weighs 35 and copying 40, there's nothing to download.
+        assert_eq!(line("Decompressing", 50).as_deref(), Some("Pipeline 23% \u{2014} Decompressing"));
+        assert_eq!(line("Copying", 50).as_deref(), Some("Pipeline 73% \u{2014} Copying"));
+        assert_eq!(line("Copying: creating directory", 5).as_deref(), Some("Pipeline 73% \u{2014} Copying"));
+        assert_eq!(line("Decompressing", 10).as_deref(), Some("Pipeline 73% \u{2014} Copying"), "the run doesn't go back");
+        assert_eq!(line("Verifying", 50), None);
+
+        // --verify reads the copy back as it goes, weighing 20 alongside it.
+        start_pipeline(&Options { progress_style: ProgressStyle::Pipeline, build_only: true, verify: true, ..Options::default() });
+        assert_eq!(line("Copying", 50).as_deref(), Some("Pipeline 58% \u{2014} Copying"));
+        assert_eq!(line("Verifying", 50).as_deref(), Some("Pipeline 68% \u{2014} Copying"));
+        assert_eq!(line("Copying", 100).as_deref(), Some("Pipeline 89% \u{2014} Copying"));
+        assert_eq!(line("Verifying", 100).as_deref(), Some("Pipeline 100% \u{2014} Copying"));
+
+        start_pipeline(&Options { progress_style: ProgressStyle::Both, build_only: true, ..Options::default() });
+        assert_eq!(line("Copying", 0).as_deref(), Some("Pipeline 47% | phase line"));
+        start_pipeline(&Options::default());
+        assert_eq!(line("Copying", 0), None);
+    }
+
+    #[test]
+    fn quiet_holds_for_an_output_too() {
+        struct Collect(Arc<Mutex<Vec<String>>>);
+        impl Output for Collect {
+            fn message(&mut self, _level: Level, msg: &str) {
+                self.0.lock().unwrap().push(msg.to_string());
+            }
+            fn progress(&mut self, progress: &Progress) {
+                self.0.lock().unwrap().push(progress.text.to_string());
+            }
+        }
+        let _logging = LOGGING.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let collected = Arc::new(Mutex::new(Vec::new()));
+        set_output(Some(Box::new(Collect(collected.clone()))));
+        let report = || {
+            debug("quiet test debug\n");
+            error("quiet test error\n");
+            progress(&Progress { label: "Quiet test", current: 1, total: 2, text: "quiet test progress" });
+        };
+        // Other tests log alongside, only this one's messages count.
+        let ours = |collected: &Arc<Mutex<Vec<String>>>| collected.lock().unwrap().iter().filter(|text| text.starts_with("quiet test")).count();
+        set_quiet(true);
+        report();
+        assert_eq!(ours(&collected), 0);
+        set_quiet(false);
+        report();
+        set_output(None);
+        assert_eq!(ours(&collected), 3);
+    }
+
+    #[test]
+    fn concurrent_messages_are_not_interleaved() {
+        const THREADS: usize = 16;
+        const LINES: usize = 50;
+        let _logging = LOGGING.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let captured = Captured::default();
+        set_sink(Some(Box::new(captured.clone())));
+        let handles: Vec<_> = (0..THREADS)
+            .map(|thread| {
+                std::thread::spawn(move || {
+                    for line in 0..LINES {
+                        info(format!("thread {} line {} end\n", thread, line).as_str());
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        set_sink(None);
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = output.lines().filter(|line| line.contains("[INFO]")).collect();
+        assert_eq!(lines.len(), THREADS * LINES);
+        for line in lines {
+            assert_eq!(line.matches("[INFO]").count(), 1, "interleaved line: {:?}", line);
+            // The tag is padded to line up with the longer ones.
+            let message = &line[line.find("[INFO]").unwrap()..];
+            let message = &message[message.find("thread ").unwrap()..];
+            assert!(message.contains(" line ") && message.contains(" end"), "interleaved line: {:?}", line);
+        }
+    }
+}
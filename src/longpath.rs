@@ -0,0 +1,137 @@
+//! FAT's limits on name and path length, checked for the whole source before anything is
+//! copied so every offending path is reported in one run instead of the copy failing on
+//! the first one halfway through.
+//!
+//! Long names are stored as UTF-16, so lengths are counted in UTF-16 units, not bytes.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use crate::dirsort::sorted_entries;
+use crate::error::UpdateError;
+use crate::image::{exclusion, FileFilter};
+use crate::options::OnLongPath;
+use crate::warn;
+
+/// The longest name a FAT long name entry can hold.
+pub const MAX_NAME_LEN: usize = 255;
+
+/// The longest path on the card, relative to its root. Windows' `MAX_PATH` of 260 less the
+/// `X:\` in front and the terminating NUL, which is also what most other readers assume.
+pub const MAX_PATH_LEN: usize = 255;
+
+/// What the copy does with a source path that is too long. `None` leaves it out, `Some`
+/// copies it under a shorter name.
+pub type LongPaths = HashMap<PathBuf, Option<String>>;
+
+fn utf16_len(value: &str) -> usize {
+    value.encode_utf16().count()
+}
+
+/// The first `max` UTF-16 units of `value`, without splitting a character.
+fn take_utf16(value: &str, max: usize) -> String {
+    let mut len = 0;
+    value
+        .chars()
+        .take_while(|c| {
+            len += c.len_utf16();
+            len <= max
+        })
+        .collect()
+}
+
+/// `name` shortened to at most `max` units, keeping a short extension and adding `~2`,
+/// `~3`... if the short name is already `taken` in the directory.
+fn truncate_name(name: &str, max: usize, taken: &HashSet<String>) -> Option<String> {
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() && utf16_len(extension) < 16 => (stem, format!(".{}", extension)),
+        _ => (name, String::new()),
+    };
+    for n in 1..1000 {
+        let suffix = if n == 1 { String::new() } else { format!("~{}", n) };
+        let budget = max.checked_sub(utf16_len(&extension) + suffix.len()).filter(|budget| *budget > 0)?;
+        let candidate = format!("{}{}{}", take_utf16(stem, budget), suffix, extension);
+        if !taken.contains(&candidate.to_lowercase()) {
+            return Some(candidate);
+        }
+    }
+    None
+}
+
+fn check_dir(
+    host_path: &Path,
+    prefix: &str,
+    policy: OnLongPath,
+    exclude: &[PathBuf],
+    filter: &FileFilter,
+    long_paths: &mut LongPaths,
+    offending: &mut usize,
+) -> Result<(), UpdateError> {
+    let paths = sorted_entries(host_path)?.collect::<Result<Vec<_>, _>>()?;
+    let mut taken: HashSet<String> = paths
+        .iter()
+        .map(|path| path.file_name().unwrap().to_string_lossy().to_lowercase())
+        .collect();
+    for path in paths {
+        if exclusion(&path, exclude, long_paths, filter)?.is_some() {
+            continue;
+        }
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        let card_path = format!("{}{}", prefix, name);
+        let mut dest_name = name.clone();
+        if utf16_len(&name) > MAX_NAME_LEN || utf16_len(&card_path) > MAX_PATH_LEN {
+            *offending += 1;
+            match policy {
+                OnLongPath::Error => {
+                    warn(format!("Too long for FAT ({} characters): {}\n", utf16_len(&card_path), card_path).as_str());
+                }
+                OnLongPath::Skip | OnLongPath::Truncate => {
+                    let max = MAX_NAME_LEN.min(MAX_PATH_LEN.saturating_sub(utf16_len(prefix)));
+                    let shorter = match policy {
+                        OnLongPath::Truncate => truncate_name(&name, max, &taken),
+                        _ => None,
+                    };
+                    match &shorter {
+                        Some(shorter) => {
+                            warn(format!("Too long for FAT, copying as {}{}: {}\n", prefix, shorter, card_path).as_str());
+                            taken.insert(shorter.to_lowercase());
+                            dest_name = shorter.clone();
+                        }
+                        // With truncate, even the shortest name can't fit below a directory
+                        // path this long.
+                        None => warn(format!("Too long for FAT, leaving out: {}\n", card_path).as_str()),
+                    }
+                    let left_out = shorter.is_none();
+                    long_paths.insert(path.clone(), shorter);
+                    if left_out {
+                        continue;
+                    }
+                }
+            }
+        }
+        if std::fs::metadata(&path)?.is_dir() {
+            check_dir(&path, &format!("{}{}/", prefix, dest_name), policy, exclude, filter, long_paths, offending)?;
+        }
+    }
+    Ok(())
+}
+
+/// Checks every path under `roots`, copied to `prefix` on the card, against the FAT limits
+/// and works out what `--on-longpath` does with the ones that don't fit. What the copy
+/// leaves out, by `exclude` or `filter`, isn't checked. With `error`, fails after listing
+/// all of them.
+pub fn check(roots: &[&Path], exclude: &[PathBuf], filter: &FileFilter, prefix: &str, policy: OnLongPath) -> Result<LongPaths, UpdateError> {
+    let mut long_paths = LongPaths::new();
+    let mut offending = 0;
+    for root in roots {
+        check_dir(root, prefix, policy, exclude, filter, &mut long_paths, &mut offending)?;
+    }
+    if offending > 0 && policy == OnLongPath::Error {
+        return Err(UpdateError::Other(format!(
+            "{} paths are too long for FAT (at most {} characters per name and {} per path), see above. \
+             Shorten them, or run with --on-longpath=skip or --on-longpath=truncate",
+            offending, MAX_NAME_LEN, MAX_PATH_LEN
+        )));
+    }
+    Ok(long_paths)
+}
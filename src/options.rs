@@ -0,0 +1,1314 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::codepage::CodePage;
+use crate::config::{Config, Setting, CONFIG_FILE};
+use crate::policy::{self, Action, Condition, Policy};
+use crate::units::format_bytes;
+
+/// Flags that don't take a value. Everything else expects one, either as
+/// `--flag value` or `--flag=value`.
+const SWITCHES: &[&str] = &["force", "force-reclone", "allow-unrelated-histories", "report-dupes", "fetch-only", "build-only", "check", "verbose", "incremental", "partitioned", "quiet", "wipe-free", "keep-going", "skip-asset-check", "assume-yes", "compare", "wait", "verify", "list-changes", "self-update", "bench", "offline", "format", "self-test", "follow-default-branch", "verify-only", "strict-head", "commit-range", "list-excluded", "preserve-attrs", "allow-rewrite", "reset-merge-state", "no-sync", "strict-sync", "no-progress", "require-signed-commit", "dump-config", "auto-repair", "no-tags", "all-tags", "health-check", "preserve-created", "auto-buffer", "skip-empty-files", "lock-output", "no-parallel-scan", "repair-image"];
+
+/// The smallest buffer `max_memory` can shrink the copy and decompression buffers to.
+/// A multiple of 4096 so writes to a device stay sector aligned.
+pub const MIN_BUFFER_SIZE: u64 = 64 * 1024;
+
+/// What an incremental copy does with a file whose copy on the card is newer than the source.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnNewer {
+    /// The source is authoritative, replace the card's copy.
+    #[default]
+    Overwrite,
+    /// Keep the card's copy.
+    Skip,
+    /// Stop the build.
+    Error,
+}
+
+/// What the copy does with a source path too long for FAT, see `longpath.rs`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnLongPath {
+    /// List every path that is too long and stop before copying anything.
+    #[default]
+    Error,
+    /// Leave them out, with a warning for each.
+    Skip,
+    /// Copy them under a shortened name.
+    Truncate,
+}
+
+/// What the build does when `sd_source` isn't the commit the last update left it at, see
+/// `git::check_source_matches`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnSourceMismatch {
+    /// Build from the checkout as it is.
+    #[default]
+    Ignore,
+    /// Build anyway, with a warning that says what differs.
+    Warn,
+    /// Stop before anything is written.
+    Error,
+}
+
+/// What the update does when `sd_source` is there but isn't a git checkout, like a copy of
+/// the MNN Build extracted there by hand.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnNotARepo {
+    /// Stop and say what the choices are.
+    #[default]
+    Error,
+    /// Build from its files as they are, without git.
+    Build,
+    /// Move it aside and download the MNN Build again.
+    Reclone,
+}
+
+/// How a fast forward updates the files in `sd_source`, from `--checkout`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CheckoutMode {
+    /// Overwrite whatever is in the working tree, local edits included.
+    #[default]
+    Force,
+    /// Stop instead of overwriting files that were changed locally.
+    Safe,
+}
+
+/// How `--dump-config` prints the settings, and `--health-check` its report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpFormat {
+    Text,
+    Json,
+}
+
+/// A setting as it was applied, for `--dump-config`.
+#[derive(Debug, Clone)]
+pub struct Applied {
+    pub name: String,
+    /// `None` for a switch given without a value.
+    pub value: Option<String>,
+    /// `command line`, `updater.conf line 3` or `profile minimal, updater.conf line 9`.
+    pub source: String,
+}
+
+impl Applied {
+    fn from_cli(name: &str, value: Option<String>) -> Applied {
+        Applied { name: name.to_string(), value, source: "command line".to_string() }
+    }
+}
+
+/// What the build does when several names in a directory share a FAT short name, see
+/// `shortname.rs`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnShortNameCollision {
+    /// Let fatfs number them, and say how many there are.
+    #[default]
+    Number,
+    /// List every one of them and stop before copying anything.
+    Error,
+}
+
+/// Whether log output is colored, from `--color`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// When stdout is a terminal and `NO_COLOR` isn't set.
+    #[default]
+    Auto,
+    /// Even when piped, for pagers and CI logs that understand ANSI codes.
+    Always,
+    Never,
+}
+
+/// What the progress lines show, from `--progress-style`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProgressStyle {
+    /// A line per phase, with its own percentage, rate and ETA.
+    #[default]
+    Phases,
+    /// One percentage for the whole run, see `progress::start_pipeline`.
+    Pipeline,
+    /// The run's percentage in front of each phase's line.
+    Both,
+}
+
+/// What `--image-hash` hashes, see `imagehash.rs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageHash {
+    /// Every byte of the image.
+    Raw,
+    /// The files on it, independent of how they are laid out.
+    Tree,
+}
+
+/// What the image at `--output` is written as, from `--output-format`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// The bare FAT image, or the partitioned disk with `--partitioned`.
+    #[default]
+    Raw,
+    /// A fixed VHD around it, see `vhd.rs`.
+    Vhd,
+}
+
+/// The codec `--compress-output` compresses the finished image with, see `compress.rs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Xz,
+    Zstd,
+    Gzip,
+}
+
+impl Compression {
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::Xz => "xz",
+            Compression::Zstd => "zstd",
+            Compression::Gzip => "gzip",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::Xz => "xz",
+            Compression::Zstd => "zst",
+            Compression::Gzip => "gz",
+        }
+    }
+
+    /// The levels the codec accepts, lowest first.
+    pub fn levels(self) -> std::ops::RangeInclusive<u32> {
+        match self {
+            Compression::Xz | Compression::Gzip => 0..=9,
+            Compression::Zstd => 1..=22,
+        }
+    }
+
+    fn default_level(self) -> u32 {
+        match self {
+            Compression::Xz | Compression::Gzip => 6,
+            Compression::Zstd => 3,
+        }
+    }
+}
+
+/// The algorithm `--hash` picks for content hashes, see `hash.rs`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    /// Fastest, but not cryptographic. Fine for catching corruption.
+    Xxh3,
+    /// Fast and cryptographic.
+    #[default]
+    Blake3,
+    /// Slower, for comparing against hashes from tools that only know SHA-256.
+    Sha256,
+}
+
+impl HashAlgorithm {
+    pub fn from_name(name: &str) -> Option<HashAlgorithm> {
+        match name {
+            "xxh3" => Some(HashAlgorithm::Xxh3),
+            "blake3" => Some(HashAlgorithm::Blake3),
+            "sha256" => Some(HashAlgorithm::Sha256),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Xxh3 => "xxh3",
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Sha256 => "sha256",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Options {
+    /// Skip the upstream check if the last successful one is more recent than this.
+    pub min_interval: Option<Duration>,
+    /// Keep running and check upstream this often, see `watch.rs`.
+    pub watch: Option<Duration>,
+    /// Check upstream regardless of `min_interval`.
+    pub force: bool,
+    /// Delete `sd_source` and clone it again from scratch.
+    pub force_reclone: bool,
+    /// Clone `sd_source` again when its git data turns out to be damaged, instead of failing.
+    /// Network errors never do this.
+    pub auto_repair: bool,
+    /// Merge upstream even if it shares no history with the local checkout.
+    pub allow_unrelated_histories: bool,
+    /// Discard a merge or other git operation `sd_source` was left in the middle of, instead
+    /// of stopping.
+    pub reset_merge_state: bool,
+    /// Take upstream's new history when it was force-pushed or rebased since the last pull,
+    /// instead of stopping.
+    pub allow_rewrite: bool,
+    /// Whether a fast forward may overwrite local edits in `sd_source`.
+    pub checkout: CheckoutMode,
+    /// Update `sd_source` but don't build the image.
+    pub fetch_only: bool,
+    /// Build the image from the current checkout without touching upstream.
+    pub build_only: bool,
+    /// Check that `sd.xz` is intact by decompressing it without writing anything.
+    pub self_test: bool,
+    /// Build from the existing `sd_source` checkout without any network access.
+    pub offline: bool,
+    /// Only report whether upstream has changes, without updating anything.
+    pub check: bool,
+    /// Report how the image differs from the source instead of updating anything.
+    pub compare: bool,
+    /// Check the existing image or `--dest-dir` against the source without rebuilding.
+    pub verify_only: bool,
+    /// Like `verify_only`, but only check this percentage of the files, picked at random.
+    pub sample_verify: Option<f64>,
+    /// Seed for picking the `sample_verify` files, to check the same ones again.
+    pub sample_seed: Option<u64>,
+    /// Report how the files on the image differ from those on this other image, instead of
+    /// updating anything.
+    pub diff_image: Option<PathBuf>,
+    /// Pack this directory into the image instead of the MNN Build, see `pack.rs`.
+    pub pack: Option<PathBuf>,
+    /// List what the build leaves out of the source and why, without copying anything,
+    /// see `excluded.rs`.
+    pub list_excluded: bool,
+    /// Log why the build copies, skips or removes the paths matching this glob, without
+    /// copying anything, see `explain.rs`.
+    pub explain: Option<String>,
+    /// Recover what can be read from a damaged image at `--output` and build a fresh one
+    /// from it, see `repair.rs`.
+    pub repair_image: bool,
+    /// If another updater is running, wait for it to finish instead of exiting.
+    pub wait: bool,
+    /// After pulling changes, list the paths and commits that came in.
+    pub list_changes: bool,
+    /// After pulling, list the commits since the one the last check recorded, see
+    /// `UpdateState::last_commit`.
+    pub commit_range: bool,
+    /// Replace the updater binary with its latest release instead of updating anything
+    /// else, see `selfupdate.rs`.
+    pub self_update: bool,
+    /// Time the decompression and the copy on generated fixtures instead of updating
+    /// anything, see `bench.rs`.
+    pub bench: bool,
+    /// How many files `bench` generates. Default 2000.
+    pub bench_files: Option<u64>,
+    /// Size of the largest file `bench` generates. Default 4M.
+    pub bench_file_size: Option<u64>,
+    /// Release JSON `self_update` checks. Default the GitHub releases API of the updater.
+    pub self_update_url: Option<String>,
+    /// Show raw error details.
+    pub verbose: bool,
+    /// Prefix for log messages, see `logging::set_log_format`.
+    pub log_format: Option<String>,
+    /// Whether to color log output.
+    pub color: ColorChoice,
+    /// Don't draw progress lines, only log the phases. Also the case when stdout isn't a
+    /// terminal.
+    pub no_progress: bool,
+    /// Whether progress lines show each phase, the whole run, or both.
+    pub progress_style: ProgressStyle,
+    /// Print nothing but a single JSON summary of the run at the end.
+    pub quiet: bool,
+    /// Also write the JSON summary `quiet` prints to this file, whatever the output mode
+    /// and whether or not the run succeeded.
+    pub summary_json_file: Option<PathBuf>,
+    /// Write a line of JSON for every file the copy touches to this file, see `copylog.rs`.
+    pub copy_log: Option<PathBuf>,
+    /// Hash files while copying and report byte-identical duplicates afterwards.
+    pub report_dupes: bool,
+    /// How many times the whole update and build is run again after failing with a
+    /// recoverable error, like a network failure. Default 0.
+    pub max_pipeline_retries: Option<u32>,
+    /// How many times a failed write to `sd.raw` is retried before giving up. Default 5.
+    pub write_retries: Option<u32>,
+    /// Bytes per second to hold writes to the card under, see `throttle.rs`.
+    pub write_throttle: Option<u64>,
+    /// Repository to clone the MNN Build from.
+    pub url: Option<String>,
+    /// Hosts `url` may point at, compared ignoring case. Anything else is refused before
+    /// connecting. Empty allows any.
+    pub allowed_hosts: Vec<String>,
+    /// Branch of `url` to follow. Default `main`.
+    pub branch: Option<String>,
+    /// Switch to upstream's default branch when the followed branch is gone from it, e.g.
+    /// after a rename. Without it the run fails, listing the branches there are.
+    pub follow_default_branch: bool,
+    /// Fail on a detached HEAD in `sd_source` instead of checking the branch out again.
+    pub strict_head: bool,
+    /// What to do when `sd_source` has files changed by hand or HEAD was moved since the last
+    /// update.
+    pub on_source_mismatch: OnSourceMismatch,
+    /// What to do with an `sd_source` that isn't a git checkout.
+    pub on_not_a_repo: OnNotARepo,
+    /// Track this release tag instead of the tip of the branch.
+    pub tag: Option<String>,
+    /// Fetch no tags at all. By default only the tags on the fetched commits come along.
+    pub no_tags: bool,
+    /// Fetch every tag upstream has, like `--tag` does.
+    pub all_tags: bool,
+    /// Who merge commits in `sd_source` are made by, as `Name <email>`. Defaults to the
+    /// git config, and to a built-in name if that has none.
+    pub merge_signature: Option<(String, String)>,
+    /// Hex Ed25519 public key the source's `MANIFEST` has to be signed with before it is
+    /// built, see `signature.rs`.
+    pub require_signature: Option<String>,
+    /// Write what built the card to this path on it, see `buildinfo.rs`.
+    pub include_manifest_in_image: Option<String>,
+    /// Refuse to build unless the commit `sd_source` is at is signed by one of the trusted
+    /// keys below, see `commitsig.rs`.
+    pub require_signed_commit: bool,
+    /// Public keys trusted to sign commits with OpenPGP, as exported by `gpg --export`.
+    pub trusted_gpg_keyring: Option<PathBuf>,
+    /// SSH keys trusted to sign commits, in the `allowed_signers` format.
+    pub trusted_ssh_signers: Option<PathBuf>,
+    /// Keep a bare mirror of upstream here and clone/pull `sd_source` from it. The mirror
+    /// holds the full history of every branch, so it costs about as much disk as the
+    /// `.git` folder of `sd_source`, but any number of checkouts can share it.
+    pub mirror_dir: Option<PathBuf>,
+    /// Where to find `sd.xz`. Unset means `assets`, or the embedded copy if there is one.
+    pub assets_dir: Option<PathBuf>,
+    /// Download `sd.xz` from here into the assets directory when it isn't there or is out
+    /// of date, see `imageurl.rs`.
+    pub image_url: Option<String>,
+    /// Bytes per second the `image_url` download is held to.
+    pub download_limit: Option<u64>,
+    /// Update an existing `sd.raw` in place, only copying files whose size or modification
+    /// time differs, instead of decompressing a fresh image. Still starts over when `sd.xz`
+    /// changed since the image was built.
+    pub incremental: bool,
+    /// What `incremental` does with files that were changed on the card after the build.
+    pub on_newer: OnNewer,
+    /// Where to write the image file. Default `sd.raw`.
+    pub output: Option<PathBuf>,
+    /// Write straight to this block device, e.g. `/dev/sdX` or `\\.\PhysicalDrive2`, instead
+    /// of to an image file. Everything on it is overwritten, so it needs `assume_yes`.
+    pub device: Option<PathBuf>,
+    /// Copy the build onto a card mounted at this directory instead of building an image.
+    /// Always updates in place like `incremental`; nothing from `sd.xz` is used.
+    pub dest_dir: Option<PathBuf>,
+    /// Confirms destructive options like `device` up front, since there's nobody to ask.
+    pub assume_yes: bool,
+    /// OEM code page for FAT short names, see `codepage.rs`.
+    pub code_page: CodePage,
+    /// Zero the free clusters after copying, so leftovers of the base image don't end up
+    /// in a recompressed copy of it.
+    pub wipe_free: bool,
+    /// Format a blank filesystem of `sd_size` instead of decompressing `sd.xz`.
+    pub format: bool,
+    /// FAT type `format` creates. Unset lets fatfs pick from the size.
+    pub fat_type: Option<fatfs::FatType>,
+    /// Cluster size of filesystems the updater formats itself, see `format.rs`. Unset lets
+    /// fatfs pick one from the volume size.
+    pub cluster_size: Option<u64>,
+    /// Size of the image `sd.xz` decompresses to. Default 2G.
+    pub sd_size: Option<u64>,
+    /// Only copy this directory of `sd_source`, relative to it, with its contents at the
+    /// root of the card.
+    pub source_subdir: Option<PathBuf>,
+    /// Copy the source under this directory of the card instead of its root, like
+    /// `apps/mnn`. `--add-file` and `--preserve` paths stay relative to the root.
+    pub target_dir: Option<String>,
+    /// Paths of the source, relative to what is copied, that are left out. Only set by the
+    /// source's own manifest, see `packaging.rs`.
+    pub exclude: Vec<String>,
+    /// Directories created on the card even when the source has nothing in them, relative
+    /// to its root. Only set by the source's own manifest.
+    pub empty_dirs: Vec<String>,
+    /// Directories copied over the source after it, replacing files at the same path.
+    /// Given more than once, later overlays win over earlier ones.
+    pub overlays: Vec<PathBuf>,
+    /// `--add-file <host-path>:<image-path>`, single files copied to a path on the card
+    /// after the source and the overlays, replacing what is there.
+    pub add_files: Vec<(PathBuf, String)>,
+    /// Directories of the existing image, relative to its root, that a full rebuild copies
+    /// back onto the fresh image. Unset means `preserve::DEFAULT_PRESERVE`, `none` clears it.
+    pub preserve: Option<Vec<String>>,
+    /// Read every file back from the card after copying it and check it against the hash
+    /// of the source taken during the copy.
+    pub verify: bool,
+    /// Give files on the image the read-only, hidden and system attributes of their
+    /// source, see `attrs.rs`.
+    pub preserve_attrs: bool,
+    /// Stamp files on the image with the creation time of their source, where the host
+    /// keeps one. Without it, or without one, they are created at their modification time.
+    pub preserve_created: bool,
+    /// Don't sync the image to disk, for backing stores where it's slow or broken.
+    pub no_sync: bool,
+    /// Fail when the filesystem can't sync the image, instead of warning, see `sync.rs`.
+    pub strict_sync: bool,
+    /// Scan the source after sd.xz is decompressed instead of while it is, for a source on
+    /// the same spinning disk as the image, where the two would fight over the heads.
+    pub no_parallel_scan: bool,
+    /// Leave out source files larger than this, with a warning for each.
+    pub exclude_larger_than: Option<u64>,
+    /// Copy only files with these extensions, lowercased and without the dot. Empty copies
+    /// every file.
+    pub include_ext: Vec<String>,
+    /// Leave out files with these extensions.
+    pub exclude_ext: Vec<String>,
+    /// Leave zero-byte files out of the copy. Their directories are still created.
+    pub skip_empty_files: bool,
+    /// Copy only files modified less than this long ago, by their modification time in the
+    /// source. A copy on the card from an earlier build stays there once a file ages out.
+    pub newer_than: Option<Duration>,
+    /// Copy only files modified more than this long ago.
+    pub older_than: Option<Duration>,
+    /// Fail the walk of the source before the copy once it comes across more entries than
+    /// this.
+    pub walk_max_entries: Option<u64>,
+    /// Fail the walk of the source before the copy if it takes longer than this.
+    pub walk_timeout: Option<Duration>,
+    /// What to do with source paths too long for FAT.
+    pub on_longpath: OnLongPath,
+    /// `--policy`, for the conditions without an option of their own.
+    pub policy: Policy,
+    /// What to do with names that share a FAT short name.
+    pub on_short_name_collision: OnShortNameCollision,
+    /// FAT volume label of the image, checked and upper-cased. Unset means the short commit
+    /// hash, empty keeps the label of the base image, see `label.rs`.
+    pub volume_label: Option<String>,
+    /// Report a hash of the finished build, and put it in the JSON summary.
+    pub image_hash: Option<ImageHash>,
+    /// Algorithm for `verify`, `compare`, `verify_only`, `report_dupes` and `image_hash`.
+    pub hash: HashAlgorithm,
+    /// Also write a compressed copy of the finished image next to it.
+    pub compress_output: Option<Compression>,
+    /// What the image is written as, raw or a VHD that mounts as a virtual disk.
+    pub output_format: OutputFormat,
+    /// Level for `compress_output`, the codec's usual default if unset.
+    pub compress_level: Option<u32>,
+    /// Log files that fail to copy and carry on with the rest, failing at the end.
+    pub keep_going: bool,
+    /// Don't check that `sd.xz` is intact before decompressing it.
+    pub skip_asset_check: bool,
+    /// Command run before the image is decompressed, see `hooks.rs`.
+    pub pre_build_hook: Option<String>,
+    /// Command run after the image is built successfully, see `hooks.rs`.
+    pub post_build_hook: Option<String>,
+    /// Make the image at `--output` read-only once it is built, see `outputlock.rs`.
+    pub lock_output: bool,
+    /// Upper bound for the I/O buffers of the copy and the decompression, for machines
+    /// with little RAM. Unset means the built-in sizes, 8M for copying and 1M for
+    /// decompressing.
+    pub max_memory: Option<u64>,
+    /// Use a copy buffer of this size instead of the built-in 8M or `auto_buffer`.
+    pub copy_buffer: Option<u64>,
+    /// Buffer between fatfs and the image or device, which gathers its sector and cluster
+    /// sized writes into fewer, larger ones. Unset means 512 bytes for an image and 64K for
+    /// a device. `--bench` with and without it compares the two.
+    pub fs_buffer: Option<u64>,
+    /// Pick the copy and decompression buffer sizes by trying some, see `buffertune.rs`.
+    pub auto_buffer: bool,
+    /// The smallest and largest sizes `auto_buffer` tries. Unset means 64K to 16M.
+    pub buffer_range: Option<(u64, u64)>,
+    /// Read source files at least this large through a memory map instead of the copy
+    /// buffer. Off by default until it has seen more use.
+    pub mmap_threshold: Option<u64>,
+    /// Scratch space for `--preserve`, `--bench` and files renamed into place, instead of
+    /// the system temp directory. Best on the same filesystem as the output, see `scratch.rs`.
+    pub tmpdir: Option<PathBuf>,
+    /// Sort the entries of larger source directories in scratch space instead of in
+    /// memory, see `dirsort.rs`.
+    pub dir_spill_threshold: Option<usize>,
+    /// Put the filesystem in an MBR partition instead of writing a bare FAT image.
+    pub partitioned: bool,
+    /// Print the effective settings and where they came from instead of running, see
+    /// `dumpconfig.rs`.
+    pub dump_config: Option<DumpFormat>,
+    /// Check that a run could work instead of running, see `healthcheck.rs`.
+    pub health_check: Option<DumpFormat>,
+    /// Every setting in the order it was applied, for `dump_config`.
+    pub applied: Vec<Applied>,
+    /// More images built in the same run, each the settings in `TARGET_KEYS` it changes
+    /// from these. See `for_target`.
+    pub targets: Vec<Vec<(String, Option<String>)>>,
+}
+
+impl Options {
+    pub fn from_args() -> Result<Options, String> {
+        Options::parse(std::env::args().skip(1))
+    }
+
+    /// Settings are applied in order of precedence: the config file, then the selected
+    /// profile, then the command line.
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+        let mut cli = Vec::new();
+        let mut config_path = None;
+        let mut profile = None;
+        while let Some(arg) = args.next() {
+            let flag = match arg.strip_prefix("--") {
+                Some(flag) => flag,
+                None => {
+                    let command = command(&arg)?;
+                    // `pack <source-dir> <output-image>` is `--pack <source-dir> --output <output-image>`.
+                    if command == "pack" {
+                        let mut path = || args.next().ok_or_else(|| "pack expects <source-dir> <output-image>".to_string());
+                        let (source, output) = (path()?, path()?);
+                        cli.push((command.to_string(), Some(source)));
+                        cli.push(("output".to_string(), Some(output)));
+                    } else {
+                        cli.push((command.to_string(), None));
+                    }
+                    continue;
+                }
+            };
+            let (name, inline_value) = match flag.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (flag, None),
+            };
+            let value = if SWITCHES.contains(&name) {
+                inline_value
+            } else {
+                match inline_value {
+                    Some(value) => Some(value),
+                    None => Some(
+                        args.next()
+                            .ok_or_else(|| format!("--{} expects a value", name))?,
+                    ),
+                }
+            };
+            match name {
+                "config" => config_path = value.map(PathBuf::from),
+                "profile" => profile = value,
+                _ => cli.push((name.to_string(), value)),
+            }
+        }
+
+        let explicit = config_path.is_some();
+        let config_path = config_path.unwrap_or_else(|| PathBuf::from(CONFIG_FILE));
+        let config = Config::load(&config_path, explicit)?;
+        let mut options = Options::default();
+        if explicit {
+            options.applied.push(Applied::from_cli("config", Some(config_path.display().to_string())));
+        }
+        if let Some(profile) = &profile {
+            options.applied.push(Applied::from_cli("profile", Some(profile.clone())));
+        }
+        options.apply(&config_path, &config.settings, None)?;
+        if let Some(profile) = &profile {
+            options.apply(&config_path, config.profile(profile)?, Some(profile))?;
+        }
+        for (name, value) in cli {
+            options.set(&name, value.clone())?;
+            options.applied.push(Applied::from_cli(&name, value));
+        }
+        if options.fetch_only && options.build_only {
+            return Err("--fetch-only and --build-only can't be combined".to_string());
+        }
+        if options.offline && (options.fetch_only || options.check || options.self_update) {
+            return Err("--offline can't be combined with --fetch-only, --check or --self-update".to_string());
+        }
+        if options.watch.is_some()
+            && (options.fetch_only || options.build_only || options.offline || options.check || options.compare || options.verify_only || options.sample_verify.is_some() || options.diff_image.is_some() || options.list_excluded || options.explain.is_some() || options.repair_image || options.self_update || options.bench || options.self_test)
+        {
+            return Err("--watch keeps updating and building, it can't be combined with another mode".to_string());
+        }
+        if options.pack.is_some()
+            && (options.fetch_only || options.build_only || options.offline || options.check || options.compare || options.verify_only || options.sample_verify.is_some() || options.diff_image.is_some() || options.list_excluded || options.explain.is_some() || options.repair_image || options.self_update || options.bench || options.self_test || options.watch.is_some())
+        {
+            return Err("pack builds an image from a directory, it can't be combined with another mode".to_string());
+        }
+        // There's no sd.xz for an arbitrary directory unless one is pointed at.
+        if options.pack.is_some() && options.assets_dir.is_none() && options.dest_dir.is_none() {
+            options.format = true;
+        }
+        if options.verify_only && options.sample_verify.is_some() {
+            return Err("--sample-verify checks a sample instead of every file, it can't be combined with verify".to_string());
+        }
+        if options.sample_verify.is_some_and(|percent| percent.is_nan() || percent <= 0.0 || percent > 100.0) {
+            return Err("--sample-verify expects a percentage above 0 and at most 100".to_string());
+        }
+        if options.sample_seed.is_some() && options.sample_verify.is_none() {
+            return Err("--sample-seed only applies with --sample-verify".to_string());
+        }
+        if options.watch.is_some_and(|interval| interval.is_zero()) {
+            return Err("--watch needs an interval of at least a second".to_string());
+        }
+        if let Some(max_memory) = options.max_memory.filter(|max_memory| *max_memory < MIN_BUFFER_SIZE) {
+            return Err(format!(
+                "--max-memory {} is too small, the copy needs at least one {} buffer",
+                max_memory,
+                format_bytes(MIN_BUFFER_SIZE)
+            ));
+        }
+        if let Some(copy_buffer) = options.copy_buffer.filter(|copy_buffer| *copy_buffer < MIN_BUFFER_SIZE) {
+            return Err(format!("--copy-buffer {} is too small, it needs at least {}", copy_buffer, format_bytes(MIN_BUFFER_SIZE)));
+        }
+        // Whole sectors, so the writes stay aligned on a device.
+        if let Some(fs_buffer) = options.fs_buffer.filter(|fs_buffer| *fs_buffer == 0 || !fs_buffer.is_multiple_of(4096)) {
+            return Err(format!("--fs-buffer {} has to be a multiple of 4K, like 64K or 1M", fs_buffer));
+        }
+        if options.buffer_range.is_some() && !options.auto_buffer {
+            return Err("--buffer-range only applies with --auto-buffer".to_string());
+        }
+        if options.auto_buffer && options.write_throttle.is_some() {
+            return Err("--auto-buffer can't tell buffer sizes apart under --write-throttle".to_string());
+        }
+        if options.walk_max_entries == Some(0) || options.walk_timeout.is_some_and(|timeout| timeout.is_zero()) {
+            return Err("--walk-max-entries and --walk-timeout need to be above 0".to_string());
+        }
+        if options.dir_spill_threshold == Some(0) {
+            return Err("--dir-spill-threshold needs at least 1 entry per run".to_string());
+        }
+        if options.dest_dir.is_some() && (options.output.is_some() || options.device.is_some()) {
+            return Err("--dest-dir can't be combined with --output or --device".to_string());
+        }
+        if options.dest_dir.is_some() && (options.partitioned || options.wipe_free || options.preserve_attrs || options.preserve_created) {
+            return Err("--partitioned, --wipe-free, --preserve-attrs and --preserve-created only apply to images, not --dest-dir".to_string());
+        }
+        if options.dest_dir.is_some() && options.volume_label.is_some() {
+            return Err("--volume-label only applies to images, a --dest-dir card keeps the label it was formatted with".to_string());
+        }
+        if options.no_tags && (options.all_tags || options.tag.is_some()) {
+            return Err("--no-tags can't be combined with --all-tags or --tag, which needs the tags".to_string());
+        }
+        if options.no_sync && options.strict_sync {
+            return Err("--no-sync and --strict-sync can't be combined".to_string());
+        }
+        if let Some(path) = &options.include_manifest_in_image {
+            if path.is_empty() || path.split('/').any(|part| part.is_empty() || part == "." || part == "..") {
+                return Err(format!("--include-manifest-in-image expects a file path on the card like updater/build.json, got '{}'", path));
+            }
+        }
+        let trusted_keys = options.trusted_gpg_keyring.is_some() || options.trusted_ssh_signers.is_some();
+        if options.require_signed_commit && !trusted_keys {
+            return Err("--require-signed-commit needs --trusted-gpg-keyring or --trusted-ssh-signers to say whose signatures to trust".to_string());
+        }
+        if trusted_keys && !options.require_signed_commit {
+            return Err("--trusted-gpg-keyring and --trusted-ssh-signers only apply with --require-signed-commit".to_string());
+        }
+        if options.diff_image.is_some() && options.dest_dir.is_some() {
+            return Err("--diff-image compares two images, it can't be combined with --dest-dir".to_string());
+        }
+        if options.compress_output.is_some() && (options.dest_dir.is_some() || options.device.is_some()) {
+            return Err("--compress-output needs an image at --output, not --dest-dir or --device".to_string());
+        }
+        if options.output_format == OutputFormat::Vhd && (options.dest_dir.is_some() || options.device.is_some()) {
+            return Err("--output-format vhd needs an image at --output, not --dest-dir or --device".to_string());
+        }
+        check_age_window(&options)?;
+        if options.repair_image && (options.dest_dir.is_some() || options.device.is_some()) {
+            return Err("--repair-image recovers an image at --output, not --dest-dir or --device".to_string());
+        }
+        if options.lock_output && (options.dest_dir.is_some() || options.device.is_some()) {
+            return Err("--lock-output needs an image at --output, not --dest-dir or --device".to_string());
+        }
+        match (options.compress_output, options.compress_level) {
+            (None, Some(_)) => return Err("--compress-level only applies with --compress-output".to_string()),
+            (Some(compression), Some(level)) if !compression.levels().contains(&level) => {
+                return Err(format!(
+                    "--compress-level {} is out of range for {}, which takes {} to {}",
+                    level,
+                    compression.name(),
+                    compression.levels().start(),
+                    compression.levels().end()
+                ));
+            }
+            _ => {}
+        }
+        if options.dest_dir.is_some() && options.image_hash == Some(ImageHash::Raw) {
+            return Err("--image-hash raw needs an image, use --image-hash tree with --dest-dir".to_string());
+        }
+        if options.format {
+            if options.dest_dir.is_some() {
+                return Err("--format only applies to images, not --dest-dir".to_string());
+            }
+            crate::format::check_size(options.sd_size(), &options)?;
+        }
+        if options.download_limit.is_some() && options.image_url.is_none() {
+            return Err("--download-limit only applies with --image-url".to_string());
+        }
+        if options.allowed_hosts.iter().any(|host| host.is_empty() || host.contains(['/', '@'])) {
+            return Err("--allowed-host takes a host name like github.com".to_string());
+        }
+        if !options.targets.is_empty() {
+            if options.dest_dir.is_some() || options.device.is_some() {
+                return Err("--target builds more images, it can't be combined with --dest-dir or --device".to_string());
+            }
+            let mut outputs = vec![options.output()];
+            for target in &options.targets {
+                let output = options.for_target(target)?.output();
+                if outputs.contains(&output) {
+                    return Err(format!("--target output={} is already built by this run", output.display()));
+                }
+                outputs.push(output);
+            }
+        }
+        if let Some(device) = &options.device {
+            if options.output.is_some() {
+                return Err("--device and --output can't be combined".to_string());
+            }
+            if !options.assume_yes {
+                return Err(format!(
+                    "--device overwrites everything on {}, add --assume-yes if that's really what you want",
+                    device.display()
+                ));
+            }
+        }
+        Ok(options)
+    }
+
+    fn apply(&mut self, config_path: &Path, settings: &[Setting], profile: Option<&str>) -> Result<(), String> {
+        for setting in settings {
+            let location = format!("{} line {}", config_path.display(), setting.line);
+            self.set(&setting.key, Some(setting.value.clone()))
+                .map_err(|e| format!("{}: {}", location, e))?;
+            self.applied.push(Applied {
+                name: setting.key.clone(),
+                value: Some(setting.value.clone()),
+                source: match profile {
+                    Some(profile) => format!("profile {}, {}", profile, location),
+                    None => location,
+                },
+            });
+        }
+        Ok(())
+    }
+
+    pub fn assets_dir(&self) -> PathBuf {
+        self.assets_dir.clone().unwrap_or_else(|| PathBuf::from("assets"))
+    }
+
+    pub fn url(&self) -> String {
+        self.url.clone().unwrap_or_else(|| "https://github.com/STulling/MNN_Build".to_string())
+    }
+
+    pub fn self_update_url(&self) -> String {
+        self.self_update_url
+            .clone()
+            .unwrap_or_else(|| crate::selfupdate::DEFAULT_RELEASES_URL.to_string())
+    }
+
+    pub fn branch(&self) -> String {
+        self.branch.clone().unwrap_or_else(|| "main".to_string())
+    }
+
+    /// Where the build goes: the `device` or `dest_dir` if there is one, otherwise the
+    /// image file.
+    pub fn output(&self) -> PathBuf {
+        self.device
+            .clone()
+            .or_else(|| self.dest_dir.clone())
+            .or_else(|| self.output.clone())
+            .unwrap_or_else(|| PathBuf::from("sd.raw"))
+    }
+
+    /// `--target-dir` with a trailing `/`, or nothing, to put in front of a source path to
+    /// get its path on the card.
+    pub fn target_prefix(&self) -> String {
+        self.target_dir.as_ref().map(|dir| format!("{}/", dir)).unwrap_or_default()
+    }
+
+    pub fn preserve(&self) -> Vec<String> {
+        match &self.preserve {
+            Some(preserve) => preserve.clone(),
+            None => crate::preserve::DEFAULT_PRESERVE.iter().map(|path| path.to_string()).collect(),
+        }
+    }
+
+    pub fn bench_files(&self) -> u64 {
+        self.bench_files.unwrap_or(2000)
+    }
+
+    pub fn bench_file_size(&self) -> u64 {
+        self.bench_file_size.unwrap_or(4 * 1024 * 1024)
+    }
+
+    pub fn compress_level(&self, compression: Compression) -> u32 {
+        self.compress_level.unwrap_or(compression.default_level())
+    }
+
+    pub fn sd_size(&self) -> u64 {
+        self.sd_size.unwrap_or(1024 * 1024 * 1024 * 2)
+    }
+
+    /// `wanted`, shrunk to fit `max_memory`. The copy and the decompression run one after
+    /// the other, so each one can have the whole budget.
+    pub fn buffer_size(&self, wanted: usize) -> usize {
+        match self.max_memory {
+            Some(max_memory) if (max_memory as usize) < wanted => max_memory as usize / 4096 * 4096,
+            _ => wanted,
+        }
+    }
+
+    /// The sizes `auto_buffer` tries between, shrunk to fit `max_memory`. `None` without it.
+    pub fn tune_range(&self) -> Option<(usize, usize)> {
+        if !self.auto_buffer {
+            return None;
+        }
+        let (min, max) = self.buffer_range.unwrap_or((MIN_BUFFER_SIZE, 16 * 1024 * 1024));
+        let max = self.buffer_size(max as usize) / 4096 * 4096;
+        Some(((min as usize / 4096 * 4096).min(max), max))
+    }
+
+    pub fn write_retries(&self) -> u32 {
+        self.write_retries.unwrap_or(5)
+    }
+
+    /// The options for one `--target` image: these, with the settings the target gives.
+    /// A target's extension lists replace the ones given here instead of adding to them.
+    pub fn for_target(&self, target: &[(String, Option<String>)]) -> Result<Options, String> {
+        let mut options = Options { targets: Vec::new(), ..self.clone() };
+        let gives = |name: &str| target.iter().any(|(key, _)| key == name);
+        if gives("include-ext") {
+            options.include_ext.clear();
+        }
+        if gives("exclude-ext") {
+            options.exclude_ext.clear();
+        }
+        for (key, value) in target {
+            options.set(key, value.clone()).map_err(|e| format!("--target: {}", e))?;
+        }
+        if options.format {
+            crate::format::check_size(options.sd_size(), &options)?;
+        }
+        check_age_window(&options).map_err(|e| format!("--target: {}", e))?;
+        Ok(options)
+    }
+
+    fn set(&mut self, name: &str, value: Option<String>) -> Result<(), String> {
+        match name {
+            "force" => self.force = parse_switch(name, value)?,
+            "force-reclone" => self.force_reclone = parse_switch(name, value)?,
+            "auto-repair" => self.auto_repair = parse_switch(name, value)?,
+            "allow-unrelated-histories" => self.allow_unrelated_histories = parse_switch(name, value)?,
+            "allow-rewrite" => self.allow_rewrite = parse_switch(name, value)?,
+            "checkout" => self.checkout = parse_checkout(name, &value.unwrap_or_default())?,
+            "reset-merge-state" => self.reset_merge_state = parse_switch(name, value)?,
+            "no-sync" => self.no_sync = parse_switch(name, value)?,
+            "no-parallel-scan" => self.no_parallel_scan = parse_switch(name, value)?,
+            "strict-sync" => self.strict_sync = parse_switch(name, value)?,
+            "fetch-only" => self.fetch_only = parse_switch(name, value)?,
+            "build-only" => self.build_only = parse_switch(name, value)?,
+            "check" => self.check = parse_switch(name, value)?,
+            "offline" => self.offline = parse_switch(name, value)?,
+            "self-test" => self.self_test = parse_switch(name, value)?,
+            "compare" => self.compare = parse_switch(name, value)?,
+            "verify-only" => self.verify_only = parse_switch(name, value)?,
+            "sample-verify" => self.sample_verify = Some(parse_number(name, value.unwrap_or_default().trim_end_matches('%'))?),
+            "sample-seed" => self.sample_seed = Some(parse_number(name, &value.unwrap_or_default())?),
+            "diff-image" => self.diff_image = value.map(PathBuf::from),
+            "pack" => self.pack = value.map(PathBuf::from),
+            "wait" => self.wait = parse_switch(name, value)?,
+            "list-changes" => self.list_changes = parse_switch(name, value)?,
+            "commit-range" => self.commit_range = parse_switch(name, value)?,
+            "list-excluded" => self.list_excluded = parse_switch(name, value)?,
+            "explain" => self.explain = value.filter(|pattern| !pattern.is_empty()),
+            "repair-image" => self.repair_image = parse_switch(name, value)?,
+            "preserve-attrs" => self.preserve_attrs = parse_switch(name, value)?,
+            "preserve-created" => self.preserve_created = parse_switch(name, value)?,
+            "self-update" => self.self_update = parse_switch(name, value)?,
+            "self-update-url" => self.self_update_url = value,
+            "bench" => self.bench = parse_switch(name, value)?,
+            "bench-files" => self.bench_files = Some(parse_number(name, &value.unwrap_or_default())?),
+            "bench-file-size" => self.bench_file_size = Some(parse_size(name, &value.unwrap_or_default())?),
+            "verbose" => self.verbose = parse_switch(name, value)?,
+            "log-format" => self.log_format = value,
+            "color" => self.color = parse_color(name, &value.unwrap_or_default())?,
+            "no-progress" => self.no_progress = parse_switch(name, value)?,
+            "progress-style" => self.progress_style = parse_progress_style(name, &value.unwrap_or_default())?,
+            "quiet" => self.quiet = parse_switch(name, value)?,
+            "summary-json-file" => self.summary_json_file = value.map(PathBuf::from),
+            "copy-log" => self.copy_log = value.map(PathBuf::from),
+            "incremental" => self.incremental = parse_switch(name, value)?,
+            "code-page" => {
+                let value = value.unwrap_or_default();
+                self.code_page = CodePage::parse(&value)
+                    .ok_or_else(|| format!("--{} expects 437, 850 or lossy, got '{}'", name, value))?;
+            }
+            "wipe-free" => self.wipe_free = parse_switch(name, value)?,
+            "format" => self.format = parse_switch(name, value)?,
+            "fat-type" => self.fat_type = crate::format::parse_fat_type(name, &value.unwrap_or_default())?,
+            "cluster-size" => {
+                let cluster_size = parse_size(name, &value.unwrap_or_default())?;
+                crate::format::check_cluster_size(cluster_size)?;
+                self.cluster_size = Some(cluster_size);
+            }
+            "sd-size" => self.sd_size = Some(parse_size(name, &value.unwrap_or_default())?),
+            "source-subdir" => {
+                let subdir = PathBuf::from(value.unwrap_or_default());
+                if subdir.is_absolute() || subdir.components().any(|part| part == std::path::Component::ParentDir) {
+                    return Err(format!("--{} expects a path inside sd_source, got '{}'", name, subdir.display()));
+                }
+                self.source_subdir = Some(subdir);
+            }
+            "target-dir" => {
+                let value = value.unwrap_or_default();
+                match card_path(value.trim().trim_end_matches(['/', '\\'])) {
+                    Some(path) => self.target_dir = Some(path),
+                    None => return Err(format!("--{} expects a directory on the card, like apps/mnn, got '{}'", name, value)),
+                }
+            }
+            "overlay" => self.overlays.extend(value.map(PathBuf::from)),
+            "add-file" => self.add_files.push(parse_add_file(name, &value.unwrap_or_default())?),
+            "preserve" => {
+                let value = value.unwrap_or_default();
+                let preserve = self.preserve.get_or_insert_with(Vec::new);
+                if value.trim() == "none" {
+                    preserve.clear();
+                } else {
+                    preserve.push(value);
+                }
+            }
+            "keep-going" => self.keep_going = parse_switch(name, value)?,
+            "include-ext" => self.include_ext.push(parse_extension(name, &value.unwrap_or_default())?),
+            "exclude-ext" => self.exclude_ext.push(parse_extension(name, &value.unwrap_or_default())?),
+            "skip-empty-files" => self.skip_empty_files = parse_switch(name, value)?,
+            "newer-than" => self.newer_than = Some(parse_duration(name, &value.unwrap_or_default())?),
+            "older-than" => self.older_than = Some(parse_duration(name, &value.unwrap_or_default())?),
+            "walk-max-entries" => self.walk_max_entries = Some(parse_number(name, &value.unwrap_or_default())?),
+            "walk-timeout" => self.walk_timeout = Some(parse_duration(name, &value.unwrap_or_default())?),
+            "exclude-larger-than" => self.exclude_larger_than = Some(parse_size(name, &value.unwrap_or_default())?),
+            "verify" => self.verify = parse_switch(name, value)?,
+            "skip-asset-check" => self.skip_asset_check = parse_switch(name, value)?,
+            "max-memory" => self.max_memory = Some(parse_size(name, &value.unwrap_or_default())?),
+            "copy-buffer" => self.copy_buffer = Some(parse_size(name, &value.unwrap_or_default())?),
+            "fs-buffer" => self.fs_buffer = Some(parse_size(name, &value.unwrap_or_default())?),
+            "auto-buffer" => self.auto_buffer = parse_switch(name, value)?,
+            "buffer-range" => self.buffer_range = Some(parse_size_range(name, &value.unwrap_or_default())?),
+            "mmap-threshold" => self.mmap_threshold = Some(parse_size(name, &value.unwrap_or_default())?),
+            "tmpdir" => self.tmpdir = value.map(PathBuf::from),
+            "dir-spill-threshold" => self.dir_spill_threshold = Some(parse_number(name, &value.unwrap_or_default())?),
+            "partitioned" => self.partitioned = parse_switch(name, value)?,
+            "on-newer" => self.on_newer = parse_on_newer(name, &value.unwrap_or_default())?,
+            "on-longpath" => self.on_longpath = parse_on_longpath(name, &value.unwrap_or_default())?,
+            "policy" => {
+                for (condition, action) in policy::parse(name, &value.unwrap_or_default())? {
+                    self.set_policy(condition, action);
+                }
+            }
+            "dump-config" => self.dump_config = Some(parse_dump_format(name, value)?),
+            "health-check" => self.health_check = Some(parse_dump_format(name, value)?),
+            "target" => self.targets.push(parse_target(name, &value.unwrap_or_default())?),
+            "on-short-name-collision" => self.on_short_name_collision = parse_on_short_name_collision(name, &value.unwrap_or_default())?,
+            "volume-label" => self.volume_label = Some(crate::label::check_label(name, &value.unwrap_or_default())?),
+            "image-hash" => self.image_hash = Some(parse_image_hash(name, &value.unwrap_or_default())?),
+            "compress-output" => self.compress_output = Some(parse_compression(name, &value.unwrap_or_default())?),
+            "output-format" => self.output_format = parse_output_format(name, &value.unwrap_or_default())?,
+            "compress-level" => self.compress_level = Some(parse_number(name, &value.unwrap_or_default())?),
+            "hash" => self.hash = parse_hash_algorithm(name, &value.unwrap_or_default())?,
+            "report-dupes" => self.report_dupes = parse_switch(name, value)?,
+            "max-pipeline-retries" => self.max_pipeline_retries = Some(parse_number(name, &value.unwrap_or_default())?),
+            "write-retries" => self.write_retries = Some(parse_number(name, &value.unwrap_or_default())?),
+            "write-throttle" => self.write_throttle = Some(parse_rate(name, &value.unwrap_or_default())?),
+            "url" => self.url = value,
+            "allowed-host" => self.allowed_hosts.push(value.unwrap_or_default().to_lowercase()),
+            "branch" => self.branch = value,
+            "follow-default-branch" => self.follow_default_branch = parse_switch(name, value)?,
+            "strict-head" => self.strict_head = parse_switch(name, value)?,
+            "on-source-mismatch" => self.on_source_mismatch = parse_on_source_mismatch(name, &value.unwrap_or_default())?,
+            "on-not-a-repo" => self.on_not_a_repo = parse_on_not_a_repo(name, &value.unwrap_or_default())?,
+            "tag" => self.tag = value,
+            "no-tags" => self.no_tags = parse_switch(name, value)?,
+            "all-tags" => self.all_tags = parse_switch(name, value)?,
+            "require-signature" => self.require_signature = value,
+            "include-manifest-in-image" => self.include_manifest_in_image = value.map(|path| path.replace('\\', "/").trim_matches('/').to_string()),
+            "require-signed-commit" => self.require_signed_commit = parse_switch(name, value)?,
+            "trusted-gpg-keyring" => self.trusted_gpg_keyring = value.map(PathBuf::from),
+            "trusted-ssh-signers" => self.trusted_ssh_signers = value.map(PathBuf::from),
+            "merge-signature" => self.merge_signature = Some(parse_signature(name, &value.unwrap_or_default())?),
+            "mirror-dir" => self.mirror_dir = value.map(PathBuf::from),
+            "output" => self.output = value.map(PathBuf::from),
+            "pre-build-hook" => self.pre_build_hook = value,
+            "post-build-hook" => self.post_build_hook = value,
+            "lock-output" => self.lock_output = parse_switch(name, value)?,
+            "device" => self.device = value.map(PathBuf::from),
+            "dest-dir" => self.dest_dir = value.map(PathBuf::from),
+            "assume-yes" => self.assume_yes = parse_switch(name, value)?,
+            "assets-dir" => self.assets_dir = value.map(PathBuf::from),
+            "image-url" => self.image_url = value,
+            "download-limit" => self.download_limit = Some(parse_rate(name, &value.unwrap_or_default())?),
+            "min-interval" => self.min_interval = Some(parse_duration(name, &value.unwrap_or_default())?),
+            "watch" => self.watch = Some(parse_duration(name, &value.unwrap_or_default())?),
+            _ => return Err(format!("Unknown option '--{}'", name)),
+        }
+        Ok(())
+    }
+
+    /// Sets what `--policy` does about `condition`. `long-path` and `dirty-tree` go to the
+    /// options that already say, so the one given last wins.
+    fn set_policy(&mut self, condition: Condition, action: Action) {
+        match condition {
+            Condition::MergeConflict => self.policy.merge_conflict = action,
+            Condition::VerifyMismatch => self.policy.verify_mismatch = action,
+            Condition::OversizedFile => self.policy.oversized_file = action,
+            Condition::PathCollision => self.policy.path_collision = action,
+            Condition::LongPath => {
+                // Skip already warns about every path it leaves out.
+                self.on_longpath = if action == Action::Error { OnLongPath::Error } else { OnLongPath::Skip };
+            }
+            Condition::DirtyTree => {
+                self.on_source_mismatch = match action {
+                    Action::Error => OnSourceMismatch::Error,
+                    Action::Warn => OnSourceMismatch::Warn,
+                    Action::Skip => OnSourceMismatch::Ignore,
+                };
+            }
+        }
+    }
+}
+
+/// Subcommands are shorthands for the equivalent flags.
+fn command(name: &str) -> Result<&'static str, String> {
+    match name {
+        "build" => Ok("build-only"),
+        "fetch" => Ok("fetch-only"),
+        "check" => Ok("check"),
+        "compare" => Ok("compare"),
+        "verify" => Ok("verify-only"),
+        "self-update" => Ok("self-update"),
+        "bench" => Ok("bench"),
+        "selftest" => Ok("self-test"),
+        "healthcheck" => Ok("health-check"),
+        "pack" => Ok("pack"),
+        _ => Err(format!("Unknown command '{}'", name)),
+    }
+}
+
+fn parse_switch(name: &str, value: Option<String>) -> Result<bool, String> {
+    match value.as_deref() {
+        None | Some("true") => Ok(true),
+        Some("false") => Ok(false),
+        Some(other) => Err(format!("--{} expects true or false, got '{}'", name, other)),
+    }
+}
+
+fn parse_on_newer(name: &str, value: &str) -> Result<OnNewer, String> {
+    match value.trim() {
+        "overwrite" => Ok(OnNewer::Overwrite),
+        "skip" => Ok(OnNewer::Skip),
+        "error" => Ok(OnNewer::Error),
+        other => Err(format!("--{} expects overwrite, skip or error, got '{}'", name, other)),
+    }
+}
+
+fn parse_on_longpath(name: &str, value: &str) -> Result<OnLongPath, String> {
+    match value.trim() {
+        "error" => Ok(OnLongPath::Error),
+        "skip" => Ok(OnLongPath::Skip),
+        "truncate" => Ok(OnLongPath::Truncate),
+        other => Err(format!("--{} expects error, skip or truncate, got '{}'", name, other)),
+    }
+}
+
+fn parse_on_source_mismatch(name: &str, value: &str) -> Result<OnSourceMismatch, String> {
+    match value.trim() {
+        "ignore" => Ok(OnSourceMismatch::Ignore),
+        "warn" => Ok(OnSourceMismatch::Warn),
+        "error" => Ok(OnSourceMismatch::Error),
+        other => Err(format!("--{} expects ignore, warn or error, got '{}'", name, other)),
+    }
+}
+
+fn parse_on_not_a_repo(name: &str, value: &str) -> Result<OnNotARepo, String> {
+    match value.trim() {
+        "error" => Ok(OnNotARepo::Error),
+        "build" => Ok(OnNotARepo::Build),
+        "reclone" => Ok(OnNotARepo::Reclone),
+        other => Err(format!("--{} expects error, build or reclone, got '{}'", name, other)),
+    }
+}
+
+fn parse_checkout(name: &str, value: &str) -> Result<CheckoutMode, String> {
+    match value.trim() {
+        "force" => Ok(CheckoutMode::Force),
+        "safe" => Ok(CheckoutMode::Safe),
+        other => Err(format!("--{} expects force or safe, got '{}'", name, other)),
+    }
+}
+
+/// What a `--target` can set, everything that says what ends up in one image and where.
+const TARGET_KEYS: &[&str] = &[
+    "output",
+    "sd-size",
+    "assets-dir",
+    "format",
+    "cluster-size",
+    "partitioned",
+    "volume-label",
+    "exclude-larger-than",
+    "include-ext",
+    "exclude-ext",
+    "newer-than",
+    "older-than",
+    "compress-output",
+    "compress-level",
+    "output-format",
+];
+
+/// Reads `output=sd-8g.raw,sd-size=8G,volume-label=SD8G` into the settings it gives.
+fn parse_target(name: &str, value: &str) -> Result<Vec<(String, Option<String>)>, String> {
+    let mut settings = Vec::new();
+    for part in value.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+        let (key, setting) = match part.split_once('=') {
+            Some((key, setting)) => (key.trim(), Some(setting.trim().to_string())),
+            None => (part, None),
+        };
+        if !TARGET_KEYS.contains(&key) {
+            return Err(format!("--{} can't set {}, only {}", name, key, TARGET_KEYS.join(", ")));
+        }
+        settings.push((key.to_string(), setting));
+    }
+    if !settings.iter().any(|(key, _)| key == "output") {
+        return Err(format!("--{} needs an output=<path> for its image, got '{}'", name, value));
+    }
+    Ok(settings)
+}
+
+fn parse_dump_format(name: &str, value: Option<String>) -> Result<DumpFormat, String> {
+    match value.as_deref().map(str::trim) {
+        None | Some("text") => Ok(DumpFormat::Text),
+        Some("json") => Ok(DumpFormat::Json),
+        Some(other) => Err(format!("--{} expects text or json, got '{}'", name, other)),
+    }
+}
+
+fn parse_on_short_name_collision(name: &str, value: &str) -> Result<OnShortNameCollision, String> {
+    match value.trim() {
+        "number" => Ok(OnShortNameCollision::Number),
+        "error" => Ok(OnShortNameCollision::Error),
+        other => Err(format!("--{} expects number or error, got '{}'", name, other)),
+    }
+}
+
+fn parse_color(name: &str, value: &str) -> Result<ColorChoice, String> {
+    match value.trim() {
+        "auto" => Ok(ColorChoice::Auto),
+        "always" => Ok(ColorChoice::Always),
+        "never" => Ok(ColorChoice::Never),
+        other => Err(format!("--{} expects auto, always or never, got '{}'", name, other)),
+    }
+}
+
+fn parse_progress_style(name: &str, value: &str) -> Result<ProgressStyle, String> {
+    match value.trim() {
+        "phases" => Ok(ProgressStyle::Phases),
+        "pipeline" => Ok(ProgressStyle::Pipeline),
+        "both" => Ok(ProgressStyle::Both),
+        other => Err(format!("--{} expects phases, pipeline or both, got '{}'", name, other)),
+    }
+}
+
+fn parse_image_hash(name: &str, value: &str) -> Result<ImageHash, String> {
+    match value.trim() {
+        "raw" => Ok(ImageHash::Raw),
+        "tree" => Ok(ImageHash::Tree),
+        other => Err(format!("--{} expects raw or tree, got '{}'", name, other)),
+    }
+}
+
+fn parse_compression(name: &str, value: &str) -> Result<Compression, String> {
+    match value.trim() {
+        "xz" => Ok(Compression::Xz),
+        "zstd" => Ok(Compression::Zstd),
+        "gzip" => Ok(Compression::Gzip),
+        other => Err(format!("--{} expects xz, zstd or gzip, got '{}'", name, other)),
+    }
+}
+
+fn parse_output_format(name: &str, value: &str) -> Result<OutputFormat, String> {
+    match value.trim() {
+        "raw" => Ok(OutputFormat::Raw),
+        "vhd" => Ok(OutputFormat::Vhd),
+        other => Err(format!("--{} expects raw or vhd, got '{}'", name, other)),
+    }
+}
+
+fn parse_hash_algorithm(name: &str, value: &str) -> Result<HashAlgorithm, String> {
+    HashAlgorithm::from_name(value.trim())
+        .ok_or_else(|| format!("--{} expects xxh3, blake3 or sha256, got '{}'", name, value.trim()))
+}
+
+/// A file extension, with or without the dot, compared case-insensitively.
+fn parse_extension(name: &str, value: &str) -> Result<String, String> {
+    let extension = value.trim().trim_start_matches('.').to_lowercase();
+    if extension.is_empty() || extension.contains(['.', '/', '\\']) {
+        return Err(format!("--{} expects a file extension like iso or .dol, got '{}'", name, value));
+    }
+    Ok(extension)
+}
+
+/// Parses `Name <email>`.
+fn parse_signature(name: &str, value: &str) -> Result<(String, String), String> {
+    value
+        .trim()
+        .strip_suffix('>')
+        .and_then(|rest| rest.split_once('<'))
+        .map(|(author, email)| (author.trim().to_string(), email.trim().to_string()))
+        .filter(|(author, email)| !author.is_empty() && !email.is_empty())
+        .ok_or_else(|| format!("--{} expects 'Name <email>', got '{}'", name, value))
+}
+
+fn parse_number<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("--{} expects a number, got '{}'", name, value))
+}
+
+/// The settings `parse_size` reads.
+pub(crate) const SIZE_SETTINGS: &[&str] = &["sd-size", "cluster-size", "exclude-larger-than", "max-memory", "copy-buffer", "fs-buffer", "mmap-threshold", "bench-file-size"];
+
+/// Parses sizes like `4096`, `512M`, `2G` or `1.5GiB`, see `units::parse_bytes`.
+pub fn parse_size(name: &str, value: &str) -> Result<u64, String> {
+    crate::units::parse_bytes(value).map_err(|e| format!("--{}: {}", name, e))
+}
+
+/// Parses ranges like `64K-16M`, at least `MIN_BUFFER_SIZE` at the low end.
+fn parse_size_range(name: &str, value: &str) -> Result<(u64, u64), String> {
+    let Some((min, max)) = value.split_once('-') else {
+        return Err(format!("--{} expects a range like 64K-16M, got '{}'", name, value));
+    };
+    let (min, max) = (parse_size(name, min.trim())?, parse_size(name, max.trim())?);
+    if min < MIN_BUFFER_SIZE || min > max {
+        return Err(format!("--{} expects sizes from {} up, smallest first, got '{}'", name, format_bytes(MIN_BUFFER_SIZE), value));
+    }
+    Ok((min, max))
+}
+
+/// Parses `<host-path>:<image-path>`, split at the last colon so `C:\` paths work.
+fn parse_add_file(name: &str, value: &str) -> Result<(PathBuf, String), String> {
+    let Some((host, image)) = value.rsplit_once(':').filter(|(host, _)| !host.is_empty()) else {
+        return Err(format!("--{} expects <host-path>:<image-path> like saves/Dolphin.ini:User/Config/Dolphin.ini, got '{}'", name, value));
+    };
+    match card_path(image) {
+        Some(image) => Ok((PathBuf::from(host), image)),
+        None => Err(format!("--{} expects a file path on the card after the colon, like User/Config/Dolphin.ini, got '{}'", name, value)),
+    }
+}
+
+/// `path` on the card with `/` separators and without a leading one, or `None` if it is
+/// empty or goes through `.` or `..`.
+pub(crate) fn card_path(path: &str) -> Option<String> {
+    let path = path.replace('\\', "/");
+    let path = path.trim_start_matches('/');
+    (!path.is_empty() && !path.split('/').any(|part| part.is_empty() || part == "." || part == "..")).then(|| path.to_string())
+}
+
+/// Parses rates like `8M` or `8MiB/s`, a size per second.
+fn parse_rate(name: &str, value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let size = value.strip_suffix("/s").unwrap_or(value);
+    match parse_size(name, size)? {
+        0 => Err(format!("--{} needs a rate above 0, leave it out to write at full speed", name)),
+        rate => Ok(rate),
+    }
+}
+
+/// Both `--newer-than` and `--older-than` only leave files in between them if the first is
+/// the longer one.
+fn check_age_window(options: &Options) -> Result<(), String> {
+    match (options.newer_than, options.older_than) {
+        (Some(newer_than), Some(older_than)) if newer_than <= older_than => Err(format!(
+            "--newer-than {}s and --older-than {}s leave no file in between, --newer-than has to be the longer one",
+            newer_than.as_secs(),
+            older_than.as_secs()
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Parses durations like `90`, `45s`, `30m`, `48h` or `7d`, see `units::parse_duration`.
+pub fn parse_duration(name: &str, value: &str) -> Result<Duration, String> {
+    crate::units::parse_duration(value).map_err(|e| format!("--{}: {}", name, e))
+}
@@ -0,0 +1,56 @@
+//! `--lock-output`: makes the image read-only once it is built, for kiosk setups where
+//! nothing should touch it between runs. The next build makes it writable again before
+//! it writes anything, with or without `--lock-output`, so a locked image never stops an
+//! update.
+
+use std::fs::Permissions;
+use std::path::Path;
+
+use crate::error::UpdateError;
+use crate::options::Options;
+use crate::report;
+use crate::{debug, info};
+
+#[cfg(unix)]
+fn writable(permissions: &mut Permissions, writable: bool) {
+    use std::os::unix::fs::PermissionsExt;
+    // Only the owner gets write access back, not everyone `set_readonly(false)` would give it to.
+    let mode = if writable { permissions.mode() | 0o200 } else { permissions.mode() & !0o222 };
+    permissions.set_mode(mode);
+}
+
+#[cfg(not(unix))]
+#[allow(clippy::permissions_set_readonly_false)]
+fn writable(permissions: &mut Permissions, writable: bool) {
+    // Windows only has the one read-only attribute.
+    permissions.set_readonly(!writable);
+}
+
+/// Makes the image at `--output` writable again if it was left read-only.
+pub(crate) fn unlock(options: &Options) -> Result<(), UpdateError> {
+    if options.dest_dir.is_some() || options.device.is_some() {
+        return Ok(());
+    }
+    let output = options.output();
+    let Ok(metadata) = std::fs::metadata(&output) else {
+        return Ok(());
+    };
+    if !metadata.is_file() || !metadata.permissions().readonly() {
+        return Ok(());
+    }
+    let mut permissions = metadata.permissions();
+    writable(&mut permissions, true);
+    std::fs::set_permissions(&output, permissions)?;
+    debug(format!("Made the read-only {} writable to rebuild it\n", output.display()).as_str());
+    Ok(())
+}
+
+/// Makes the finished image at `output` read-only.
+pub(crate) fn lock(output: &Path) -> Result<(), UpdateError> {
+    let mut permissions = std::fs::metadata(output)?.permissions();
+    writable(&mut permissions, false);
+    std::fs::set_permissions(output, permissions)?;
+    report::record_output_locked();
+    info(format!("Locked {} read-only until the next build\n", output.display()).as_str());
+    Ok(())
+}
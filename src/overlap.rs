@@ -0,0 +1,88 @@
+//! Catches paths that point into each other before any work is done: an `--output` inside
+//! the source would be copied into itself, a `--dest-dir` holding the source would have
+//! the copy read what it just wrote, and an `sd.xz` inside the source would end up on the
+//! card it is the base of. Fumbled flags like these otherwise show up as a build that
+//! never ends or an image full of itself.
+
+use std::path::{Component, Path, PathBuf};
+
+use crate::error::UpdateError;
+use crate::image::sd_xz_path;
+use crate::options::Options;
+
+/// `path` made absolute, with the part of it that exists resolved through symlinks, so
+/// paths that don't exist yet, like a first `--output`, compare too.
+fn resolve(path: &Path) -> PathBuf {
+    let absolute = std::env::current_dir().map(|dir| dir.join(path)).unwrap_or_else(|_| path.to_path_buf());
+    let mut missing = Vec::new();
+    let mut existing = absolute.as_path();
+    loop {
+        if let Ok(canonical) = existing.canonicalize() {
+            return missing.iter().rev().fold(canonical, |path, name| path.join(name));
+        }
+        match (existing.parent(), existing.components().next_back()) {
+            (Some(parent), Some(Component::Normal(name))) => {
+                missing.push(name.to_os_string());
+                existing = parent;
+            }
+            _ => return absolute,
+        }
+    }
+}
+
+/// Fails with `Usage` if the output, `source`, the overlays, `--add-file`, `sd.xz` or
+/// `--tmpdir` would read or write one another.
+pub(crate) fn check(source: &Path, options: &Options) -> Result<(), UpdateError> {
+    let output = resolve(&options.output());
+    // Only an image is built from it.
+    let sd_xz = sd_xz_path(options).filter(|_| !options.format && options.dest_dir.is_none()).map(|path| resolve(&path));
+    let mut trees = vec![("the source", resolve(source))];
+    trees.extend(options.overlays.iter().map(|overlay| ("the overlay", resolve(overlay))));
+    for (what, tree) in &trees {
+        if output.starts_with(tree) {
+            return Err(UpdateError::Usage(format!(
+                "{} is inside {} {}, the build would copy its own output into itself",
+                output.display(),
+                what,
+                tree.display()
+            )));
+        }
+        if options.dest_dir.is_some() && tree.starts_with(&output) {
+            return Err(UpdateError::Usage(format!(
+                "--dest-dir {} holds {} {}, the copy would read what it writes",
+                output.display(),
+                what,
+                tree.display()
+            )));
+        }
+        if let Some(sd_xz) = sd_xz.as_ref().filter(|sd_xz| sd_xz.starts_with(tree)) {
+            return Err(UpdateError::Usage(format!(
+                "{} is inside {} {}, the base image would be copied onto the card. Keep --assets-dir outside of it",
+                sd_xz.display(),
+                what,
+                tree.display()
+            )));
+        }
+        if let Some(tmpdir) = options.tmpdir.as_deref().map(resolve).filter(|tmpdir| tmpdir.starts_with(tree) || tree.starts_with(tmpdir)) {
+            return Err(UpdateError::Usage(format!(
+                "--tmpdir {} overlaps {} {}, what is kept there would be copied onto the card",
+                tmpdir.display(),
+                what,
+                tree.display()
+            )));
+        }
+    }
+    if sd_xz.as_ref() == Some(&output) {
+        return Err(UpdateError::Usage(format!("--output {} is sd.xz, the build would overwrite the image it starts from", output.display())));
+    }
+    for (host_path, image_path) in &options.add_files {
+        if resolve(host_path) == output {
+            return Err(UpdateError::Usage(format!(
+                "--add-file {} is the output itself, it can't go into the image as {}",
+                host_path.display(),
+                image_path
+            )));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,25 @@
+//! `pack <source-dir> <output-image>`: the image building on its own, for any directory
+//! instead of the MNN Build. Nothing is fetched and `sd_source` isn't looked at, the
+//! directory is copied into a fresh FAT image of `--sd-size` the same way a build copies
+//! the checkout, so `--cluster-size`, `--volume-label`, `--partitioned`, the filters and
+//! `--compress-output` all apply.
+//!
+//! The base is a blank filesystem, or `sd.xz` from `--assets-dir` when one is given.
+//! With `--dest-dir` the directory goes onto a mounted card instead.
+
+use std::path::Path;
+
+use crate::error::UpdateError;
+use crate::image::build;
+use crate::options::{OnSourceMismatch, Options};
+use crate::info;
+
+pub fn pack(source: &Path, options: &Options) -> Result<(), UpdateError> {
+    if !source.is_dir() {
+        return Err(UpdateError::Usage(format!("pack: {} isn't a directory", source.display())));
+    }
+    // What the updater recorded about `sd_source` says nothing about another directory.
+    let options = Options { on_source_mismatch: OnSourceMismatch::Ignore, ..options.clone() };
+    info(format!("Packing {} into {}\n", source.display(), options.output().display()).as_str());
+    build(source, &options)
+}
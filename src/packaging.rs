@@ -0,0 +1,94 @@
+//! `.updater/manifest.toml` in `sd_source`: how the build author wants the build packaged,
+//! read after every checkout so it can change along with the build.
+//!
+//! ```toml
+//! # Copied to the root of the card instead of the whole checkout.
+//! source-subdir = "sd"
+//! # Never copied, relative to what is copied.
+//! exclude = ["README.md", "docs"]
+//! # Created on the card even though git can't hold them empty.
+//! empty-dirs = ["saves", "wiiflow/cache"]
+//! # Kept across full rebuilds, like --preserve.
+//! preserve = ["saves", "wiiflow/settings"]
+//! # Copied under this directory of the card instead of its root, like --target-dir.
+//! target-dir = "apps/mnn"
+//! ```
+//!
+//! What the user set wins: `--source-subdir`, `--preserve` and `--target-dir`, on the
+//! command line or in `updater.conf`, replace the manifest's. `exclude` and `empty-dirs` only come from here.
+
+use std::path::{Component, Path, PathBuf};
+
+use crate::error::UpdateError;
+use crate::options::{card_path, Options};
+use crate::textfile::read_text;
+use crate::debug;
+
+/// Where the manifest is, relative to `sd_source`.
+pub const PACKAGING_MANIFEST: &str = ".updater/manifest.toml";
+
+/// A relative path without `..`, with `/` separators and no leading or trailing `/`.
+fn check_path(key: &str, value: &toml::Value) -> Result<String, String> {
+    let path = value.as_str().ok_or_else(|| format!("{} expects paths as strings, got {}", key, value))?;
+    let trimmed = path.trim_matches('/');
+    let inside = Path::new(trimmed).components().all(|component| matches!(component, Component::Normal(_)));
+    if trimmed.is_empty() || !inside || path.starts_with('/') {
+        return Err(format!("{} expects paths inside the source, got '{}'", key, path));
+    }
+    Ok(trimmed.to_string())
+}
+
+fn check_paths(key: &str, value: &toml::Value) -> Result<Vec<String>, String> {
+    let paths = value.as_array().ok_or_else(|| format!("{} expects a list of paths, got {}", key, value))?;
+    paths.iter().map(|path| check_path(key, path)).collect()
+}
+
+/// Merges the manifest into `options`, see above.
+fn merge(contents: &str, options: &mut Options) -> Result<(), String> {
+    let table: toml::Table = contents.parse().map_err(|e: toml::de::Error| e.to_string().trim_end().to_string())?;
+    for (key, value) in &table {
+        match key.as_str() {
+            "source-subdir" => {
+                let subdir = check_path(key, value)?;
+                options.source_subdir.get_or_insert_with(|| PathBuf::from(subdir));
+            }
+            "exclude" => options.exclude = check_paths(key, value)?,
+            "empty-dirs" => options.empty_dirs = check_paths(key, value)?,
+            "preserve" => {
+                let preserve = check_paths(key, value)?;
+                options.preserve.get_or_insert(preserve);
+            }
+            "target-dir" => {
+                let target_dir = value
+                    .as_str()
+                    .and_then(|path| card_path(path.trim_end_matches('/')))
+                    .ok_or_else(|| format!("{} expects a directory on the card like \"apps/mnn\", got {}", key, value))?;
+                options.target_dir.get_or_insert(target_dir);
+            }
+            other => return Err(format!("unknown key {}, expected source-subdir, exclude, empty-dirs, preserve or target-dir", other)),
+        }
+    }
+    Ok(())
+}
+
+/// `options` with the manifest in `sd_source_path` merged in, and whether it has one.
+pub(crate) fn merge_manifest(sd_source_path: &Path, options: &Options) -> Result<(Options, bool), UpdateError> {
+    let mut options = options.clone();
+    let path = sd_source_path.join(PACKAGING_MANIFEST);
+    let contents = match read_text(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((options, false)),
+        Err(e) => return Err(UpdateError::Other(format!("Can't read {}: {}", path.display(), e))),
+    };
+    merge(&contents, &mut options).map_err(|e| UpdateError::Other(format!("{}: {}", path.display(), e)))?;
+    Ok((options, true))
+}
+
+/// `options` with the manifest in `sd_source_path` merged in, if it has one.
+pub fn merged(sd_source_path: &Path, options: &Options) -> Result<Options, UpdateError> {
+    let (options, found) = merge_manifest(sd_source_path, options)?;
+    if found {
+        debug(format!("Packaging as {} asks\n", sd_source_path.join(PACKAGING_MANIFEST).display()).as_str());
+    }
+    Ok(options)
+}
@@ -0,0 +1,44 @@
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where the FAT partition of a `--partitioned` image starts. 1 MiB keeps it aligned to
+/// the erase blocks of real SD cards, matching what card formatters do.
+pub const PARTITION_OFFSET: u64 = 1024 * 1024;
+
+const SECTOR_SIZE: u64 = 512;
+
+/// MBR partition type for a FAT filesystem addressed by LBA.
+fn partition_type(fat_type: fatfs::FatType) -> u8 {
+    match fat_type {
+        fatfs::FatType::Fat12 => 0x01,
+        fatfs::FatType::Fat16 => 0x0E,
+        fatfs::FatType::Fat32 => 0x0C,
+    }
+}
+
+/// Writes an MBR to the first sector of `image` with a single partition covering
+/// `partition_size` bytes from `PARTITION_OFFSET`. The CHS fields are set to the usual
+/// "use LBA" placeholder since nothing that reads SD cards looks at them anymore.
+pub fn write_mbr(image: &mut File, partition_size: u64, fat_type: fatfs::FatType) -> std::io::Result<()> {
+    let start_lba = (PARTITION_OFFSET / SECTOR_SIZE) as u32;
+    let sectors = u32::try_from(partition_size / SECTOR_SIZE)
+        .map_err(|_| std::io::Error::other("The partition is too large for an MBR partition table"))?;
+    let mut mbr = [0_u8; SECTOR_SIZE as usize];
+    // Disk signature, only needs to differ between disks attached at the same time.
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let signature = now.subsec_nanos() ^ now.as_secs() as u32;
+    mbr[440..444].copy_from_slice(&signature.to_le_bytes());
+    let entry = &mut mbr[446..462];
+    entry[0] = 0x00; // not bootable
+    entry[1..4].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+    entry[4] = partition_type(fat_type);
+    entry[5..8].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+    entry[8..12].copy_from_slice(&start_lba.to_le_bytes());
+    entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+    mbr[510] = 0x55;
+    mbr[511] = 0xAA;
+    image.seek(SeekFrom::Start(0))?;
+    image.write_all(&mbr)?;
+    image.sync_data()
+}
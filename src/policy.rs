@@ -0,0 +1,131 @@
+//! `--policy <condition>=<action>`: what the update does when it runs into one of the
+//! conditions below, set from the command line or the config file like any other option.
+//! Each one can error (stop the run), warn (carry on, saying so) or skip (carry on quietly,
+//! leaving out what the condition is about).
+//!
+//! | condition         | default | what it is about                                         |
+//! |-------------------|---------|----------------------------------------------------------|
+//! | `merge-conflict`  | error   | upstream doesn't merge into `sd_source`                  |
+//! | `verify-mismatch` | error   | the card doesn't read back like the source, `--verify`   |
+//! | `oversized-file`  | warn    | a source file larger than `--exclude-larger-than`        |
+//! | `long-path`       | error   | a source path too long for FAT, as `--on-longpath`       |
+//! | `dirty-tree`      | skip    | `sd_source` changed by hand, as `--on-source-mismatch`   |
+//! | `path-collision`  | skip    | more than one tree writes a path, the last one wins      |
+//!
+//! On a merge conflict warn builds from `sd_source` as it was before the pull, skip leaves
+//! out the build too. A mismatch on the card is warned about with either of them, they only
+//! let the run succeed. An oversized file is left out with either, error stops the build
+//! before anything is written instead. Colliding paths are listed before the copy with
+//! which tree wins, only with `--verbose` for skip.
+
+use crate::{debug, warn};
+
+/// Something an update can run into, see the table above.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    MergeConflict,
+    VerifyMismatch,
+    OversizedFile,
+    LongPath,
+    DirtyTree,
+    PathCollision,
+}
+
+impl Condition {
+    pub fn name(self) -> &'static str {
+        match self {
+            Condition::MergeConflict => "merge-conflict",
+            Condition::VerifyMismatch => "verify-mismatch",
+            Condition::OversizedFile => "oversized-file",
+            Condition::LongPath => "long-path",
+            Condition::DirtyTree => "dirty-tree",
+            Condition::PathCollision => "path-collision",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Condition> {
+        [
+            Condition::MergeConflict,
+            Condition::VerifyMismatch,
+            Condition::OversizedFile,
+            Condition::LongPath,
+            Condition::DirtyTree,
+            Condition::PathCollision,
+        ]
+        .into_iter()
+        .find(|condition| condition.name() == name)
+    }
+}
+
+/// What to do about a `Condition`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Action {
+    /// Stop the run.
+    #[default]
+    Error,
+    /// Carry on, with a warning.
+    Warn,
+    /// Carry on, leaving out what the condition is about.
+    Skip,
+}
+
+impl Action {
+    fn parse(value: &str) -> Option<Action> {
+        match value {
+            "error" => Some(Action::Error),
+            "warn" => Some(Action::Warn),
+            "skip" => Some(Action::Skip),
+            _ => None,
+        }
+    }
+
+    /// Logs `message` for a condition that didn't stop the run: a warning, or only with
+    /// `--verbose` for `Skip`.
+    pub fn report(self, message: &str) {
+        match self {
+            Action::Error | Action::Warn => warn(message),
+            Action::Skip => debug(message),
+        }
+    }
+}
+
+/// The actions for the conditions that aren't kept in an option of their own. `long-path`
+/// and `dirty-tree` set `--on-longpath` and `--on-source-mismatch`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Policy {
+    pub merge_conflict: Action,
+    pub verify_mismatch: Action,
+    pub oversized_file: Action,
+    pub path_collision: Action,
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy { merge_conflict: Action::Error, verify_mismatch: Action::Error, oversized_file: Action::Warn, path_collision: Action::Skip }
+    }
+}
+
+/// Reads `merge-conflict=warn,oversized-file=error` into the actions it sets.
+pub fn parse(name: &str, value: &str) -> Result<Vec<(Condition, Action)>, String> {
+    let mut actions = Vec::new();
+    for part in value.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+        let Some((condition, action)) = part.split_once('=') else {
+            return Err(format!("--{} expects <condition>=<action>, got '{}'", name, part));
+        };
+        let Some(condition) = Condition::parse(condition.trim()) else {
+            return Err(format!(
+                "--{} doesn't know the condition '{}', pick merge-conflict, verify-mismatch, oversized-file, long-path, dirty-tree or path-collision",
+                name,
+                condition.trim()
+            ));
+        };
+        let Some(action) = Action::parse(action.trim()) else {
+            return Err(format!("--{} expects error, warn or skip for {}, got '{}'", name, condition.name(), action.trim()));
+        };
+        actions.push((condition, action));
+    }
+    if actions.is_empty() {
+        return Err(format!("--{} expects <condition>=<action>, got '{}'", name, value));
+    }
+    Ok(actions)
+}
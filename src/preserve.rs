@@ -0,0 +1,134 @@
+//! `--preserve`: directories a full rebuild would otherwise wipe, like savegames written on
+//! the card by the Wii. They are copied off the existing image before `sd.xz` is
+//! decompressed over it, and copied back on top of the fresh build like an overlay.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use fatfs::{FileSystem, ReadWriteSeek};
+
+use crate::error::UpdateError;
+use crate::image::open_fs_region;
+use crate::options::Options;
+use crate::scratch::ScratchDir;
+use crate::timestamps;
+use crate::units::format_bytes;
+use crate::{debug, info, warn};
+
+/// Preserved when `--preserve` isn't given.
+pub const DEFAULT_PRESERVE: &[&str] = &["saves"];
+
+/// Directories copied off the old image, held in a scratch directory until they are back
+/// on the card. The old image is gone by then, so the copies outlive an error unless they
+/// are discarded.
+pub struct Preserved {
+    scratch: ScratchDir,
+}
+
+impl Preserved {
+    pub fn dir(&self) -> &Path {
+        self.scratch.path()
+    }
+
+    /// Removes the copies once they are safely on the new image.
+    pub fn discard(mut self) {
+        self.scratch.discard();
+    }
+
+    /// Keeps the copies when the build failed, so they can still be recovered by hand.
+    pub fn keep(self) {
+        warn(format!("The preserved directories were not restored, they are kept in {}\n", self.dir().display()).as_str());
+    }
+}
+
+/// Size of the files under `sd_folder`.
+fn dir_size<IO: ReadWriteSeek, TP: fatfs::TimeProvider, OCC: fatfs::OemCpConverter>(sd_folder: &fatfs::Dir<IO, TP, OCC>) -> Result<u64, UpdateError> {
+    let mut total = 0;
+    for entry in sd_folder.iter() {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name == "." || name == ".." {
+            continue;
+        }
+        total += if entry.is_dir() { dir_size(&entry.to_dir())? } else { entry.len() };
+    }
+    Ok(total)
+}
+
+fn extract_dir<IO: ReadWriteSeek, TP: fatfs::TimeProvider, OCC: fatfs::OemCpConverter>(
+    sd_folder: &fatfs::Dir<IO, TP, OCC>,
+    host_path: &Path,
+    buffer: &mut [u8],
+) -> Result<u64, UpdateError> {
+    std::fs::create_dir_all(host_path)?;
+    let mut total = 0;
+    for entry in sd_folder.iter() {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name == "." || name == ".." {
+            continue;
+        }
+        if entry.is_dir() {
+            total += extract_dir(&entry.to_dir(), &host_path.join(&name), buffer)?;
+            continue;
+        }
+        let mut sd_file = entry.to_file();
+        let mut file = File::create(host_path.join(&name))?;
+        loop {
+            let bytes_read = fatfs::Read::read(&mut sd_file, buffer)?;
+            if bytes_read == 0 {
+                break;
+            }
+            file.write_all(&buffer[..bytes_read])?;
+        }
+        // Restored like any other copy, which carries the modification time over.
+        file.set_modified(timestamps::from_fat_datetime(entry.modified()))?;
+        total += entry.len();
+    }
+    Ok(total)
+}
+
+/// Copies the `--preserve` directories off the image at `--output` before it is rebuilt.
+/// Returns `None` when there is no image yet or none of the directories are on it.
+pub fn extract(options: &Options, offset: u64) -> Result<Option<Preserved>, UpdateError> {
+    let output = options.output();
+    if !output.exists() {
+        debug("No existing image, nothing to preserve\n");
+        return Ok(None);
+    }
+    let (fs_region, _) = open_fs_region(options, offset)?;
+    let fs_options = fatfs::FsOptions::new().oem_cp_converter(options.code_page);
+    let fs = match FileSystem::new(fs_region, fs_options) {
+        Ok(fs) => fs,
+        Err(e) => {
+            // Nothing can be saved from an image that can't be read.
+            warn(format!("Can't read {} to preserve anything from it: {:?}\n", output.display(), e).as_str());
+            return Ok(None);
+        }
+    };
+    let mut found = Vec::new();
+    for path in options.preserve() {
+        let path = path.trim_matches('/').to_string();
+        match fs.root_dir().open_dir(&path) {
+            Ok(sd_dir) => found.push((path, sd_dir)),
+            Err(_) => debug(format!("{} isn't on the existing image, nothing to preserve\n", path).as_str()),
+        }
+    }
+    if found.is_empty() {
+        return Ok(None);
+    }
+    let mut needed = 0;
+    for (_, sd_dir) in &found {
+        needed += dir_size(sd_dir)?;
+    }
+    // Removed again if anything below fails, the old image is still intact then.
+    let mut scratch = ScratchDir::create("preserve", needed)?;
+    let mut buffer = vec![0_u8; 1024 * 1024];
+    for (path, sd_dir) in &found {
+        let bytes = extract_dir(sd_dir, &scratch.path().join(path), &mut buffer)?;
+        info(format!("Preserving {} ({}) from the existing image\n", path, format_bytes(bytes)).as_str());
+    }
+    scratch.keep();
+    Ok(Some(Preserved { scratch }))
+}
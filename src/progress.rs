@@ -0,0 +1,287 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::logging::{self, set_phase, show_progress, Progress};
+use crate::options::{Options, ProgressStyle};
+use crate::units::format_bytes;
+use crate::{debug, end_line, info};
+
+/// A named step of the run. Logged when it starts and timed when it finishes, so a long
+/// blocking call (like a final sync) shows up as its own step instead of a hang.
+pub struct Phase {
+    name: &'static str,
+    started: Instant,
+}
+
+impl Phase {
+    pub fn start(name: &'static str) -> Phase {
+        info(format!("{}...\n", name).as_str());
+        set_phase(Some(name));
+        Phase { name, started: Instant::now() }
+    }
+
+    /// Logs and returns how long the phase took.
+    pub fn finish(self) -> Duration {
+        let elapsed = self.started.elapsed();
+        set_phase(None);
+        debug(format!("{} took {:.1}s\n", self.name, elapsed.as_secs_f64()).as_str());
+        elapsed
+    }
+}
+
+/// The shortest time between two redraws of a progress line, 20 a second. Small files or
+/// git callbacks can report progress thousands of times a second, far more than a terminal
+/// can show or keeps up with.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Rate limits redraws of a progress line. Progress is still counted on every update, only
+/// drawing it is skipped.
+#[derive(Default)]
+pub struct Throttle {
+    last_draw: Option<Instant>,
+}
+
+impl Throttle {
+    /// Whether to redraw now. `done` always draws, so the last state shown is accurate.
+    /// Never with progress lines turned off, see `logging::set_progress`.
+    pub fn ready(&mut self, done: bool) -> bool {
+        if !show_progress() {
+            return false;
+        }
+        let now = Instant::now();
+        if !done && self.last_draw.is_some_and(|last_draw| now.duration_since(last_draw) < REFRESH_INTERVAL) {
+            return false;
+        }
+        self.last_draw = Some(now);
+        true
+    }
+}
+
+/// How far back the transfer rate is averaged, long enough that a brief stall doesn't
+/// throw the ETA around.
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// A percentage, transfer rate and ETA redrawn in place on a single line.
+pub struct ProgressBar {
+    label: &'static str,
+    total: u64,
+    current: u64,
+    /// Recent `(time, current)` samples, oldest first, covering about `RATE_WINDOW`. Taken
+    /// when the line is redrawn, not on every update.
+    samples: VecDeque<(Instant, u64)>,
+    throttle: Throttle,
+    /// Files done out of how many, shown next to the bytes, see `with_files`.
+    files: Option<(u64, u64)>,
+}
+
+impl ProgressBar {
+    pub fn new(label: &'static str, total: u64) -> ProgressBar {
+        let mut samples = VecDeque::new();
+        samples.push_back((Instant::now(), 0));
+        ProgressBar { label, total, current: 0, samples, throttle: Throttle::default(), files: None }
+    }
+
+    /// Also counts the `total` files the bytes are spread over, see `file_done`.
+    pub fn with_files(mut self, total: u64) -> ProgressBar {
+        self.files = Some((0, total));
+        self
+    }
+
+    pub fn file_done(&mut self) {
+        if let Some((done, _)) = &mut self.files {
+            *done += 1;
+        }
+    }
+
+    pub fn inc(&mut self, amount: u64) {
+        self.current += amount;
+        if self.throttle.ready(self.current >= self.total) {
+            self.sample();
+            self.draw();
+        }
+    }
+
+    fn sample(&mut self) {
+        let now = Instant::now();
+        self.samples.push_back((now, self.current));
+        // Keep one sample older than the window so the average always spans all of it.
+        while self.samples.len() > 2 && now.duration_since(self.samples[1].0) > RATE_WINDOW {
+            self.samples.pop_front();
+        }
+    }
+
+    /// How much of `total` is done.
+    pub fn current(&self) -> u64 {
+        self.current
+    }
+
+    pub fn remaining(&self) -> u64 {
+        self.total.saturating_sub(self.current)
+    }
+
+    /// Draws the final state and moves to the next line.
+    pub fn finish(&mut self) {
+        if show_progress() {
+            self.sample();
+            self.draw();
+            end_line();
+        }
+    }
+
+    /// Bytes per second over the window.
+    fn rate(&self) -> f64 {
+        let (first_time, first) = self.samples[0];
+        let (last_time, last) = self.samples[self.samples.len() - 1];
+        let elapsed = last_time.duration_since(first_time).as_secs_f64();
+        if elapsed > 0.0 {
+            (last - first) as f64 / elapsed
+        } else {
+            0.0
+        }
+    }
+
+    fn draw(&self) {
+        let percentage = if self.total > 0 {
+            (self.current as f64 / self.total as f64) * 100.0
+        } else {
+            0.0
+        };
+        let rate = self.rate();
+        let eta = if rate > 0.0 {
+            format_eta(Duration::from_secs_f64(self.total.saturating_sub(self.current) as f64 / rate))
+        } else {
+            "--:--".to_string()
+        };
+        let files = self.files.map(|(done, total)| format!(" \u{2014} {}/{} files", done, total)).unwrap_or_default();
+        let text = format!("{}: {:.1}%{} \u{2014} {}/s \u{2014} ETA {}", self.label, percentage, files, format_bytes(rate as u64), eta);
+        logging::progress(&Progress { label: self.label, current: self.current, total: self.total, text: &text });
+    }
+}
+
+/// A count of steps, like directories created, redrawn in place as `label n/total` with
+/// the same rate limit as `ProgressBar`. For work that moves no bytes but still takes time.
+pub struct Counter {
+    label: &'static str,
+    total: u64,
+    current: u64,
+    throttle: Throttle,
+}
+
+impl Counter {
+    pub fn new(label: &'static str, total: u64) -> Counter {
+        Counter { label, total, current: 0, throttle: Throttle::default() }
+    }
+
+    pub fn inc(&mut self) {
+        self.current += 1;
+        if self.throttle.ready(self.current >= self.total) {
+            let total = self.total.max(self.current);
+            let text = format!("{} {}/{}", self.label, self.current, total);
+            logging::progress(&Progress { label: self.label, current: self.current, total, text: &text });
+        }
+    }
+}
+
+/// `mm:ss`, or `h:mm:ss` from an hour up.
+fn format_eta(eta: Duration) -> String {
+    let secs = eta.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs % 3600 / 60, secs % 60)
+    } else {
+        format!("{:02}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+/// The progress of the whole run for `--progress-style pipeline` and `both`, from the
+/// progress lines of its phases. Each phase is weighted by roughly how much of a typical
+/// run it takes, decompressing and copying most of it. A phase that doesn't draw a line,
+/// like a fetch with nothing new, counts as done once a later one starts.
+struct Pipeline {
+    style: ProgressStyle,
+    /// The label of each phase's progress line and its weight, in the order they run.
+    stages: Vec<(&'static str, u32)>,
+    /// The phase drawing now, and how much of it is done.
+    stage: usize,
+    fraction: f64,
+    /// How much of the copy `--verify` read back so far. It reads each file back right
+    /// after writing it, so its phase runs alongside the copy instead of after it.
+    verified: f64,
+}
+
+impl Pipeline {
+    /// How much of the whole run is done, from 0 to 100.
+    fn percentage(&self) -> f64 {
+        let total: u32 = self.stages.iter().map(|(_, weight)| weight).sum();
+        let done: u32 = self.stages[..self.stage].iter().map(|(_, weight)| weight).sum();
+        let verifying: u32 = self.stages[self.stage + 1..].iter().filter(|(label, _)| *label == VERIFYING).map(|(_, weight)| weight).sum();
+        let verified = verifying as f64 * self.verified;
+        (done as f64 + self.stages[self.stage].1 as f64 * self.fraction + verified) / total.max(1) as f64 * 100.0
+    }
+
+    /// Moves on to the phase whose line `progress` is, and returns whether it is one of
+    /// the run's phases at all. Lines of other counts in a phase, like the directories of a
+    /// copy, leave it where it is.
+    fn update(&mut self, progress: &Progress) -> bool {
+        let Some(stage) = self.stages.iter().position(|(label, _)| progress.label.starts_with(label)) else {
+            return false;
+        };
+        if progress.label == VERIFYING && stage > self.stage {
+            self.verified = fraction(progress);
+            return true;
+        }
+        // A later `--target` starts over at an earlier phase, the run doesn't go back.
+        if stage < self.stage || progress.label != self.stages[stage].0 {
+            return true;
+        }
+        self.stage = stage;
+        self.fraction = fraction(progress);
+        true
+    }
+}
+
+/// How much of its phase `progress` is, from 0 to 1.
+fn fraction(progress: &Progress) -> f64 {
+    if progress.total > 0 { (progress.current as f64 / progress.total as f64).min(1.0) } else { 0.0 }
+}
+
+static PIPELINE: Mutex<Option<Pipeline>> = Mutex::new(None);
+
+/// The label of the progress line `--verify` draws as it reads the copy back.
+pub(crate) const VERIFYING: &str = "Verifying";
+
+/// Starts counting the run's progress over the phases `options` has it go through, for
+/// `--progress-style`. Nothing changes with the default, a line per phase.
+pub fn start_pipeline(options: &Options) {
+    let mut stages = Vec::new();
+    if !(options.build_only || options.offline || options.pack.is_some()) {
+        stages.push(("Downloading", 15));
+    }
+    if options.dest_dir.is_none() && !options.format {
+        stages.push(("Decompressing", 35));
+    }
+    stages.push(("Copying", 40));
+    if options.verify {
+        stages.push((VERIFYING, 20));
+    }
+    if options.compress_output.is_some() {
+        stages.push(("Compressing", 10));
+    }
+    let pipeline = (options.progress_style != ProgressStyle::Phases).then_some(Pipeline { style: options.progress_style, stages, stage: 0, fraction: 0.0, verified: 0.0 });
+    *PIPELINE.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = pipeline;
+}
+
+/// The line to draw for `progress` with `--progress-style`, `None` for its own.
+pub(crate) fn pipeline_text(progress: &Progress) -> Option<String> {
+    let mut pipeline = PIPELINE.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let pipeline = pipeline.as_mut()?;
+    // Like a `verify`, which isn't part of the build's pipeline.
+    if !pipeline.update(progress) {
+        return None;
+    }
+    let (label, _) = pipeline.stages[pipeline.stage];
+    Some(match pipeline.style {
+        ProgressStyle::Both => format!("Pipeline {:.0}% | {}", pipeline.percentage(), progress.text),
+        _ => format!("Pipeline {:.0}% \u{2014} {}", pipeline.percentage(), label),
+    })
+}
@@ -0,0 +1,190 @@
+//! `--repair-image`: recovers what can still be read from an image at `--output` whose FAT
+//! or directories were damaged, say by a write that was cut off, and builds a fresh image
+//! from it, instead of downloading and building everything again.
+//!
+//! The files are read off the damaged image through fatfs, going on past whatever can't be
+//! read, the same way `--preserve` copies directories off an image. A file that can't be
+//! read to its full size is lost, and so is the rest of a directory that can't be listed.
+//! The recovered files are then built into a new image like any source. This is a
+//! heuristic: a file whose clusters were handed to another one reads back fine but holds
+//! the other's data, so check what matters after a repair. The damaged image is kept next
+//! to the new one as `<output>.damaged`.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use fatfs::{FileSystem, ReadWriteSeek};
+
+use crate::error::UpdateError;
+use crate::image::{build, open_fs_region};
+use crate::options::Options;
+use crate::scratch::ScratchDir;
+use crate::timestamps;
+use crate::units::format_bytes;
+use crate::vhd;
+use crate::{info, partition, warn};
+
+/// The most entries a FAT directory can hold, so a directory whose clusters loop back on
+/// themselves is given up on instead of listed forever.
+const MAX_DIR_ENTRIES: usize = 65536;
+
+/// What `repair_image` got off the damaged image.
+#[derive(Debug, Default)]
+pub struct Repaired {
+    /// Files read back in full.
+    pub recovered: u64,
+    pub bytes: u64,
+    /// Paths on the card that couldn't be, with why.
+    pub lost: Vec<String>,
+}
+
+/// Reads the file `entry` into `host_path`. Returns why it is lost if it can't be read in
+/// full; only errors writing `host_path` fail.
+fn recover_file<IO: ReadWriteSeek, TP: fatfs::TimeProvider, OCC: fatfs::OemCpConverter>(
+    entry: &fatfs::DirEntry<IO, TP, OCC>,
+    host_path: &Path,
+    buffer: &mut [u8],
+) -> Result<Option<String>, UpdateError> {
+    let mut sd_file = entry.to_file();
+    let mut file = File::create(host_path)?;
+    let mut read = 0;
+    loop {
+        let bytes_read = match fatfs::Read::read(&mut sd_file, buffer) {
+            Ok(bytes_read) => bytes_read,
+            Err(e) => return Ok(Some(format!("unreadable after {}: {:?}", format_bytes(read), e))),
+        };
+        if bytes_read == 0 {
+            break;
+        }
+        file.write_all(&buffer[..bytes_read])?;
+        read += bytes_read as u64;
+    }
+    if read != entry.len() {
+        return Ok(Some(format!("only {} of {} could be read", format_bytes(read), format_bytes(entry.len()))));
+    }
+    file.set_modified(timestamps::from_fat_datetime(entry.modified()))?;
+    Ok(None)
+}
+
+/// Recovers everything under `sd_folder`, at `card_path` on the card, into `host_path`.
+fn recover_dir<IO: ReadWriteSeek, TP: fatfs::TimeProvider, OCC: fatfs::OemCpConverter>(
+    sd_folder: &fatfs::Dir<IO, TP, OCC>,
+    card_path: &str,
+    host_path: &Path,
+    buffer: &mut [u8],
+    repaired: &mut Repaired,
+) -> Result<(), UpdateError> {
+    std::fs::create_dir_all(host_path)?;
+    for (index, entry) in sd_folder.iter().enumerate() {
+        if index == MAX_DIR_ENTRIES {
+            repaired.lost.push(format!("{}* (the directory runs on past what FAT allows)", card_path));
+            break;
+        }
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                repaired.lost.push(format!("{}* (the rest of the directory can't be listed: {:?})", card_path, e));
+                break;
+            }
+        };
+        let name = entry.file_name();
+        if name == "." || name == ".." {
+            continue;
+        }
+        let path = format!("{}{}", card_path, name);
+        // A build leaves them out, so they wouldn't make it onto the new image anyway.
+        if name.starts_with('.') {
+            repaired.lost.push(format!("{} (a build doesn't copy dotfiles)", path));
+            continue;
+        }
+        if entry.is_dir() {
+            recover_dir(&entry.to_dir(), &format!("{}/", path), &host_path.join(&name), buffer, repaired)?;
+            continue;
+        }
+        match recover_file(&entry, &host_path.join(&name), buffer)? {
+            None => {
+                repaired.recovered += 1;
+                repaired.bytes += entry.len();
+            }
+            Some(reason) => {
+                std::fs::remove_file(host_path.join(&name))?;
+                repaired.lost.push(format!("{} ({})", path, reason));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The options the recovered files are built with: the image's own, without anything that
+/// adds to or picks from the source, which the recovered files already are the result of.
+fn rebuild_options(options: &Options) -> Options {
+    let mut rebuild = options.clone();
+    rebuild.repair_image = false;
+    rebuild.incremental = false;
+    rebuild.overlays.clear();
+    rebuild.add_files.clear();
+    rebuild.targets.clear();
+    rebuild.exclude.clear();
+    rebuild.empty_dirs.clear();
+    rebuild.newer_than = None;
+    rebuild.older_than = None;
+    rebuild.source_subdir = None;
+    rebuild.target_dir = None;
+    rebuild.include_manifest_in_image = None;
+    rebuild.require_signature = None;
+    rebuild.require_signed_commit = false;
+    rebuild.pre_build_hook = None;
+    rebuild.post_build_hook = None;
+    rebuild
+}
+
+/// Recovers the files of the damaged image at `--output` and builds a fresh image there
+/// from them, keeping the damaged one as `<output>.damaged`.
+pub fn repair_image(options: &Options) -> Result<Repaired, UpdateError> {
+    let output = options.output();
+    if !output.is_file() {
+        return Err(UpdateError::Other(format!("--repair-image needs the damaged image at --output, {} isn't one", output.display())));
+    }
+    vhd::strip(options)?;
+    let offset = if options.partitioned { partition::PARTITION_OFFSET } else { 0 };
+    let (fs_region, image_size) = open_fs_region(options, offset)?;
+    let fs_options = fatfs::FsOptions::new().oem_cp_converter(options.code_page);
+    let fs = FileSystem::new(fs_region, fs_options).map_err(|e| {
+        UpdateError::Other(format!(
+            "{} can't be mounted at all ({:?}), there is nothing to recover from it. Build it again without --repair-image",
+            output.display(),
+            e
+        ))
+    })?;
+    // The FAT may be what is damaged, so the free space it gives is only a guess.
+    let needed = fs.stats().map(|stats| (stats.total_clusters() - stats.free_clusters()) as u64 * stats.cluster_size() as u64).unwrap_or(image_size);
+    let mut scratch = ScratchDir::create("repair", needed)?;
+    let mut repaired = Repaired::default();
+    info(format!("Recovering the files of {}\n", output.display()).as_str());
+    recover_dir(&fs.root_dir(), "", scratch.path(), &mut vec![0_u8; 1024 * 1024], &mut repaired)?;
+    drop(fs);
+    for lost in &repaired.lost {
+        warn(format!("Lost: {}\n", lost).as_str());
+    }
+    info(format!(
+        "Recovered {} files ({}), lost {}\n",
+        repaired.recovered,
+        format_bytes(repaired.bytes),
+        repaired.lost.len()
+    ).as_str());
+
+    let mut damaged = output.clone().into_os_string();
+    damaged.push(".damaged");
+    let damaged = PathBuf::from(damaged);
+    std::fs::rename(&output, &damaged)?;
+    if let Err(e) = build(scratch.path(), &rebuild_options(options)) {
+        // Back to where it was, a half built image is no better than the damaged one.
+        std::fs::rename(&damaged, &output)?;
+        scratch.keep();
+        warn(format!("The recovered files are kept in {}\n", scratch.path().display()).as_str());
+        return Err(e);
+    }
+    info(format!("Rebuilt {} from the recovered files, the damaged image is kept as {}\n", output.display(), damaged.display()).as_str());
+    Ok(repaired)
+}
@@ -0,0 +1,165 @@
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::options::HashAlgorithm;
+use crate::scratch;
+
+/// Totals collected over the run for the `--quiet` summary.
+#[derive(Debug, Default)]
+pub struct RunReport {
+    pub files_copied: u64,
+    pub bytes_written: u64,
+    /// Zero-byte files `--skip-empty-files` left out.
+    pub empty_files_skipped: u64,
+    /// Files `--newer-than` and `--older-than` left out.
+    pub files_filtered_by_age: u64,
+    /// Summary, author and date of the commit `sd_source` is at after the update.
+    pub commit_summary: Option<String>,
+    pub commit_author: Option<String>,
+    pub commit_date: Option<String>,
+    /// `--image-hash` of the finished build, and the `--hash` algorithm it was taken with.
+    pub image_hash: Option<String>,
+    pub image_hash_algorithm: Option<&'static str>,
+    /// Time spent in each phase, in the order they ran.
+    pub phases: Vec<(&'static str, Duration)>,
+    /// Bytes written under `--write-throttle`, how long that took and how much of it was
+    /// spent waiting on the throttle.
+    pub throttled_writes: Option<(u64, Duration, Duration)>,
+    /// Whether `--lock-output` left the image read-only.
+    pub output_locked: bool,
+}
+
+static REPORT: Mutex<RunReport> = Mutex::new(RunReport {
+    files_copied: 0,
+    bytes_written: 0,
+    empty_files_skipped: 0,
+    files_filtered_by_age: 0,
+    commit_summary: None,
+    commit_author: None,
+    commit_date: None,
+    image_hash: None,
+    image_hash_algorithm: None,
+    phases: Vec::new(),
+    throttled_writes: None,
+    output_locked: false,
+});
+
+/// Starts the totals over, for another attempt at the run whose summary they go into.
+pub fn reset() {
+    *REPORT.lock().unwrap() = RunReport::default();
+}
+
+pub fn record_phase(name: &'static str, elapsed: Duration) {
+    REPORT.lock().unwrap().phases.push((name, elapsed));
+}
+
+/// The phases recorded so far, in the order they ran.
+pub fn phases() -> Vec<(&'static str, Duration)> {
+    REPORT.lock().unwrap().phases.clone()
+}
+
+pub fn record_copy(bytes: u64) {
+    let mut report = REPORT.lock().unwrap();
+    report.files_copied += 1;
+    report.bytes_written += bytes;
+}
+
+pub fn record_empty_files_skipped(count: u64) {
+    REPORT.lock().unwrap().empty_files_skipped += count;
+}
+
+pub fn record_files_filtered_by_age(count: u64) {
+    REPORT.lock().unwrap().files_filtered_by_age += count;
+}
+
+pub fn record_throttled_writes(bytes: u64, elapsed: Duration, waited: Duration) {
+    let mut report = REPORT.lock().unwrap();
+    let (total, total_elapsed, total_waited) = report.throttled_writes.get_or_insert((0, Duration::ZERO, Duration::ZERO));
+    *total += bytes;
+    *total_elapsed += elapsed;
+    *total_waited += waited;
+}
+
+pub fn record_head(summary: &str, author: &str, date: &str) {
+    let mut report = REPORT.lock().unwrap();
+    report.commit_summary = Some(summary.to_string());
+    report.commit_author = Some(author.to_string());
+    report.commit_date = Some(date.to_string());
+}
+
+pub fn record_image_hash(hash: &str, algorithm: HashAlgorithm) {
+    let mut report = REPORT.lock().unwrap();
+    report.image_hash = Some(hash.to_string());
+    report.image_hash_algorithm = Some(algorithm.name());
+}
+
+pub fn record_output_locked() {
+    REPORT.lock().unwrap().output_locked = true;
+}
+
+/// Renders the final record as one line of JSON.
+pub fn to_json(updated: bool, commit: Option<&str>, exit_code: i32, error: Option<&str>) -> String {
+    let report = REPORT.lock().unwrap();
+    let phases: Vec<String> = report
+        .phases
+        .iter()
+        .map(|(name, elapsed)| format!("{}:{:.3}", json_string(name), elapsed.as_secs_f64()))
+        .collect();
+    // Leaves `write_rate` and `write_throttled` out without `--write-throttle`.
+    let throttled = match report.throttled_writes {
+        Some((bytes, elapsed, waited)) => format!(
+            ",\"write_rate\":{},\"write_throttled\":{:.3}",
+            if elapsed.is_zero() { 0 } else { (bytes as f64 / elapsed.as_secs_f64()) as u64 },
+            waited.as_secs_f64()
+        ),
+        None => String::new(),
+    };
+    format!(
+        "{{\"updated\":{},\"commit\":{},\"commit_summary\":{},\"commit_author\":{},\"commit_date\":{},\"files_copied\":{},\"bytes_written\":{},\"empty_files_skipped\":{},\"files_filtered_by_age\":{},\"image_hash\":{},\"image_hash_algorithm\":{},\"phases\":{{{}}}{},\"output_locked\":{},\"exit_code\":{},\"error\":{}}}",
+        updated,
+        commit.map(json_string).unwrap_or_else(|| "null".to_string()),
+        optional_json_string(&report.commit_summary),
+        optional_json_string(&report.commit_author),
+        optional_json_string(&report.commit_date),
+        report.files_copied,
+        report.bytes_written,
+        report.empty_files_skipped,
+        report.files_filtered_by_age,
+        optional_json_string(&report.image_hash),
+        report.image_hash_algorithm.map(json_string).unwrap_or_else(|| "null".to_string()),
+        phases.join(","),
+        throttled,
+        report.output_locked,
+        exit_code,
+        error.map(json_string).unwrap_or_else(|| "null".to_string()),
+    )
+}
+
+/// Writes `contents` and a newline to `path` through a temporary file, so a wrapper
+/// reading it never sees half a summary.
+pub fn write_atomically(path: &Path, contents: &str) -> std::io::Result<()> {
+    scratch::write_atomically(path, format!("{}\n", contents).as_bytes())
+}
+
+fn optional_json_string(value: &Option<String>) -> String {
+    value.as_deref().map(json_string).unwrap_or_else(|| "null".to_string())
+}
+
+pub(crate) fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
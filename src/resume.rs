@@ -0,0 +1,237 @@
+//! Resuming a build that was interrupted, from one checkpoint file next to the output,
+//! `<output>.checkpoint`. It says how far the build got, and the next run picks it up at
+//! the furthest phase it can trust:
+//!
+//! - `source`: `sd_source` was cloned or pulled, but the image wasn't built from it yet.
+//!   The next run builds even when upstream has nothing new, instead of calling an image
+//!   it never finished up to date.
+//! - `decompressing`: every time the decompression syncs the image, it records how much of
+//!   it is on disk. The next run keeps that much and decompresses the rest, if `sd.xz` lets
+//!   it start part way in, see `xzseek.rs`.
+//! - `copying`: the image holds a complete base image and some of the build. The next run
+//!   copies into it like `--incremental` instead of starting over: files already on the
+//!   card with the right size and modification time are skipped, and one that was only
+//!   partly written has the wrong size and is copied again.
+//!
+//! A phase is only resumed while what it was made from still matches: the output and its
+//! size, the size and modification time of `sd.xz` (`asset.rs` checks its hash), and for
+//! the copy the commit of `sd_source`. Otherwise the build starts over. The checkpoint is
+//! removed once the build finished. A `--dest-dir` build is never resumed and keeps no
+//! checkpoint, it would only be left next to the card's mount point.
+
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+use crate::image::sd_xz_path;
+use crate::options::Options;
+
+/// Where the checkpoint goes for a `--device`, which has no directory to put it next to.
+pub const DEVICE_CHECKPOINT_FILE: &str = ".updater.checkpoint";
+
+/// How far an interrupted build got, see above.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Phase {
+    #[default]
+    Source,
+    Decompressing,
+    Copying,
+}
+
+impl Phase {
+    fn name(self) -> &'static str {
+        match self {
+            Phase::Source => "source",
+            Phase::Decompressing => "decompressing",
+            Phase::Copying => "copying",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Phase> {
+        [Phase::Source, Phase::Decompressing, Phase::Copying].into_iter().find(|phase| phase.name() == name)
+    }
+}
+
+/// The checkpoint file, as `key=value` lines.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Checkpoint {
+    output: String,
+    sd_size: u64,
+    phase: Phase,
+    /// `<size>:<modification time>` of the `sd.xz` on disk, unset for the embedded one.
+    sd_xz: Option<String>,
+    /// What the copy was copying.
+    commit: Option<String>,
+    /// Where in the output the filesystem starts.
+    offset: u64,
+    /// How much of the image the decompression synced to disk.
+    decompressed: u64,
+    /// How much the copy copied before it stopped with an error. Not known when the
+    /// process was killed.
+    copied: Option<u64>,
+}
+
+impl Checkpoint {
+    /// A fresh checkpoint for the build into `--output`.
+    fn new(options: &Options, phase: Phase) -> Checkpoint {
+        Checkpoint { output: options.output().display().to_string(), sd_size: options.sd_size(), phase, sd_xz: sd_xz_stamp(options), ..Checkpoint::default() }
+    }
+
+    fn parse(contents: &str) -> Option<Checkpoint> {
+        let mut checkpoint = Checkpoint::default();
+        for line in contents.lines() {
+            match line.split_once('=')? {
+                ("output", value) => checkpoint.output = value.to_string(),
+                ("sd_size", value) => checkpoint.sd_size = value.parse().ok()?,
+                ("phase", value) => checkpoint.phase = Phase::parse(value)?,
+                ("sd_xz", value) => checkpoint.sd_xz = Some(value.to_string()),
+                ("commit", value) => checkpoint.commit = Some(value.to_string()),
+                ("offset", value) => checkpoint.offset = value.parse().ok()?,
+                ("decompressed", value) => checkpoint.decompressed = value.parse().ok()?,
+                ("copied", value) => checkpoint.copied = Some(value.parse().ok()?),
+                _ => {}
+            }
+        }
+        Some(checkpoint)
+    }
+
+    fn render(&self) -> String {
+        let mut rendered = format!("output={}\nsd_size={}\nphase={}\n", self.output, self.sd_size, self.phase.name());
+        if let Some(sd_xz) = &self.sd_xz {
+            rendered.push_str(&format!("sd_xz={}\n", sd_xz));
+        }
+        if let Some(commit) = &self.commit {
+            rendered.push_str(&format!("commit={}\n", commit));
+        }
+        match self.phase {
+            Phase::Source => {}
+            Phase::Decompressing => rendered.push_str(&format!("offset={}\ndecompressed={}\n", self.offset, self.decompressed)),
+            Phase::Copying => {
+                if let Some(copied) = self.copied {
+                    rendered.push_str(&format!("copied={}\n", copied));
+                }
+            }
+        }
+        rendered
+    }
+}
+
+/// Where the checkpoint goes, none for a `--dest-dir`.
+fn checkpoint_path(options: &Options) -> Option<PathBuf> {
+    if options.dest_dir.is_some() {
+        return None;
+    }
+    if options.device.is_some() {
+        return Some(PathBuf::from(DEVICE_CHECKPOINT_FILE));
+    }
+    let mut path = options.output().into_os_string();
+    path.push(".checkpoint");
+    Some(PathBuf::from(path))
+}
+
+/// The size and modification time of the `sd.xz` on disk. The embedded one can't change
+/// without the updater.
+fn sd_xz_stamp(options: &Options) -> Option<String> {
+    let metadata = std::fs::metadata(sd_xz_path(options)?).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_nanos();
+    Some(format!("{}:{}", metadata.len(), modified))
+}
+
+/// The checkpoint of an interrupted build into the same output, with the same image size.
+fn load(options: &Options) -> Option<Checkpoint> {
+    let checkpoint = Checkpoint::parse(&std::fs::read_to_string(checkpoint_path(options)?).ok()?)?;
+    (checkpoint.output == options.output().display().to_string() && checkpoint.sd_size == options.sd_size()).then_some(checkpoint)
+}
+
+fn save(options: &Options, checkpoint: &Checkpoint) -> std::io::Result<()> {
+    match checkpoint_path(options) {
+        Some(path) => std::fs::write(path, checkpoint.render()),
+        None => Ok(()),
+    }
+}
+
+/// Records that `sd_source` was cloned or pulled and the image is about to be built from it.
+pub fn source_ready(options: &Options) -> std::io::Result<()> {
+    save(options, &Checkpoint::new(options, Phase::Source))
+}
+
+/// Whether a build into the output was interrupted, in whatever phase.
+pub fn pending(options: &Options) -> bool {
+    load(options).is_some()
+}
+
+/// Whether the last build into the output was interrupted copying `commit`, into an image
+/// decompressed from the `sd.xz` in use.
+pub fn can_resume(options: &Options, commit: Option<&str>) -> bool {
+    options.output().exists()
+        && load(options).is_some_and(|checkpoint| {
+            checkpoint.phase == Phase::Copying && checkpoint.sd_xz == sd_xz_stamp(options) && checkpoint.commit.as_deref() == commit
+        })
+}
+
+/// How much the interrupted copy had copied, if it stopped with an error that said so.
+pub fn copied_before(options: &Options) -> Option<u64> {
+    load(options)?.copied
+}
+
+/// Records that the output now holds a complete base image the copy of `commit` can
+/// resume into.
+pub fn start(options: &Options, commit: Option<&str>) -> std::io::Result<()> {
+    save(options, &Checkpoint { commit: commit.map(str::to_string), ..Checkpoint::new(options, Phase::Copying) })
+}
+
+/// Records how much the copy copied before it stopped.
+pub fn stopped_copying(options: &Options, copied: u64) -> std::io::Result<()> {
+    match load(options) {
+        Some(checkpoint) if checkpoint.phase == Phase::Copying => save(options, &Checkpoint { copied: Some(copied), ..checkpoint }),
+        _ => Ok(()),
+    }
+}
+
+/// Drops the checkpoint, the build finished.
+pub fn finish(options: &Options) -> std::io::Result<()> {
+    let Some(path) = checkpoint_path(options) else {
+        return Ok(());
+    };
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// How many bytes of the image, from `offset` on, an interrupted decompression into the
+/// same output got onto disk from the same `sd.xz`, as long as that is within `--sd-size`.
+pub fn decompressed(options: &Options, offset: u64) -> Option<u64> {
+    let checkpoint = load(options)?;
+    if checkpoint.phase != Phase::Decompressing || checkpoint.offset != offset || checkpoint.sd_xz.is_none() || checkpoint.sd_xz != sd_xz_stamp(options) {
+        return None;
+    }
+    // Recorded before anything checks that sd.xz fits --sd-size, so it can be past the end.
+    if checkpoint.decompressed > options.sd_size() {
+        return None;
+    }
+    // A device keeps its size whatever was written to it.
+    let on_disk = match options.device {
+        Some(_) => u64::MAX,
+        None => std::fs::metadata(options.output()).ok()?.len(),
+    };
+    (on_disk >= offset + checkpoint.decompressed).then_some(checkpoint.decompressed)
+}
+
+/// Records that `reached` bytes of the image are synced to disk. The embedded `sd.xz`
+/// can't be read part way in, so there is nothing to record for it.
+pub fn checkpoint(options: &Options, offset: u64, reached: u64) -> std::io::Result<()> {
+    let checkpoint = Checkpoint::new(options, Phase::Decompressing);
+    if checkpoint.sd_xz.is_none() {
+        return Ok(());
+    }
+    save(options, &Checkpoint { offset, decompressed: reached, ..checkpoint })
+}
+
+/// Drops the decompression's progress, it finished or starts over. The build is still
+/// pending until it finished too.
+pub fn forget_decompression(options: &Options) -> std::io::Result<()> {
+    match load(options) {
+        Some(checkpoint) if checkpoint.phase != Phase::Decompressing => Ok(()),
+        _ => source_ready(options),
+    }
+}
@@ -0,0 +1,87 @@
+//! FAT12 and FAT16 keep the root directory in a fixed region, 512 entries on most images,
+//! and a name that isn't a plain upper-case 8.3 name takes extra entries for its long
+//! name. Once the region is full fatfs can only report that there is no space left, which
+//! is hard to make sense of on an image with plenty of free clusters. The root is checked
+//! up front instead, so a build with too much at the top level fails before copying.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::error::UpdateError;
+use crate::wipe::read_layout;
+
+/// Characters of a long name each long name entry holds.
+const LFN_CHARS: usize = 13;
+
+/// Whether fatfs can store `name` in a short entry alone.
+fn is_short_name(name: &str) -> bool {
+    let valid = |part: &str, max: usize| {
+        !part.is_empty()
+            && part.len() <= max
+            && part.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || "!#$%&'()-@^_`{}~".contains(c))
+    };
+    match name.split_once('.') {
+        Some((base, extension)) => valid(base, 8) && valid(extension, 3),
+        None => valid(name, 8),
+    }
+}
+
+/// Directory entries `name` takes.
+fn entries_for(name: &str) -> usize {
+    if is_short_name(name) {
+        1
+    } else {
+        1 + name.encode_utf16().count().div_ceil(LFN_CHARS)
+    }
+}
+
+/// How full the fixed root directory of a FAT12 or FAT16 filesystem is.
+pub struct RootUsage {
+    fat_type: fatfs::FatType,
+    capacity: usize,
+    used: usize,
+}
+
+/// Reads how full the root directory of the filesystem starting `offset` bytes into
+/// `image` is, or `None` for FAT32, which keeps its root directory in clusters like any
+/// other directory. Reads `image` directly, so it has to happen before fatfs mounts it.
+pub fn usage(image: &mut File, offset: u64) -> std::io::Result<Option<RootUsage>> {
+    let layout = read_layout(image, offset)?;
+    if layout.fat_type == fatfs::FatType::Fat32 {
+        return Ok(None);
+    }
+    let mut region = vec![0_u8; layout.root_dir_bytes as usize];
+    image.seek(SeekFrom::Start(layout.root_dir_start))?;
+    image.read_exact(&mut region)?;
+    // Deleted entries (0xE5) can be reused, free ones (0x00) are all at the end.
+    let used = region.chunks(32).filter(|entry| entry[0] != 0x00 && entry[0] != 0xE5).count();
+    Ok(Some(RootUsage { fat_type: layout.fat_type, capacity: region.len() / 32, used }))
+}
+
+/// Checks that the root directory has room for `names`, the top-level files and
+/// directories of the build. `existing` are the lowercased names already in it, which are
+/// reused.
+pub fn check_capacity<'a>(usage: &RootUsage, names: impl Iterator<Item = &'a str>, existing: &HashSet<String>) -> Result<(), UpdateError> {
+    let mut added = HashSet::new();
+    let mut needed = 0;
+    for name in names {
+        if !existing.contains(&name.to_lowercase()) && added.insert(name.to_lowercase()) {
+            needed += entries_for(name);
+        }
+    }
+    if usage.used + needed <= usage.capacity {
+        return Ok(());
+    }
+    let fat_type = if usage.fat_type == fatfs::FatType::Fat12 { "FAT12" } else { "FAT16" };
+    Err(UpdateError::DiskFull(format!(
+        "The root directory of this {} image has room for {} entries, {} are taken and the {} files and \
+         directories the build adds at the top level need {} more, counting their long names. \
+         Use a FAT32 image or move some of them into subdirectories",
+        fat_type,
+        usage.capacity,
+        usage.used,
+        added.len(),
+        needed
+    )))
+}
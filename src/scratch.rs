@@ -0,0 +1,104 @@
+//! `--tmpdir`: where scratch space comes from. That is the directories `--preserve` and
+//! `--bench` work in, and files that are renamed into place once they are complete. Unset
+//! means the system temp directory, which is a small tmpfs on some systems.
+//!
+//! It is best on the same filesystem as the output, since a rename can't cross
+//! filesystems. Files that are renamed into place are staged next to their destination
+//! instead when it isn't.
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::error::UpdateError;
+use crate::units::format_bytes;
+
+static TMPDIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+pub fn set_tmpdir(dir: Option<PathBuf>) {
+    *TMPDIR.lock().unwrap() = dir;
+}
+
+fn configured() -> Option<PathBuf> {
+    TMPDIR.lock().unwrap().clone()
+}
+
+/// `--tmpdir`, or the system temp directory.
+pub fn tmpdir() -> PathBuf {
+    configured().unwrap_or_else(std::env::temp_dir)
+}
+
+/// Fails up front if `dir` can't hold `needed` more bytes. Filesystems that don't say how
+/// much room they have are given the benefit of the doubt.
+fn check_space(dir: &Path, needed: u64) -> Result<(), UpdateError> {
+    match fs2::available_space(dir) {
+        Ok(available) if available < needed => Err(UpdateError::DiskFull(format!(
+            "{} has {} free but {} of scratch space is needed, point --tmpdir somewhere with more room",
+            dir.display(),
+            format_bytes(available),
+            format_bytes(needed)
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// A scratch directory. It is removed with everything in it when dropped, also when an
+/// error unwinds past it, unless `keep` was called.
+pub struct ScratchDir {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl ScratchDir {
+    /// Creates an empty scratch directory for up to `needed` bytes in `--tmpdir`.
+    pub fn create(name: &str, needed: u64) -> Result<ScratchDir, UpdateError> {
+        let base = tmpdir();
+        std::fs::create_dir_all(&base)?;
+        check_space(&base, needed)?;
+        let path = base.join(format!("dolphin_auto_updater_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path)?;
+        Ok(ScratchDir { path, keep: false })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Leaves the directory behind when dropped.
+    pub fn keep(&mut self) {
+        self.keep = true;
+    }
+
+    /// Removes the directory when dropped after all.
+    pub fn discard(&mut self) {
+        self.keep = false;
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+}
+
+/// Writes `contents` to `path` through a temporary file that is renamed over it, so a
+/// reader never sees half of it.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+    if let Some(dir) = configured() {
+        let temp = dir.join(format!("{}.{}.tmp", name, std::process::id()));
+        std::fs::write(&temp, contents)?;
+        if std::fs::rename(&temp, path).is_ok() {
+            return Ok(());
+        }
+        // Most likely on another filesystem than `path`.
+        let _ = std::fs::remove_file(&temp);
+    }
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    std::fs::write(&temp, contents)?;
+    std::fs::rename(&temp, path)
+}
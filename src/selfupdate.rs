@@ -0,0 +1,170 @@
+//! `--self-update`: replaces the updater binary with the latest release of itself.
+//!
+//! The release has to carry the binary for this platform (`dolphin_auto_updater` or
+//! `dolphin_auto_updater.exe`) and a `<binary>.blake3` with its hash, in the format `b3sum`
+//! prints. With `--require-signature` it also needs a `<binary>.sig` signed by the pinned
+//! key. A running executable can't be overwritten on Windows, but it can be renamed, so
+//! the old binary is moved aside to `<exe>.old` and the new one put in its place. The
+//! next run starts the new binary and removes the old one.
+
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::error::UpdateError;
+use crate::hash::ContentHasher;
+use crate::options::{HashAlgorithm, Options};
+use crate::{debug, info, signature, warn};
+
+/// The release JSON of the newest updater release.
+pub const DEFAULT_RELEASES_URL: &str = "https://api.github.com/repos/STulling/DolphinAutoUpdater/releases/latest";
+
+/// The updater's own version, as released.
+const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+fn asset_name() -> String {
+    format!("dolphin_auto_updater{}", std::env::consts::EXE_SUFFIX)
+}
+
+fn old_binary(exe: &Path) -> PathBuf {
+    let mut name = exe.as_os_str().to_owned();
+    name.push(".old");
+    PathBuf::from(name)
+}
+
+/// Removes the binary an earlier `--self-update` moved aside, once it isn't running anymore.
+pub fn remove_old_binary() {
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => {
+            debug(format!("Can't tell where the updater binary is to look for an old one: {}\n", e).as_str());
+            return;
+        }
+    };
+    let old = old_binary(&exe);
+    if old.exists() {
+        match std::fs::remove_file(&old) {
+            Ok(()) => debug(format!("Removed {} left by the last self-update\n", old.display()).as_str()),
+            Err(e) => debug(format!("Can't remove {} yet: {}\n", old.display(), e).as_str()),
+        }
+    }
+}
+
+/// `1.2.3` as numbers, ignoring a leading `v` and anything after a `-`.
+fn parse_version(version: &str) -> Vec<u64> {
+    version
+        .trim()
+        .trim_start_matches('v')
+        .split('-')
+        .next()
+        .unwrap_or_default()
+        .split('.')
+        .map(|part| part.parse().unwrap_or(0))
+        .collect()
+}
+
+fn is_newer(available: &str, current: &str) -> bool {
+    parse_version(available) > parse_version(current)
+}
+
+fn get(url: &str) -> Result<ureq::Response, UpdateError> {
+    ureq::get(url)
+        .set("User-Agent", concat!("dolphin_auto_updater/", env!("CARGO_PKG_VERSION")))
+        .call()
+        .map_err(|e| UpdateError::Other(format!("Can't download {}: {}", url, e)))
+}
+
+fn download(url: &str) -> Result<Vec<u8>, UpdateError> {
+    let mut bytes = Vec::new();
+    get(url)?.into_reader().read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// The download URL of the release asset called `name`, if there is one.
+fn asset_url(release: &serde_json::Value, name: &str) -> Option<String> {
+    release["assets"]
+        .as_array()?
+        .iter()
+        .find(|asset| asset["name"].as_str() == Some(name))
+        .and_then(|asset| asset["browser_download_url"].as_str())
+        .map(str::to_string)
+}
+
+/// Downloads `name` from the release, along with its checksum and, if a key is pinned, its
+/// signature, and checks it against both.
+fn download_verified(release: &serde_json::Value, name: &str, options: &Options) -> Result<Vec<u8>, UpdateError> {
+    let missing = |what: &str| UpdateError::UntrustedSource(format!("The release has no {}, refusing to install it", what));
+    let binary_url = asset_url(release, name).ok_or_else(|| missing(name))?;
+    let checksum_name = format!("{}.blake3", name);
+    let checksum_url = asset_url(release, &checksum_name).ok_or_else(|| missing(&checksum_name))?;
+
+    info(format!("Downloading {}\n", binary_url).as_str());
+    let binary = download(&binary_url)?;
+    let checksum = String::from_utf8_lossy(&download(&checksum_url)?).to_string();
+    let expected = checksum.split_whitespace().next().unwrap_or_default();
+    let mut hasher = ContentHasher::new(HashAlgorithm::Blake3);
+    hasher.update(&binary);
+    if !hasher.finish().eq_ignore_ascii_case(expected) {
+        return Err(UpdateError::UntrustedSource(format!("{} doesn't match the checksum in {}", name, checksum_name)));
+    }
+    debug(format!("{} matches {}\n", name, checksum_name).as_str());
+
+    if let Some(public_key) = &options.require_signature {
+        let sig_name = format!("{}.sig", name);
+        let sig_url = asset_url(release, &sig_name).ok_or_else(|| missing(&sig_name))?;
+        signature::verify_bytes(public_key, &binary, &download(&sig_url)?, name, &sig_name)?;
+        info(format!("{} is signed by the pinned key\n", name).as_str());
+    }
+    Ok(binary)
+}
+
+/// Moves the running binary aside and puts `binary` in its place.
+fn swap_binary(exe: &Path, binary: &[u8]) -> Result<(), UpdateError> {
+    // Written next to the executable first, so a failed download or a full disk never
+    // leaves it half replaced.
+    let mut staged = exe.as_os_str().to_owned();
+    staged.push(".new");
+    let staged = PathBuf::from(staged);
+    std::fs::write(&staged, binary)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o755))?;
+    }
+    let old = old_binary(exe);
+    let _ = std::fs::remove_file(&old);
+    std::fs::rename(exe, &old)?;
+    if let Err(e) = std::fs::rename(&staged, exe) {
+        // Put the old binary back rather than leaving no updater at all.
+        let _ = std::fs::rename(&old, exe);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Checks for a newer release of the updater and installs it. Returns whether it did.
+pub fn self_update(options: &Options) -> Result<bool, UpdateError> {
+    let url = options.self_update_url();
+    info(format!("Checking {} for a newer updater\n", url).as_str());
+    let body = get(&url)?.into_string()?;
+    let release: serde_json::Value = serde_json::from_str(&body)
+        .map_err(|e| UpdateError::Other(format!("{} didn't return a release: {}", url, e)))?;
+    let available = release["tag_name"]
+        .as_str()
+        .ok_or_else(|| UpdateError::Other(format!("The release at {} has no tag_name", url)))?;
+    info(format!("This is version {}, the latest release is {}\n", CURRENT_VERSION, available).as_str());
+    if !is_newer(available, CURRENT_VERSION) {
+        info("The updater is up to date\n");
+        return Ok(false);
+    }
+
+    // Fails if the binary was deleted or moved while running, or where the platform can't
+    // tell. Nothing else depends on it, the other paths are relative to the working directory.
+    let exe = std::env::current_exe().map_err(|e| {
+        UpdateError::Other(format!("Can't tell where the running updater binary is, so it can't be replaced: {}", e))
+    })?;
+    let binary = download_verified(&release, &asset_name(), options)?;
+    swap_binary(&exe, &binary)?;
+    info(format!("Updated {} to {}\n", exe.display(), available).as_str());
+    warn("The new version is used from the next run on\n");
+    Ok(true)
+}
@@ -0,0 +1,164 @@
+//! FAT short (8.3) names for the files the copy writes. fatfs gives every file one: a
+//! name that already is a valid short name keeps it, upper-cased. Any other name is
+//! upper-cased and cut to 8.3, with `_` for what 8.3 doesn't allow, and gets a numeric tail:
+//! the first six characters and the first free number from 1 to 4, `PHOTOS~1.JPG`. Once
+//! those are taken it uses the first two characters, a checksum of the long name and a
+//! number up to 9, `PH3F2A~1.JPG`, and when even those are taken the next checksum, so
+//! creating a file never fails over its short name.
+//!
+//! Which file ends up with which short name does depend on the order they are created in,
+//! and in a directory of similar names most get a checksum name that says nothing about
+//! the file. Dolphin and libfat read the long names, but a reader that only sees the short
+//! ones (see `codepage.rs`) can't tell those files apart. `--on-short-name-collision`
+//! decides whether that is only reported or stops the build.
+//!
+//! Only images are checked: with `--dest-dir` the host's FAT driver makes the short names.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::dirsort::sorted_entries;
+use crate::error::UpdateError;
+use crate::image::{exclusion, FileFilter};
+use crate::longpath::LongPaths;
+use crate::options::{OnShortNameCollision, Options};
+use crate::{debug, info, warn};
+
+/// How many names fatfs numbers after their first six characters before it falls back to
+/// a checksum.
+const NUMBERED_TAILS: usize = 4;
+
+/// A directory on the card and the names copied into it, both keyed by their lowercased
+/// path since overlays merge into the source case-insensitively.
+#[derive(Default)]
+struct CardDir {
+    path: String,
+    names: BTreeMap<String, String>,
+}
+
+/// Copies `src` into a short name part of at most `max` characters like fatfs does, and
+/// returns it with whether all of it fit and whether anything had to be changed.
+fn short_part(src: &str, max: usize) -> (String, bool, bool) {
+    let mut part = String::new();
+    let mut lossy = false;
+    for c in src.chars() {
+        if part.len() == max {
+            return (part, false, lossy);
+        }
+        let fixed = match c {
+            ' ' | '.' => {
+                lossy = true;
+                continue;
+            }
+            'A'..='Z' | 'a'..='z' | '0'..='9' | '!' | '#' | '$' | '%' | '&' | '\'' | '(' | ')' | '-' | '@' | '^' | '_' | '`' | '{'
+            | '}' | '~' => c,
+            _ => '_',
+        };
+        lossy |= fixed != c;
+        part.push(fixed.to_ascii_uppercase());
+    }
+    (part, true, lossy)
+}
+
+/// What fatfs numbers `name` under, `PHOTOS` and `JPG` for `Photos from 2019.jpg`, or
+/// `None` if `name` is a valid short name as it is.
+fn numbered_under(name: &str) -> Option<(String, String)> {
+    // A leading dot doesn't start an extension.
+    let (stem, extension) = match name.rfind('.').filter(|dot| *dot > 0) {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+    let (stem, stem_fits, stem_lossy) = short_part(stem, 8);
+    let (extension, extension_fits, extension_lossy) = short_part(extension, 3);
+    if stem_fits && extension_fits && !stem_lossy && !extension_lossy {
+        return None;
+    }
+    Some((stem.chars().take(6).collect(), extension))
+}
+
+/// Adds the names `recursive_copy` writes copying `host_path` to `prefix` on the card.
+fn collect(
+    host_path: &Path,
+    prefix: &str,
+    exclude: &[PathBuf],
+    long_paths: &LongPaths,
+    filter: &FileFilter,
+    dirs: &mut BTreeMap<String, CardDir>,
+) -> Result<(), UpdateError> {
+    for path in sorted_entries(host_path)? {
+        let path = path?;
+        if exclusion(&path, exclude, long_paths, filter)?.is_some() {
+            continue;
+        }
+        let name = match long_paths.get(&path) {
+            Some(Some(shorter)) => shorter.clone(),
+            _ => path.file_name().unwrap().to_string_lossy().to_string(),
+        };
+        // Follow symlinks, like the copy does.
+        if std::fs::metadata(&path)?.is_dir() {
+            collect(&path, &format!("{}{}/", prefix, name), exclude, long_paths, filter, dirs)?;
+        }
+        let dir = dirs.entry(prefix.to_lowercase()).or_insert_with(|| CardDir { path: format!("/{}", prefix), ..CardDir::default() });
+        dir.names.entry(name.to_lowercase()).or_insert(name);
+    }
+    Ok(())
+}
+
+/// Finds the names under `roots`, the source and then the overlays, that share their
+/// short name with another name in the same directory. With
+/// `--on-short-name-collision=error`, fails after listing all of them.
+pub(crate) fn check(roots: &[&Path], options: &Options, long_paths: &LongPaths) -> Result<(), UpdateError> {
+    let filter = FileFilter::new(options);
+    let exclude: Vec<PathBuf> = options.exclude.iter().map(|path| roots[0].join(path)).collect();
+    let mut dirs = BTreeMap::new();
+    for (index, root) in roots.iter().enumerate() {
+        let exclude = if index == 0 { exclude.as_slice() } else { &[] };
+        collect(root, "", exclude, long_paths, &filter, &mut dirs)?;
+    }
+    let policy = options.on_short_name_collision;
+    let (mut colliding, mut directories) = (0, 0);
+    for dir in dirs.values() {
+        let mut numbered: BTreeMap<(String, String), Vec<&str>> = BTreeMap::new();
+        for name in dir.names.values() {
+            if let Some(basis) = numbered_under(name) {
+                numbered.entry(basis).or_default().push(name);
+            }
+        }
+        let before = colliding;
+        for ((stem, extension), names) in numbered.iter().filter(|(_, names)| names.len() > 1) {
+            colliding += names.len();
+            let short = match extension.as_str() {
+                "" => format!("{}~N", stem),
+                extension => format!("{}~N.{}", stem, extension),
+            };
+            let checksums = if names.len() > NUMBERED_TAILS {
+                format!(", all but {} get a checksum name", NUMBERED_TAILS)
+            } else {
+                String::new()
+            };
+            let message = format!("{} names in {} share the short name {}{}: {}\n", names.len(), dir.path, short, checksums, names.join(", "));
+            match policy {
+                OnShortNameCollision::Error => warn(message.as_str()),
+                OnShortNameCollision::Number => debug(message.as_str()),
+            }
+        }
+        if colliding > before {
+            directories += 1;
+        }
+    }
+    if colliding == 0 {
+        return Ok(());
+    }
+    if policy == OnShortNameCollision::Error {
+        return Err(UpdateError::Other(format!(
+            "{} names share their FAT short name with another name in the same directory, see above. \
+             Rename them, or run with --on-short-name-collision=number to let fatfs number them",
+            colliding
+        )));
+    }
+    info(format!(
+        "{} names in {} directories share a FAT short name with another, fatfs numbers them\n",
+        colliding, directories
+    ).as_str());
+    Ok(())
+}
@@ -0,0 +1,133 @@
+//! `--require-signature`: refuse to build a source that wasn't signed with a pinned key, in
+//! case upstream is compromised and starts serving malicious homebrew.
+//!
+//! The source has to carry a `MANIFEST` listing the BLAKE3 hash of every file, in the
+//! format `b3sum` prints (`<hash>  <path>`, paths relative to the source with `/`), and a
+//! `MANIFEST.sig` holding the Ed25519 signature over `MANIFEST`, as 64 raw bytes or hex.
+//! A first line `# hash: sha256` says the manifest was written with SHA-256 instead. It is
+//! part of what is signed, so the check always uses the algorithm the signer did, whatever
+//! `--hash` is set to. xxh3 isn't accepted: files could be swapped for others with the same
+//! hash without touching the signature.
+
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::path::Path;
+
+use ed25519_dalek::{Signature, VerifyingKey};
+
+use crate::error::UpdateError;
+use crate::hash::hash_reader;
+use crate::info;
+use crate::options::HashAlgorithm;
+
+const MANIFEST: &str = "MANIFEST";
+const MANIFEST_SIG: &str = "MANIFEST.sig";
+
+fn parse_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    let hex = hex.trim();
+    if hex.len() != N * 2 || !hex.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0_u8; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+fn untrusted(message: String) -> UpdateError {
+    UpdateError::UntrustedSource(message)
+}
+
+/// Every file `recursive_copy` would copy, as `/`-separated paths relative to `root`.
+fn source_files(root: &Path, prefix: &str, files: &mut BTreeSet<String>) -> std::io::Result<()> {
+    for entry in root.read_dir()? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') {
+            continue;
+        }
+        let path = format!("{}{}", prefix, name);
+        if std::fs::metadata(entry.path())?.is_dir() {
+            source_files(&entry.path(), &format!("{}/", path), files)?;
+        } else {
+            files.insert(path);
+        }
+    }
+    Ok(())
+}
+
+fn parse_key(public_key: &str) -> Result<VerifyingKey, UpdateError> {
+    let key = parse_hex::<32>(public_key)
+        .ok_or_else(|| UpdateError::Usage(format!("--require-signature expects a 64 digit hex Ed25519 public key, got '{}'", public_key)))?;
+    VerifyingKey::from_bytes(&key)
+        .map_err(|e| UpdateError::Usage(format!("--require-signature: invalid public key: {}", e)))
+}
+
+/// Checks that `signature`, as 64 raw bytes or hex, is `public_key`'s signature over
+/// `data`. `name` and `sig_name` say what was checked in the error.
+pub fn verify_bytes(public_key: &str, data: &[u8], signature: &[u8], name: &str, sig_name: &str) -> Result<(), UpdateError> {
+    let key = parse_key(public_key)?;
+    let signature = match <[u8; 64]>::try_from(signature) {
+        Ok(raw) => raw,
+        Err(_) => parse_hex::<64>(&String::from_utf8_lossy(signature))
+            .ok_or_else(|| untrusted(format!("{} is neither 64 raw bytes nor 128 hex digits", sig_name)))?,
+    };
+    key.verify_strict(data, &Signature::from_bytes(&signature))
+        .map_err(|_| untrusted(format!("The signature in {} doesn't match {} and the pinned key", sig_name, name)))
+}
+
+/// Checks the signature on the manifest of `sd_source_path` against `public_key` (64 hex
+/// digits), then checks every file against the manifest.
+pub fn verify_source(sd_source_path: &Path, public_key: &str) -> Result<(), UpdateError> {
+    // Reject a malformed key before complaining about a missing manifest.
+    parse_key(public_key)?;
+    let manifest = std::fs::read(sd_source_path.join(MANIFEST))
+        .map_err(|e| untrusted(format!("The source has no readable {} to check the signature of: {}", MANIFEST, e)))?;
+    let signature = std::fs::read(sd_source_path.join(MANIFEST_SIG))
+        .map_err(|e| untrusted(format!("The source has no readable {}: {}", MANIFEST_SIG, e)))?;
+    verify_bytes(public_key, &manifest, &signature, MANIFEST, MANIFEST_SIG)?;
+    info(format!("{} is signed by the pinned key {}\n", MANIFEST, &public_key.trim()[..16]).as_str());
+
+    let mut unlisted = BTreeSet::new();
+    source_files(sd_source_path, "", &mut unlisted)?;
+    unlisted.remove(MANIFEST);
+    unlisted.remove(MANIFEST_SIG);
+    let mut buffer = vec![0_u8; 1024 * 1024];
+    let manifest = String::from_utf8_lossy(&manifest);
+    let mut algorithm = HashAlgorithm::Blake3;
+    if let Some(name) = manifest.lines().next().and_then(|line| line.strip_prefix("# hash:")) {
+        algorithm = HashAlgorithm::from_name(name.trim())
+            .filter(|algorithm| *algorithm != HashAlgorithm::Xxh3)
+            .ok_or_else(|| untrusted(format!("{} is hashed with {}, which isn't blake3 or sha256", MANIFEST, name.trim())))?;
+    }
+    let mut checked = 0;
+    for (index, line) in manifest.lines().enumerate() {
+        if line.trim().is_empty() || (index == 0 && line.starts_with("# hash:")) {
+            continue;
+        }
+        let (hash, path) = line
+            .split_once(char::is_whitespace)
+            .map(|(hash, path)| (hash, path.trim_start()))
+            .ok_or_else(|| untrusted(format!("{} line {}: expected '<hash>  <path>'", MANIFEST, index + 1)))?;
+        let actual = match File::open(sd_source_path.join(path)) {
+            Ok(mut file) => hash_reader(&mut file, &mut buffer, algorithm)?,
+            Err(e) => return Err(untrusted(format!("{} is listed in {} but can't be read: {}", path, MANIFEST, e))),
+        };
+        if !actual.eq_ignore_ascii_case(hash) {
+            return Err(untrusted(format!("{} doesn't match its hash in {}", path, MANIFEST)));
+        }
+        unlisted.remove(path);
+        checked += 1;
+    }
+    if let Some(path) = unlisted.iter().next() {
+        return Err(untrusted(format!(
+            "{} files aren't listed in {}, e.g. {}",
+            unlisted.len(),
+            MANIFEST,
+            path
+        )));
+    }
+    info(format!("All {} files match the signed {}\n", checked, MANIFEST).as_str());
+    Ok(())
+}
@@ -0,0 +1,151 @@
+//! Checks that an update in place fits on the image before anything is copied. A fresh
+//! build learns it's out of space part way through, which costs nothing, the image gets
+//! decompressed again next time. An update in place that runs out has already replaced
+//! some files and not others, so it's checked up front.
+//!
+//! What counts is how many clusters the copy adds: a new file takes all of its clusters, a
+//! replaced one only what it grew by, and a new directory at least one for its table. Files
+//! that shrink aren't counted against ones that grow, the copy may not get to them first.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::delta::Delta;
+use crate::dest::{CardEntry, DestDir};
+use crate::dirsort::sorted_entries;
+use crate::error::UpdateError;
+use crate::image::{exclusion, FileFilter};
+use crate::longpath::LongPaths;
+use crate::options::Options;
+use crate::units::format_bytes;
+use crate::debug;
+
+struct Growth<'a> {
+    cluster_size: u64,
+    long_paths: &'a LongPaths,
+    filter: FileFilter,
+}
+
+impl Growth<'_> {
+    fn clusters(&self, len: u64) -> u64 {
+        len.div_ceil(self.cluster_size)
+    }
+
+    /// What copying a file of `len` bytes over `on_card` adds.
+    fn file(&self, len: u64, on_card: Option<&CardEntry>) -> u64 {
+        let old = on_card.filter(|entry| !entry.is_dir).map_or(0, |entry| self.clusters(entry.len));
+        self.clusters(len).saturating_sub(old)
+    }
+
+    /// What `recursive_copy` adds copying `host_path` into `sd_folder`, or into a new
+    /// directory for `None`.
+    fn tree<D: DestDir>(&self, host_path: &Path, sd_folder: Option<&D>, exclude: &[PathBuf]) -> Result<u64, UpdateError> {
+        let existing = match sd_folder {
+            Some(sd_folder) => sd_folder.entries()?,
+            None => HashMap::new(),
+        };
+        let mut clusters = 0;
+        for path in sorted_entries(host_path)? {
+            let path = path?;
+            if exclusion(&path, exclude, self.long_paths, &self.filter)?.is_some() {
+                continue;
+            }
+            let name = match self.long_paths.get(&path) {
+                Some(Some(shorter)) => shorter.clone(),
+                _ => path.file_name().unwrap().to_string_lossy().to_string(),
+            };
+            let on_card = existing.get(&name.to_lowercase());
+            // Follow symlinks, like the copy does.
+            let metadata = std::fs::metadata(&path)?;
+            clusters += match (metadata.is_dir(), on_card, sd_folder) {
+                (true, Some(entry), Some(sd_folder)) if entry.is_dir => self.tree(&path, Some(&sd_folder.open_dir(&entry.name)?), exclude)?,
+                (true, ..) => 1 + self.tree::<D>(&path, None, exclude)?,
+                (false, ..) => self.file(metadata.len(), on_card),
+            };
+        }
+        Ok(clusters)
+    }
+
+    /// What `recursive_copy` adds copying `host_path` into the directory at `dir` in
+    /// `sd_folder`, `--target-dir`, with the directories on the way that aren't there yet.
+    fn tree_in<D: DestDir>(&self, host_path: &Path, sd_folder: Option<&D>, dir: &str, exclude: &[PathBuf]) -> Result<u64, UpdateError> {
+        if dir.is_empty() {
+            return self.tree(host_path, sd_folder, exclude);
+        }
+        let (name, rest) = dir.split_once('/').unwrap_or((dir, ""));
+        let existing = match sd_folder {
+            Some(sd_folder) => sd_folder.entries()?,
+            None => HashMap::new(),
+        };
+        match (existing.get(&name.to_lowercase()), sd_folder) {
+            (Some(entry), Some(sd_folder)) if entry.is_dir => self.tree_in(host_path, Some(&sd_folder.open_dir(&entry.name)?), rest, exclude),
+            _ => Ok(1 + self.tree_in::<D>(host_path, None, rest, exclude)?),
+        }
+    }
+
+    /// What `copy_path` adds copying a file of `len` bytes to `path` in `sd_folder`.
+    fn path<D: DestDir>(&self, sd_folder: Option<&D>, path: &str, len: u64) -> Result<u64, UpdateError> {
+        let existing = match sd_folder {
+            Some(sd_folder) => sd_folder.entries()?,
+            None => HashMap::new(),
+        };
+        let Some((dir_name, rest)) = path.split_once('/') else {
+            return Ok(self.file(len, existing.get(&path.to_lowercase())));
+        };
+        match (existing.get(&dir_name.to_lowercase()), sd_folder) {
+            (Some(entry), Some(sd_folder)) if entry.is_dir => self.path(Some(&sd_folder.open_dir(&entry.name)?), rest, len),
+            _ => Ok(1 + self.path::<D>(None, rest, len)?),
+        }
+    }
+}
+
+/// Fails with `DiskFull` if copying the source and overlays, or only the `delta`, and the
+/// `--add-file` files into `root_dir` takes more than the `free_clusters` of `cluster_size`
+/// bytes left on it.
+pub(crate) fn check_free_space<D: DestDir>(
+    root_dir: &D,
+    sd_source_path: &Path,
+    options: &Options,
+    long_paths: &LongPaths,
+    delta: Option<&Delta>,
+    cluster_size: u64,
+    free_clusters: u64,
+) -> Result<(), UpdateError> {
+    let growth = Growth { cluster_size, long_paths, filter: FileFilter::new(options) };
+    let mut needed = 0;
+    let target_dir = options.target_dir.as_deref().unwrap_or_default();
+    match delta {
+        Some(delta) => {
+            let prefix = options.target_prefix();
+            for path in &delta.changed {
+                let host_path = sd_source_path.join(path);
+                let metadata = host_path.metadata()?;
+                if growth.filter.excludes(&host_path, &metadata).is_none() {
+                    needed += growth.path(Some(root_dir), &format!("{}{}", prefix, path), metadata.len())?;
+                }
+            }
+        }
+        None => {
+            let exclude: Vec<PathBuf> = options.exclude.iter().map(|path| sd_source_path.join(path)).collect();
+            needed += growth.tree_in(sd_source_path, Some(root_dir), target_dir, &exclude)?;
+            for overlay in &options.overlays {
+                needed += growth.tree_in(overlay, Some(root_dir), target_dir, &[])?;
+            }
+        }
+    }
+    for (host_path, path) in &options.add_files {
+        needed += growth.path(Some(root_dir), path, host_path.metadata()?.len())?;
+    }
+    let (needed, free) = (needed * cluster_size, free_clusters * cluster_size);
+    if needed > free {
+        return Err(UpdateError::DiskFull(format!(
+            "Updating {} in place needs {} more but it only has {} free, so nothing was copied. \
+             Rebuild it without --incremental, use a larger base image in sd.xz (with a matching --sd-size), or copy fewer files",
+            options.output().display(),
+            format_bytes(needed),
+            format_bytes(free)
+        )));
+    }
+    debug(format!("The update needs {} of the {} free\n", format_bytes(needed), format_bytes(free)).as_str());
+    Ok(())
+}
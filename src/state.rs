@@ -0,0 +1,83 @@
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Where the state of the last update is recorded, next to `sd.raw` and `sd_source`.
+pub const STATE_FILE: &str = "updater.state";
+
+/// What the last successful update check found. Stored as `key=value` lines.
+#[derive(Debug, Default)]
+pub struct UpdateState {
+    /// Unix timestamp of the last successful check against upstream.
+    pub last_check: Option<u64>,
+    /// The commit `sd_source` was at after that check.
+    pub last_commit: Option<String>,
+    /// The upstream commit the last pull merged. Upstream's next tip has to descend from
+    /// it unless it was force-pushed or rebased, see `--allow-rewrite`.
+    pub upstream: Option<String>,
+    /// Upstream's default branch, followed with `--follow-default-branch` since the
+    /// configured branch went away.
+    pub branch: Option<String>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl UpdateState {
+    /// Loads the state file, falling back to an empty state if it is missing or unreadable.
+    pub fn load(path: &Path) -> UpdateState {
+        let mut state = UpdateState::default();
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(_) => return state,
+        };
+        for line in contents.lines() {
+            let (key, value) = match line.split_once('=') {
+                Some(pair) => pair,
+                None => continue,
+            };
+            let value = value.trim();
+            match key.trim() {
+                "last_check" => state.last_check = value.parse().ok(),
+                "last_commit" if !value.is_empty() => state.last_commit = Some(value.to_string()),
+                "branch" if !value.is_empty() => state.branch = Some(value.to_string()),
+                "upstream" if !value.is_empty() => state.upstream = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        state
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let mut contents = String::new();
+        if let Some(last_check) = self.last_check {
+            contents.push_str(&format!("last_check={}\n", last_check));
+        }
+        if let Some(last_commit) = &self.last_commit {
+            contents.push_str(&format!("last_commit={}\n", last_commit));
+        }
+        if let Some(branch) = &self.branch {
+            contents.push_str(&format!("branch={}\n", branch));
+        }
+        if let Some(upstream) = &self.upstream {
+            contents.push_str(&format!("upstream={}\n", upstream));
+        }
+        std::fs::write(path, contents)
+    }
+
+    /// Time since the last successful check, if there was one.
+    pub fn since_last_check(&self) -> Option<Duration> {
+        self.last_check
+            .map(|last_check| Duration::from_secs(now().saturating_sub(last_check)))
+    }
+
+    pub fn record_check(&mut self, commit: Option<String>) {
+        self.last_check = Some(now());
+        if commit.is_some() {
+            self.last_commit = commit;
+        }
+    }
+}
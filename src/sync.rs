@@ -0,0 +1,45 @@
+//! Syncing the image to disk. Some network and FUSE filesystems reject fsync as not
+//! supported or as an invalid argument even though the data was written fine, which would
+//! fail the build at the very end. Unless `--strict-sync` asks for the error, that's a
+//! warning instead, and `--no-sync` skips syncing altogether.
+
+use std::fs::File;
+use std::io::{ErrorKind, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::error::UpdateError;
+use crate::options::Options;
+use crate::warn;
+
+/// The warning is given once per run, not for every sync of the decompression.
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+fn sync_with(file: &mut File, options: &Options, sync: fn(&File) -> std::io::Result<()>) -> Result<(), UpdateError> {
+    if options.no_sync {
+        return Ok(());
+    }
+    let e = match sync(file) {
+        Ok(()) => return Ok(()),
+        Err(e) => e,
+    };
+    if options.strict_sync || !matches!(e.kind(), ErrorKind::Unsupported | ErrorKind::InvalidInput) {
+        return Err(e.into());
+    }
+    // As much as can be done without a sync.
+    file.flush()?;
+    if !WARNED.swap(true, Ordering::Relaxed) {
+        warn(format!(
+            "The filesystem the image is on can't sync it to disk ({}), carrying on without. Use --strict-sync to fail instead\n",
+            e
+        ).as_str());
+    }
+    Ok(())
+}
+
+pub fn sync_all(file: &mut File, options: &Options) -> Result<(), UpdateError> {
+    sync_with(file, options, File::sync_all)
+}
+
+pub fn sync_data(file: &mut File, options: &Options) -> Result<(), UpdateError> {
+    sync_with(file, options, File::sync_data)
+}
@@ -0,0 +1,48 @@
+//! Reading the text files people edit by hand, `updater.conf` and the packaging manifest.
+//! Notepad and PowerShell on Windows save as UTF-16 or with a UTF-8 byte order mark, which
+//! `read_to_string` either rejects as invalid UTF-8 or hands to the parser as a stray
+//! character before the first key. Both are turned into plain UTF-8 here, with a warning
+//! so the file can be saved the usual way.
+
+use std::path::Path;
+
+use crate::warn;
+
+/// UTF-16 without a byte order mark is recognized by the zero half of its first character,
+/// since both files start with ASCII.
+fn encoding(bytes: &[u8]) -> Option<(&'static str, usize)> {
+    match bytes {
+        [0xef, 0xbb, 0xbf, ..] => Some(("UTF-8 with a byte order mark", 3)),
+        [0xff, 0xfe, ..] => Some(("UTF-16LE", 2)),
+        [0xfe, 0xff, ..] => Some(("UTF-16BE", 2)),
+        [first, 0, ..] if *first != 0 => Some(("UTF-16LE", 0)),
+        [0, second, ..] if *second != 0 => Some(("UTF-16BE", 0)),
+        _ => None,
+    }
+}
+
+fn invalid(encoding: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("not valid {}", encoding))
+}
+
+/// Reads `path` as text, from UTF-8 with or without a byte order mark, or UTF-16 of either
+/// byte order.
+pub(crate) fn read_text(path: &Path) -> std::io::Result<String> {
+    let bytes = std::fs::read(path)?;
+    let Some((name, bom)) = encoding(&bytes) else {
+        return String::from_utf8(bytes).map_err(|_| invalid("UTF-8"));
+    };
+    warn(format!("{} is saved as {}, reading it as that. Save it as plain UTF-8 to skip this\n", path.display(), name).as_str());
+    let body = &bytes[bom..];
+    if name.starts_with("UTF-8") {
+        return String::from_utf8(body.to_vec()).map_err(|_| invalid(name));
+    }
+    if body.len() % 2 != 0 {
+        return Err(invalid(name));
+    }
+    let units = body.chunks_exact(2).map(|pair| match name {
+        "UTF-16LE" => u16::from_le_bytes([pair[0], pair[1]]),
+        _ => u16::from_be_bytes([pair[0], pair[1]]),
+    });
+    char::decode_utf16(units).collect::<Result<String, _>>().map_err(|_| invalid(name))
+}
@@ -0,0 +1,73 @@
+//! `--write-throttle <rate>`: paces writes to the card to stay under a rate, for cheap SD
+//! cards and USB readers whose controllers stall or throw errors under a sustained full
+//! speed write. Covers the copy into the image or `--dest-dir`, and the decompression when
+//! it goes straight to a `--device`. Decompressing into an image file on the host is left
+//! at full speed, the host disk has no such trouble.
+//!
+//! The rate is averaged from the first write on, so a write that came in late can be caught
+//! up on, but never by more than a second's worth in a burst.
+
+use std::time::{Duration, Instant};
+
+use crate::report;
+
+/// How far behind the average the writes may fall before it stops counting, so a long pause
+/// somewhere else isn't followed by a burst at full speed.
+const MAX_CATCH_UP: Duration = Duration::from_secs(1);
+
+pub(crate) struct WriteThrottle {
+    /// Bytes per second.
+    rate: u64,
+    started: Instant,
+    /// Since `started`.
+    written: u64,
+    /// Time spent waiting so far, over every stretch.
+    waited: Duration,
+    total_written: u64,
+    first_write: Option<Instant>,
+}
+
+impl WriteThrottle {
+    pub(crate) fn new(rate: u64) -> WriteThrottle {
+        WriteThrottle {
+            rate,
+            started: Instant::now(),
+            written: 0,
+            waited: Duration::ZERO,
+            total_written: 0,
+            first_write: None,
+        }
+    }
+
+    /// Counts `bytes` just written, then waits until writing them fits under the rate.
+    pub(crate) fn pace(&mut self, bytes: u64) {
+        let now = Instant::now();
+        self.first_write.get_or_insert(now);
+        if now.duration_since(self.started) > self.due() + MAX_CATCH_UP {
+            self.started = now;
+            self.written = 0;
+        }
+        self.written += bytes;
+        self.total_written += bytes;
+        let due = self.due();
+        let elapsed = self.started.elapsed();
+        if due > elapsed {
+            std::thread::sleep(due - elapsed);
+            self.waited += due - elapsed;
+        }
+    }
+
+    /// How long writing `written` should have taken.
+    fn due(&self) -> Duration {
+        Duration::from_secs_f64(self.written as f64 / self.rate as f64)
+    }
+
+    /// Records the throttled writes for the summary, and returns the rate they went at and
+    /// how long the throttle held them back.
+    pub(crate) fn finish(&self) -> (u64, Duration) {
+        let elapsed = self.first_write.map_or(Duration::ZERO, |first_write| first_write.elapsed());
+        report::record_throttled_writes(self.total_written, elapsed, self.waited);
+        let rate = if elapsed.is_zero() { 0 } else { (self.total_written as f64 / elapsed.as_secs_f64()) as u64 };
+        (rate, self.waited)
+    }
+}
@@ -0,0 +1,98 @@
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use fatfs::{Date, DateTime, Time, TimeProvider};
+
+/// Converts a host timestamp to a FAT one. FAT has no time zone and stores seconds in
+/// 2-second steps between 1980 and 2107, so this uses UTC, rounds down to an even second
+/// and clamps to that range. Timestamps read back from the card compare equal to this.
+pub fn to_fat_datetime(time: SystemTime) -> DateTime {
+    let secs = match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(_) => 0,
+    };
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    if year < 1980 {
+        return DateTime::new(Date::new(1980, 1, 1), Time::new(0, 0, 0, 0));
+    }
+    if year > 2107 {
+        return DateTime::new(Date::new(2107, 12, 31), Time::new(23, 59, 58, 0));
+    }
+    let day_secs = secs.rem_euclid(86400);
+    DateTime::new(
+        Date::new(year as u16, month as u16, day as u16),
+        Time::new(
+            (day_secs / 3600) as u16,
+            (day_secs % 3600 / 60) as u16,
+            (day_secs % 60 / 2 * 2) as u16,
+            0,
+        ),
+    )
+}
+
+/// Converts a FAT timestamp back to a host one, reading it as UTC like `to_fat_datetime`
+/// writes it.
+pub fn from_fat_datetime(time: DateTime) -> SystemTime {
+    let days = days_from_civil(time.date.year as i64, time.date.month as u32, time.date.day as u32);
+    let secs = days * 86400 + time.time.hour as i64 * 3600 + time.time.min as i64 * 60 + time.time.sec as i64;
+    UNIX_EPOCH + std::time::Duration::from_secs(secs.max(0) as u64)
+}
+
+/// Formats a Unix timestamp as `2024-03-01 14:05 UTC`, for the log.
+pub fn format_utc(secs: i64) -> String {
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let day_secs = secs.rem_euclid(86400);
+    format!("{:04}-{:02}-{:02} {:02}:{:02} UTC", year, month, day, day_secs / 3600, day_secs % 3600 / 60)
+}
+
+/// Days since the Unix epoch to a (year, month, day) civil date.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// A (year, month, day) civil date to days since the Unix epoch.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Hands fatfs the timestamp of whatever is being copied right now, so entries on the
+/// card carry the source's modification time instead of a fixed 1980 date. fatfs asks
+/// the time provider when an entry is created and on every write.
+#[derive(Debug, Clone, Default)]
+pub struct SourceTimeProvider {
+    current: Rc<Cell<Option<DateTime>>>,
+}
+
+impl SourceTimeProvider {
+    pub fn set(&self, time: DateTime) {
+        self.current.set(Some(time));
+    }
+}
+
+impl TimeProvider for SourceTimeProvider {
+    fn get_current_date(&self) -> Date {
+        self.get_current_date_time().date
+    }
+
+    fn get_current_date_time(&self) -> DateTime {
+        self.current
+            .get()
+            .unwrap_or_else(|| fatfs::NullTimeProvider::new().get_current_date_time())
+    }
+}
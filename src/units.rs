@@ -0,0 +1,97 @@
+use std::time::Duration;
+
+/// Formats a byte count for humans, e.g. `1.5 MB`. Uses binary (1024) steps.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// The size suffixes `parse_bytes` takes, largest first. Card and image sizes are binary
+/// multiples, so `G`, `GB` and `GiB` all mean 1024³.
+const SUFFIXES: &[(&str, u64)] = &[("T", 1 << 40), ("G", 1 << 30), ("M", 1 << 20), ("K", 1 << 10)];
+
+/// Parses sizes like `4096`, `512M`, `2G` or `1.5GiB`. A bare number is bytes, and a
+/// fraction has to come out as a whole number of bytes.
+pub fn parse_bytes(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let invalid = || format!("'{}' is not a size, expected a number with an optional K, M, G or T suffix, like 512M or 1.5GiB", value);
+    let split = value.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let unit = unit.trim().to_ascii_uppercase();
+    let multiplier = match unit.as_str() {
+        "" | "B" => 1,
+        unit => SUFFIXES
+            .iter()
+            .find(|(suffix, _)| unit == *suffix || unit == format!("{}B", suffix) || unit == format!("{}IB", suffix))
+            .map(|(_, multiplier)| *multiplier)
+            .ok_or_else(invalid)?,
+    };
+    let (whole, fraction) = number.split_once('.').unwrap_or((number, ""));
+    if whole.is_empty() || (number.contains('.') && fraction.is_empty()) || fraction.len() > 12 || fraction.contains('.') {
+        return Err(invalid());
+    }
+    let too_large = || format!("'{}' is too large", value);
+    // Only digits are left, so this can only fail by overflowing.
+    let whole: u64 = whole.parse().map_err(|_| too_large())?;
+    let mut bytes = whole.checked_mul(multiplier).ok_or_else(too_large)?;
+    if !fraction.is_empty() {
+        // Exactly, without going through floating point.
+        let scale = 10_u128.pow(fraction.len() as u32);
+        let part = fraction.parse::<u128>().map_err(|_| invalid())? * multiplier as u128;
+        if part % scale != 0 {
+            return Err(format!("'{}' isn't a whole number of bytes", value));
+        }
+        bytes = bytes.checked_add((part / scale) as u64).ok_or_else(too_large)?;
+    }
+    Ok(bytes)
+}
+
+/// `bytes` as `parse_bytes` reads it back, in the largest suffix that keeps it exact with
+/// at most two decimals: `1.5G`, `512M`, or a plain `1000`.
+pub fn format_size(bytes: u64) -> String {
+    for (suffix, multiplier) in SUFFIXES {
+        let hundredths = bytes as u128 * 100;
+        if bytes >= *multiplier && hundredths % *multiplier as u128 == 0 {
+            let hundredths = hundredths / *multiplier as u128;
+            return match hundredths % 100 {
+                0 => format!("{}{}", hundredths / 100, suffix),
+                fraction if fraction % 10 == 0 => format!("{}.{}{}", hundredths / 100, fraction / 10, suffix),
+                fraction => format!("{}.{:02}{}", hundredths / 100, fraction, suffix),
+            };
+        }
+    }
+    bytes.to_string()
+}
+
+/// The duration suffixes `parse_duration` takes, in seconds.
+const DURATION_UNITS: &[(&str, u64)] = &[("s", 1), ("m", 60), ("h", 60 * 60), ("d", 60 * 60 * 24)];
+
+/// Parses durations like `90`, `45s`, `30m`, `48h` or `7d`. A bare number is seconds.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let invalid = || format!("'{}' is not a duration, expected a whole number with an optional s, m, h or d suffix, like 30m, 48h or 7d", value);
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    if number.is_empty() {
+        return Err(invalid());
+    }
+    let unit = unit.trim().to_ascii_lowercase();
+    let multiplier = match unit.as_str() {
+        "" => 1,
+        unit => DURATION_UNITS.iter().find(|(suffix, _)| unit == *suffix).map(|(_, multiplier)| *multiplier).ok_or_else(invalid)?,
+    };
+    let too_long = || format!("'{}' is too long", value);
+    // Only digits are left, so this can only fail by overflowing.
+    let number: u64 = number.parse().map_err(|_| too_long())?;
+    Ok(Duration::from_secs(number.checked_mul(multiplier).ok_or_else(too_long)?))
+}
@@ -0,0 +1,147 @@
+//! `--output-format vhd`: the image as a fixed VHD, which Windows mounts as a virtual disk
+//! and VMs take as a drive. A fixed VHD is the raw image followed by a 512-byte footer that
+//! describes it, so the image is built raw as always and the footer goes on at the end.
+//! The next build takes it off again before it writes anything, so an update in place
+//! works on a VHD as on a raw image, with or without `--output-format vhd`.
+
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::UpdateError;
+use crate::options::Options;
+use crate::{debug, info};
+
+const FOOTER_LEN: u64 = 512;
+const COOKIE: &[u8; 8] = b"conectix";
+/// The footer's fields are big-endian, at these offsets.
+const CURRENT_SIZE: usize = 48;
+const DISK_TYPE: usize = 60;
+const CHECKSUM: usize = 64;
+const FIXED_DISK: u32 = 2;
+/// VHD timestamps count from 2000-01-01 00:00:00 UTC.
+const VHD_EPOCH: u64 = 946_684_800;
+
+/// The CHS geometry the VHD spec derives from the size, which Windows checks.
+fn geometry(size: u64) -> (u16, u8, u8) {
+    let total_sectors = (size / 512).min(65535 * 16 * 255);
+    let (sectors_per_track, heads, cylinder_times_heads) = if total_sectors >= 65535 * 16 * 63 {
+        (255, 16, total_sectors / 255)
+    } else {
+        let mut sectors_per_track = 17;
+        let mut cylinder_times_heads = total_sectors / sectors_per_track;
+        let mut heads = cylinder_times_heads.div_ceil(1024).max(4);
+        if cylinder_times_heads >= heads * 1024 || heads > 16 {
+            sectors_per_track = 31;
+            heads = 16;
+            cylinder_times_heads = total_sectors / sectors_per_track;
+        }
+        if cylinder_times_heads >= heads * 1024 {
+            sectors_per_track = 63;
+            heads = 16;
+            cylinder_times_heads = total_sectors / sectors_per_track;
+        }
+        (sectors_per_track, heads, cylinder_times_heads)
+    };
+    ((cylinder_times_heads / heads) as u16, heads as u8, sectors_per_track as u8)
+}
+
+/// The ones' complement of the sum of every footer byte but the checksum's own.
+fn checksum(footer: &[u8]) -> u32 {
+    let sum = footer
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !(CHECKSUM..CHECKSUM + 4).contains(i))
+        .fold(0_u32, |sum, (_, byte)| sum.wrapping_add(*byte as u32));
+    !sum
+}
+
+/// A disk's unique ID, from what makes this build different from another.
+fn unique_id(output: &Path, now: &std::time::Duration) -> [u8; 16] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(output.to_string_lossy().as_bytes());
+    hasher.update(&now.as_nanos().to_le_bytes());
+    hasher.update(&std::process::id().to_le_bytes());
+    let mut id = [0_u8; 16];
+    id.copy_from_slice(&hasher.finalize().as_bytes()[..16]);
+    // A random (version 4) UUID, as far as anything reading it can tell.
+    id[6] = (id[6] & 0x0f) | 0x40;
+    id[8] = (id[8] & 0x3f) | 0x80;
+    id
+}
+
+/// The footer of a fixed VHD of `size` bytes of data.
+fn footer(size: u64, output: &Path) -> [u8; FOOTER_LEN as usize] {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut footer = [0_u8; FOOTER_LEN as usize];
+    footer[0..8].copy_from_slice(COOKIE);
+    // Features: reserved, always set.
+    footer[8..12].copy_from_slice(&2_u32.to_be_bytes());
+    // Format version 1.0.
+    footer[12..16].copy_from_slice(&0x0001_0000_u32.to_be_bytes());
+    // Data offset: none, a fixed disk has no dynamic header.
+    footer[16..24].copy_from_slice(&u64::MAX.to_be_bytes());
+    footer[24..28].copy_from_slice(&(now.as_secs().saturating_sub(VHD_EPOCH) as u32).to_be_bytes());
+    footer[28..32].copy_from_slice(b"dau ");
+    footer[32..36].copy_from_slice(&0x0001_0000_u32.to_be_bytes());
+    footer[36..40].copy_from_slice(b"Wi2k");
+    // Original and current size.
+    footer[40..48].copy_from_slice(&size.to_be_bytes());
+    footer[CURRENT_SIZE..CURRENT_SIZE + 8].copy_from_slice(&size.to_be_bytes());
+    let (cylinders, heads, sectors_per_track) = geometry(size);
+    footer[56..58].copy_from_slice(&cylinders.to_be_bytes());
+    footer[58] = heads;
+    footer[59] = sectors_per_track;
+    footer[DISK_TYPE..DISK_TYPE + 4].copy_from_slice(&FIXED_DISK.to_be_bytes());
+    footer[68..84].copy_from_slice(&unique_id(output, &now));
+    let checksum = checksum(&footer);
+    footer[CHECKSUM..CHECKSUM + 4].copy_from_slice(&checksum.to_be_bytes());
+    footer
+}
+
+/// Whether `footer` is a fixed VHD footer for `size` bytes of data in front of it.
+fn is_footer(footer: &[u8], size: u64) -> bool {
+    let field = |offset: usize, len: usize| footer[offset..offset + len].iter().fold(0_u64, |value, byte| (value << 8) | *byte as u64);
+    &footer[0..8] == COOKIE
+        && field(CURRENT_SIZE, 8) == size
+        && field(DISK_TYPE, 4) == FIXED_DISK as u64
+        && field(CHECKSUM, 4) == checksum(footer) as u64
+}
+
+/// Takes the footer an earlier `--output-format vhd` build put on the image at `--output`
+/// off again, so the build goes on with the raw image.
+pub(crate) fn strip(options: &Options) -> Result<(), UpdateError> {
+    if options.dest_dir.is_some() || options.device.is_some() {
+        return Ok(());
+    }
+    let output = options.output();
+    let Ok(mut image) = OpenOptions::new().read(true).write(true).open(&output) else {
+        return Ok(());
+    };
+    let len = image.metadata()?.len();
+    if len < FOOTER_LEN {
+        return Ok(());
+    }
+    let mut footer = [0_u8; FOOTER_LEN as usize];
+    image.seek(SeekFrom::Start(len - FOOTER_LEN))?;
+    image.read_exact(&mut footer)?;
+    if is_footer(&footer, len - FOOTER_LEN) {
+        image.set_len(len - FOOTER_LEN)?;
+        debug(format!("Took the VHD footer off {} to build it\n", output.display()).as_str());
+    }
+    Ok(())
+}
+
+/// Appends the VHD footer to the finished image at `output`.
+pub(crate) fn wrap(output: &Path) -> Result<(), UpdateError> {
+    let mut image = OpenOptions::new().append(true).open(output)?;
+    let size = image.metadata()?.len();
+    if !size.is_multiple_of(512) {
+        return Err(UpdateError::Other(format!("{} isn't a whole number of sectors, it can't be made a VHD", output.display())));
+    }
+    image.write_all(&footer(size, output))?;
+    image.sync_all()?;
+    info(format!("Wrapped {} as a fixed VHD\n", output.display()).as_str());
+    Ok(())
+}
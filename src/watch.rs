@@ -0,0 +1,87 @@
+//! `--watch <interval>`: stays running and checks upstream every interval, updating the
+//! image in place whenever something changed, for always-on setups that keep a card image
+//! current on a home server.
+//!
+//! Each cycle takes the lock like a one-shot run, so a manual run in between waits its
+//! turn or is waited for. A failed cycle doesn't end the watch: the next one comes sooner,
+//! backing off up to the interval. Ctrl+C stops after the cycle that is running, a second
+//! one stops right away.
+
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::cancel::{self, catch_ctrl_c};
+use crate::error::UpdateError;
+use crate::lock::{self, LOCK_FILE};
+use crate::logging::error;
+use crate::options::Options;
+use crate::{head_commit, info, run_with_retries, warn, Outcome};
+
+/// How long after a failed cycle the next one starts. Doubles with every failure in a row,
+/// up to the interval.
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// How often a sleep checks whether Ctrl+C was pressed.
+const STOP_POLL: Duration = Duration::from_millis(200);
+
+/// Sleeps for `duration`, returning early with `false` if Ctrl+C was pressed.
+fn sleep(duration: Duration) -> bool {
+    let until = Instant::now() + duration;
+    while Instant::now() < until {
+        if cancel::requested() {
+            return false;
+        }
+        std::thread::sleep(STOP_POLL.min(until - Instant::now()));
+    }
+    !cancel::requested()
+}
+
+fn run_cycle(options: &Options, sd_source_path: &Path) -> Result<Outcome, UpdateError> {
+    let _lock = lock::acquire(Path::new(LOCK_FILE), options.wait)?;
+    run_with_retries(options, sd_source_path)
+}
+
+/// Runs a cycle every `interval` until Ctrl+C.
+pub fn watch(options: &Options, sd_source_path: &Path, interval: Duration) -> Result<(), UpdateError> {
+    catch_ctrl_c("Stopping after this cycle, press Ctrl+C again to stop right away\n")?;
+    // Every cycle is a check, and the image is only ever updated in place.
+    let mut cycle_options = options.clone();
+    cycle_options.min_interval = None;
+    cycle_options.incremental = true;
+    info(format!("Watching for upstream changes every {}s, press Ctrl+C to stop\n", interval.as_secs()).as_str());
+    let mut cycle = 0;
+    let mut failures = 0;
+    loop {
+        cycle += 1;
+        let started = Instant::now();
+        let wait = match run_cycle(&cycle_options, sd_source_path) {
+            Ok(outcome) => {
+                failures = 0;
+                let commit = git2::Repository::open(sd_source_path).ok().and_then(|repo| head_commit(&repo));
+                info(format!(
+                    "Cycle {}: {} (commit {}, {}s)\n",
+                    cycle,
+                    outcome.describe(),
+                    commit.as_deref().map_or("none", |commit| &commit[..8]),
+                    started.elapsed().as_secs()
+                ).as_str());
+                interval
+            }
+            Err(e) => {
+                failures += 1;
+                let mut wait = (RETRY_DELAY * (1 << (failures - 1).min(6))).min(interval);
+                // The rate limit wins over the interval, the next cycle would only hit it again.
+                if let Some(limit) = e.rate_limit_delay() {
+                    wait = wait.max(limit);
+                    warn(format!("Cycle {}: upstream's rate limit was hit, waiting {}s\n", cycle, wait.as_secs()).as_str());
+                }
+                error(format!("Cycle {}: failed, {} (trying again in {}s)\n", cycle, e, wait.as_secs()).as_str());
+                wait
+            }
+        };
+        if cancel::requested() || !sleep(wait) {
+            info("Stopped watching\n");
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// The parts of a FAT boot sector needed to find the FAT, the root directory and the data
+/// region.
+pub(crate) struct Layout {
+    pub bytes_per_sector: u64,
+    pub sectors_per_cluster: u64,
+    pub fat_start: u64,
+    pub fat_bytes: u64,
+    /// Where the fixed root directory of FAT12 and FAT16 starts and how long it is. FAT32
+    /// keeps its root directory in clusters, starting at `root_cluster`.
+    pub root_dir_start: u64,
+    pub root_dir_bytes: u64,
+    pub root_cluster: u32,
+    pub data_start: u64,
+    pub clusters: u32,
+    pub fat_type: fatfs::FatType,
+}
+
+fn u16_at(sector: &[u8], at: usize) -> u64 {
+    u16::from_le_bytes([sector[at], sector[at + 1]]) as u64
+}
+
+fn u32_at(sector: &[u8], at: usize) -> u64 {
+    u32::from_le_bytes([sector[at], sector[at + 1], sector[at + 2], sector[at + 3]]) as u64
+}
+
+pub(crate) fn read_boot_sector(image: &mut File, offset: u64) -> std::io::Result<[u8; 512]> {
+    let mut sector = [0_u8; 512];
+    image.seek(SeekFrom::Start(offset))?;
+    image.read_exact(&mut sector)?;
+    Ok(sector)
+}
+
+/// Why `sector` isn't the boot sector of a FAT filesystem, naming the bytes that gave it
+/// away, or `None` if it looks like one.
+pub(crate) fn boot_sector_problem(sector: &[u8; 512]) -> Option<String> {
+    let hex = |bytes: &[u8]| bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(" ");
+    if sector[510..] != [0x55, 0xAA] {
+        return Some(format!("it has no boot sector signature, it starts with {}", hex(&sector[..16])));
+    }
+    let bytes_per_sector = u16_at(sector, 11);
+    if !matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096) {
+        // An MBR carries the same signature, but no BPB.
+        if sector[446 + 4] != 0 {
+            return Some("it starts with a partition table, not a filesystem".to_string());
+        }
+        return Some(format!("it claims {} bytes per sector ({})", bytes_per_sector, hex(&sector[11..13])));
+    }
+    if !sector[13].is_power_of_two() {
+        return Some(format!("it claims {} sectors per cluster", sector[13]));
+    }
+    if u16_at(sector, 14) == 0 || sector[16] == 0 {
+        return Some(format!("it claims {} reserved sectors and {} FATs", u16_at(sector, 14), sector[16]));
+    }
+    None
+}
+
+pub(crate) fn read_layout(image: &mut File, offset: u64) -> std::io::Result<Layout> {
+    let sector = read_boot_sector(image, offset)?;
+    if let Some(problem) = boot_sector_problem(&sector) {
+        return Err(std::io::Error::other(format!("The image isn't a FAT filesystem: {}", problem)));
+    }
+    let bytes_per_sector = u16_at(&sector, 11);
+    let sectors_per_cluster = sector[13] as u64;
+    let reserved_sectors = u16_at(&sector, 14);
+    let fats = sector[16] as u64;
+    let root_entries = u16_at(&sector, 17);
+    let total_sectors = match u16_at(&sector, 19) {
+        0 => u32_at(&sector, 32),
+        n => n,
+    };
+    let sectors_per_fat = match u16_at(&sector, 22) {
+        0 => u32_at(&sector, 36),
+        n => n,
+    };
+    let root_dir_sectors = (root_entries * 32).div_ceil(bytes_per_sector);
+    let data_sector = reserved_sectors + fats * sectors_per_fat + root_dir_sectors;
+    let clusters = (total_sectors.saturating_sub(data_sector) / sectors_per_cluster) as u32;
+    // FAT type is defined by the cluster count alone.
+    let fat_type = if clusters < 4085 {
+        fatfs::FatType::Fat12
+    } else if clusters < 65525 {
+        fatfs::FatType::Fat16
+    } else {
+        fatfs::FatType::Fat32
+    };
+    Ok(Layout {
+        bytes_per_sector,
+        sectors_per_cluster,
+        fat_start: offset + reserved_sectors * bytes_per_sector,
+        fat_bytes: sectors_per_fat * bytes_per_sector,
+        root_dir_start: offset + (reserved_sectors + fats * sectors_per_fat) * bytes_per_sector,
+        root_dir_bytes: root_entries * 32,
+        root_cluster: u32_at(&sector, 44) as u32,
+        data_start: offset + data_sector * bytes_per_sector,
+        clusters,
+        fat_type,
+    })
+}
+
+/// The FAT entry of `cluster`: 0 if it's free, otherwise the next cluster of its chain or
+/// an end-of-chain marker.
+pub(crate) fn fat_entry(fat: &[u8], fat_type: fatfs::FatType, cluster: u32) -> u32 {
+    let cluster = cluster as usize;
+    match fat_type {
+        fatfs::FatType::Fat12 => {
+            let at = cluster + cluster / 2;
+            let pair = u16::from_le_bytes([fat[at], fat[at + 1]]);
+            (if cluster.is_multiple_of(2) { pair & 0x0FFF } else { pair >> 4 }) as u32
+        }
+        fatfs::FatType::Fat16 => u16_at(fat, cluster * 2) as u32,
+        // The top four bits of a FAT32 entry are reserved.
+        fatfs::FatType::Fat32 => u32_at(fat, cluster * 4) as u32 & 0x0FFF_FFFF,
+    }
+}
+
+pub(crate) fn read_fat(image: &mut File, layout: &Layout) -> std::io::Result<Vec<u8>> {
+    let mut fat = vec![0_u8; layout.fat_bytes as usize];
+    image.seek(SeekFrom::Start(layout.fat_start))?;
+    image.read_exact(&mut fat)?;
+    Ok(fat)
+}
+
+/// The FAT type of the filesystem starting `offset` bytes into `image`, read from its boot
+/// sector without mounting it.
+pub fn fat_type(image: &mut File, offset: u64) -> std::io::Result<fatfs::FatType> {
+    Ok(read_layout(image, offset)?.fat_type)
+}
+
+/// Zeroes every cluster the first FAT marks as free in the filesystem starting at
+/// `offset`, and returns how many bytes that was. Must run after the filesystem is
+/// unmounted so the FAT on disk is final, and leaves syncing to the caller. Device targets
+/// are zeroed too rather than discarded, since there's no portable way to issue a discard.
+pub fn wipe_free_clusters(image: &mut File, offset: u64) -> std::io::Result<u64> {
+    let layout = read_layout(image, offset)?;
+    let fat = read_fat(image, &layout)?;
+
+    let cluster_bytes = layout.bytes_per_sector * layout.sectors_per_cluster;
+    let zeroes = vec![0_u8; cluster_bytes as usize];
+    let mut wiped = 0;
+    // Data clusters are numbered from 2.
+    for cluster in 2..layout.clusters + 2 {
+        if fat_entry(&fat, layout.fat_type, cluster) != 0 {
+            continue;
+        }
+        image.seek(SeekFrom::Start(layout.data_start + (cluster as u64 - 2) * cluster_bytes))?;
+        image.write_all(&zeroes)?;
+        wiped += cluster_bytes;
+    }
+    Ok(wiped)
+}
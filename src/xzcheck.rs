@@ -0,0 +1,156 @@
+//! A quick structural check of an `.xz` file, so a truncated or corrupt archive is reported
+//! before any decompression work is done. Only the headers, footers and indexes of the
+//! streams are read; the compressed blocks themselves are left to the decoder.
+
+use std::io::{Read, Seek, SeekFrom};
+
+const HEADER_MAGIC: [u8; 6] = [0xFD, b'7', b'z', b'X', b'Z', 0x00];
+const FOOTER_MAGIC: [u8; 2] = [b'Y', b'Z'];
+
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0_u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Reads an xz variable-length integer from the start of `bytes`, returning it and its length.
+fn varint(bytes: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0_u64;
+    for (i, byte) in bytes.iter().enumerate().take(9) {
+        value |= ((byte & 0x7F) as u64) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+/// Checks the stream header of the stream starting at `start`, returning its flags.
+fn check_header<R: Read + Seek>(archive: &mut R, start: u64) -> Result<[u8; 2], String> {
+    let mut header = [0_u8; 12];
+    archive.seek(SeekFrom::Start(start)).map_err(|e| e.to_string())?;
+    archive
+        .read_exact(&mut header)
+        .map_err(|_| "too short to be an xz archive".to_string())?;
+    if header[..6] != HEADER_MAGIC {
+        return Err("not an xz archive (bad header magic)".to_string());
+    }
+    if crc32(&header[6..8]) != le_u32(&header[8..12]) {
+        return Err("the stream header is corrupt".to_string());
+    }
+    Ok([header[6], header[7]])
+}
+
+/// One stream of an archive, as its footer and index describe it.
+pub(crate) struct Stream {
+    /// Where its header starts.
+    pub start: u64,
+    /// Where its index starts, right after the last block.
+    pub index_start: u64,
+    /// Where its footer ends, before any padding.
+    pub end: u64,
+    pub flags: [u8; 2],
+    /// The unpadded and the uncompressed size of every block, in order.
+    pub blocks: Vec<(u64, u64)>,
+}
+
+impl Stream {
+    pub fn uncompressed(&self) -> u64 {
+        self.blocks.iter().map(|(_, size)| size).sum()
+    }
+}
+
+/// Checks the footer and index of the stream ending at `end` and returns what they say.
+fn check_stream_end<R: Read + Seek>(archive: &mut R, end: u64) -> Result<Stream, String> {
+    let mut footer = [0_u8; 12];
+    archive.seek(SeekFrom::Start(end - 12)).map_err(|e| e.to_string())?;
+    archive.read_exact(&mut footer).map_err(|e| e.to_string())?;
+    if footer[10..12] != FOOTER_MAGIC {
+        return Err("the archive is truncated or corrupt (bad footer magic)".to_string());
+    }
+    if crc32(&footer[4..10]) != le_u32(&footer[0..4]) {
+        return Err("the stream footer is corrupt".to_string());
+    }
+
+    let index_size = (le_u32(&footer[4..8]) as u64 + 1) * 4;
+    if index_size + 24 > end {
+        return Err("the index size in the footer is out of range".to_string());
+    }
+    let index_start = end - 12 - index_size;
+    let mut index = vec![0_u8; index_size as usize];
+    archive.seek(SeekFrom::Start(index_start)).map_err(|e| e.to_string())?;
+    archive.read_exact(&mut index).map_err(|e| e.to_string())?;
+    let (body, stored_crc) = index.split_at(index.len() - 4);
+    if body.first() != Some(&0x00) || crc32(body) != le_u32(stored_crc) {
+        return Err("the index is corrupt".to_string());
+    }
+    let bad_index = || "the index can't be parsed".to_string();
+    let (records, mut at) = varint(&body[1..]).ok_or_else(bad_index)?;
+    at += 1;
+    let mut blocks_size = 0_u64;
+    let mut blocks = Vec::new();
+    for _ in 0..records {
+        let (unpadded, len) = varint(body.get(at..).ok_or_else(bad_index)?).ok_or_else(bad_index)?;
+        at += len;
+        let (size, len) = varint(body.get(at..).ok_or_else(bad_index)?).ok_or_else(bad_index)?;
+        at += len;
+        // Blocks are padded to a multiple of four bytes.
+        blocks_size += unpadded.div_ceil(4) * 4;
+        blocks.push((unpadded, size));
+    }
+    if body[at..].iter().any(|byte| *byte != 0) {
+        return Err("the index has trailing garbage".to_string());
+    }
+    let start = index_start
+        .checked_sub(blocks_size + 12)
+        .ok_or_else(|| "the index describes more data than the archive holds".to_string())?;
+    Ok(Stream { start, index_start, end, flags: [footer[8], footer[9]], blocks })
+}
+
+/// Checks the container structure of `archive`, which may hold several concatenated
+/// streams, and returns the uncompressed size their indexes add up to.
+pub fn check<R: Read + Seek>(archive: &mut R) -> Result<u64, String> {
+    Ok(streams(archive)?.iter().map(Stream::uncompressed).sum())
+}
+
+/// Checks the container structure of `archive` like `check`, and returns its streams in
+/// order.
+pub(crate) fn streams<R: Read + Seek>(archive: &mut R) -> Result<Vec<Stream>, String> {
+    check_header(archive, 0)?;
+    let mut end = archive.seek(SeekFrom::End(0)).map_err(|e| e.to_string())?;
+    let mut streams = Vec::new();
+    // Walk the streams back to front, since only the footer says where a stream starts.
+    while end > 0 {
+        // Streams may be followed by padding in multiples of four zero bytes.
+        let mut padding = [0_u8; 4];
+        loop {
+            if end < 24 {
+                return Err("the archive is truncated (no stream footer)".to_string());
+            }
+            archive.seek(SeekFrom::Start(end - 4)).map_err(|e| e.to_string())?;
+            archive.read_exact(&mut padding).map_err(|e| e.to_string())?;
+            if padding != [0, 0, 0, 0] {
+                break;
+            }
+            end -= 4;
+        }
+        let stream = check_stream_end(archive, end)?;
+        if check_header(archive, stream.start)? != stream.flags {
+            return Err("a stream header and its footer don't match".to_string());
+        }
+        end = stream.start;
+        streams.push(stream);
+    }
+    archive.seek(SeekFrom::Start(0)).map_err(|e| e.to_string())?;
+    streams.reverse();
+    Ok(streams)
+}
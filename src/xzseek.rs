@@ -0,0 +1,138 @@
+//! Starting the decompression of `sd.xz` part way in, to resume one that was interrupted,
+//! see `resume.rs`. xz can't be decoded from the middle of a block, but the index of every
+//! stream says where each of its blocks starts and how much it decompresses to. So the
+//! decoder is handed the header of the stream holding the first byte still needed, that
+//! stream's blocks from the one holding it on, an index rebuilt for just those blocks, and
+//! then the rest of the archive as it is: a valid archive again, which decompresses to the
+//! tail of the image from the start of that block.
+//!
+//! Plain `xz` writes one block per stream, which leaves nothing to skip to unless the
+//! archive is several streams. `xz -T0` or `--block-size` write many.
+
+use std::collections::VecDeque;
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::xzcheck::{crc32, streams, Stream};
+
+/// Stream headers and footers are 12 bytes.
+const HEADER_SIZE: u64 = 12;
+
+fn push_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// The index and footer of a stream that holds only `blocks`.
+fn index_and_footer(blocks: &[(u64, u64)], flags: [u8; 2]) -> Vec<u8> {
+    let mut index = vec![0x00];
+    push_varint(&mut index, blocks.len() as u64);
+    for (unpadded, uncompressed) in blocks {
+        push_varint(&mut index, *unpadded);
+        push_varint(&mut index, *uncompressed);
+    }
+    index.resize(index.len().div_ceil(4) * 4, 0);
+    index.extend_from_slice(&crc32(&index).to_le_bytes());
+    // The footer stores the index size in units of four bytes, minus one.
+    let mut footer = (index.len() as u32 / 4 - 1).to_le_bytes().to_vec();
+    footer.extend_from_slice(&flags);
+    let mut tail = index;
+    tail.extend_from_slice(&crc32(&footer).to_le_bytes());
+    tail.extend_from_slice(&footer);
+    tail.extend_from_slice(b"YZ");
+    tail
+}
+
+enum Part {
+    Bytes(Vec<u8>),
+    /// `len` bytes of the archive from `start`.
+    Range { start: u64, len: u64 },
+}
+
+/// The archive put back together to start at a block, see above.
+pub(crate) struct Resumed<R> {
+    archive: R,
+    parts: VecDeque<Part>,
+    /// Where `archive` is positioned, so reading on through a range doesn't seek each time.
+    position: Option<u64>,
+}
+
+impl<R: Read + Seek> Read for Resumed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while let Some(part) = self.parts.front_mut() {
+            let read = match part {
+                Part::Bytes(bytes) => {
+                    let read = bytes.len().min(buf.len());
+                    buf[..read].copy_from_slice(&bytes[..read]);
+                    bytes.drain(..read);
+                    read
+                }
+                Part::Range { start, len } => {
+                    let wanted = (*len).min(buf.len() as u64) as usize;
+                    if self.position != Some(*start) {
+                        self.archive.seek(SeekFrom::Start(*start))?;
+                    }
+                    let read = self.archive.read(&mut buf[..wanted])?;
+                    if read == 0 && wanted > 0 {
+                        return Err(std::io::ErrorKind::UnexpectedEof.into());
+                    }
+                    *start += read as u64;
+                    *len -= read as u64;
+                    self.position = Some(*start);
+                    read
+                }
+            };
+            let finished = match part {
+                Part::Bytes(bytes) => bytes.is_empty(),
+                Part::Range { len, .. } => *len == 0,
+            };
+            if finished {
+                self.parts.pop_front();
+            }
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+        }
+        Ok(0)
+    }
+}
+
+/// The block of `streams` that decompresses to the byte at `needed`: its stream, its number
+/// in the stream and the uncompressed offset it starts at.
+fn block_holding(streams: &[Stream], needed: u64) -> Option<(&Stream, usize, u64)> {
+    let mut offset = 0;
+    for stream in streams {
+        for (number, (_, size)) in stream.blocks.iter().enumerate() {
+            if needed < offset + size {
+                return Some((stream, number, offset));
+            }
+            offset += size;
+        }
+    }
+    None
+}
+
+/// Starts `archive` at the last block boundary at or before the uncompressed offset
+/// `needed`. Returns the offset it starts at and the archive from there on, or `None` when
+/// that is the very start or `needed` is past the end.
+pub(crate) fn resume_at<R: Read + Seek>(mut archive: R, needed: u64) -> Result<Option<(u64, Resumed<R>)>, String> {
+    let streams = streams(&mut archive)?;
+    let Some((stream, number, offset)) = block_holding(&streams, needed).filter(|(_, _, offset)| *offset > 0) else {
+        return Ok(None);
+    };
+    let mut header = vec![0; HEADER_SIZE as usize];
+    archive.seek(SeekFrom::Start(stream.start)).map_err(|e| e.to_string())?;
+    archive.read_exact(&mut header).map_err(|e| e.to_string())?;
+    let skipped: u64 = stream.blocks[..number].iter().map(|(unpadded, _)| unpadded.div_ceil(4) * 4).sum();
+    let block_start = stream.start + HEADER_SIZE + skipped;
+    let archive_end = archive.seek(SeekFrom::End(0)).map_err(|e| e.to_string())?;
+    let parts = VecDeque::from([
+        Part::Bytes(header),
+        Part::Range { start: block_start, len: stream.index_start - block_start },
+        Part::Bytes(index_and_footer(&stream.blocks[number..], stream.flags)),
+        Part::Range { start: stream.end, len: archive_end - stream.end },
+    ]);
+    Ok(Some((offset, Resumed { archive, parts, position: None })))
+}